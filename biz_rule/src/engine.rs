@@ -0,0 +1,248 @@
+use crate::rule::{Action, BizRule, RULE_SETTING};
+use card::events;
+use card::events::CardEvent;
+use card::query::Condition;
+use card::settings::SettingStore;
+use common::newtypes::tenant_id::TenantId;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::{error, fmt};
+use tokio::sync::broadcast::error::RecvError;
+
+//规则链的最大长度，超过后不再触发规则，防止多条规则之间互相触发
+pub const MAX_CHAIN_DEPTH: usize = 8;
+
+//规则的条件判断和动作执行，生产环境使用图数据库和卡片存储实现
+pub trait RuleExecutor {
+    fn matches(&self, event: &CardEvent, condition: &Condition) -> impl Future<Output = bool> + Send;
+    fn execute(&self, event: &CardEvent, action: &Action) -> impl Future<Output = Result<(), RuleError>> + Send;
+}
+
+pub struct RuleEngine<E: RuleExecutor> {
+    rules: RwLock<HashMap<TenantId, Vec<BizRule>>>, //按组织分组
+    executor: E,
+}
+
+impl<E: RuleExecutor + Send + Sync + 'static> RuleEngine<E> {
+    pub fn new(executor: E) -> Self {
+        Self {
+            rules: RwLock::new(HashMap::new()),
+            executor,
+        }
+    }
+
+    pub fn add_rule(&self, rule: BizRule) {
+        let mut rules = self.rules.write().unwrap();
+        rules.entry(rule.org_id.clone()).or_default().push(rule);
+    }
+
+    //替换id相同的规则，保持规则的顺序，不存在时追加
+    pub fn put_rule(&self, rule: BizRule) {
+        let mut rules = self.rules.write().unwrap();
+        let org_rules = rules.entry(rule.org_id.clone()).or_default();
+        match org_rules.iter_mut().find(|it| it.id == rule.id) {
+            Some(existing) => *existing = rule,
+            None => org_rules.push(rule),
+        }
+    }
+
+    //启动时加载保存在图数据库中的规则
+    pub async fn load(&self) -> Result<usize, Box<dyn error::Error>> {
+        let rules: Vec<BizRule> = SettingStore::load_all(RULE_SETTING).await?;
+        let size = rules.len();
+        for rule in rules {
+            self.put_rule(rule);
+        }
+        Ok(size)
+    }
+
+    pub fn remove_rule(&self, org_id: &TenantId, rule_id: &str) -> bool {
+        let mut rules = self.rules.write().unwrap();
+        if let Some(org_rules) = rules.get_mut(org_id) {
+            let size = org_rules.len();
+            org_rules.retain(|it| it.id != rule_id);
+            return org_rules.len() < size;
+        }
+        false
+    }
+
+    pub fn rules_of(&self, org_id: &TenantId) -> Vec<BizRule> {
+        let rules = self.rules.read().unwrap();
+        rules.get(org_id).cloned().unwrap_or_default()
+    }
+
+    //订阅卡片事件总线
+    pub async fn run(self: Arc<Self>) {
+        let mut receiver = events::subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let engine = self.clone();
+                    tokio::spawn(async move {
+                        engine.on_event(&event).await;
+                    });
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("rule engine lagged behind, {skipped} card events skipped");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    //返回执行了的规则id
+    pub async fn on_event(&self, event: &CardEvent) -> Vec<String> {
+        let candidates: Vec<BizRule> = self.rules_of(&event.org_id).into_iter()
+            .filter(|it| it.is_triggered_by(event))
+            .collect();
+        let mut executed = Vec::new();
+        for rule in candidates {
+            //规则已经出现在导致本次事件的规则链中，再执行就会形成环
+            if event.caused_by.contains(&rule.id) {
+                eprintln!("rule {} skipped, it is already in the chain {:?}", rule.id, event.caused_by);
+                continue;
+            }
+            if event.caused_by.len() >= MAX_CHAIN_DEPTH {
+                eprintln!("rule {} skipped, the chain {:?} is too long", rule.id, event.caused_by);
+                continue;
+            }
+            if !self.executor.matches(event, &rule.condition).await {
+                continue;
+            }
+            let mut chain = event.caused_by.clone();
+            chain.push(rule.id.clone());
+            //动作产生的事件都会带上规则链
+            events::with_caused_by(chain, async {
+                for action in &rule.actions {
+                    if let Err(err) = self.executor.execute(event, action).await {
+                        //一个动作失败后不再执行后续动作
+                        eprintln!("failed to execute action of rule {}: {}", rule.id, err);
+                        break;
+                    }
+                }
+            }).await;
+            executed.push(rule.id);
+        }
+        executed
+    }
+}
+
+#[derive(Debug)]
+pub struct RuleError {
+    message: String,
+}
+
+impl RuleError {
+    pub fn new(message: &str) -> Self {
+        Self { message: message.to_string() }
+    }
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for RuleError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::Trigger;
+    use card::card::{Field, FieldValue, FlowStatus};
+    use card::events::{CardEventKind, CardEventType};
+    use common::newtypes::card_id::CardId;
+    use common::newtypes::field_id::FieldId;
+    use std::sync::Mutex;
+
+    //模拟执行器：条件总是满足，动作直接产生对应的事件
+    #[derive(Default)]
+    struct MockExecutor {
+        produced: Mutex<Vec<CardEvent>>,
+        executed: Mutex<Vec<Action>>,
+    }
+
+    impl RuleExecutor for MockExecutor {
+        async fn matches(&self, _event: &CardEvent, _condition: &Condition) -> bool {
+            true
+        }
+
+        async fn execute(&self, event: &CardEvent, action: &Action) -> Result<(), RuleError> {
+            self.executed.lock().unwrap().push(action.clone());
+            let kind = match action {
+                Action::SetField(field) => CardEventKind::Updated(vec![field.id.clone()]),
+                Action::ChangeFlowStatus(status) => CardEventKind::FlowStatusChanged {
+                    flow_id: status.flow_id.clone(),
+                    from: None,
+                    to: status.flow_status_id.clone(),
+                },
+                _ => return Err(RuleError::new("unsupported")),
+            };
+            let produced = CardEvent::new(&event.org_id, &event.card_id, &event.card_type_id, kind, &event.operator_id);
+            self.produced.lock().unwrap().push(produced);
+            Ok(())
+        }
+    }
+
+    fn trigger(event_type: CardEventType) -> Trigger {
+        Trigger {
+            card_type_id: String::from("任务"),
+            event_type,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_loop_protection() {
+        let engine = RuleEngine::new(MockExecutor::default());
+        //规则A：属性更新后流转状态；规则B：状态流转后更新属性，两者会互相触发
+        let rule_a = BizRule::new(&TenantId::from_str("o1"), "A", trigger(CardEventType::Updated), Condition::default(),
+                                  vec![Action::ChangeFlowStatus(FlowStatus::new("f1", "进行中"))]);
+        let rule_b = BizRule::new(&TenantId::from_str("o1"), "B", trigger(CardEventType::FlowStatusChanged), Condition::default(),
+                                  vec![Action::SetField(Field::new(FieldId::from_str("进度"), FieldValue::Int(50)))]);
+        let (a, b) = (rule_a.id.clone(), rule_b.id.clone());
+        engine.add_rule(rule_a);
+        engine.add_rule(rule_b);
+
+        let mut pending = vec![CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "任务", CardEventKind::Updated(vec![FieldId::from_str("估时")]), &CardId::from_str("m1"))];
+        let mut executed = Vec::new();
+        while let Some(event) = pending.pop() {
+            executed.extend(engine.on_event(&event).await);
+            pending.extend(engine.executor.produced.lock().unwrap().drain(..));
+        }
+        //A -> B -> (A已在规则链中，停止)
+        assert_eq!(executed, vec![a.clone(), b.clone()]);
+        assert_eq!(engine.executor.executed.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_chain_depth() {
+        let engine = RuleEngine::new(MockExecutor::default());
+        let rule = BizRule::new(&TenantId::from_str("o1"), "A", trigger(CardEventType::Created), Condition::default(),
+                                vec![Action::SetField(Field::new(FieldId::from_str("进度"), FieldValue::Int(0)))]);
+        engine.add_rule(rule);
+        let mut event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "任务", CardEventKind::Created, &CardId::from_str("m1"));
+        event.caused_by = (0..MAX_CHAIN_DEPTH).map(|i| i.to_string()).collect();
+        assert!(engine.on_event(&event).await.is_empty());
+        event.caused_by.pop();
+        assert_eq!(engine.on_event(&event).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stop_after_failed_action() {
+        let engine = RuleEngine::new(MockExecutor::default());
+        let rule = BizRule::new(&TenantId::from_str("o1"), "A", trigger(CardEventType::Created), Condition::default(), vec![
+            Action::CallWebhook(String::from("http://localhost")),
+            Action::SetField(Field::new(FieldId::from_str("进度"), FieldValue::Int(0))),
+        ]);
+        let rule_id = rule.id.clone();
+        engine.add_rule(rule);
+        let event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "任务", CardEventKind::Created, &CardId::from_str("m1"));
+        engine.on_event(&event).await;
+        assert_eq!(engine.executor.executed.lock().unwrap().len(), 1);
+        assert!(engine.remove_rule(&TenantId::from_str("o1"), &rule_id));
+        assert!(engine.rules_of(&TenantId::from_str("o1")).is_empty());
+    }
+}
@@ -0,0 +1,138 @@
+use crate::engine::{RuleError, RuleExecutor};
+use crate::rule::{Action, Receivers};
+use card::card::{Card, Field};
+use card::computed::ComputedFieldRegistry;
+use card::events::CardEvent;
+use card::query;
+use card::query::{Condition, QueryContext, CURRENT_CARD_PARAMETER};
+use card::store::neo4j_store::Neo4jStore;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const NOTIFICATION_SERVER: &str = "http://127.0.0.1:8081";
+
+//基于图数据库和卡片存储的规则执行器
+//动作直接调用卡片存储，不经过SecuredStore：规则只能由有ManageSchema权限的成员维护，动作代表规则执行，
+//不受触发成员的角色和属性读写规则限制，触发成员只用于记录操作人和判断条件中的当前成员；计算属性仍然不能被动作修改
+pub struct StoreExecutor {
+    client: reqwest::Client,
+    notification_server: String,
+    service_token: String, //调用通知服务时使用的服务令牌
+    computed: Arc<ComputedFieldRegistry>,
+}
+
+impl StoreExecutor {
+    pub fn new(notification_server: Option<&str>, service_token: &str, computed: Arc<ComputedFieldRegistry>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            notification_server: String::from(notification_server.unwrap_or(NOTIFICATION_SERVER)),
+            service_token: String::from(service_token),
+            computed,
+        }
+    }
+
+    //内置属性和计算属性都不能由动作直接写入
+    fn ensure_writable(&self, event: &CardEvent, card_type_id: &str, fields: &[Field]) -> Result<(), RuleError> {
+        if let Some(field) = card::card::find_reserved(fields) {
+            return Err(RuleError::new(&format!("field {} is a built-in property", field.id)));
+        }
+        match fields.iter().find(|it| self.computed.is_computed(&event.org_id, card_type_id, &it.id)) {
+            Some(field) => Err(RuleError::new(&format!("field {} of card type {} is computed", field.id, card_type_id))),
+            None => Ok(()),
+        }
+    }
+
+    //服务令牌只发给通知服务，不发给外部webhook
+    async fn post(&self, url: &str, body: serde_json::Value, service_token: Option<&str>) -> Result<(), RuleError> {
+        let mut request = self.client.post(url).json(&body);
+        if let Some(token) = service_token {
+            request = request.header(reqwest::header::AUTHORIZATION, format!("Service {token}"));
+        }
+        let response = request.send().await
+            .map_err(|err| RuleError::new(&err.to_string()))?;
+        if !response.status().is_success() {
+            return Err(RuleError::new(&format!("{} responded with {}", url, response.status())));
+        }
+        Ok(())
+    }
+}
+
+fn check(success: bool, action: &str) -> Result<(), RuleError> {
+    if success {
+        Ok(())
+    } else {
+        Err(RuleError::new(&format!("failed to {action}")))
+    }
+}
+
+impl RuleExecutor for StoreExecutor {
+    async fn matches(&self, event: &CardEvent, condition: &Condition) -> bool {
+        //规则条件中的当前成员为触发事件的成员，当前卡片为触发卡片
+        let parameters = HashMap::from([(String::from(CURRENT_CARD_PARAMETER), event.card_id.to_string())]);
+        let query_context = QueryContext::new(&event.org_id, &event.operator_id, parameters);
+        match query::matches(&event.card_id, condition, &query_context).await {
+            Ok(matched) => matched,
+            Err(err) => {
+                eprintln!("failed to match condition of card {}: {}", event.card_id, err);
+                false
+            }
+        }
+    }
+
+    async fn execute(&self, event: &CardEvent, action: &Action) -> Result<(), RuleError> {
+        match action {
+            Action::SetField(field) => {
+                self.ensure_writable(event, &event.card_type_id, std::slice::from_ref(field))?;
+                check(Neo4jStore::update_fields(&event.org_id, &event.card_id, std::slice::from_ref(field), &event.operator_id).await, "set field")
+            }
+            Action::ChangeFlowStatus(flow_status) => {
+                check(Neo4jStore::change_flow_status(&event.org_id, &event.card_id, flow_status, &event.operator_id).await, "change flow status")
+            }
+            Action::CreateLinkedCard { card_type_id, name, rs_type, fields } => {
+                self.ensure_writable(event, card_type_id, fields)?;
+                let card = Card::new(String::new(), name.clone(), card_type_id, &event.org_id, None, fields.clone(), HashMap::new());
+                check(Neo4jStore::create(&card, &event.operator_id).await.is_some(), "create linked card")?;
+                check(Neo4jStore::link(&event.org_id, &event.card_id, &card.id, rs_type, &event.operator_id).await, "link created card")
+            }
+            Action::Notify { receivers, message } => {
+                let member_ids = match receivers {
+                    Receivers::Operator => vec![event.operator_id.clone()],
+                    Receivers::Members(member_ids) => member_ids.clone(),
+                };
+                let url = format!("{}/notifications", self.notification_server);
+                self.post(&url, json!({
+                    "event": event,
+                    "member_ids": member_ids,
+                    "message": message,
+                }), Some(&self.service_token)).await
+            }
+            Action::CallWebhook(url) => self.post(url, json!(event), None).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use card::card::FieldValue;
+    use card::computed::ComputedField;
+    use card::events::CardEventKind;
+    use card::formula::Formula;
+    use common::newtypes::card_id::CardId;
+    use common::newtypes::field_id::FieldId;
+    use common::newtypes::tenant_id::TenantId;
+
+    #[test]
+    fn test_ensure_writable() {
+        let org_id = TenantId::from_str("o1");
+        let computed = Arc::new(ComputedFieldRegistry::default());
+        computed.add(ComputedField::new(&org_id, "需求", FieldId::from_str("总估时"), Formula::Field(FieldId::from_str("估时")))).unwrap();
+        let executor = StoreExecutor::new(None, "token", computed);
+        let event = CardEvent::new(&org_id, &CardId::from_str("c1"), "需求", CardEventKind::Created, &CardId::from_str("m1"));
+        let field = |id: &str| Field::new(FieldId::from_str(id), FieldValue::Int(1));
+        assert!(executor.ensure_writable(&event, "需求", &[field("估时")]).is_ok());
+        assert!(executor.ensure_writable(&event, "需求", &[field("总估时")]).is_err());
+        assert_eq!(executor.ensure_writable(&event, "需求", &[field("org_id")]).unwrap_err().to_string(), "field org_id is a built-in property");
+    }
+}
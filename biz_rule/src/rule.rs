@@ -0,0 +1,129 @@
+//业务规则定义：事件(触发器) + 条件 + 动作
+use card::card::{Field, FlowStatus};
+use card::events::{CardEvent, CardEventType};
+use card::query::Condition;
+use common::id_generator;
+use common::newtypes::card_id::CardId;
+use common::newtypes::tenant_id::TenantId;
+use serde::{Deserialize, Serialize};
+
+//规则在配置存储中的种类
+pub const RULE_SETTING: &str = "BizRule";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BizRule {
+    pub id: String,
+    pub org_id: TenantId,
+    pub name: String,
+    pub enabled: bool,
+    pub trigger: Trigger,
+    pub condition: Condition, //触发卡片需要满足的条件
+    pub actions: Vec<Action>, //按顺序执行
+}
+
+//某类卡片上发生了某类事件
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trigger {
+    pub card_type_id: String,
+    pub event_type: CardEventType,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Action {
+    SetField(Field), //设置触发卡片的属性
+    ChangeFlowStatus(FlowStatus), //变更触发卡片的价值流状态
+    CreateLinkedCard {
+        card_type_id: String,
+        name: String,
+        rs_type: String, //触发卡片与新卡片之间的关联关系，触发卡片为起点
+        fields: Vec<Field>,
+    },
+    Notify {
+        receivers: Receivers,
+        message: String,
+    },
+    CallWebhook(String), //把触发事件推送到该url
+}
+
+//通知的接收人
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Receivers {
+    Operator, //触发事件的成员
+    Members(Vec<CardId>),
+}
+
+impl BizRule {
+    pub fn new(org_id: &TenantId, name: &str, trigger: Trigger, condition: Condition, actions: Vec<Action>) -> Self {
+        Self {
+            id: id_generator::generate_id(),
+            org_id: org_id.clone(),
+            name: String::from(name),
+            enabled: true,
+            trigger,
+            condition,
+            actions,
+        }
+    }
+
+    //不需要访问数据库的初步判断
+    pub fn is_triggered_by(&self, event: &CardEvent) -> bool {
+        self.enabled
+            && self.org_id == event.org_id
+            && self.trigger.card_type_id == event.card_type_id
+            && self.trigger.event_type == event.event_type()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use card::events::CardEventKind;
+    use card::query::ConditionItem;
+    use common::newtypes::field_id::FieldId;
+    use card::card::FieldValue;
+
+    #[test]
+    fn test_is_triggered_by() {
+        let mut rule = BizRule::new(&TenantId::from_str("o1"), "需求完成时通知创建人", Trigger {
+            card_type_id: String::from("需求"),
+            event_type: CardEventType::FlowStatusChanged,
+        }, Condition::default(), vec![Action::Notify {
+            receivers: Receivers::Operator,
+            message: String::from("需求已完成"),
+        }]);
+        let event = |card_type_id: &str, kind: CardEventKind| {
+            CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), card_type_id, kind, &CardId::from_str("m1"))
+        };
+        let changed = CardEventKind::FlowStatusChanged {
+            flow_id: String::from("f1"),
+            from: None,
+            to: String::from("已完成"),
+        };
+        assert!(rule.is_triggered_by(&event("需求", changed.clone())));
+        assert!(!rule.is_triggered_by(&event("任务", changed.clone())));
+        assert!(!rule.is_triggered_by(&event("需求", CardEventKind::Created)));
+        rule.enabled = false;
+        assert!(!rule.is_triggered_by(&event("需求", changed)));
+    }
+
+    #[test]
+    fn test_rule_serde() {
+        let mut condition = Condition::default();
+        condition.and(ConditionItem::Code(String::from("1")));
+        let rule = BizRule::new(&TenantId::from_str("o1"), "设置估时", Trigger {
+            card_type_id: String::from("任务"),
+            event_type: CardEventType::Created,
+        }, condition, vec![
+            Action::SetField(Field::new(FieldId::from_str("估时"), FieldValue::Int(1))),
+            Action::CreateLinkedCard {
+                card_type_id: String::from("子任务"),
+                name: String::from("评审"),
+                rs_type: String::from("子任务"),
+                fields: vec![],
+            },
+        ]);
+        let json = serde_json::to_string(&rule).unwrap();
+        let deserialized: BizRule = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized.actions, rule.actions);
+    }
+}
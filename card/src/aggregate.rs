@@ -0,0 +1,247 @@
+//聚合查询：按维度分组，在图数据库中计算度量值，卡片本身不会被取回
+//一张卡片在多值枚举或者关联了多张卡片时会落入多个分组，各分组分别统计
+use crate::cypher;
+use crate::cypher::CypherCompiler;
+use crate::graph::get_graph;
+use crate::newtypes::card_id::CardId;
+use crate::newtypes::field_id::FieldId;
+use crate::newtypes::tenant_id::TenantId;
+use crate::query::{match_cards, scoped_query, Condition, FieldRestriction, QueryContext, QueryError, Yields};
+use crate::types::LinkDescriptor;
+use neo4rs::BoltType;
+use serde::{Deserialize, Serialize};
+use std::error;
+
+//分组维度
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Dimension {
+    Status, //价值流状态
+    CardType,
+    Enum(FieldId), //多值枚举按每个值分别分组
+    Link(LinkDescriptor), //按关联的卡片分组，分组key为关联卡片的id
+    Date(FieldId, DateBucket),
+}
+
+//日期按UTC切分，只有日期的属性保存为UTC零点，不受影响
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DateBucket {
+    Day,
+    Week, //周一开始，key为周一的日期
+    Month, //key为当月1日
+}
+
+//度量，Count以外的度量只对数字属性有意义
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Measure {
+    Count,
+    Sum(FieldId),
+    Avg(FieldId),
+    Min(FieldId),
+    Max(FieldId),
+}
+
+//keys与维度一一对应，没有值的卡片落入key为None的分组；values与度量一一对应，分组中没有数值时为None
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AggregateRow {
+    pub keys: Vec<Option<String>>,
+    pub values: Vec<Option<f64>>,
+}
+
+//活动卡片的分组信息，linked_ids为沿指定关联关联的卡片，如负责的团队
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardGroupKeys {
+    pub card_id: CardId,
+    pub card_type_id: String,
+    pub flow_status_id: Option<String>,
+    pub linked_ids: Vec<CardId>,
+}
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+//条件和可见范围与普通查询相同，当前成员不可读的属性值按空值统计
+pub async fn aggregate(condition: &Condition, dimensions: &[Dimension], measures: &[Measure], query_context: &QueryContext, yields: &Yields) -> Result<Vec<AggregateRow>> {
+    if measures.is_empty() {
+        return Err(Box::new(QueryError::new("aggregate requires at least one measure")));
+    }
+    yields.ensure_unrestricted(condition, None)?;
+    let mut compiler = CypherCompiler::new("c", query_context);
+    let where_str = compiler.compile(condition)?;
+    let mut params = compiler.into_params();
+    let cypher = aggregate_cypher(&match_cards(&where_str), dimensions, measures, &yields.restrictions, &mut params);
+    let graph = get_graph().await;
+    let mut result = graph.execute(scoped_query(&cypher, query_context, params)).await?;
+    let mut rows = Vec::new();
+    while let Some(row) = result.next().await? {
+        rows.push(AggregateRow {
+            keys: (0..dimensions.len()).map(|i| row.get::<String>(&format!("k{i}")).ok()).collect(),
+            values: (0..measures.len()).map(|i| row.get::<f64>(&format!("m{i}")).ok()).collect(),
+        });
+    }
+    Ok(rows)
+}
+
+fn aggregate_cypher(match_str: &str, dimensions: &[Dimension], measures: &[Measure], restrictions: &[FieldRestriction], params: &mut Vec<(String, BoltType)>) -> String {
+    let mut readable = |field_id: &FieldId| readable_field("c", field_id, restrictions, params);
+    let mut clauses = vec![String::from(match_str)];
+    let mut keys = Vec::new();
+    for (i, dimension) in dimensions.iter().enumerate() {
+        let key = match dimension {
+            Dimension::Status => String::from("c.flow_status_id"),
+            Dimension::CardType => String::from("c.card_type_id"),
+            Dimension::Enum(field_id) => {
+                let value = readable(field_id);
+                clauses.push(format!("UNWIND CASE WHEN {value} IS NULL OR size({value}) = 0 THEN [null] ELSE {value} END AS e{i}"));
+                format!("e{i}")
+            }
+            Dimension::Link(descriptor) => {
+                clauses.push(format!("OPTIONAL MATCH (c){}(l{i}:Card {{org_id:$tenant_id}})", cypher::relationship(descriptor)));
+                format!("l{i}.id")
+            }
+            Dimension::Date(field_id, bucket) => {
+                let value = readable(field_id);
+                let time = format!("datetime({{epochMillis: {value}}})");
+                let date = match bucket {
+                    DateBucket::Day => format!("date({time})"),
+                    DateBucket::Week => format!("date.truncate('week', {time})"),
+                    DateBucket::Month => format!("date.truncate('month', {time})"),
+                };
+                format!("CASE WHEN {value} IS NULL THEN null ELSE {date} END")
+            }
+        };
+        keys.push(format!("toString({key}) AS k{i}"));
+    }
+    let values: Vec<String> = measures.iter().enumerate()
+        .map(|(i, measure)| {
+            let value = match measure {
+                Measure::Count => String::from("count(c)"),
+                Measure::Sum(field_id) => format!("sum({})", readable(field_id)),
+                Measure::Avg(field_id) => format!("avg({})", readable(field_id)),
+                Measure::Min(field_id) => format!("min({})", readable(field_id)),
+                Measure::Max(field_id) => format!("max({})", readable(field_id)),
+            };
+            format!("toFloat({value}) AS m{i}")
+        })
+        .collect();
+    let order: Vec<String> = (0..dimensions.len()).map(|i| format!("k{i}")).collect();
+    let mut cypher = format!("{} RETURN {}", clauses.join(" "), keys.into_iter().chain(values).collect::<Vec<_>>().join(", "));
+    if !order.is_empty() {
+        cypher.push_str(&format!(" ORDER BY {}", order.join(", ")));
+    }
+    cypher
+}
+
+//组织中所有活动卡片的分组信息，用于统计快照的全量校准；这是组织级的统计，不经过成员的可见范围
+pub async fn active_card_keys(tenant_id: &TenantId, link: Option<&LinkDescriptor>) -> Result<Vec<CardGroupKeys>> {
+    let keys_query = neo4rs::query(&active_card_keys_cypher(link, false)).param("tenant_id", tenant_id.as_str());
+    load_card_keys(keys_query).await
+}
+
+//一张活动卡片的分组信息，卡片不存在或不是活动状态时返回None
+pub async fn active_card_key(tenant_id: &TenantId, card_id: &CardId, link: Option<&LinkDescriptor>) -> Result<Option<CardGroupKeys>> {
+    let key_query = neo4rs::query(&active_card_keys_cypher(link, true))
+        .param("tenant_id", tenant_id.as_str())
+        .param("card_id", card_id.as_str());
+    Ok(load_card_keys(key_query).await?.into_iter().next())
+}
+
+//图数据库中有卡片的所有组织
+pub async fn org_ids() -> Result<Vec<TenantId>> {
+    let graph = get_graph().await;
+    let mut result = graph.execute(neo4rs::query("MATCH (c:Card) RETURN DISTINCT c.org_id AS org_id")).await?;
+    let mut org_ids = Vec::new();
+    while let Some(row) = result.next().await? {
+        org_ids.push(TenantId::from(row.get::<String>("org_id")?));
+    }
+    Ok(org_ids)
+}
+
+async fn load_card_keys(keys_query: neo4rs::Query) -> Result<Vec<CardGroupKeys>> {
+    let graph = get_graph().await;
+    let mut result = graph.execute(keys_query).await?;
+    let mut keys = Vec::new();
+    while let Some(row) = result.next().await? {
+        let linked_ids: Vec<String> = row.get("linked")?;
+        keys.push(CardGroupKeys {
+            card_id: CardId::from(row.get::<String>("id")?),
+            card_type_id: row.get("card_type_id")?,
+            flow_status_id: row.get("flow_status_id").ok(),
+            linked_ids: linked_ids.into_iter().map(CardId::from).collect(),
+        });
+    }
+    Ok(keys)
+}
+
+fn active_card_keys_cypher(link: Option<&LinkDescriptor>, single: bool) -> String {
+    let card_str = if single { " AND c.id = $card_id" } else { "" };
+    let match_str = format!("MATCH (c:Card) WHERE c.org_id = $tenant_id AND c.state = 'Active'{card_str}");
+    let return_str = "RETURN c.id AS id, c.card_type_id AS card_type_id, c.flow_status_id AS flow_status_id";
+    match link {
+        Some(link) => format!("{match_str} OPTIONAL MATCH (c){}(l:Card {{org_id:$tenant_id}}) {return_str}, collect(l.id) AS linked", cypher::relationship(link)),
+        None => format!("{match_str} {return_str}, [] AS linked"),
+    }
+}
+
+//受属性级权限限制的属性，在不可读的卡片上取空值，与查询结果中不返回该属性一致
+fn readable_field(var: &str, field_id: &FieldId, restrictions: &[FieldRestriction], params: &mut Vec<(String, BoltType)>) -> String {
+    let prop = cypher::property(var, field_id);
+    let mut param = |value: BoltType| {
+        let key = format!("r{}", params.len());
+        params.push((key.clone(), value));
+        format!("${key}")
+    };
+    let readable: Vec<String> = restrictions.iter()
+        .filter(|it| it.field_id == *field_id)
+        .map(|it| {
+            let card_type = param(it.card_type_id.as_str().into());
+            match &it.readable_in {
+                Some(status_ids) => format!("({var}.card_type_id <> {card_type} OR coalesce({var}.flow_status_id IN {}, false))", param(status_ids.clone().into())),
+                None => format!("{var}.card_type_id <> {card_type}"),
+            }
+        })
+        .collect();
+    if readable.is_empty() {
+        prop
+    } else {
+        format!("CASE WHEN {} THEN {prop} END", readable.join(" AND "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate_cypher() {
+        let dimensions = vec![Dimension::Status, Dimension::Link(LinkDescriptor::Dest(String::from("负责"))), Dimension::Date(FieldId::from_str("计划完成时间"), DateBucket::Week)];
+        let measures = vec![Measure::Count, Measure::Sum(FieldId::from_str("估算"))];
+        let cypher = aggregate_cypher("MATCH (c:Card) WHERE c.org_id = $tenant_id AND true", &dimensions, &measures, &[], &mut vec![]);
+        assert_eq!(cypher, "MATCH (c:Card) WHERE c.org_id = $tenant_id AND true OPTIONAL MATCH (c)<-[:`负责`]-(l1:Card {org_id:$tenant_id}) \
+            RETURN toString(c.flow_status_id) AS k0, toString(l1.id) AS k1, \
+            toString(CASE WHEN c.`计划完成时间` IS NULL THEN null ELSE date.truncate('week', datetime({epochMillis: c.`计划完成时间`})) END) AS k2, \
+            toFloat(count(c)) AS m0, toFloat(sum(c.`估算`)) AS m1 ORDER BY k0, k1, k2");
+    }
+
+    #[test]
+    fn test_active_card_keys_cypher() {
+        assert_eq!(active_card_keys_cypher(Some(&LinkDescriptor::Src(String::from("负责团队"))), false),
+                   "MATCH (c:Card) WHERE c.org_id = $tenant_id AND c.state = 'Active' OPTIONAL MATCH (c)-[:`负责团队`]->(l:Card {org_id:$tenant_id}) \
+                   RETURN c.id AS id, c.card_type_id AS card_type_id, c.flow_status_id AS flow_status_id, collect(l.id) AS linked");
+        assert_eq!(active_card_keys_cypher(None, true),
+                   "MATCH (c:Card) WHERE c.org_id = $tenant_id AND c.state = 'Active' AND c.id = $card_id \
+                   RETURN c.id AS id, c.card_type_id AS card_type_id, c.flow_status_id AS flow_status_id, [] AS linked");
+    }
+
+    #[test]
+    fn test_restricted_fields_are_not_aggregated() {
+        let restrictions = vec![
+            FieldRestriction { card_type_id: String::from("需求"), field_id: FieldId::from_str("成本"), readable_in: Some(vec![String::from("已完成")]) },
+            FieldRestriction { card_type_id: String::from("员工"), field_id: FieldId::from_str("成本"), readable_in: None },
+        ];
+        let mut params = vec![];
+        let cypher = aggregate_cypher("MATCH (c:Card)", &[Dimension::Enum(FieldId::from_str("标签"))], &[Measure::Avg(FieldId::from_str("成本"))], &restrictions, &mut params);
+        assert_eq!(cypher, "MATCH (c:Card) UNWIND CASE WHEN c.`标签` IS NULL OR size(c.`标签`) = 0 THEN [null] ELSE c.`标签` END AS e0 \
+            RETURN toString(e0) AS k0, \
+            toFloat(avg(CASE WHEN (c.card_type_id <> $r0 OR coalesce(c.flow_status_id IN $r1, false)) AND c.card_type_id <> $r2 THEN c.`成本` END)) AS m0 ORDER BY k0");
+        assert_eq!(params.len(), 3);
+    }
+}
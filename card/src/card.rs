@@ -4,8 +4,8 @@ use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
 use common::newtypes::card_id::CardId;
-use common::newtypes::card_type_id::CardTypeId;
 use common::newtypes::field_id::FieldId;
+use common::newtypes::tenant_id::TenantId;
 use common::newtypes::timestamp::Timestamp;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -16,26 +16,38 @@ pub struct Card<'a> {
     pub state: CardState,
     pub flow_status: Option<FlowStatus>, //不是所有类型的卡都有流动状态，仅工作项类型卡具有
     pub card_type_id: &'a str,
-    pub org_id: &'a str,
+    pub org_id: TenantId,
     pub create_time: Timestamp,
     pub update_time: Timestamp,
     pub fields: Vec<Field>,
     pub links: HashMap<LinkDescriptor, HashSet<Card<'a>>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Field {
     pub id: FieldId,
     pub value: FieldValue,
 }
 
+//卡片节点上的内置属性，只能通过创建、流转、归档等专门的操作修改，不能作为属性写入
+pub const RESERVED_PROPERTIES: [&str; 11] = ["id", "code", "name", "state", "card_type_id", "org_id", "flow_id", "flow_status_id", "create_time", "update_time", "abandon_reason"];
+
 impl Field {
     pub fn new(id: FieldId, value: FieldValue) -> Self {
         Self { id, value }
     }
+
+    pub fn is_reserved(&self) -> bool {
+        RESERVED_PROPERTIES.contains(&self.id.as_str())
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+//属性中第一个使用了内置属性名的属性
+pub fn find_reserved(fields: &[Field]) -> Option<&Field> {
+    fields.iter().find(|it| it.is_reserved())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum FieldValue {
     Int(i32),
     Float(f32),
@@ -45,7 +57,7 @@ pub enum FieldValue {
     DateTime(Timestamp),
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CardState {
     Active = 1,
     Archived = 2,
@@ -54,7 +66,6 @@ pub enum CardState {
 
 impl Display for CardState {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let c = CardTypeId(String::new());
         match self {
             CardState::Active => { write!(f, "Active") }
             CardState::Archived => { write!(f, "Archived") }
@@ -63,7 +74,7 @@ impl Display for CardState {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FlowStatus {
     pub flow_id: String,
     pub flow_status_id: String,
@@ -79,7 +90,7 @@ impl FlowStatus {
 }
 
 impl<'a> Card<'a> {
-    pub fn new(code: String, name: String, card_type_id: &'a str, org_id: &'a str, flow_status: Option<FlowStatus>, fields: Vec<Field>, links: HashMap<LinkDescriptor, HashSet<Card<'a>>>) -> Card<'a> {
+    pub fn new(code: String, name: String, card_type_id: &'a str, org_id: &TenantId, flow_status: Option<FlowStatus>, fields: Vec<Field>, links: HashMap<LinkDescriptor, HashSet<Card<'a>>>) -> Card<'a> {
         let now = Timestamp::now();
         Card {
             id: CardId::new(),
@@ -88,7 +99,7 @@ impl<'a> Card<'a> {
             state: CardState::Active,
             flow_status,
             card_type_id,
-            org_id,
+            org_id: org_id.clone(),
             create_time: now.clone(),
             update_time: now.clone(),
             fields,
@@ -139,12 +150,12 @@ mod tests {
 
     #[test]
     fn test_new_card() {
-        let mut card = Card::new("10001".to_string(), "卡片01".to_string(), "1", "1", None, vec![], HashMap::new());
+        let mut card = Card::new("10001".to_string(), "卡片01".to_string(), "1", &TenantId::from_str("1"), None, vec![], HashMap::new());
         card.code = String::from("10001");
         println!("{:?}", card);
         assert_eq!(card.code, "10001");
         assert_eq!(card.card_type_id, "1");
-        assert_eq!(card.org_id, "1");
+        assert_eq!(card.org_id, TenantId::from_str("1"));
         assert_eq!(card.fields.len(), 0);
         assert_eq!(card.name, "卡片01");
         assert_eq!(card.state, CardState::Active);
@@ -152,17 +163,27 @@ mod tests {
     #[test]
     fn test_card_serde() {
         let card_type_id = CardTypeId::from_str("1");
-        let card = Card::new("10001".to_string(), "卡片01".to_string(), &card_type_id, "1", None, vec![], HashMap::new());
+        let card = Card::new("10001".to_string(), "卡片01".to_string(), &card_type_id, &TenantId::from_str("1"), None, vec![], HashMap::new());
         let json = serde_json::to_string(&card).unwrap();
         println!("serialize = {}", json);
         let card = serde_json::from_str::<Card>(&json).unwrap();
         println!("deserialize = {:?}", card);
     }
 
+    #[test]
+    fn test_reserved_fields() {
+        let estimate = Field::new(FieldId::from_str("估时"), FieldValue::Int(1));
+        let org_id = Field::new(FieldId::from_str("org_id"), FieldValue::Text(String::from("o2")));
+        assert!(!estimate.is_reserved());
+        assert!(org_id.is_reserved());
+        assert_eq!(find_reserved(&[estimate.clone(), org_id.clone()]), Some(&org_id));
+        assert_eq!(find_reserved(&[estimate]), None);
+    }
+
     #[test]
     fn test_rename() {
         let card_type_id = CardTypeId::from_str("1");
-        let mut card = Card::new("10001".to_string(), "卡片01".to_string(), &card_type_id, "1", None, vec![], HashMap::new());
+        let mut card = Card::new("10001".to_string(), "卡片01".to_string(), &card_type_id, &TenantId::from_str("1"), None, vec![], HashMap::new());
         card.rename("第一张卡片");
         assert_eq!(card.name, "第一张卡片");
         println!("{:?}", card.state);
@@ -0,0 +1,133 @@
+//卡片编号：在组织内连续分配，卡片类型设置了前缀时按前缀单独编号，如REQ-1024
+//序号保存在图数据库的CodeSequence节点上，在创建卡片的事务中递增：递增时持有序号节点的写锁，并发创建的事务依次分配，不会重号；
+//事务回滚时序号一并回滚，不会出现空号
+//序号节点在第一次分配时创建，并发创建违反唯一约束的事务由Neo4jStore::create重试
+use crate::graph::get_graph;
+use crate::newtypes::tenant_id::TenantId;
+use neo4rs::{Query, Txn};
+use std::error;
+use std::fmt::{Display, Formatter};
+
+const MAX_PREFIX_LEN: usize = 10;
+
+#[derive(Debug)]
+pub struct CodeError {
+    message: String,
+}
+
+impl CodeError {
+    pub fn new(message: &str) -> Self {
+        Self { message: String::from(message) }
+    }
+}
+
+impl Display for CodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for CodeError {}
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+//前缀以大写字母开头，只包含大写字母和数字
+pub fn check_prefix(prefix: &str) -> std::result::Result<(), CodeError> {
+    let valid = prefix.len() <= MAX_PREFIX_LEN
+        && prefix.starts_with(|it: char| it.is_ascii_uppercase())
+        && prefix.chars().all(|it| it.is_ascii_uppercase() || it.is_ascii_digit());
+    if valid {
+        Ok(())
+    } else {
+        Err(CodeError::new(&format!("code prefix {prefix} should be at most {MAX_PREFIX_LEN} uppercase letters or digits, starting with a letter")))
+    }
+}
+
+//没有前缀的卡片类型共用组织的序号，编号只有数字
+pub fn format_code(prefix: &str, value: i64) -> String {
+    if prefix.is_empty() {
+        value.to_string()
+    } else {
+        format!("{prefix}-{value}")
+    }
+}
+
+//为卡片类型预留count个连续的序号，返回前缀和最后一个序号
+//前缀不同的卡片类型使用各自的序号，前缀相同的卡片类型共用一个序号
+pub(crate) fn reserve_query(org_id: &str, card_type_id: &str, count: i64) -> Query {
+    neo4rs::query("OPTIONAL MATCH (p:CodePrefix {org_id:$org_id, card_type_id:$card_type_id}) \
+        WITH coalesce(p.prefix, '') AS prefix \
+        MERGE (s:CodeSequence {org_id:$org_id, prefix:prefix}) ON CREATE SET s.value = 0 \
+        SET s.value = s.value + $count \
+        RETURN prefix, s.value AS value")
+        .param("org_id", org_id)
+        .param("card_type_id", card_type_id)
+        .param("count", count)
+}
+
+//并发MERGE同一个序号节点时，Neo4j和Memgraph报告的唯一约束冲突
+pub(crate) fn is_constraint_violation(message: &str) -> bool {
+    message.contains("ConstraintValidationFailed") || message.contains("unique constraint violation")
+}
+
+//在创建卡片的事务中分配一个编号
+pub(crate) async fn allocate(txn: &mut Txn, org_id: &str, card_type_id: &str) -> Result<String> {
+    let mut rows = txn.execute(reserve_query(org_id, card_type_id, 1)).await?;
+    let row = rows.next(txn.handle()).await?
+        .ok_or_else(|| CodeError::new(&format!("no code allocated for card type {card_type_id}")))?;
+    Ok(format_code(&row.get::<String>("prefix")?, row.get::<i64>("value")?))
+}
+
+//设置或者清除卡片类型的编号前缀，只影响之后创建的卡片，已有卡片的编号不变
+pub async fn set_prefix(tenant_id: &TenantId, card_type_id: &str, prefix: Option<&str>) -> Result<()> {
+    let query = match prefix {
+        Some(prefix) => {
+            check_prefix(prefix)?;
+            neo4rs::query("MERGE (p:CodePrefix {org_id:$org_id, card_type_id:$card_type_id}) SET p.prefix = $prefix")
+                .param("prefix", prefix)
+        }
+        None => neo4rs::query("MATCH (p:CodePrefix {org_id:$org_id, card_type_id:$card_type_id}) DELETE p"),
+    };
+    let graph = get_graph().await;
+    graph.run(query.param("org_id", tenant_id.as_str()).param("card_type_id", card_type_id)).await?;
+    Ok(())
+}
+
+//组织中是否有该卡片类型的卡片，包括已归档和丢弃的卡片
+pub async fn has_cards(tenant_id: &TenantId, card_type_id: &str) -> Result<bool> {
+    let exists_query = neo4rs::query("MATCH (c:Card {org_id:$org_id, card_type_id:$card_type_id}) RETURN c.id AS id LIMIT 1")
+        .param("org_id", tenant_id.as_str())
+        .param("card_type_id", card_type_id);
+    let graph = get_graph().await;
+    let mut rows = graph.execute(exists_query).await?;
+    Ok(rows.next().await?.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_code() {
+        assert_eq!(format_code("", 1024), "1024");
+        assert_eq!(format_code("REQ", 1024), "REQ-1024");
+    }
+
+    #[test]
+    fn test_is_constraint_violation() {
+        assert!(is_constraint_violation("Neo.ClientError.Schema.ConstraintValidationFailed: Node(12) already exists with label `CodeSequence`"));
+        assert!(is_constraint_violation("Unable to commit due to unique constraint violation on :CodeSequence(org_id, prefix)"));
+        assert!(!is_constraint_violation("connection refused"));
+    }
+
+    #[test]
+    fn test_check_prefix() {
+        assert!(check_prefix("REQ").is_ok());
+        assert!(check_prefix("V2").is_ok());
+        assert!(check_prefix("").is_err());
+        assert!(check_prefix("req").is_err());
+        assert!(check_prefix("2V").is_err());
+        assert!(check_prefix("REQ-").is_err());
+        assert!(check_prefix("ABCDEFGHIJK").is_err());
+    }
+}
@@ -0,0 +1,337 @@
+//计算属性：值由公式得出，保存在卡片节点上，因此可以像普通属性一样被查询
+//公式的输入(属性或关联)发生变化时，根据卡片事件增量地重新计算受影响的卡片
+use crate::events;
+use crate::events::{CardEvent, CardEventKind};
+use crate::formula::Formula;
+use crate::graph::get_graph;
+use crate::cypher::{chain, property};
+use crate::newtypes::card_id::CardId;
+use crate::newtypes::field_id::FieldId;
+use crate::newtypes::tenant_id::TenantId;
+use crate::settings::SettingStore;
+use crate::types::LinkDescriptor;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, RwLock};
+use std::{error, fmt};
+use tokio::sync::broadcast::error::RecvError;
+
+//计算属性在配置存储中的种类
+pub const COMPUTED_FIELD_SETTING: &str = "ComputedField";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComputedField {
+    pub id: FieldId,
+    pub org_id: TenantId,
+    pub card_type_id: String,
+    pub formula: Formula,
+}
+
+//需要重新计算的卡片：锚点卡片本身，或者沿路径前缀能到达锚点卡片的卡片
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Anchor<'a> {
+    card_id: &'a CardId,
+    prefix: Vec<&'a LinkDescriptor>,
+}
+
+impl ComputedField {
+    pub fn new(org_id: &TenantId, card_type_id: &str, id: FieldId, formula: Formula) -> Self {
+        Self {
+            id,
+            org_id: org_id.clone(),
+            card_type_id: String::from(card_type_id),
+            formula,
+        }
+    }
+
+    //事件影响到的卡片，自身的变更不会再次触发计算
+    fn anchors<'a>(&'a self, event: &'a CardEvent) -> HashSet<Anchor<'a>> {
+        let mut anchors = HashSet::new();
+        let own = Anchor { card_id: &event.card_id, prefix: vec![] };
+        match &event.kind {
+            CardEventKind::Created if event.card_type_id == self.card_type_id => {
+                anchors.insert(own);
+            }
+            CardEventKind::Updated(field_ids) => {
+                let changed = |field_id: &FieldId| *field_id != self.id && field_ids.contains(field_id);
+                if event.card_type_id == self.card_type_id && self.formula.fields().into_iter().any(changed) {
+                    anchors.insert(own);
+                }
+                for (descriptors, field_id) in self.formula.paths() {
+                    if field_id.is_some_and(|it| field_ids.contains(it)) {
+                        anchors.insert(Anchor { card_id: &event.card_id, prefix: descriptors });
+                    }
+                }
+            }
+            CardEventKind::Linked(descriptor, other) | CardEventKind::Unlinked(descriptor, other) => {
+                //关联的两端可能位于路径的任意位置，以两端为锚点沿路径的每个前缀反向查找
+                let linked_rs_type = rs_type(descriptor);
+                for (descriptors, _) in self.formula.paths() {
+                    if !descriptors.iter().any(|it| rs_type(it) == linked_rs_type) {
+                        continue;
+                    }
+                    for i in 0..descriptors.len() {
+                        for card_id in [&event.card_id, other] {
+                            anchors.insert(Anchor { card_id, prefix: descriptors[..i].to_vec() });
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        anchors
+    }
+
+    //公式引用的属性及其所在的卡片类型，None表示关联卡片上的属性，类型不确定
+    fn references(&self) -> Vec<(Option<&str>, &FieldId)> {
+        let mut references: Vec<(Option<&str>, &FieldId)> = self.formula.fields().into_iter()
+            .map(|it| (Some(self.card_type_id.as_str()), it))
+            .collect();
+        references.extend(self.formula.paths().into_iter().filter_map(|(_, field_id)| field_id).map(|it| (None, it)));
+        references
+    }
+
+    //在配置存储中的id，同一组织内卡片类型和属性确定一个计算属性
+    pub fn setting_id(&self) -> String {
+        format!("{}/{}", self.card_type_id, self.id)
+    }
+
+    //重新计算并返回值发生了变化的卡片
+    async fn recompute(&self, anchor: &Anchor<'_>) -> Result<Vec<CardId>> {
+        let pattern = if anchor.prefix.is_empty() {
+            String::from("(c:Card) WHERE c.id = $card_id")
+        } else {
+            format!("(c:Card){} WHERE x.id = $card_id", chain(&anchor.prefix, "x"))
+        };
+        self.recompute_matching(&pattern, Some(anchor.card_id)).await
+    }
+
+    //新注册或者修改了公式后，该卡片类型的所有卡片都要重新计算
+    async fn recompute_all(&self) -> Result<Vec<CardId>> {
+        self.recompute_matching("(c:Card) WHERE true", None).await
+    }
+
+    async fn recompute_matching(&self, pattern: &str, card_id: Option<&CardId>) -> Result<Vec<CardId>> {
+        let prop = property("c", &self.id);
+        let cypher = format!("MATCH {pattern} AND c.org_id = $org_id AND c.card_type_id = $card_type_id \
+            WITH DISTINCT c, {prop} AS old SET {prop} = {} \
+            WITH c, old WHERE coalesce(old <> {prop}, (old IS NULL) <> ({prop} IS NULL)) \
+            RETURN c.id AS id", self.formula.compile("c"));
+        let recompute_query = neo4rs::query(&cypher)
+            .param("card_id", card_id.map(|it| it.as_str()).unwrap_or_default())
+            .param("org_id", self.org_id.as_str())
+            .param("card_type_id", self.card_type_id.as_str());
+        let graph = get_graph().await;
+        let mut result = graph.execute(recompute_query).await?;
+        let mut changed = Vec::new();
+        while let Some(row) = result.next().await? {
+            let id: String = row.get("id")?;
+            changed.push(CardId::from(id));
+        }
+        Ok(changed)
+    }
+}
+
+fn rs_type(descriptor: &LinkDescriptor) -> &str {
+    match descriptor {
+        LinkDescriptor::Src(rs_type) | LinkDescriptor::Dest(rs_type) => rs_type,
+    }
+}
+
+#[derive(Default)]
+pub struct ComputedFieldRegistry {
+    fields: RwLock<HashMap<TenantId, Vec<ComputedField>>>, //按组织分组
+}
+
+impl ComputedFieldRegistry {
+    //公式本身合法，并且计算属性之间不能循环引用
+    pub fn ensure_valid(&self, field: &ComputedField) -> Result<()> {
+        field.formula.validate()?;
+        self.ensure_acyclic(field)
+    }
+
+    //求和引用的是关联卡片上的属性，关联卡片的类型无法确定，视为引用所有卡片类型上的同名计算属性
+    fn ensure_acyclic(&self, field: &ComputedField) -> Result<()> {
+        let fields = self.fields.read().unwrap();
+        let others: Vec<&ComputedField> = fields.get(&field.org_id).into_iter().flatten()
+            .filter(|it| !(it.card_type_id == field.card_type_id && it.id == field.id))
+            .collect();
+        let mut visiting = field.references();
+        let mut visited = HashSet::new();
+        while let Some((card_type_id, field_id)) = visiting.pop() {
+            if *field_id == field.id && card_type_id.is_none_or(|it| it == field.card_type_id) {
+                return Err(Box::new(ComputedFieldError::new(&format!("computed field {} references itself", field.id))));
+            }
+            if visited.insert((card_type_id, field_id)) {
+                for referenced in others.iter().filter(|it| it.id == *field_id && card_type_id.is_none_or(|t| t == it.card_type_id)) {
+                    visiting.extend(referenced.references());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add(&self, field: ComputedField) -> Result<()> {
+        self.ensure_valid(&field)?;
+        let mut fields = self.fields.write().unwrap();
+        let org_fields = fields.entry(field.org_id.clone()).or_default();
+        org_fields.retain(|it| !(it.card_type_id == field.card_type_id && it.id == field.id));
+        org_fields.push(field);
+        Ok(())
+    }
+
+    //启动时加载保存在图数据库中的计算属性
+    pub async fn load(&self) -> Result<usize> {
+        let fields: Vec<ComputedField> = SettingStore::load_all(COMPUTED_FIELD_SETTING).await
+            .map_err(|err| ComputedFieldError::new(&err.to_string()))?;
+        let size = fields.len();
+        for field in fields {
+            if let Err(err) = self.add(field) {
+                eprintln!("skipped computed field: {}", err);
+            }
+        }
+        Ok(size)
+    }
+
+    pub fn remove(&self, org_id: &TenantId, card_type_id: &str, field_id: &FieldId) -> bool {
+        let mut fields = self.fields.write().unwrap();
+        if let Some(org_fields) = fields.get_mut(org_id) {
+            let size = org_fields.len();
+            org_fields.retain(|it| !(it.card_type_id == card_type_id && it.id == *field_id));
+            return org_fields.len() < size;
+        }
+        false
+    }
+
+    pub fn list(&self, org_id: &TenantId) -> Vec<ComputedField> {
+        let fields = self.fields.read().unwrap();
+        fields.get(org_id).cloned().unwrap_or_default()
+    }
+
+    //卡片的属性是否是计算属性，计算属性不允许直接修改
+    pub fn is_computed(&self, org_id: &TenantId, card_type_id: &str, field_id: &FieldId) -> bool {
+        self.list(org_id).iter().any(|it| it.card_type_id == card_type_id && it.id == *field_id)
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        let mut receiver = events::subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let registry = self.clone();
+                    tokio::spawn(async move {
+                        registry.on_event(&event).await;
+                    });
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("computed fields lagged behind, {skipped} card events skipped");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    //重新计算某个计算属性的所有卡片，返回值发生了变化的卡片数
+    pub async fn recompute_all(&self, field: &ComputedField, operator_id: &CardId) -> Result<usize> {
+        let changed = field.recompute_all().await?;
+        for card_id in &changed {
+            let kind = CardEventKind::Updated(vec![field.id.clone()]);
+            events::publish(CardEvent::new(&field.org_id, card_id, &field.card_type_id, kind, operator_id));
+        }
+        Ok(changed.len())
+    }
+
+    //值发生变化的卡片会发布属性更新事件，引用了该计算属性的其他计算属性随之重新计算
+    pub async fn on_event(&self, event: &CardEvent) {
+        for field in self.list(&event.org_id) {
+            for anchor in field.anchors(event) {
+                match field.recompute(&anchor).await {
+                    Ok(changed) => {
+                        for card_id in changed {
+                            let kind = CardEventKind::Updated(vec![field.id.clone()]);
+                            events::publish(CardEvent::new(&field.org_id, &card_id, &field.card_type_id, kind, &event.operator_id));
+                        }
+                    }
+                    Err(err) => eprintln!("failed to recompute field {}: {}", field.id, err),
+                }
+            }
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
+
+#[derive(Debug)]
+pub struct ComputedFieldError {
+    message: String,
+}
+
+impl ComputedFieldError {
+    pub fn new(message: &str) -> Self {
+        Self { message: message.to_string() }
+    }
+}
+
+impl Display for ComputedFieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for ComputedFieldError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formula::ArithmeticOperator;
+    use crate::types::Path;
+
+    fn sum_of_tasks() -> ComputedField {
+        let path = Path::Segment(LinkDescriptor::Src(String::from("子任务")), Box::new(Path::Nil));
+        ComputedField::new(&TenantId::from_str("o1"), "需求", FieldId::from_str("总估时"), Formula::Sum(path, FieldId::from_str("估时")))
+    }
+
+    fn event(card_type_id: &str, kind: CardEventKind) -> CardEvent {
+        CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), card_type_id, kind, &CardId::from_str("m1"))
+    }
+
+    #[test]
+    fn test_anchors() {
+        let field = sum_of_tasks();
+        //子任务估时变化，重新计算通过子任务关联到它的需求
+        let updated = event("系统任务", CardEventKind::Updated(vec![FieldId::from_str("估时")]));
+        let anchors = field.anchors(&updated);
+        assert_eq!(anchors.len(), 1);
+        assert_eq!(anchors.iter().next().unwrap().prefix.len(), 1);
+        //需求挂上新的子任务，重新计算需求本身
+        let linked = event("需求", CardEventKind::Linked(LinkDescriptor::Src(String::from("子任务")), CardId::from_str("c2")));
+        let anchors = field.anchors(&linked);
+        assert!(anchors.contains(&Anchor { card_id: &linked.card_id, prefix: vec![] }));
+        //无关的关联和属性不触发计算
+        let linked = event("需求", CardEventKind::Linked(LinkDescriptor::Src(String::from("负责人")), CardId::from_str("c2")));
+        assert!(field.anchors(&linked).is_empty());
+        let updated = event("需求", CardEventKind::Updated(vec![FieldId::from_str("优先级")]));
+        assert!(field.anchors(&updated).is_empty());
+    }
+
+    #[test]
+    fn test_reject_cycle() {
+        let registry = ComputedFieldRegistry::default();
+        registry.add(sum_of_tasks()).unwrap();
+        let a = ComputedField::new(&TenantId::from_str("o1"), "需求", FieldId::from_str("a"), Formula::Binary(
+            Box::new(Formula::Field(FieldId::from_str("总估时"))), ArithmeticOperator::Add, Box::new(Formula::Field(FieldId::from_str("b")))));
+        registry.add(a).unwrap();
+        let b = ComputedField::new(&TenantId::from_str("o1"), "需求", FieldId::from_str("b"), Formula::Field(FieldId::from_str("a")));
+        assert!(registry.add(b).is_err());
+        //跨卡片类型的求和同样不能形成循环：需求的总估时汇总子任务的估时，估时又汇总需求的总估时
+        let estimate = ComputedField::new(&TenantId::from_str("o1"), "系统任务", FieldId::from_str("估时"), Formula::Sum(
+            Path::Segment(LinkDescriptor::Dest(String::from("子任务")), Box::new(Path::Nil)), FieldId::from_str("总估时")));
+        assert_eq!(registry.add(estimate).unwrap_err().to_string(), "computed field 估时 references itself");
+        let count = ComputedField::new(&TenantId::from_str("o1"), "系统任务", FieldId::from_str("估时"), Formula::Count(Path::Nil));
+        assert!(registry.add(count).is_err());
+        assert!(registry.is_computed(&TenantId::from_str("o1"), "需求", &FieldId::from_str("a")));
+        assert!(registry.remove(&TenantId::from_str("o1"), "需求", &FieldId::from_str("a")));
+        assert_eq!(registry.list(&TenantId::from_str("o1")).len(), 1);
+    }
+}
@@ -0,0 +1,457 @@
+//将查询条件编译成Cypher的WHERE子句，条件中的值一律以参数的形式传递，避免注入
+use crate::query::{CardTypeOperator, Condition, ConditionItem, DateOperator, EnumOperator, LinkOperator, LinkValue, NumberOperator, PropertyValue, QueryContext, QueryError, ReferPoint, TextOperator};
+use std::mem;
+use crate::types::{LinkDescriptor, Path};
+use neo4rs::BoltType;
+
+pub(crate) struct CypherCompiler<'a> {
+    var: String, //被过滤的卡片在Cypher中的变量名
+    query_context: &'a QueryContext,
+    params: Vec<(String, BoltType)>,
+    var_counter: u32, //用于生成列表推导式中不冲突的变量名
+    restricted: bool, //是否需要附加行级可见性条件，编译可见性条件本身时关闭，避免递归
+}
+
+type Result<T> = std::result::Result<T, QueryError>;
+
+impl<'a> CypherCompiler<'a> {
+    pub(crate) fn new(var: &str, query_context: &'a QueryContext) -> Self {
+        Self {
+            var: String::from(var),
+            query_context,
+            params: Vec::new(),
+            var_counter: 0,
+            restricted: true,
+        }
+    }
+
+    //编译整个条件，空条件编译为true；上下文中有可见性条件时，被过滤的卡片必须可见
+    pub(crate) fn compile(&mut self, condition: &Condition) -> Result<String> {
+        let compiled = self.compile_condition(condition)?;
+        let var = self.var.clone();
+        match self.visible(&var)? {
+            Some(visible) if compiled == "true" => Ok(visible),
+            Some(visible) => Ok(format!("({compiled} AND {visible})")),
+            None => Ok(compiled),
+        }
+    }
+
+    fn compile_condition(&mut self, condition: &Condition) -> Result<String> {
+        let mut ands = Vec::new();
+        for item in &condition.items {
+            ands.push(self.compile_item(item)?);
+        }
+        for bulk in &condition.logic_condition_bulks {
+            for group in &bulk.groups {
+                let mut ors = Vec::new();
+                for item in &group.items {
+                    ors.push(self.compile_item(item)?);
+                }
+                if !ors.is_empty() {
+                    ands.push(format!("({})", ors.join(" OR ")));
+                }
+            }
+        }
+        if ands.is_empty() {
+            return Ok(String::from("true"));
+        }
+        Ok(format!("({})", ands.join(" AND ")))
+    }
+
+    pub(crate) fn into_params(self) -> Vec<(String, BoltType)> {
+        self.params
+    }
+
+    fn compile_item(&mut self, item: &ConditionItem) -> Result<String> {
+        let var = self.var.clone();
+        let compiled = match item {
+            ConditionItem::CardType(CardTypeOperator::AnyIn(ids)) => {
+                format!("{var}.card_type_id IN {}", self.param(ids.clone()))
+            }
+            ConditionItem::State(states) => {
+                let states: Vec<String> = states.iter().map(|it| it.to_string()).collect();
+                format!("{var}.state IN {}", self.param(states))
+            }
+            ConditionItem::Status(status_ids) => {
+                format!("{var}.flow_status_id IN {}", self.param(status_ids.clone()))
+            }
+            ConditionItem::Code(code) => {
+                format!("{var}.code = {}", self.param(code.as_str()))
+            }
+            ConditionItem::Title(title) => {
+                format!("{var}.name CONTAINS {}", self.param(title.as_str()))
+            }
+            ConditionItem::Text(field_id, operator) => {
+                let prop = property(&var, field_id);
+                match operator {
+                    TextOperator::StartsWith(v) => format!("{prop} STARTS WITH {}", self.param(v.as_str())),
+                    TextOperator::Contains(v) => format!("{prop} CONTAINS {}", self.param(v.as_str())),
+                    TextOperator::NotContains(v) => format!("NOT {prop} CONTAINS {}", self.param(v.as_str())),
+                    TextOperator::Equals(v) => format!("{prop} = {}", self.property_value(v)?),
+                    TextOperator::NotEquals(v) => format!("{prop} <> {}", self.property_value(v)?),
+                    TextOperator::IsNull(is_null) => is_null_str(&prop, *is_null),
+                }
+            }
+            ConditionItem::Number(field_id, operator) => {
+                let prop = property(&var, field_id);
+                match operator {
+                    NumberOperator::LessThan(v) => format!("{prop} < {}", self.property_value(v)?),
+                    NumberOperator::GreaterThan(v) => format!("{prop} > {}", self.property_value(v)?),
+                    NumberOperator::LessThanOrEqualTo(v) => format!("{prop} <= {}", self.property_value(v)?),
+                    NumberOperator::GreaterThanOrEqualTo(v) => format!("{prop} >= {}", self.property_value(v)?),
+                    NumberOperator::Between(start, end) => self.between(&prop, start, end)?,
+                    NumberOperator::NotBetween(start, end) => format!("NOT {}", self.between(&prop, start, end)?),
+                    NumberOperator::Equals(v) => format!("{prop} = {}", self.property_value(v)?),
+                    NumberOperator::NotEquals(v) => format!("{prop} <> {}", self.property_value(v)?),
+                    NumberOperator::IsNull(is_null) => is_null_str(&prop, *is_null),
+                }
+            }
+            ConditionItem::Enum(field_id, operator) => {
+                let prop = property(&var, field_id);
+                match operator {
+                    EnumOperator::AnyIn(v) => {
+                        let values = self.property_value(v)?;
+                        self.any_in(&prop, &values)
+                    }
+                    EnumOperator::AllIn(v) => {
+                        let values = self.property_value(v)?;
+                        self.all_in(&prop, &values)
+                    }
+                    EnumOperator::AnyNotIn(v) => {
+                        let values = self.property_value(v)?;
+                        format!("NOT {}", self.all_in(&prop, &values))
+                    }
+                    EnumOperator::AllNotIn(v) => {
+                        let values = self.property_value(v)?;
+                        format!("NOT {}", self.any_in(&prop, &values))
+                    }
+                    EnumOperator::IsNull(is_null) => is_null_str(&prop, *is_null),
+                }
+            }
+            ConditionItem::Date(field_id, operator) => {
+                let prop = property(&var, field_id);
+                match operator {
+                    DateOperator::After(v) => format!("{prop} > {}", self.property_value(v)?),
+                    DateOperator::Before(v) => format!("{prop} < {}", self.property_value(v)?),
+                    DateOperator::Equals(v) => format!("{prop} = {}", self.property_value(v)?),
+                    DateOperator::NotEquals(v) => format!("{prop} <> {}", self.property_value(v)?),
+                    DateOperator::Between(start, end) => self.between(&prop, start, end)?,
+                    DateOperator::NotBetween(start, end) => format!("NOT {}", self.between(&prop, start, end)?),
+                    DateOperator::IsNull(is_null) => is_null_str(&prop, *is_null),
+                }
+            }
+            ConditionItem::Link(descriptor, operator) => {
+                let x = self.next_var();
+                let pattern = self.traverse(&format!("({var})"), &[descriptor], &x)?;
+                let linked = format!("[{pattern} | {x}.id]");
+                self.link(&linked, operator)?
+            }
+            ConditionItem::MySelf(operator) => {
+                let linked = format!("[{var}.id]");
+                self.link(&linked, operator)?
+            }
+        };
+        Ok(compiled)
+    }
+
+    fn link(&mut self, linked: &str, operator: &LinkOperator) -> Result<String> {
+        let compiled = match operator {
+            LinkOperator::AnyIn(v) => {
+                let values = self.link_value(v)?;
+                self.any_in(linked, &values)
+            }
+            LinkOperator::AllIn(v) => {
+                let values = self.link_value(v)?;
+                self.all_in(linked, &values)
+            }
+            LinkOperator::AnyNotIn(v) => {
+                let values = self.link_value(v)?;
+                format!("NOT {}", self.all_in(linked, &values))
+            }
+            LinkOperator::AllNotIn(v) => {
+                let values = self.link_value(v)?;
+                format!("NOT {}", self.any_in(linked, &values))
+            }
+            LinkOperator::IsNull(true) => format!("size({linked}) = 0"),
+            LinkOperator::IsNull(false) => format!("size({linked}) > 0"),
+        };
+        Ok(compiled)
+    }
+
+    //列表中至少有一个值在values中
+    fn any_in(&mut self, list: &str, values: &str) -> String {
+        let x = self.next_var();
+        format!("any({x} IN coalesce({list}, []) WHERE {x} IN {values})")
+    }
+
+    //values中的每个值都在列表中
+    fn all_in(&mut self, list: &str, values: &str) -> String {
+        let x = self.next_var();
+        format!("all({x} IN {values} WHERE {x} IN coalesce({list}, []))")
+    }
+
+    fn between<T: Clone + Into<BoltType>>(&mut self, prop: &str, start: &PropertyValue<T>, end: &PropertyValue<T>) -> Result<String> {
+        Ok(format!("({prop} >= {} AND {prop} <= {})", self.property_value(start)?, self.property_value(end)?))
+    }
+
+    fn property_value<T: Clone + Into<BoltType>>(&mut self, value: &PropertyValue<T>) -> Result<String> {
+        match value {
+            PropertyValue::StaticValue(v) => Ok(self.param(v.clone())),
+            PropertyValue::ReferValue(refer_point, path, field_id) => {
+                let start = self.refer_point(refer_point)?;
+                let x = self.next_var();
+                let descriptors = path_descriptors(path);
+                let pattern = if descriptors.is_empty() {
+                    format!("(r_{x}:Card {{id:{start}, org_id:$tenant_id}})-[*0]-({x})")
+                } else {
+                    self.traverse(&format!("(r_{x}:Card {{id:{start}, org_id:$tenant_id}})"), &descriptors, &x)?
+                };
+                Ok(format!("head([{pattern} | {}])", property(&x, field_id)))
+            }
+        }
+    }
+
+    fn link_value(&mut self, value: &LinkValue) -> Result<String> {
+        match value {
+            LinkValue::StaticValue(ids) => Ok(self.param(ids.clone())),
+            LinkValue::ReferValue(refer_point, descriptors) => {
+                let start = self.refer_point(refer_point)?;
+                if descriptors.is_empty() {
+                    return Ok(format!("[{start}]"));
+                }
+                let x = self.next_var();
+                let descriptors: Vec<&LinkDescriptor> = descriptors.iter().collect();
+                let pattern = self.traverse(&format!("(r_{x}:Card {{id:{start}, org_id:$tenant_id}})"), &descriptors, &x)?;
+                Ok(format!("[{pattern} | {x}.id]"))
+            }
+        }
+    }
+
+    //沿关联路径遍历，路径上的每张卡片都必须可见，否则不能经由它到达后面的卡片
+    fn traverse(&mut self, start: &str, descriptors: &[&LinkDescriptor], end: &str) -> Result<String> {
+        if !self.is_restricted() {
+            return Ok(format!("{start}{}", chain(descriptors, end)));
+        }
+        let mut pattern = String::from(start);
+        let mut visibles = Vec::new();
+        for (i, descriptor) in descriptors.iter().enumerate() {
+            let node = if i == descriptors.len() - 1 { String::from(end) } else { format!("{end}_{i}") };
+            pattern.push_str(&format!("{}({node})", relationship(descriptor)));
+            if let Some(visible) = self.visible(&node)? {
+                visibles.push(visible);
+            }
+        }
+        Ok(format!("{pattern} WHERE {}", visibles.join(" AND ")))
+    }
+
+    fn is_restricted(&self) -> bool {
+        self.restricted && (!self.query_context.visibility_filters.is_empty() || self.query_context.viewable_card_types.is_some())
+    }
+
+    //卡片var对当前成员可见的条件：卡片类型可以查看并且满足该类型的行级可见性，没有限制时返回None
+    pub(crate) fn visible(&mut self, var: &str) -> Result<Option<String>> {
+        if !self.is_restricted() {
+            return Ok(None);
+        }
+        let query_context = self.query_context;
+        let saved_var = mem::replace(&mut self.var, String::from(var));
+        self.restricted = false;
+        let mut compiled = Ok(Vec::new());
+        if let Some(card_type_ids) = &query_context.viewable_card_types {
+            let card_type_ids = self.param(card_type_ids.clone());
+            compiled = Ok(vec![format!("{var}.card_type_id IN {card_type_ids}")]);
+        }
+        for filter in &query_context.visibility_filters {
+            let card_type_id = self.param(filter.card_type_id.as_str());
+            match self.compile_condition(&filter.condition) {
+                Ok(condition) => {
+                    if let Ok(ands) = &mut compiled {
+                        ands.push(format!("({var}.card_type_id <> {card_type_id} OR {condition})"));
+                    }
+                }
+                Err(err) => {
+                    compiled = Err(err);
+                    break;
+                }
+            }
+        }
+        self.restricted = true;
+        self.var = saved_var;
+        Ok(Some(compiled?.join(" AND ")))
+    }
+
+    //参考点卡片的id参数，匹配参考点卡片时还要限定org_id为$tenant_id，避免经由参数引用到其他租户的卡片
+    fn refer_point(&mut self, refer_point: &ReferPoint) -> Result<String> {
+        match self.query_context.refer_point_id(refer_point) {
+            Some(id) => {
+                let id = String::from(id);
+                Ok(self.param(id))
+            }
+            None => Err(QueryError::new(&format!("missing refer point {:?} in query context", refer_point))),
+        }
+    }
+
+    fn param<T: Into<BoltType>>(&mut self, value: T) -> String {
+        let key = format!("p{}", self.params.len());
+        self.params.push((key.clone(), value.into()));
+        format!("${key}")
+    }
+
+    fn next_var(&mut self) -> String {
+        self.var_counter += 1;
+        format!("x{}", self.var_counter)
+    }
+}
+
+//属性id可能包含中文或特殊字符，所以需要用反引号包裹
+pub(crate) fn property(var: &str, field_id: &str) -> String {
+    format!("{var}.`{}`", field_id.replace('`', "``"))
+}
+
+fn is_null_str(prop: &str, is_null: bool) -> String {
+    if is_null {
+        format!("{prop} IS NULL")
+    } else {
+        format!("{prop} IS NOT NULL")
+    }
+}
+
+//Src表示当前卡片是关联关系的起点，Dest表示当前卡片是关联关系的终点
+pub(crate) fn relationship(descriptor: &LinkDescriptor) -> String {
+    match descriptor {
+        LinkDescriptor::Src(rs_type) => format!("-[:`{}`]->", rs_type.replace('`', "``")),
+        LinkDescriptor::Dest(rs_type) => format!("<-[:`{}`]-", rs_type.replace('`', "``")),
+    }
+}
+
+//按关联描述符逐段拼接路径，中间节点匿名，最后一个节点命名为end
+pub(crate) fn chain(descriptors: &[&LinkDescriptor], end: &str) -> String {
+    let mut pattern = String::new();
+    for (i, descriptor) in descriptors.iter().enumerate() {
+        pattern.push_str(&relationship(descriptor));
+        if i == descriptors.len() - 1 {
+            pattern.push_str(&format!("({end})"));
+        } else {
+            pattern.push_str("()");
+        }
+    }
+    pattern
+}
+
+pub(crate) fn path_descriptors(path: &Path) -> Vec<&LinkDescriptor> {
+    let mut descriptors = Vec::new();
+    let mut current = path;
+    while let Path::Segment(descriptor, next) = current {
+        descriptors.push(descriptor);
+        current = next;
+    }
+    descriptors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::card::CardState;
+    use crate::newtypes::field_id::FieldId;
+    use crate::newtypes::tenant_id::TenantId;
+    use crate::query::{LogicConditionBulk, LogicConditionGroup, VisibilityFilter};
+    use std::collections::HashMap;
+
+    fn context() -> QueryContext {
+        QueryContext::new(&TenantId::from_str("o1"), "m1", HashMap::new())
+    }
+
+    #[test]
+    fn test_compile_empty_condition() {
+        let ctx = context();
+        let mut compiler = CypherCompiler::new("c", &ctx);
+        assert_eq!(compiler.compile(&Condition::default()).unwrap(), "true");
+        assert!(compiler.into_params().is_empty());
+    }
+
+    #[test]
+    fn test_compile_and_or() {
+        let ctx = context();
+        let mut condition = Condition::default();
+        condition.and(ConditionItem::CardType(CardTypeOperator::AnyIn(vec!["系统任务".to_string()])))
+            .and(ConditionItem::State(vec![CardState::Active]))
+            .and_logic(LogicConditionBulk::new(vec![
+                LogicConditionGroup::new(vec![
+                    ConditionItem::Code("1".to_string()),
+                    ConditionItem::Number(FieldId::from_str("估时"), NumberOperator::Between(PropertyValue::StaticValue(1), PropertyValue::StaticValue(5))),
+                ])
+            ]));
+        let mut compiler = CypherCompiler::new("c", &ctx);
+        let compiled = compiler.compile(&condition).unwrap();
+        assert_eq!(compiled, "(c.card_type_id IN $p0 AND c.state IN $p1 AND (c.code = $p2 OR (c.`估时` >= $p3 AND c.`估时` <= $p4)))");
+        assert_eq!(compiler.into_params().len(), 5);
+    }
+
+    #[test]
+    fn test_compile_link_refer_current_member() {
+        let ctx = context();
+        let mut condition = Condition::default();
+        condition.and(ConditionItem::Link(
+            LinkDescriptor::Src("owner".to_string()),
+            LinkOperator::AnyIn(LinkValue::ReferValue(ReferPoint::CurrentMember, vec![])),
+        ));
+        let mut compiler = CypherCompiler::new("c", &ctx);
+        let compiled = compiler.compile(&condition).unwrap();
+        assert_eq!(compiled, "(any(x2 IN coalesce([(c)-[:`owner`]->(x1) | x1.id], []) WHERE x2 IN [$p0]))");
+        let params = compiler.into_params();
+        assert_eq!(params[0].1, BoltType::from("m1"));
+    }
+
+    #[test]
+    fn test_compile_refer_value_through_path() {
+        let ctx = context();
+        let mut condition = Condition::default();
+        let path = Path::Segment(LinkDescriptor::Dest("member".to_string()), Box::new(Path::Nil));
+        condition.and(ConditionItem::Text(
+            FieldId::from_str("team"),
+            TextOperator::Equals(PropertyValue::ReferValue(ReferPoint::CurrentMember, path, "name".to_string())),
+        ));
+        let mut compiler = CypherCompiler::new("c", &ctx);
+        let compiled = compiler.compile(&condition).unwrap();
+        assert_eq!(compiled, "(c.`team` = head([(r_x1:Card {id:$p0, org_id:$tenant_id})<-[:`member`]-(x1) | x1.`name`]))");
+    }
+
+    #[test]
+    fn test_visibility_filter() {
+        let mut private = Condition::default();
+        private.and(ConditionItem::Link(
+            LinkDescriptor::Src("member".to_string()),
+            LinkOperator::AnyIn(LinkValue::ReferValue(ReferPoint::CurrentMember, vec![])),
+        ));
+        let ctx = context().with_visibility_filters(vec![VisibilityFilter::new("私有任务", private)]);
+        let mut condition = Condition::default();
+        condition.and(ConditionItem::Link(LinkDescriptor::Dest("子任务".to_string()), LinkOperator::IsNull(false)));
+        let mut compiler = CypherCompiler::new("c", &ctx);
+        let compiled = compiler.compile(&condition).unwrap();
+        //经由关联到达的卡片和被查询的卡片都要满足可见性条件
+        assert_eq!(compiled, "((size([(c)<-[:`子任务`]-(x1) WHERE (x1.card_type_id <> $p0 OR (any(x3 IN coalesce([(x1)-[:`member`]->(x2) | x2.id], []) WHERE x3 IN [$p1]))) | x1.id]) > 0) AND (c.card_type_id <> $p2 OR (any(x5 IN coalesce([(c)-[:`member`]->(x4) | x4.id], []) WHERE x5 IN [$p3]))))");
+    }
+
+    #[test]
+    fn test_viewable_card_types() {
+        let ctx = context().with_viewable_card_types(vec![String::from("需求")]);
+        let mut condition = Condition::default();
+        condition.and(ConditionItem::Link(LinkDescriptor::Dest("子任务".to_string()), LinkOperator::IsNull(false)));
+        let mut compiler = CypherCompiler::new("c", &ctx);
+        let compiled = compiler.compile(&condition).unwrap();
+        assert_eq!(compiled, "((size([(c)<-[:`子任务`]-(x1) WHERE x1.card_type_id IN $p0 | x1.id]) > 0) AND c.card_type_id IN $p1)");
+        //没有任何可以查看的卡片类型时什么都查不到
+        let ctx = context().with_viewable_card_types(vec![]);
+        let mut compiler = CypherCompiler::new("c", &ctx);
+        assert_eq!(compiler.compile(&Condition::default()).unwrap(), "c.card_type_id IN $p0");
+    }
+
+    #[test]
+    fn test_missing_refer_point() {
+        let ctx = context();
+        let mut condition = Condition::default();
+        condition.and(ConditionItem::MySelf(LinkOperator::AnyIn(LinkValue::ReferValue(ReferPoint::Parameter, vec![]))));
+        let mut compiler = CypherCompiler::new("c", &ctx);
+        assert!(compiler.compile(&condition).is_err());
+    }
+}
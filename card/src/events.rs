@@ -0,0 +1,147 @@
+use crate::newtypes::card_id::CardId;
+use crate::newtypes::field_id::FieldId;
+use crate::newtypes::tenant_id::TenantId;
+use crate::newtypes::timestamp::Timestamp;
+use crate::types::LinkDescriptor;
+use common::id_generator;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::LazyLock;
+use tokio::sync::broadcast;
+
+//卡片事件，卡片变更成功后发布，通知、业务规则、统计等模块通过订阅事件来响应卡片的变化
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CardEvent {
+    pub id: String,
+    pub org_id: TenantId,
+    pub card_id: CardId,
+    pub card_type_id: String,
+    pub kind: CardEventKind,
+    pub operator_id: CardId, //触发事件的成员
+    pub occur_time: Timestamp,
+    #[serde(default)]
+    pub caused_by: Vec<String>, //由业务规则引起的变更，记录依次触发的规则id，用于防止规则之间循环触发
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CardEventKind {
+    Created,
+    Updated(Vec<FieldId>), //发生变化的属性
+    Archived,
+    Abandoned(String), //丢弃原因
+    Restored,
+    FlowStatusChanged {
+        flow_id: String,
+        from: Option<String>,
+        to: String,
+    },
+    Linked(LinkDescriptor, CardId),
+    Unlinked(LinkDescriptor, CardId),
+}
+
+//事件类型，不携带事件数据，用于订阅时按类型过滤
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CardEventType {
+    Created,
+    Updated,
+    Archived,
+    Abandoned,
+    Restored,
+    FlowStatusChanged,
+    Linked,
+    Unlinked,
+}
+
+impl CardEventKind {
+    pub fn event_type(&self) -> CardEventType {
+        match self {
+            CardEventKind::Created => CardEventType::Created,
+            CardEventKind::Updated(_) => CardEventType::Updated,
+            CardEventKind::Archived => CardEventType::Archived,
+            CardEventKind::Abandoned(_) => CardEventType::Abandoned,
+            CardEventKind::Restored => CardEventType::Restored,
+            CardEventKind::FlowStatusChanged { .. } => CardEventType::FlowStatusChanged,
+            CardEventKind::Linked(_, _) => CardEventType::Linked,
+            CardEventKind::Unlinked(_, _) => CardEventType::Unlinked,
+        }
+    }
+}
+
+impl CardEvent {
+    pub fn new(org_id: &TenantId, card_id: &CardId, card_type_id: &str, kind: CardEventKind, operator_id: &CardId) -> Self {
+        Self {
+            id: id_generator::generate_id(),
+            org_id: org_id.clone(),
+            card_id: card_id.clone(),
+            card_type_id: String::from(card_type_id),
+            kind,
+            operator_id: operator_id.clone(),
+            occur_time: Timestamp::now(),
+            caused_by: CAUSED_BY.try_with(|it| it.clone()).unwrap_or_default(),
+        }
+    }
+
+    pub fn event_type(&self) -> CardEventType {
+        self.kind.event_type()
+    }
+}
+
+tokio::task_local! {
+    static CAUSED_BY: Vec<String>;
+}
+
+//在规则链上下文中执行变更，期间产生的事件都会带上该规则链
+pub async fn with_caused_by<F: Future>(caused_by: Vec<String>, f: F) -> F::Output {
+    CAUSED_BY.scope(caused_by, f).await
+}
+
+//进程内的事件总线，订阅者过慢时会丢失最旧的事件
+const EVENT_BUS_CAPACITY: usize = 4096;
+
+static EVENT_BUS: LazyLock<broadcast::Sender<CardEvent>> = LazyLock::new(|| broadcast::channel(EVENT_BUS_CAPACITY).0);
+
+pub fn publish(event: CardEvent) {
+    //没有订阅者时send会返回错误，事件直接丢弃即可
+    let _ = EVENT_BUS.send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<CardEvent> {
+    EVENT_BUS.subscribe()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_and_subscribe() {
+        let mut receiver = subscribe();
+        let event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "t1", CardEventKind::Created, &CardId::from_str("m1"));
+        publish(event.clone());
+        let received = receiver.recv().await.unwrap();
+        assert_eq!(received, event);
+        assert_eq!(received.event_type(), CardEventType::Created);
+    }
+
+    #[tokio::test]
+    async fn test_caused_by() {
+        let event = with_caused_by(vec!["r1".to_string()], async {
+            CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "t1", CardEventKind::Created, &CardId::from_str("m1"))
+        }).await;
+        assert_eq!(event.caused_by, vec!["r1".to_string()]);
+        let event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "t1", CardEventKind::Created, &CardId::from_str("m1"));
+        assert!(event.caused_by.is_empty());
+    }
+
+    #[test]
+    fn test_event_serde() {
+        let event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "t1", CardEventKind::FlowStatusChanged {
+            flow_id: "f1".to_string(),
+            from: None,
+            to: "s1".to_string(),
+        }, &CardId::from_str("m1"));
+        let json = serde_json::to_string(&event).unwrap();
+        let deserialized: CardEvent = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, event);
+    }
+}
@@ -0,0 +1,307 @@
+//计算属性的公式，编译成Cypher表达式后在图数据库中求值
+use crate::cypher::{chain, path_descriptors, property};
+use crate::newtypes::field_id::FieldId;
+use crate::types::{LinkDescriptor, Path};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::{error, fmt};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Formula {
+    Number(f64),
+    Field(FieldId), //当前卡片的数字、日期属性
+    Binary(Box<Formula>, ArithmeticOperator, Box<Formula>),
+    DateDiff {
+        from: Box<Formula>,
+        to: Box<Formula>,
+        unit: DateUnit,
+    },
+    Count(Path), //沿路径关联到的卡片数
+    Sum(Path, FieldId), //沿路径关联到的卡片的属性之和，空值按0计算
+    If {
+        predicate: Box<Predicate>,
+        then: Box<Formula>,
+        otherwise: Box<Formula>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ArithmeticOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide, //除数为0时结果为空
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DateUnit {
+    Day,
+    Hour,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Predicate {
+    Compare(Box<Formula>, CompareOperator, Box<Formula>),
+    IsNull(Box<Formula>),
+    And(Vec<Predicate>),
+    Or(Vec<Predicate>),
+    Not(Box<Predicate>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CompareOperator {
+    Equals,
+    NotEquals,
+    LessThan,
+    GreaterThan,
+    LessThanOrEqualTo,
+    GreaterThanOrEqualTo,
+}
+
+impl DateUnit {
+    //时间戳为毫秒
+    fn millis(&self) -> i64 {
+        match self {
+            DateUnit::Day => 24 * 60 * 60 * 1000,
+            DateUnit::Hour => 60 * 60 * 1000,
+        }
+    }
+}
+
+impl Formula {
+    //计数和求和至少要沿一段关联，空路径无法编译成合法的Cypher
+    pub fn validate(&self) -> Result<(), FormulaError> {
+        let mut invalid = None;
+        self.visit(&mut |formula| match formula {
+            Formula::Count(Path::Nil) => invalid = Some(String::from("count over an empty path")),
+            Formula::Sum(Path::Nil, field_id) => invalid = Some(format!("sum of {} over an empty path", field_id)),
+            _ => {}
+        });
+        match invalid {
+            Some(message) => Err(FormulaError::new(&message)),
+            None => Ok(()),
+        }
+    }
+
+    //当前卡片上被公式直接引用的属性
+    pub fn fields(&self) -> Vec<&FieldId> {
+        let mut fields = Vec::new();
+        self.visit(&mut |formula| {
+            if let Formula::Field(field_id) = formula {
+                fields.push(field_id);
+            }
+        });
+        fields
+    }
+
+    //公式中的关联路径，以及路径终点上被引用的属性
+    pub fn paths(&self) -> Vec<(Vec<&LinkDescriptor>, Option<&FieldId>)> {
+        let mut paths = Vec::new();
+        self.visit(&mut |formula| match formula {
+            Formula::Count(path) => paths.push((path_descriptors(path), None)),
+            Formula::Sum(path, field_id) => paths.push((path_descriptors(path), Some(field_id))),
+            _ => {}
+        });
+        paths
+    }
+
+    fn visit<'a>(&'a self, f: &mut impl FnMut(&'a Formula)) {
+        f(self);
+        match self {
+            Formula::Binary(left, _, right) => {
+                left.visit(f);
+                right.visit(f);
+            }
+            Formula::DateDiff { from, to, .. } => {
+                from.visit(f);
+                to.visit(f);
+            }
+            Formula::If { predicate, then, otherwise } => {
+                predicate.visit(f);
+                then.visit(f);
+                otherwise.visit(f);
+            }
+            Formula::Number(_) | Formula::Field(_) | Formula::Count(_) | Formula::Sum(_, _) => {}
+        }
+    }
+
+    //编译成以var为当前卡片的Cypher表达式，公式由管理员定义且常量只有数字，所以直接内联
+    pub(crate) fn compile(&self, var: &str) -> String {
+        let mut var_counter = 0;
+        self.compile_with(var, &mut var_counter)
+    }
+
+    fn compile_with(&self, var: &str, var_counter: &mut u32) -> String {
+        match self {
+            Formula::Number(v) => format!("{v:?}"),
+            Formula::Field(field_id) => property(var, field_id),
+            Formula::Binary(left, operator, right) => {
+                let left = left.compile_with(var, var_counter);
+                let right = right.compile_with(var, var_counter);
+                match operator {
+                    ArithmeticOperator::Add => format!("({left} + {right})"),
+                    ArithmeticOperator::Subtract => format!("({left} - {right})"),
+                    ArithmeticOperator::Multiply => format!("({left} * {right})"),
+                    ArithmeticOperator::Divide => format!("(CASE WHEN {right} = 0 THEN null ELSE toFloat({left}) / {right} END)"),
+                }
+            }
+            Formula::DateDiff { from, to, unit } => {
+                //日期属性以毫秒时间戳的形式保存，可能是字符串
+                let from = from.compile_with(var, var_counter);
+                let to = to.compile_with(var, var_counter);
+                format!("((toInteger({to}) - toInteger({from})) / {}.0)", unit.millis())
+            }
+            Formula::Count(path) => {
+                let x = next_var(var_counter);
+                format!("size([({var}){} | {x}])", chain(&path_descriptors(path), &x))
+            }
+            Formula::Sum(path, field_id) => {
+                let x = next_var(var_counter);
+                let s = next_var(var_counter);
+                let v = next_var(var_counter);
+                let values = format!("[({var}){} | coalesce({}, 0)]", chain(&path_descriptors(path), &x), property(&x, field_id));
+                format!("reduce({s} = 0, {v} IN {values} | {s} + {v})")
+            }
+            Formula::If { predicate, then, otherwise } => {
+                let predicate = predicate.compile_with(var, var_counter);
+                let then = then.compile_with(var, var_counter);
+                let otherwise = otherwise.compile_with(var, var_counter);
+                format!("(CASE WHEN {predicate} THEN {then} ELSE {otherwise} END)")
+            }
+        }
+    }
+}
+
+impl Predicate {
+    fn visit<'a>(&'a self, f: &mut impl FnMut(&'a Formula)) {
+        match self {
+            Predicate::Compare(left, _, right) => {
+                left.visit(f);
+                right.visit(f);
+            }
+            Predicate::IsNull(formula) => formula.visit(f),
+            Predicate::And(predicates) | Predicate::Or(predicates) => {
+                for predicate in predicates {
+                    predicate.visit(f);
+                }
+            }
+            Predicate::Not(predicate) => predicate.visit(f),
+        }
+    }
+
+    fn compile_with(&self, var: &str, var_counter: &mut u32) -> String {
+        match self {
+            Predicate::Compare(left, operator, right) => {
+                let left = left.compile_with(var, var_counter);
+                let right = right.compile_with(var, var_counter);
+                let operator = match operator {
+                    CompareOperator::Equals => "=",
+                    CompareOperator::NotEquals => "<>",
+                    CompareOperator::LessThan => "<",
+                    CompareOperator::GreaterThan => ">",
+                    CompareOperator::LessThanOrEqualTo => "<=",
+                    CompareOperator::GreaterThanOrEqualTo => ">=",
+                };
+                format!("{left} {operator} {right}")
+            }
+            Predicate::IsNull(formula) => format!("{} IS NULL", formula.compile_with(var, var_counter)),
+            Predicate::And(predicates) => join(predicates, " AND ", "true", var, var_counter),
+            Predicate::Or(predicates) => join(predicates, " OR ", "false", var, var_counter),
+            Predicate::Not(predicate) => format!("NOT {}", predicate.compile_with(var, var_counter)),
+        }
+    }
+}
+
+fn join(predicates: &[Predicate], separator: &str, empty: &str, var: &str, var_counter: &mut u32) -> String {
+    if predicates.is_empty() {
+        return String::from(empty);
+    }
+    let compiled: Vec<String> = predicates.iter().map(|it| it.compile_with(var, var_counter)).collect();
+    format!("({})", compiled.join(separator))
+}
+
+fn next_var(var_counter: &mut u32) -> String {
+    *var_counter += 1;
+    format!("f{}", var_counter)
+}
+
+#[derive(Debug)]
+pub struct FormulaError {
+    message: String,
+}
+
+impl FormulaError {
+    pub fn new(message: &str) -> Self {
+        Self { message: message.to_string() }
+    }
+}
+
+impl Display for FormulaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for FormulaError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(id: &str) -> Box<Formula> {
+        Box::new(Formula::Field(FieldId::from_str(id)))
+    }
+
+    #[test]
+    fn test_compile_arithmetic() {
+        let formula = Formula::Binary(field("估时"), ArithmeticOperator::Divide, Box::new(Formula::Number(8.0)));
+        assert_eq!(formula.compile("c"), "(CASE WHEN 8.0 = 0 THEN null ELSE toFloat(c.`估时`) / 8.0 END)");
+        let formula = Formula::DateDiff { from: field("计划开始时间"), to: field("计划完成时间"), unit: DateUnit::Day };
+        assert_eq!(formula.compile("c"), "((toInteger(c.`计划完成时间`) - toInteger(c.`计划开始时间`)) / 86400000.0)");
+    }
+
+    #[test]
+    fn test_compile_aggregation() {
+        //需求下所有系统任务的估时之和
+        let path = Path::Segment(LinkDescriptor::Src(String::from("子任务")), Box::new(Path::Nil));
+        let formula = Formula::Sum(path.clone(), FieldId::from_str("估时"));
+        assert_eq!(formula.compile("c"), "reduce(f2 = 0, f3 IN [(c)-[:`子任务`]->(f1) | coalesce(f1.`估时`, 0)] | f2 + f3)");
+        assert_eq!(Formula::Count(path).compile("c"), "size([(c)-[:`子任务`]->(f1) | f1])");
+    }
+
+    #[test]
+    fn test_validate() {
+        let path = Path::Segment(LinkDescriptor::Src(String::from("子任务")), Box::new(Path::Nil));
+        assert!(Formula::Sum(path.clone(), FieldId::from_str("估时")).validate().is_ok());
+        assert!(Formula::Count(path).validate().is_ok());
+        assert!(Formula::Count(Path::Nil).validate().is_err());
+        //嵌套在其他公式中的空路径同样不允许
+        let formula = Formula::Binary(field("额外估时"), ArithmeticOperator::Add, Box::new(Formula::Sum(Path::Nil, FieldId::from_str("估时"))));
+        assert_eq!(formula.validate().unwrap_err().to_string(), "sum of 估时 over an empty path");
+    }
+
+    #[test]
+    fn test_compile_conditional() {
+        let formula = Formula::If {
+            predicate: Box::new(Predicate::Or(vec![
+                Predicate::IsNull(field("估时")),
+                Predicate::Compare(field("估时"), CompareOperator::LessThan, Box::new(Formula::Number(0.0))),
+            ])),
+            then: Box::new(Formula::Number(0.0)),
+            otherwise: field("估时"),
+        };
+        assert_eq!(formula.compile("c"), "(CASE WHEN (c.`估时` IS NULL OR c.`估时` < 0.0) THEN 0.0 ELSE c.`估时` END)");
+        assert_eq!(formula.fields().len(), 3);
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let path = Path::Segment(LinkDescriptor::Src(String::from("子任务")), Box::new(Path::Segment(LinkDescriptor::Dest(String::from("负责人")), Box::new(Path::Nil))));
+        let formula = Formula::Binary(Box::new(Formula::Sum(path, FieldId::from_str("估时"))), ArithmeticOperator::Add, field("额外估时"));
+        assert_eq!(formula.fields(), vec![&FieldId::from_str("额外估时")]);
+        let paths = formula.paths();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].0.len(), 2);
+        assert_eq!(paths[0].1, Some(&FieldId::from_str("估时")));
+    }
+}
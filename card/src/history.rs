@@ -0,0 +1,80 @@
+//卡片历史：流转、关联、归档等事件以JSON保存在图数据库的CardHistory节点上，统计时按组织和卡片读取
+//关联事件同时记录关联另一端的卡片，以便从两端都能查到
+use crate::events::{CardEvent, CardEventKind};
+use crate::graph::get_graph;
+use crate::newtypes::card_id::CardId;
+use crate::newtypes::tenant_id::TenantId;
+use std::collections::HashSet;
+use std::error;
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+pub struct HistoryStore;
+
+impl HistoryStore {
+    //同一事件重复保存时覆盖
+    pub async fn save(event: &CardEvent) -> Result<()> {
+        let other_id = match &event.kind {
+            CardEventKind::Linked(_, other_id) | CardEventKind::Unlinked(_, other_id) => Some(other_id),
+            _ => None,
+        };
+        let set_other = if other_id.is_some() { ", h.other_id = $other_id" } else { "" };
+        let mut save_query = neo4rs::query(&format!("MERGE (h:CardHistory {{org_id:$org_id, id:$id}}) SET h.card_id = $card_id, h.occur_time = $occur_time, h.value = $value{set_other}"))
+            .param("org_id", event.org_id.as_str())
+            .param("id", event.id.as_str())
+            .param("card_id", event.card_id.as_str())
+            .param("occur_time", *event.occur_time)
+            .param("value", serde_json::to_string(event)?);
+        if let Some(other_id) = other_id {
+            save_query = save_query.param("other_id", other_id.as_str());
+        }
+        get_graph().await.run(save_query).await?;
+        Ok(())
+    }
+
+    //某些卡片上since之后发生的事件，按发生时间排列
+    pub async fn events_of(tenant_id: &TenantId, card_ids: &HashSet<CardId>, since: i64) -> Result<Vec<CardEvent>> {
+        let ids: Vec<String> = card_ids.iter().map(|it| it.to_string()).collect();
+        let events_query = neo4rs::query("MATCH (h:CardHistory) WHERE h.org_id = $org_id AND h.card_id IN $card_ids AND h.occur_time >= $since \
+            RETURN h.value AS value ORDER BY h.occur_time")
+            .param("org_id", tenant_id.as_str())
+            .param("card_ids", ids)
+            .param("since", since);
+        Self::load(events_query).await
+    }
+
+    //卡片作为任意一端的关联事件
+    pub async fn link_events_of(tenant_id: &TenantId, card_id: &CardId, since: i64) -> Result<Vec<CardEvent>> {
+        let events_query = neo4rs::query("MATCH (h:CardHistory) WHERE h.org_id = $org_id AND (h.card_id = $card_id OR h.other_id = $card_id) \
+            AND h.other_id IS NOT NULL AND h.occur_time >= $since RETURN h.value AS value ORDER BY h.occur_time")
+            .param("org_id", tenant_id.as_str())
+            .param("card_id", card_id.as_str())
+            .param("since", since);
+        Self::load(events_query).await
+    }
+
+    //删除before之前的历史，返回删除的条数
+    pub async fn prune(before: i64) -> Result<i64> {
+        let prune_query = neo4rs::query("MATCH (h:CardHistory) WHERE h.occur_time < $before DELETE h RETURN count(h) AS removed")
+            .param("before", before);
+        let mut rows = get_graph().await.execute(prune_query).await?;
+        match rows.next().await? {
+            Some(row) => Ok(row.get::<i64>("removed")?),
+            None => Ok(0),
+        }
+    }
+
+    //无法解析的历史跳过并记录
+    async fn load(events_query: neo4rs::Query) -> Result<Vec<CardEvent>> {
+        let mut rows = get_graph().await.execute(events_query).await?;
+        let mut events = Vec::new();
+        while let Some(row) = rows.next().await? {
+            let value = row.get::<String>("value")?;
+            match serde_json::from_str(&value) {
+                Ok(event) => events.push(event),
+                Err(err) => eprintln!("skipped card history {}: {}", value, err),
+            }
+        }
+        Ok(events)
+    }
+}
@@ -1,27 +1,38 @@
 pub mod card;
+pub mod code;
 pub mod store;
 pub mod query;
+pub mod aggregate;
+pub mod events;
+pub mod formula;
+pub mod computed;
+pub mod team;
+pub mod settings;
+pub mod history;
 mod graph;
+mod cypher;
 mod mock_neo4j_data;
-mod events;
 mod relationship;
-mod types;
+pub mod types;
 mod mock_memgraph_data;
 
+use common::newtypes;
+
 #[cfg(test)]
 mod tests {
     use crate::card::{Card, CardState};
+    use crate::newtypes::tenant_id::TenantId;
     use std::collections::HashMap;
 
     #[test]
     #[test]
     fn test_new_card() {
-        let card = Card::new("10001".to_string(), "卡片01".to_string(), "1", "1", None, vec![], HashMap::new());
+        let card = Card::new("10001".to_string(), "卡片01".to_string(), "1", &TenantId::from_str("1"), None, vec![], HashMap::new());
         println!("{:?}", card);
         println!("{}", card.id);
         assert_eq!(card.code, "10001");
         assert_eq!(card.card_type_id, "1");
-        assert_eq!(card.org_id, "1");
+        assert_eq!(card.org_id, TenantId::from_str("1"));
         assert_eq!(card.fields.len(), 0);
         assert_eq!(card.name, "卡片01");
         assert_eq!(card.state, CardState::Active);
@@ -2,8 +2,9 @@
 #[cfg(test)]
 mod tests {
     use crate::graph::get_graph;
-    use crate::mock_neo4j_data::CODE_COUNTER;
+    use crate::code;
     use crate::newtypes::timestamp::Timestamp;
+    use crate::team::{TeamRole, MEMBER_OF_RS_TYPE, PARENT_TEAM_RS_TYPE};
     use chrono::NaiveDateTime;
     use neo4rs::{BoltInteger, BoltString, BoltType, Graph, Node, Query, Txn};
     use rand::Rng;
@@ -42,7 +43,7 @@ mod tests {
         create_index(graph.clone()).await;
         //因为一次事务里不能创建太多数据，这会导致内存超出，所以创建卡片和关联使用graph的api
         create_cards(graph.clone()).await;
-        create_relationships();
+        create_relationships(graph.clone()).await;
     }
 
     async fn create_cards(graph: Graph) {
@@ -69,8 +70,13 @@ mod tests {
     async fn do_create_cards(graph: Graph, card_type: String, size: u32) {
         let mut maps: Vec<HashMap<String, BoltType>> = vec![];
         let now = *Timestamp::now();
+        //一次预留整批卡片的编号
+        let mut reserved = graph.execute(code::reserve_query(ORG_ID, &card_type, size as i64)).await.expect("预留编号失败");
+        let row = reserved.next().await.expect("预留编号失败").expect("预留编号失败");
+        let prefix: String = row.get("prefix").unwrap();
+        let first = row.get::<i64>("value").unwrap() - size as i64 + 1;
         for i in 0..size {
-            let code = CODE_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            let code = code::format_code(&prefix, first + i as i64);
             let mut map: HashMap<String, BoltType> = HashMap::new();
             let state = if i % 3 == 0 {
                 "Active"
@@ -87,7 +93,7 @@ mod tests {
                 "已完成"
             };
             map.insert("id".to_string(), BoltType::String(BoltString::new(&format!("{}-{}", &card_type, i))));
-            map.insert("code".to_string(), BoltType::String(BoltString::new(code.to_string().as_str())));
+            map.insert("code".to_string(), BoltType::String(BoltString::new(&code)));
             map.insert("name".to_string(), BoltType::String(BoltString::new(&format!("{}-{}", &card_type, i))));
             map.insert("org_id".to_string(), BoltType::String(BoltString::new(ORG_ID)));
             map.insert("card_type_id".to_string(), BoltType::String(BoltString::new(&card_type)));
@@ -99,7 +105,6 @@ mod tests {
             map.insert("计划开始时间".to_string(), BoltType::Integer(BoltInteger::new(generate_random_timestamp())));
             map.insert("计划完成时间".to_string(), BoltType::Integer(BoltInteger::new(generate_random_timestamp())));
             maps.push(map);
-            //*CODE_COUNTER += 1;
             if i % 1000 == 0 {
                 //用merge的话注意要提前给唯一键，不能在 +=中给，否则会多次创建
                 let query = neo4rs::query("UNWIND $maps AS map CREATE (n:Card {id: map.id}) SET n += map")
@@ -115,7 +120,31 @@ mod tests {
         }
     }
 
-    fn create_relationships() {}
+    async fn create_relationships(graph: Graph) {
+        //成员加入小队，每个小队的第一个成员是队长；小队隶属于部落
+        create_team_links(graph.clone(), "成员", "小队", MEMBER_SCALE, MEMBER_OF_RS_TYPE).await;
+        create_team_links(graph.clone(), "小队", "部落", TEAM_SCALE, PARENT_TEAM_RS_TYPE).await;
+    }
+
+    async fn create_team_links(graph: Graph, child_type: &str, parent_type: &str, size: u32, rs_type: &str) {
+        let cypher = format!("UNWIND $pairs AS pair MATCH (c:Card {{id: pair.child}}) MATCH (p:Card {{id: pair.parent}}) CREATE (c)-[:{rs_type} {{role: pair.role}}]->(p)");
+        let mut pairs: Vec<HashMap<String, BoltType>> = vec![];
+        for i in 0..size {
+            let mut pair: HashMap<String, BoltType> = HashMap::new();
+            let role = if i % 10 == 0 { TeamRole::Leader } else { TeamRole::Member };
+            pair.insert("child".to_string(), BoltType::String(BoltString::new(&format!("{}-{}", child_type, i))));
+            pair.insert("parent".to_string(), BoltType::String(BoltString::new(&format!("{}-{}", parent_type, i / 10))));
+            pair.insert("role".to_string(), BoltType::String(BoltString::new(&role.to_string())));
+            pairs.push(pair);
+            if pairs.len() == 1000 {
+                graph.run(neo4rs::query(&cypher).param("pairs", pairs)).await.expect("创建团队关联失败");
+                pairs = Vec::new();
+            }
+        }
+        if !pairs.is_empty() {
+            graph.run(neo4rs::query(&cypher).param("pairs", pairs)).await.expect("创建团队关联失败");
+        }
+    }
 
     async fn create_index(graph: Graph) {
         let mut queries = vec![
@@ -153,6 +182,9 @@ mod tests {
             "CREATE CONSTRAINT ON (c:Card) ASSERT EXISTS (c.name);",
             "CREATE CONSTRAINT ON (c:Card) ASSERT EXISTS (c.create_time);",
             "CREATE CONSTRAINT ON (c:Card) ASSERT EXISTS (c.update_time);",
+            //编号序号和前缀，并发MERGE时由唯一约束保证只有一个节点
+            "CREATE CONSTRAINT ON (s:CodeSequence) ASSERT s.org_id,s.prefix IS UNIQUE;",
+            "CREATE CONSTRAINT ON (p:CodePrefix) ASSERT p.org_id,p.card_type_id IS UNIQUE;",
         ];
         for q in queries {
             graph.run(neo4rs::query(q)).await.expect("创建约束失败");
@@ -1,13 +1,8 @@
-use std::sync::atomic::AtomicI32;
-use std::sync::LazyLock;
-
-pub(crate) static CODE_COUNTER: LazyLock<AtomicI32> = LazyLock::new(|| AtomicI32::new(0));
-
 
 #[cfg(test)]
 mod tests {
     use crate::graph::get_graph;
-    use crate::mock_neo4j_data::CODE_COUNTER;
+    use crate::code;
     use crate::newtypes::timestamp::Timestamp;
     use chrono::NaiveDateTime;
     use neo4rs::{BoltInteger, BoltString, BoltType, Graph, Node, Query, Txn};
@@ -77,8 +72,13 @@ mod tests {
     async fn do_create_cards(graph: Graph, card_type: String, size: u32) {
         let mut maps: Vec<HashMap<String, BoltType>> = vec![];
         let now = *Timestamp::now();
+        //一次预留整批卡片的编号
+        let mut reserved = graph.execute(code::reserve_query(ORG_ID, &card_type, size as i64)).await.expect("预留编号失败");
+        let row = reserved.next().await.expect("预留编号失败").expect("预留编号失败");
+        let prefix: String = row.get("prefix").unwrap();
+        let first = row.get::<i64>("value").unwrap() - size as i64 + 1;
         for i in 0..size {
-            let code = CODE_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            let code = code::format_code(&prefix, first + i as i64);
             let mut map: HashMap<String, BoltType> = HashMap::new();
             let state = if i % 3 == 0 {
                 "Active"
@@ -95,7 +95,7 @@ mod tests {
                 "已完成"
             };
             map.insert("id".to_string(), BoltType::String(BoltString::new(&format!("{}-{}", &card_type, i))));
-            map.insert("code".to_string(), BoltType::String(BoltString::new(code.to_string().as_str())));
+            map.insert("code".to_string(), BoltType::String(BoltString::new(&code)));
             map.insert("name".to_string(), BoltType::String(BoltString::new(&format!("{}-{}", &card_type, i))));
             map.insert("org_id".to_string(), BoltType::String(BoltString::new(ORG_ID)));
             map.insert("card_type_id".to_string(), BoltType::String(BoltString::new(&card_type)));
@@ -107,7 +107,6 @@ mod tests {
             map.insert("计划开始时间".to_string(), BoltType::Integer(BoltInteger::new(generate_random_timestamp())));
             map.insert("计划完成时间".to_string(), BoltType::Integer(BoltInteger::new(generate_random_timestamp())));
             maps.push(map);
-            //*CODE_COUNTER += 1;
             if i % 1000 == 0 {
                 //用merge的话注意要提前给唯一键，不能在 +=中给，否则会多次创建
                 let query = neo4rs::query("UNWIND $maps AS map CREATE (n:Card {id: map.id}) SET n += map")
@@ -157,6 +156,10 @@ mod tests {
     async fn create_constraint(txn: &mut Txn) {
         let queries = vec![
             "CREATE CONSTRAINT card_id_is_unique IF NOT EXISTS FOR (c:Card) REQUIRE c.id IS UNIQUE;",
+            "CREATE CONSTRAINT card_code_is_unique IF NOT EXISTS FOR (c:Card) REQUIRE (c.org_id, c.code) IS UNIQUE;",
+            //编号序号和前缀，并发MERGE时由唯一约束保证只有一个节点
+            "CREATE CONSTRAINT code_sequence_is_unique IF NOT EXISTS FOR (s:CodeSequence) REQUIRE (s.org_id, s.prefix) IS UNIQUE;",
+            "CREATE CONSTRAINT code_prefix_is_unique IF NOT EXISTS FOR (p:CodePrefix) REQUIRE (p.org_id, p.card_type_id) IS UNIQUE;",
             //属性非空约束要求企业版
             //"CREATE CONSTRAINT card_org_id_is_not_null IF NOT EXISTS FOR (c:Card) REQUIRE c.org_id IS NOT NULL;",
             //"CREATE CONSTRAINT card_state_is_not_null IF NOT EXISTS FOR (c:Card) REQUIRE c.state IS NOT NULL;",
@@ -1,7 +1,13 @@
-use crate::card::Card;
+use crate::card::{CardState, Field, FieldValue, FlowStatus};
+use crate::cypher;
+use crate::cypher::CypherCompiler;
 use crate::graph::get_graph;
+use crate::newtypes::card_id::CardId;
+use crate::newtypes::field_id::FieldId;
+use crate::newtypes::tenant_id::TenantId;
+use crate::newtypes::timestamp::Timestamp;
 use crate::types::{LinkDescriptor, Path};
-use neo4rs::Node;
+use neo4rs::{BoltType, Node};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::{Debug, Display};
@@ -9,36 +15,36 @@ use std::time::Duration;
 use std::{error, fmt};
 
 //查询条件
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Condition {
-    items: Vec<ConditionItem>, //且条件
-    logic_condition_bulks: Vec<LogicConditionBulk>, //多个或条件集，集之间是And关系
+    pub(crate) items: Vec<ConditionItem>, //且条件
+    pub(crate) logic_condition_bulks: Vec<LogicConditionBulk>, //多个或条件集，集之间是And关系
 }
 
-//单个条件项
-#[derive(Debug, Serialize, Deserialize)]
+//单个条件项，属性类条件项需要指明属性id，关联类条件项需要指明关联描述符
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ConditionItem {
     CardType(CardTypeOperator), //卡片类型条件项
-    State, //卡片活跃状态条件项
-    Status, //卡片价值流状态条件项
+    State(Vec<CardState>), //卡片活跃状态条件项，卡片状态为其中之一
+    Status(Vec<String>), //卡片价值流状态条件项，价值流状态id为其中之一
     Code(String),
     Title(String),
-    Text(TextOperator), //文本属性条件项
-    Number(NumberOperator), //数字属性条件项
-    Enum(EnumOperator), //枚举属性条件项
-    Date(DateOperator), //日期属性条件项
-    Link(LinkOperator), //关联属性条件项
+    Text(FieldId, TextOperator), //文本属性条件项
+    Number(FieldId, NumberOperator), //数字属性条件项
+    Enum(FieldId, EnumOperator), //枚举属性条件项
+    Date(FieldId, DateOperator), //日期属性条件项
+    Link(LinkDescriptor, LinkOperator), //关联属性条件项
     MySelf(LinkOperator), //针对卡片自身的关联过滤
 }
 
 //卡片类型条件项的操作符，仅支持AnyIn
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CardTypeOperator {
     AnyIn(Vec<String>)
 }
 
 //文本属性条件项的操作符
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TextOperator {
     StartsWith(String),
     Contains(String),
@@ -49,14 +55,14 @@ pub enum TextOperator {
 }
 
 //普通属性类型条件项的值，可能是一个引用值，或者是一个直接的静态值
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PropertyValue<T> {
     ReferValue(ReferPoint, Path, String), //引用值
     StaticValue(T), //某个具体的值
 }
 
 //引用参考点
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ReferPoint {
     CurrentMember, //引用自当前成员
     CurrentCard, //引用自当前卡
@@ -65,7 +71,7 @@ pub enum ReferPoint {
 
 
 //数字属性条件项的操作符
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum NumberOperator {
     LessThan(PropertyValue<i64>),
     GreaterThan(PropertyValue<i64>),
@@ -79,7 +85,7 @@ pub enum NumberOperator {
 }
 
 //枚举属性条件项的操作符
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum EnumOperator {
     AnyIn(PropertyValue<Vec<String>>),
     AllIn(PropertyValue<Vec<String>>),
@@ -88,21 +94,21 @@ pub enum EnumOperator {
     IsNull(bool),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DateOperator {
     //日期支持精度，精度由日期属性定义决定
-    After(PropertyValue<u64>),
-    Before(PropertyValue<u64>),
-    Equals(PropertyValue<u64>),
-    NotEquals(PropertyValue<u64>),
-    Between(PropertyValue<u64>, PropertyValue<u64>),
-    NotBetween(PropertyValue<u64>, PropertyValue<u64>),
+    After(PropertyValue<i64>),
+    Before(PropertyValue<i64>),
+    Equals(PropertyValue<i64>),
+    NotEquals(PropertyValue<i64>),
+    Between(PropertyValue<i64>, PropertyValue<i64>),
+    NotBetween(PropertyValue<i64>, PropertyValue<i64>),
     IsNull(bool),
 }
 
 
 //关联属性条件项的操作符
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum LinkOperator {
     AnyIn(LinkValue),
     AllIn(LinkValue),
@@ -112,55 +118,311 @@ pub enum LinkOperator {
 }
 
 //关联属性条件项的值
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum LinkValue {
     ReferValue(ReferPoint, Vec<LinkDescriptor>),
     StaticValue(Vec<String>),
 }
 
 //或条件集，由多个或条件组构成，组之间是And的关系
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LogicConditionBulk {
-    groups: Vec<LogicConditionGroup>, // And
+    pub(crate) groups: Vec<LogicConditionGroup>, // And
 }
 
 //或条件组，有多个之间为Or关系的条件项组成
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LogicConditionGroup {
-    items: Vec<ConditionItem>, //Or
+    pub(crate) items: Vec<ConditionItem>, //Or
 }
 
 
 //查询结果
-#[derive(Debug)]
-pub struct QueryResult<'a> {
-    cards: Vec<Card<'a>>,
-    total: u32,
+#[derive(Debug, Serialize)]
+pub struct QueryResult {
+    pub cards: Vec<CardRecord>,
+    pub total: u64, //满足条件的卡片总数，不受分页影响
+}
+
+//查询返回的卡片，数据归查询结果所有，属性由Yields决定
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CardRecord {
+    pub id: CardId,
+    pub code: String,
+    pub name: String,
+    pub card_type_id: String,
+    pub org_id: TenantId,
+    pub state: CardState,
+    pub flow_status: Option<FlowStatus>,
+    pub fields: Vec<Field>,
+}
+
+impl CardRecord {
+    fn from_node(node: &Node, yields: &Yields) -> Result<Self> {
+        let state: String = node.get("state")?;
+        let state = match state.as_str() {
+            "Archived" => CardState::Archived,
+            "Abandoned" => CardState::Abandoned,
+            _ => CardState::Active,
+        };
+        let flow_status = match (node.get::<String>("flow_id"), node.get::<String>("flow_status_id")) {
+            (Ok(flow_id), Ok(flow_status_id)) => Some(FlowStatus::new(&flow_id, &flow_status_id)),
+            _ => None,
+        };
+        let card_type_id: String = node.get("card_type_id")?;
+        let flow_status_id = flow_status.as_ref().map(|it| it.flow_status_id.as_str());
+        let fields = yields.fields.iter()
+            .filter(|it| yields.is_readable(&card_type_id, flow_status_id, it))
+            .filter_map(|it| field_value(node, it).map(|value| Field::new(it.clone(), value)))
+            .collect();
+        Ok(Self {
+            id: CardId::from(node.get("id")?),
+            code: node.get("code")?,
+            name: node.get("name")?,
+            card_type_id,
+            org_id: TenantId::from(node.get("org_id")?),
+            state,
+            flow_status,
+            fields,
+        })
+    }
+}
+
+//节点上没有类型信息，按存储时的类型还原，日期以毫秒时间戳保存，超出Int范围的整数还原为DateTime
+fn field_value(node: &Node, field_id: &FieldId) -> Option<FieldValue> {
+    if let Ok(v) = node.get::<i64>(field_id) {
+        return Some(match i32::try_from(v) {
+            Ok(v) => FieldValue::Int(v),
+            Err(_) => FieldValue::DateTime(Timestamp::from(v)),
+        });
+    }
+    if let Ok(v) = node.get::<f64>(field_id) {
+        return Some(FieldValue::Float(v as f32));
+    }
+    if let Ok(v) = node.get::<String>(field_id) {
+        return Some(FieldValue::Text(v));
+    }
+    node.get::<Vec<String>>(field_id).ok().map(FieldValue::Enum)
 }
 
 
 //查询时指定的分页参数
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Page {
     Limit(u32/*num*/, u8/*size*/),
     LimitAfterSort(Sort, u32, u8),
+    AllAfterSort(Sort), //不分页，只排序
     None,
 }
 
-//分页查询时是否开启排序
-#[derive(Debug, Serialize, Deserialize)]
-pub enum Sort {}
+//分页查询时的排序项，可以按卡片的内置属性或者自定义属性排序
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Sort {
+    Property(BuiltInProperty, Order),
+    Field(FieldId, Order),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+//卡片的内置属性，与自定义属性不同，所有卡片都有
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BuiltInProperty {
+    Code,
+    Name,
+    CardType,
+    State,
+    FlowStatus,
+    CreateTime,
+    UpdateTime,
+}
+
+impl BuiltInProperty {
+    //内置属性在卡片节点上的属性名
+    pub(crate) fn key(&self) -> &'static str {
+        match self {
+            BuiltInProperty::Code => "code",
+            BuiltInProperty::Name => "name",
+            BuiltInProperty::CardType => "card_type_id",
+            BuiltInProperty::State => "state",
+            BuiltInProperty::FlowStatus => "flow_status_id",
+            BuiltInProperty::CreateTime => "create_time",
+            BuiltInProperty::UpdateTime => "update_time",
+        }
+    }
+}
+
+impl Sort {
+    fn order_by(&self, var: &str) -> String {
+        let (prop, order) = match self {
+            Sort::Property(property, order) => (cypher::property(var, property.key()), order),
+            Sort::Field(field_id, order) => (cypher::property(var, field_id), order),
+        };
+        match order {
+            Order::Asc => format!("{prop} ASC"),
+            Order::Desc => format!("{prop} DESC"),
+        }
+    }
+}
+
+impl Page {
+    fn sort(&self) -> Option<&Sort> {
+        match self {
+            Page::LimitAfterSort(sort, _, _) | Page::AllAfterSort(sort) => Some(sort),
+            Page::Limit(_, _) | Page::None => None,
+        }
+    }
+}
+
+//排序值相同时按创建时间倒序，保证分页稳定
+fn order_and_page(page: &Page, var: &str) -> String {
+    let default_order = format!("{var}.create_time DESC");
+    //页码从1开始
+    let skip_limit = |num: &u32, size: &u8| format!(" SKIP {} LIMIT {}", num.saturating_sub(1) as u64 * *size as u64, size);
+    match page {
+        Page::Limit(num, size) => format!(" ORDER BY {default_order}{}", skip_limit(num, size)),
+        Page::LimitAfterSort(sort, num, size) => format!(" ORDER BY {}, {default_order}{}", sort.order_by(var), skip_limit(num, size)),
+        Page::AllAfterSort(sort) => format!(" ORDER BY {}, {default_order}", sort.order_by(var)),
+        Page::None => format!(" ORDER BY {default_order}"),
+    }
+}
 
 //查询时希望返回卡片上的哪些属性
-#[derive(Debug)]
-pub struct Yields {}
+#[derive(Debug, Clone, Default)]
+pub struct Yields {
+    pub(crate) fields: Vec<FieldId>,
+    pub(crate) restrictions: Vec<FieldRestriction>,
+}
+
+//当前成员对某类卡片上某个属性的读限制，由权限模块根据属性级权限计算得出
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldRestriction {
+    pub card_type_id: String,
+    pub field_id: FieldId,
+    pub readable_in: Option<Vec<String>>, //卡片处于这些价值流状态时可读，None表示不可读
+}
+
+impl Yields {
+    pub fn new(fields: Vec<FieldId>) -> Self {
+        Self { fields, restrictions: Vec::new() }
+    }
+
+    pub fn with_restrictions(mut self, restrictions: Vec<FieldRestriction>) -> Self {
+        self.restrictions = restrictions;
+        self
+    }
+
+    //沿用当前成员的读限制，换成另一组返回属性，用于读取关联的卡片
+    pub fn with_fields(&self, fields: Vec<FieldId>) -> Self {
+        Self { fields, restrictions: self.restrictions.clone() }
+    }
+
+    //按受限属性过滤或者排序时，可以从结果推断出不可读的属性值，因此直接拒绝这样的查询
+    pub(crate) fn ensure_unrestricted(&self, condition: &Condition, sort: Option<&Sort>) -> std::result::Result<(), QueryError> {
+        let sort_field_id = match sort {
+            Some(Sort::Field(field_id, _)) => Some(field_id.as_str()),
+            _ => None,
+        };
+        match condition.field_ids().chain(sort_field_id).find(|field_id| self.restrictions.iter().any(|it| it.field_id.as_str() == *field_id)) {
+            Some(field_id) => Err(QueryError::new(&format!("field {} is not readable", field_id))),
+            None => Ok(()),
+        }
+    }
+
+    //不可读的属性不会出现在查询结果中
+    pub fn is_readable(&self, card_type_id: &str, flow_status_id: Option<&str>, field_id: &FieldId) -> bool {
+        self.restrictions.iter()
+            .filter(|it| it.card_type_id == card_type_id && it.field_id == *field_id)
+            .all(|it| match (&it.readable_in, flow_status_id) {
+                (Some(status_ids), Some(status_id)) => status_ids.iter().any(|it| it == status_id),
+                _ => false,
+            })
+    }
+}
 
 //查询发生时的上下文
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct QueryContext {
-    tenant_id: String,
-    member_id: String,
-    parameters: HashMap<String, String>,
+    pub(crate) tenant_id: TenantId,
+    pub(crate) member_id: String,
+    pub(crate) parameters: HashMap<String, String>,
+    pub(crate) visibility_filters: Vec<VisibilityFilter>,
+    pub(crate) viewable_card_types: Option<Vec<String>>, //成员有查看权限的卡片类型，None表示不限
+}
+
+//行级可见性：某类卡片只有满足条件时才对当前成员可见，条件中一般引用ReferPoint::CurrentMember
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VisibilityFilter {
+    pub card_type_id: String,
+    pub condition: Condition,
+}
+
+impl VisibilityFilter {
+    pub fn new(card_type_id: &str, condition: Condition) -> Self {
+        Self {
+            card_type_id: String::from(card_type_id),
+            condition,
+        }
+    }
+}
+
+//ReferPoint::CurrentCard 和 ReferPoint::Parameter 在上下文参数中对应的卡片id
+pub const CURRENT_CARD_PARAMETER: &str = "current_card_id";
+pub const PARAMETER_CARD_PARAMETER: &str = "parameter_card_id";
+
+impl QueryContext {
+    pub fn new(tenant_id: &TenantId, member_id: &str, parameters: HashMap<String, String>) -> Self {
+        Self {
+            tenant_id: tenant_id.clone(),
+            member_id: String::from(member_id),
+            parameters,
+            visibility_filters: Vec::new(),
+            viewable_card_types: None,
+        }
+    }
+
+    //由权限模块根据成员的授权设置，查询时会与查询条件以及关联遍历一起生效
+    pub fn with_visibility_filters(mut self, visibility_filters: Vec<VisibilityFilter>) -> Self {
+        self.visibility_filters = visibility_filters;
+        self
+    }
+
+    //由权限模块根据成员的授权设置，没有查看权限的卡片类型与行级可见性一样对成员不可见
+    pub fn with_viewable_card_types(mut self, card_type_ids: Vec<String>) -> Self {
+        self.viewable_card_types = Some(card_type_ids);
+        self
+    }
+
+    pub fn tenant_id(&self) -> &TenantId {
+        &self.tenant_id
+    }
+
+    pub fn viewable_card_types(&self) -> Option<&[String]> {
+        self.viewable_card_types.as_deref()
+    }
+
+    pub fn member_id(&self) -> &str {
+        &self.member_id
+    }
+
+    //成员看某类卡片（None表示所有卡片）时是否受可见范围限制，不受限制时组织级的统计与成员看到的一致
+    pub fn is_restricted(&self, card_type_id: Option<&str>) -> bool {
+        let filtered = self.visibility_filters.iter().any(|it| card_type_id.is_none_or(|id| it.card_type_id == id));
+        let unviewable = self.viewable_card_types.as_ref().is_some_and(|ids| card_type_id.is_none_or(|id| !ids.iter().any(|it| it == id)));
+        filtered || unviewable
+    }
+
+    //取得引用参考点对应的卡片id
+    pub(crate) fn refer_point_id(&self, refer_point: &ReferPoint) -> Option<&str> {
+        match refer_point {
+            ReferPoint::CurrentMember => Some(&self.member_id),
+            ReferPoint::CurrentCard => self.parameters.get(CURRENT_CARD_PARAMETER).map(|it| it.as_str()),
+            ReferPoint::Parameter => self.parameters.get(PARAMETER_CARD_PARAMETER).map(|it| it.as_str()),
+        }
+    }
 }
 
 impl Condition {
@@ -177,6 +439,63 @@ impl Condition {
         self.logic_condition_bulks.push(logic_condition_bulk);
         self
     }
+
+    //两个条件需要同时满足
+    pub fn and_condition(&mut self, condition: Condition) -> &mut Self {
+        self.items.extend(condition.items);
+        self.logic_condition_bulks.extend(condition.logic_condition_bulks);
+        self
+    }
+
+    //条件中引用的所有自定义属性，包括或条件集中的
+    fn field_ids(&self) -> impl Iterator<Item = &str> {
+        self.items.iter()
+            .chain(self.logic_condition_bulks.iter().flat_map(|it| it.groups.iter()).flat_map(|it| it.items.iter()))
+            .flat_map(|it| it.field_ids())
+    }
+}
+
+impl ConditionItem {
+    //条件项过滤的属性，以及比较值从参考点卡片上引用的属性
+    fn field_ids(&self) -> Vec<&str> {
+        let (field_id, refer_field_ids) = match self {
+            ConditionItem::Text(field_id, operator) => (field_id, match operator {
+                TextOperator::Equals(value) | TextOperator::NotEquals(value) => vec![value.refer_field_id()],
+                _ => vec![],
+            }),
+            ConditionItem::Number(field_id, operator) => (field_id, match operator {
+                NumberOperator::LessThan(value) | NumberOperator::GreaterThan(value)
+                | NumberOperator::LessThanOrEqualTo(value) | NumberOperator::GreaterThanOrEqualTo(value)
+                | NumberOperator::Equals(value) | NumberOperator::NotEquals(value) => vec![value.refer_field_id()],
+                NumberOperator::Between(start, end) | NumberOperator::NotBetween(start, end) => vec![start.refer_field_id(), end.refer_field_id()],
+                NumberOperator::IsNull(_) => vec![],
+            }),
+            ConditionItem::Enum(field_id, operator) => (field_id, match operator {
+                EnumOperator::AnyIn(value) | EnumOperator::AllIn(value)
+                | EnumOperator::AnyNotIn(value) | EnumOperator::AllNotIn(value) => vec![value.refer_field_id()],
+                EnumOperator::IsNull(_) => vec![],
+            }),
+            ConditionItem::Date(field_id, operator) => (field_id, match operator {
+                DateOperator::After(value) | DateOperator::Before(value)
+                | DateOperator::Equals(value) | DateOperator::NotEquals(value) => vec![value.refer_field_id()],
+                DateOperator::Between(start, end) | DateOperator::NotBetween(start, end) => vec![start.refer_field_id(), end.refer_field_id()],
+                DateOperator::IsNull(_) => vec![],
+            }),
+            _ => return vec![],
+        };
+        let mut field_ids = vec![field_id.as_str()];
+        field_ids.extend(refer_field_ids.into_iter().flatten());
+        field_ids
+    }
+}
+
+impl<T> PropertyValue<T> {
+    fn refer_field_id(&self) -> Option<&str> {
+        match self {
+            PropertyValue::ReferValue(_, _, field_id) => Some(field_id),
+            PropertyValue::StaticValue(_) => None,
+        }
+    }
 }
 
 impl Default for Condition {
@@ -216,31 +535,104 @@ impl Default for LogicConditionGroup {
 
 
 type Result<T> = std::result::Result<T, Box<dyn error::Error>>; //因为Error是一个动态类型，大小无法在编译期确定，所以需要用Box分配到堆上
-pub async fn query<'a>(condition: Condition, query_context: QueryContext, yields: Yields, page: Page) -> Result<QueryResult<'a>> {
-    let mut graph = get_graph();
-    let mut result = graph.await.execute(
-        neo4rs::query("MATCH (c:Card {codeInOrg: $code}) RETURN c").param("code", "1")
-    ).await.unwrap();
-    while let Ok(Some(row)) = result.next().await {
-        let node: Node = row.get("c").unwrap();
-        let name: String = node.get("name").unwrap();
-        println!("{}", name);
+pub async fn query(condition: Condition, query_context: QueryContext, yields: Yields, page: Page) -> Result<QueryResult> {
+    yields.ensure_unrestricted(&condition, page.sort())?;
+    let mut compiler = CypherCompiler::new("c", &query_context);
+    let where_str = compiler.compile(&condition)?;
+    let params = compiler.into_params();
+    let match_str = match_cards(&where_str);
+    let order_str = order_and_page(&page, "c");
+    let graph = get_graph().await;
+
+    let count_query = scoped_query(&format!("{match_str} RETURN count(c) AS total"), &query_context, params.clone());
+    let mut total = 0;
+    if let Some(row) = graph.execute(count_query).await?.next().await? {
+        total = row.get::<i64>("total")? as u64;
+    }
+
+    let cards_query = scoped_query(&format!("{match_str} RETURN c{order_str}"), &query_context, params);
+    let mut result = graph.execute(cards_query).await?;
+    let mut cards = Vec::new();
+    while let Some(row) = result.next().await? {
+        let node: Node = row.get("c")?;
+        cards.push(CardRecord::from_node(&node, &yields)?);
+    }
+    Ok(QueryResult { cards, total })
+}
+
+//按id查找当前成员可见的卡片
+pub async fn find(card_id: &CardId, query_context: QueryContext, yields: Yields) -> Result<Option<CardRecord>> {
+    let mut condition = Condition::default();
+    condition.and(ConditionItem::MySelf(LinkOperator::AnyIn(LinkValue::StaticValue(vec![card_id.to_string()]))));
+    let result = query(condition, query_context, yields, Page::Limit(1, 1)).await?;
+    Ok(result.cards.into_iter().next())
+}
+
+//被查询的卡片限定在当前租户中
+pub(crate) fn match_cards(where_str: &str) -> String {
+    format!("MATCH (c:Card) WHERE c.org_id = $tenant_id AND {where_str}")
+}
+
+//所有读取卡片的语句都经由这里绑定租户参数，条件中引用的参考点卡片也通过$tenant_id限定租户
+pub(crate) fn scoped_query(cypher: &str, query_context: &QueryContext, params: Vec<(String, BoltType)>) -> neo4rs::Query {
+    let mut scoped_query = neo4rs::query(cypher).param("tenant_id", query_context.tenant_id.as_str());
+    for (key, value) in params {
+        scoped_query = scoped_query.param(&key, value);
     }
-    //Err(Box::new(QueryError::new("查询出错了")))
-    Ok(QueryResult {
-        cards: vec![],
-        total: 0,
-    })
+    scoped_query
 }
 
+//判断某张卡片当前是否满足条件，用于事件到达后对订阅、规则等条件的匹配
+pub async fn matches(card_id: &CardId, condition: &Condition, query_context: &QueryContext) -> Result<bool> {
+    let mut compiler = CypherCompiler::new("c", query_context);
+    let where_str = compiler.compile(condition)?;
+    let cypher = format!("MATCH (c:Card {{id:$card_id}}) WHERE c.org_id = $tenant_id AND {where_str} RETURN count(c) AS total");
+    let matches_query = scoped_query(&cypher, query_context, compiler.into_params())
+        .param("card_id", card_id.as_str());
+    let graph = get_graph().await;
+    let mut result = graph.execute(matches_query).await?;
+    if let Some(row) = result.next().await? {
+        let total: i64 = row.get("total")?;
+        return Ok(total > 0);
+    }
+    Ok(false)
+}
+
+//卡片沿关联描述符直接关联的卡片id，两端都限定在当前租户
+pub async fn linked_ids(card_ids: &[CardId], descriptor: &LinkDescriptor, query_context: &QueryContext) -> Result<HashMap<CardId, Vec<CardId>>> {
+    let ids: Vec<String> = card_ids.iter().map(|it| it.to_string()).collect();
+    let (cypher, mut params) = linked_ids_cypher(descriptor, query_context)?;
+    params.push((String::from("card_ids"), ids.into()));
+    let linked_query = scoped_query(&cypher, query_context, params);
+    let graph = get_graph().await;
+    let mut result = graph.execute(linked_query).await?;
+    let mut linked = HashMap::new();
+    while let Some(row) = result.next().await? {
+        let id: String = row.get("id")?;
+        let linked_ids: Vec<String> = row.get("linked")?;
+        linked.insert(CardId::from(id), linked_ids.into_iter().map(CardId::from).collect());
+    }
+    Ok(linked)
+}
+
+//两端的卡片都必须对当前成员可见
+fn linked_ids_cypher(descriptor: &LinkDescriptor, query_context: &QueryContext) -> Result<(String, Vec<(String, BoltType)>)> {
+    let mut compiler = CypherCompiler::new("c", query_context);
+    let visible_str = compiler.compile(&Condition::default())?;
+    let linked_visible_str = compiler.visible("l")?.unwrap_or_else(|| String::from("true"));
+    let cypher = format!("MATCH (c:Card) WHERE c.org_id = $tenant_id AND c.id IN $card_ids AND {visible_str} \
+        MATCH (c){}(l:Card {{org_id:$tenant_id}}) WHERE {linked_visible_str} RETURN c.id AS id, collect(l.id) AS linked",
+                         cypher::relationship(descriptor));
+    Ok((cypher, compiler.into_params()))
+}
 
 #[derive(Debug)]
-struct QueryError {
+pub struct QueryError {
     message: String,
 }
 
 impl QueryError {
-    fn new(message: &str) -> Self {
+    pub(crate) fn new(message: &str) -> Self {
         Self { message: message.to_string() }
     }
 }
@@ -275,27 +667,108 @@ mod tests {
                 items: vec![],
                 logic_condition_bulks: vec![],
             },
-            QueryContext {
-                tenant_id: String::from("1"),
-                member_id: String::from("!"),
-                parameters: HashMap::new(),
-            },
-            Yields {},
+            QueryContext::new(&TenantId::from_str("1"), "!", HashMap::new()),
+            Yields::default(),
             Page::None,
         ).await.unwrap();
     }
 
+    #[test]
+    async fn test_query_is_tenant_scoped() {
+        //参数引用的卡片即使属于其他租户，也只能在当前租户中被匹配到
+        let mut parameters = HashMap::new();
+        parameters.insert(String::from(PARAMETER_CARD_PARAMETER), String::from("other-tenant-card"));
+        let query_context = QueryContext::new(&TenantId::from_str("o1"), "m1", parameters);
+        let mut condition = Condition::default();
+        condition.and(ConditionItem::Link(
+            LinkDescriptor::Src(String::from("子任务")),
+            LinkOperator::AnyIn(LinkValue::ReferValue(ReferPoint::Parameter, vec![LinkDescriptor::Src(String::from("子任务"))])),
+        ));
+        let mut compiler = CypherCompiler::new("c", &query_context);
+        let where_str = compiler.compile(&condition).unwrap();
+        assert!(where_str.contains("org_id:$tenant_id"));
+        assert!(match_cards(&where_str).starts_with("MATCH (c:Card) WHERE c.org_id = $tenant_id AND "));
+        let scoped = scoped_query(&match_cards(&where_str), &query_context, compiler.into_params());
+        assert!(scoped.has_param_key("tenant_id"));
+    }
+
+    #[test]
+    async fn test_order_and_page() {
+        assert_eq!(order_and_page(&Page::Limit(2, 20), "c"), " ORDER BY c.create_time DESC SKIP 20 LIMIT 20");
+        let sort = Sort::Field(FieldId::from_str("优先级"), Order::Asc);
+        assert_eq!(order_and_page(&Page::LimitAfterSort(sort, 1, 10), "c"), " ORDER BY c.`优先级` ASC, c.create_time DESC SKIP 0 LIMIT 10");
+        let sort = Sort::Property(BuiltInProperty::UpdateTime, Order::Desc);
+        assert_eq!(order_and_page(&Page::LimitAfterSort(sort, 1, 10), "c"), " ORDER BY c.`update_time` DESC, c.create_time DESC SKIP 0 LIMIT 10");
+        let sort = Sort::Field(FieldId::from_str("排序"), Order::Asc);
+        assert_eq!(order_and_page(&Page::AllAfterSort(sort), "c"), " ORDER BY c.`排序` ASC, c.create_time DESC");
+    }
+
+    #[test]
+    async fn test_linked_ids_is_tenant_scoped() {
+        let query_context = QueryContext::new(&TenantId::from_str("o1"), "m1", HashMap::new());
+        let (cypher, _) = linked_ids_cypher(&LinkDescriptor::Dest(String::from("包含")), &query_context).unwrap();
+        assert_eq!(cypher, "MATCH (c:Card) WHERE c.org_id = $tenant_id AND c.id IN $card_ids AND true \
+            MATCH (c)<-[:`包含`]-(l:Card {org_id:$tenant_id}) WHERE true RETURN c.id AS id, collect(l.id) AS linked");
+        //关联到的卡片同样要可见
+        let query_context = query_context.with_viewable_card_types(vec![String::from("需求")]);
+        let (cypher, params) = linked_ids_cypher(&LinkDescriptor::Dest(String::from("包含")), &query_context).unwrap();
+        assert!(cypher.contains("AND c.card_type_id IN $p0") && cypher.contains("WHERE l.card_type_id IN $p1"));
+        assert_eq!(params.len(), 2);
+    }
+
+    #[test]
+    async fn test_is_restricted() {
+        let query_context = QueryContext::new(&TenantId::from_str("o1"), "m1", HashMap::new());
+        assert!(!query_context.is_restricted(None));
+        let query_context = query_context.with_visibility_filters(vec![VisibilityFilter { card_type_id: String::from("员工"), condition: Condition::default() }]);
+        assert!(query_context.is_restricted(None));
+        assert!(query_context.is_restricted(Some("员工")));
+        assert!(!query_context.is_restricted(Some("需求")));
+        let query_context = query_context.with_viewable_card_types(vec![String::from("需求")]);
+        assert!(!query_context.is_restricted(Some("需求")));
+        assert!(query_context.is_restricted(Some("缺陷")));
+    }
+
+    #[test]
+    async fn test_yields_restrictions() {
+        let salary = FieldId::from_str("薪资");
+        let cost = FieldId::from_str("成本");
+        let yields = Yields::new(vec![salary.clone(), cost.clone()]).with_restrictions(vec![
+            FieldRestriction { card_type_id: String::from("员工"), field_id: salary.clone(), readable_in: None },
+            FieldRestriction { card_type_id: String::from("需求"), field_id: cost.clone(), readable_in: Some(vec![String::from("已完成")]) },
+        ]);
+        assert!(!yields.is_readable("员工", None, &salary));
+        assert!(yields.is_readable("需求", None, &salary));
+        assert!(!yields.is_readable("需求", Some("进行中"), &cost));
+        assert!(yields.is_readable("需求", Some("已完成"), &cost));
+
+        //受限属性不能用于过滤和排序，其他属性不受影响
+        let mut condition = Condition::default();
+        condition.and(ConditionItem::Title(String::from("需求")));
+        assert!(yields.ensure_unrestricted(&condition, Some(&Sort::Field(FieldId::from_str("估算"), Order::Asc))).is_ok());
+        assert!(yields.ensure_unrestricted(&condition, Some(&Sort::Field(salary.clone(), Order::Asc))).is_err());
+        let mut group = LogicConditionGroup::default();
+        group.or(ConditionItem::Number(cost.clone(), NumberOperator::GreaterThan(PropertyValue::StaticValue(100))));
+        condition.and_logic(LogicConditionBulk::new(vec![group]));
+        assert_eq!(yields.ensure_unrestricted(&condition, None).unwrap_err().to_string(), "field 成本 is not readable");
+
+        //比较值引用参数卡片上的受限属性，同样可以推断出属性值
+        let mut condition = Condition::default();
+        condition.and(ConditionItem::Number(FieldId::from_str("估算"), NumberOperator::LessThan(PropertyValue::ReferValue(ReferPoint::Parameter, Path::Nil, String::from("薪资")))));
+        assert_eq!(yields.ensure_unrestricted(&condition, None).unwrap_err().to_string(), "field 薪资 is not readable");
+    }
+
     #[test]
     async fn test_condition_serde() {
         {
             let mut condition = Condition::default();
             condition.and(ConditionItem::CardType(CardTypeOperator::AnyIn(vec!["123".to_string()])))
-                .and(ConditionItem::Text(TextOperator::StartsWith("hello".to_string())))
+                .and(ConditionItem::Text(FieldId::from_str("text-field"), TextOperator::StartsWith("hello".to_string())))
                 .and_logic(LogicConditionBulk::new(
                     vec![
                         LogicConditionGroup::new(
                             vec![
-                                ConditionItem::Number(NumberOperator::GreaterThan(PropertyValue::StaticValue(12)))
+                                ConditionItem::Number(FieldId::from_str("estimate"), NumberOperator::GreaterThan(PropertyValue::StaticValue(12)))
                             ]
                         )
                     ]
@@ -0,0 +1,52 @@
+//组织级的配置（业务规则、角色授权、价值流等）以JSON保存在图数据库的Setting节点上，
+//服务启动时加载到内存中的注册表，修改时先保存再更新注册表
+use crate::graph::get_graph;
+use crate::newtypes::tenant_id::TenantId;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error;
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+pub struct SettingStore;
+
+impl SettingStore {
+    //kind区分配置的种类，同一组织同一种类下id唯一，已存在时覆盖
+    pub async fn save<T: Serialize>(tenant_id: &TenantId, kind: &str, id: &str, value: &T) -> Result<()> {
+        let save_query = neo4rs::query("MERGE (s:Setting {org_id:$org_id, kind:$kind, id:$id}) SET s.value = $value")
+            .param("org_id", tenant_id.as_str())
+            .param("kind", kind)
+            .param("id", id)
+            .param("value", serde_json::to_string(value)?);
+        get_graph().await.run(save_query).await?;
+        Ok(())
+    }
+
+    pub async fn remove(tenant_id: &TenantId, kind: &str, id: &str) -> Result<bool> {
+        let remove_query = neo4rs::query("MATCH (s:Setting {org_id:$org_id, kind:$kind, id:$id}) DELETE s RETURN count(s) AS removed")
+            .param("org_id", tenant_id.as_str())
+            .param("kind", kind)
+            .param("id", id);
+        let mut rows = get_graph().await.execute(remove_query).await?;
+        match rows.next().await? {
+            Some(row) => Ok(row.get::<i64>("removed")? > 0),
+            None => Ok(false),
+        }
+    }
+
+    //加载所有组织的某种配置，只在启动时使用；无法解析的配置跳过并记录
+    pub async fn load_all<T: DeserializeOwned>(kind: &str) -> Result<Vec<T>> {
+        let load_query = neo4rs::query("MATCH (s:Setting {kind:$kind}) RETURN s.org_id AS org_id, s.id AS id, s.value AS value")
+            .param("kind", kind);
+        let mut rows = get_graph().await.execute(load_query).await?;
+        let mut values = Vec::new();
+        while let Some(row) = rows.next().await? {
+            let value = row.get::<String>("value")?;
+            match serde_json::from_str(&value) {
+                Ok(value) => values.push(value),
+                Err(err) => eprintln!("skipped {} setting {} of org {}: {}", kind, row.get::<String>("id")?, row.get::<String>("org_id")?, err),
+            }
+        }
+        Ok(values)
+    }
+}
@@ -2,100 +2,326 @@ use crate::card::Card;
 use std::future::Future;
 
 pub mod neo4j_store {
-    use crate::card::{Card, FieldValue};
+    use crate::card::{Card, CardState, Field, FieldValue, FlowStatus};
+    use crate::code;
+    use crate::events;
+    use crate::events::{CardEvent, CardEventKind};
     use crate::graph::get_graph;
     use crate::newtypes::card_id::CardId;
+    use crate::newtypes::tenant_id::TenantId;
+    use crate::newtypes::timestamp::Timestamp;
+    use crate::types::LinkDescriptor;
     use neo4rs::{Query, RowStream, Txn};
 
     pub struct Neo4jStore;
 
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct CardScope {
+        pub org_id: TenantId,
+        pub card_type_id: String,
+        pub flow_id: Option<String>,
+        pub flow_status_id: Option<String>,
+    }
+
     impl Neo4jStore {
-        pub async fn create<'a>(card: &'a Card<'a>, member_id: &'a CardId) -> bool {
-            let mut graph = get_graph().await;
-            let mut txn = graph.start_txn().await.unwrap();
-            let create_card_query = Self::build_create_query(card);
-            let create_rs_with_member_query = Self::build_create_rs_with_member_query(&card.id, member_id);
+        //卡片的编号在事务中分配，忽略card.code；成功时返回分配的编号
+        //组织或前缀的第一张卡片并发创建时，序号节点的MERGE可能违反唯一约束，此时重试一次，序号节点已经存在
+        pub async fn create<'a>(card: &'a Card<'a>, member_id: &'a CardId) -> Option<String> {
+            let mut result = Self::create_in_txn(card, member_id).await;
+            if let Err(err) = &result {
+                if code::is_constraint_violation(err) {
+                    result = Self::create_in_txn(card, member_id).await;
+                }
+            }
+            match result {
+                Ok(Some(code)) => {
+                    let tenant_id = card.org_id.clone();
+                    events::publish(CardEvent::new(&tenant_id, &card.id, card.card_type_id, CardEventKind::Created, member_id));
+                    //创建时就进入价值流的卡片，统计等模块需要知道它进入了哪个状态
+                    if let Some(flow_status) = &card.flow_status {
+                        let kind = CardEventKind::FlowStatusChanged { flow_id: flow_status.flow_id.clone(), from: None, to: flow_status.flow_status_id.clone() };
+                        events::publish(CardEvent::new(&tenant_id, &card.id, card.card_type_id, kind, member_id));
+                    }
+                    Some(code)
+                }
+                Ok(None) => None,
+                Err(err) => {
+                    eprintln!("failed to create card {}: {}", card.id, err);
+                    None
+                }
+            }
+        }
+
+        //错误不能跨越await持有，否则future不是Send的，所以转换为字符串返回
+        async fn create_in_txn<'a>(card: &'a Card<'a>, member_id: &'a CardId) -> Result<Option<String>, String> {
+            let graph = get_graph().await;
+            let mut txn = graph.start_txn().await.map_err(|err| err.to_string())?;
+            let code = match code::allocate(&mut txn, &card.org_id, card.card_type_id).await.map_err(|err| err.to_string()) {
+                Ok(code) => code,
+                Err(err) => {
+                    let _ = txn.rollback().await;
+                    return Err(format!("failed to allocate code: {}", err));
+                }
+            };
+            let create_card_query = Self::build_create_query(card, &code);
+            let create_rs_with_member_query = Self::build_create_rs_with_member_query(card, member_id);
             let result = txn.run(create_card_query).await; //在memgraph上不能用execute，因为返回了不正确的结果
             if result.is_ok() {
-                //卡片和卡片创建人的关联 todo 因为run方法不返回结果，所以不知道是否成功关联
-                let mut result = txn.run(create_rs_with_member_query).await;
-                if result.is_ok() {
+                //卡片和卡片创建人的关联，创建人不在卡片所属的租户中时不会返回结果，整个事务回滚
+                let mut result = txn.execute(create_rs_with_member_query).await;
+                if let Ok(row_stream) = &mut result {
+                    if !create_rs_with_member_success(row_stream, &mut txn).await {
+                        let _ = txn.rollback().await;
+                        return Ok(None);
+                    }
                     return match txn.commit().await {
-                        Ok(_) => true,
-                        Err(err) => {
-                            eprintln!("failed to commit transaction: {:?}", err);
-                            false
-                        }
+                        Ok(_) => Ok(Some(code)),
+                        Err(err) => Err(format!("failed to commit transaction: {}", err)),
                     };
                 }
             }
-            false
+            let _ = txn.rollback().await;
+            Ok(None)
         }
 
-        fn build_create_query(card: &Card) -> Query {
-            let flow_status_str;
-            if let Some(_) = card.flow_status {
-                flow_status_str = ",flow_id:$flow_id,flow_status_id:$flow_status_id"
-            } else {
-                flow_status_str = ""
-            }
-            let mut props_str = String::new();
-            for field in &card.fields {
-                props_str.push_str(&format!(",`{}`:$`{}`", field.id, field.id))
-            }
-            let query = format!("CREATE (n:Card {{ id:$id, code:$code, name:$name, state:$state, card_type_id:$card_type_id, org_id:$org_id, create_time:$create_time, update_time:$update_time {flow_status_str} {props_str}}})");
-            let mut create_query = neo4rs::query(&query)
+        fn build_create_query(card: &Card, code: &str) -> Query {
+            let mut create_query = neo4rs::query(&Self::create_cypher(card))
                 //.param("id", *card.id) 不能移动，因为String没有实现Copy
                 .param("id", card.id.as_str())
-                .param("code", card.code.as_str())
+                .param("code", code)
                 .param("name", card.name.as_str())
                 .param("create_time", *card.create_time)
                 .param("update_time", *card.update_time)
                 .param("card_type_id", card.card_type_id)
-                .param("org_id", card.org_id)
+                .param("org_id", card.org_id.as_str())
                 .param("state", card.state.to_string());
             if let Some(flow_status) = &card.flow_status {
                 create_query = create_query.param("flow_id", flow_status.flow_id.as_str())
                     .param("flow_status_id", flow_status.flow_status_id.as_str());
             }
-            for field in &card.fields {
-                let key = field.id.as_str();
-                create_query = match &field.value {
-                    FieldValue::Int(v) => {
-                        create_query.param(key, *v)
-                    }
-                    FieldValue::Float(v) => {
-                        create_query.param(key, *v)
-                    }
-                    FieldValue::Text(v) => {
-                        create_query.param(key, String::from(v))
-                    }
-                    FieldValue::Enum(v) => {
-                        create_query.param(key, v.clone())
+            for (i, field) in card.fields.iter().enumerate() {
+                create_query = Self::field_param(create_query, &format!("f{i}"), &field.value);
+            }
+            create_query
+        }
+
+        //属性id作为属性名时转义反引号，属性值按序号传参
+        pub(crate) fn create_cypher(card: &Card) -> String {
+            let flow_status_str = if card.flow_status.is_some() { ",flow_id:$flow_id,flow_status_id:$flow_status_id" } else { "" };
+            let mut props_str = String::new();
+            for (i, field) in card.fields.iter().enumerate() {
+                props_str.push_str(&format!(",`{}`:$f{i}", field.id.replace('`', "``")))
+            }
+            format!("CREATE (n:Card {{ id:$id, code:$code, name:$name, state:$state, card_type_id:$card_type_id, org_id:$org_id, create_time:$create_time, update_time:$update_time {flow_status_str} {props_str}}})")
+        }
+
+        fn field_param(query: Query, key: &str, value: &FieldValue) -> Query {
+            match value {
+                FieldValue::Int(v) => {
+                    query.param(key, *v)
+                }
+                FieldValue::Float(v) => {
+                    query.param(key, *v)
+                }
+                FieldValue::Text(v) => {
+                    query.param(key, String::from(v))
+                }
+                FieldValue::Enum(v) => {
+                    query.param(key, v.clone())
+                }
+                //日期保存为时间戳数字，才能按DateOperator做范围比较
+                FieldValue::Date(v) => {
+                    query.param(key, **v)
+                }
+                FieldValue::DateTime(v) => {
+                    query.param(key, **v)
+                }
+            }
+        }
+
+        //更新卡片的属性
+        pub async fn update_fields<'a>(tenant_id: &'a TenantId, card_id: &'a CardId, fields: &'a [Field], member_id: &'a CardId) -> bool {
+            if fields.is_empty() {
+                return true;
+            }
+            let mut set_str = String::from("n.update_time = $update_time");
+            for (i, field) in fields.iter().enumerate() {
+                set_str.push_str(&format!(", n.`{}` = $f{i}", field.id.replace('`', "``")));
+            }
+            let cypher = format!("MATCH (n:Card {{id:$card_id, org_id:$tenant_id}}) SET {set_str} RETURN n.card_type_id AS card_type_id");
+            let mut update_query = neo4rs::query(&cypher)
+                .param("tenant_id", tenant_id.as_str())
+                .param("card_id", card_id.as_str())
+                .param("update_time", *Timestamp::now());
+            for (i, field) in fields.iter().enumerate() {
+                update_query = Self::field_param(update_query, &format!("f{i}"), &field.value);
+            }
+            let field_ids = fields.iter().map(|it| it.id.clone()).collect();
+            Self::execute_and_publish(tenant_id, update_query, card_id, CardEventKind::Updated(field_ids), member_id).await
+        }
+
+        //变更卡片的价值流状态，不校验流转是否合法，由调用方保证
+        pub async fn change_flow_status<'a>(tenant_id: &'a TenantId, card_id: &'a CardId, flow_status: &'a FlowStatus, member_id: &'a CardId) -> bool {
+            Self::set_flow_status(tenant_id, card_id, None, flow_status, member_id).await
+        }
+
+        //只有卡片仍处于from状态（None表示还没有进入价值流）时才变更，
+        //调用方校验流转合法之后卡片被其他请求流转了时不做修改并返回false
+        pub async fn transit<'a>(tenant_id: &'a TenantId, card_id: &'a CardId, from: Option<&'a str>, flow_status: &'a FlowStatus, member_id: &'a CardId) -> bool {
+            Self::set_flow_status(tenant_id, card_id, Some(from), flow_status, member_id).await
+        }
+
+        pub(crate) fn flow_status_cypher(expected: Option<Option<&str>>) -> String {
+            let guard = match expected {
+                Some(Some(_)) => " WHERE n.flow_status_id = $from",
+                Some(None) => " WHERE n.flow_status_id IS NULL",
+                None => "",
+            };
+            format!("MATCH (n:Card {{id:$card_id, org_id:$tenant_id}}){guard} WITH n, n.flow_status_id AS from SET n.flow_id = $flow_id, n.flow_status_id = $flow_status_id, n.update_time = $update_time RETURN from, n.card_type_id AS card_type_id")
+        }
+
+        async fn set_flow_status(tenant_id: &TenantId, card_id: &CardId, expected: Option<Option<&str>>, flow_status: &FlowStatus, member_id: &CardId) -> bool {
+            let graph = get_graph().await;
+            let mut change_query = neo4rs::query(&Self::flow_status_cypher(expected))
+                .param("tenant_id", tenant_id.as_str())
+                .param("card_id", card_id.as_str())
+                .param("flow_id", flow_status.flow_id.as_str())
+                .param("flow_status_id", flow_status.flow_status_id.as_str())
+                .param("update_time", *Timestamp::now());
+            if let Some(Some(from)) = expected {
+                change_query = change_query.param("from", from);
+            }
+            match graph.execute(change_query).await {
+                Ok(mut result) => {
+                    if let Ok(Some(row)) = result.next().await {
+                        let from: Option<String> = row.get("from").ok();
+                        let card_type_id: String = row.get("card_type_id").unwrap_or_default();
+                        let kind = CardEventKind::FlowStatusChanged {
+                            flow_id: flow_status.flow_id.clone(),
+                            from,
+                            to: flow_status.flow_status_id.clone(),
+                        };
+                        events::publish(CardEvent::new(tenant_id, card_id, &card_type_id, kind, member_id));
+                        return true;
                     }
-                    FieldValue::Date(v) => {
-                        create_query.param(key, v.to_string())
+                    false
+                }
+                Err(err) => {
+                    eprintln!("failed to change flow status: {:?}", err);
+                    false
+                }
+            }
+        }
+
+        //在两张卡片之间建立关联，src_id为关联关系的起点，两张卡片都必须属于该租户
+        pub async fn link<'a>(tenant_id: &'a TenantId, src_id: &'a CardId, dest_id: &'a CardId, rs_type: &'a str, member_id: &'a CardId) -> bool {
+            let link_query = neo4rs::query(&Self::link_cypher(rs_type))
+                .param("tenant_id", tenant_id.as_str())
+                .param("src_id", src_id.as_str())
+                .param("dest_id", dest_id.as_str());
+            let kind = CardEventKind::Linked(LinkDescriptor::Src(String::from(rs_type)), dest_id.clone());
+            Self::execute_and_publish(tenant_id, link_query, src_id, kind, member_id).await
+        }
+
+        pub(crate) fn link_cypher(rs_type: &str) -> String {
+            format!("MATCH (n:Card {{id:$src_id, org_id:$tenant_id}}) MATCH (m:Card {{id:$dest_id, org_id:$tenant_id}}) CREATE (n)-[:`{}`]->(m) RETURN n.card_type_id AS card_type_id", rs_type.replace('`', "``"))
+        }
+
+        //卡片的卡片类型以及当前价值流状态，用于权限判断，其他租户的卡片视为不存在
+        pub async fn scope_of(tenant_id: &TenantId, card_id: &CardId) -> Option<CardScope> {
+            let graph = get_graph().await;
+            let find_query = neo4rs::query("MATCH (n:Card {id:$card_id, org_id:$tenant_id}) RETURN n.card_type_id AS card_type_id, n.flow_id AS flow_id, n.flow_status_id AS flow_status_id")
+                .param("tenant_id", tenant_id.as_str())
+                .param("card_id", card_id.as_str());
+            match graph.execute(find_query).await {
+                Ok(mut result) => {
+                    if let Ok(Some(row)) = result.next().await {
+                        return Some(CardScope {
+                            org_id: tenant_id.clone(),
+                            card_type_id: row.get("card_type_id").ok()?,
+                            flow_id: row.get("flow_id").ok(),
+                            flow_status_id: row.get("flow_status_id").ok(),
+                        });
                     }
-                    FieldValue::DateTime(v) => {
-                        create_query.param(key, v.to_string())
+                    None
+                }
+                Err(err) => {
+                    eprintln!("failed to find card: {:?}", err);
+                    None
+                }
+            }
+        }
+
+        //执行返回card_type_id的变更语句，成功后发布事件
+        async fn execute_and_publish(tenant_id: &TenantId, query: Query, card_id: &CardId, kind: CardEventKind, member_id: &CardId) -> bool {
+            let graph = get_graph().await;
+            match graph.execute(query).await {
+                Ok(mut result) => {
+                    if let Ok(Some(row)) = result.next().await {
+                        let card_type_id: String = row.get("card_type_id").unwrap_or_default();
+                        events::publish(CardEvent::new(tenant_id, card_id, &card_type_id, kind, member_id));
+                        return true;
                     }
+                    false
+                }
+                Err(err) => {
+                    eprintln!("failed to update card: {:?}", err);
+                    false
                 }
             }
-            create_query
         }
 
-        fn build_create_rs_with_member_query(card_id: &CardId, member_id: &CardId) -> Query {
-            //如果创建成功则会返回1
-            neo4rs::query("MATCH (n:Card {id:$card_id}) MATCH (m:Card {id:$member_id}) CREATE (n)-[:creator]->(m)")
-                .param("card_id", card_id.as_str())
+        //创建人必须和卡片属于同一个租户，如果创建成功则会返回1
+        pub(crate) fn build_create_rs_with_member_query(card: &Card, member_id: &CardId) -> Query {
+            neo4rs::query("MATCH (n:Card {id:$card_id}) MATCH (m:Card {id:$member_id, org_id:$org_id}) CREATE (n)-[:creator]->(m) RETURN 1")
+                .param("card_id", card.id.as_str())
                 .param("member_id", member_id.as_str())
+                .param("org_id", card.org_id.as_str())
         }
 
-        async fn archive<'a>(card_id: &'a str, member_id: &'a str) {}
+        //归档已完成的卡片，只有活跃的卡片可以归档
+        pub async fn archive<'a>(tenant_id: &'a TenantId, card_id: &'a CardId, member_id: &'a CardId) -> bool {
+            let archive_query = Self::build_change_state_query(tenant_id, card_id, &[CardState::Active], CardState::Archived);
+            Self::execute_and_publish(tenant_id, archive_query, card_id, CardEventKind::Archived, member_id).await
+        }
 
-        async fn abandon<'a>(card_id: &'a str, reason: &'a str, member_id: &'a str) {}
+        //丢弃不再需要的卡片，只有活跃的卡片可以丢弃
+        pub async fn abandon<'a>(tenant_id: &'a TenantId, card_id: &'a CardId, reason: &'a str, member_id: &'a CardId) -> bool {
+            let abandon_query = Self::build_change_state_query(tenant_id, card_id, &[CardState::Active], CardState::Abandoned)
+                .param("abandon_reason", reason);
+            Self::execute_and_publish(tenant_id, abandon_query, card_id, CardEventKind::Abandoned(String::from(reason)), member_id).await
+        }
 
-        async fn restore() {}
+        //把归档或丢弃的卡片恢复为活跃状态
+        pub async fn restore<'a>(tenant_id: &'a TenantId, card_id: &'a CardId, member_id: &'a CardId) -> bool {
+            let restore_query = Self::build_change_state_query(tenant_id, card_id, &[CardState::Archived, CardState::Abandoned], CardState::Active);
+            Self::execute_and_publish(tenant_id, restore_query, card_id, CardEventKind::Restored, member_id).await
+        }
+
+        //卡片当前不处于from中的任一状态时不会返回结果
+        pub(crate) fn build_change_state_query(tenant_id: &TenantId, card_id: &CardId, from: &[CardState], to: CardState) -> Query {
+            let abandon_reason = if to == CardState::Abandoned { ", n.abandon_reason = $abandon_reason" } else { ", n.abandon_reason = null" };
+            let cypher = format!("MATCH (n:Card {{id:$card_id, org_id:$tenant_id}}) WHERE n.state IN $from \
+                SET n.state = $to, n.update_time = $update_time{abandon_reason} RETURN n.card_type_id AS card_type_id");
+            neo4rs::query(&cypher)
+                .param("tenant_id", tenant_id.as_str())
+                .param("card_id", card_id.as_str())
+                .param("from", from.iter().map(|it| it.to_string()).collect::<Vec<String>>())
+                .param("to", to.to_string())
+                .param("update_time", *Timestamp::now())
+        }
+
+        //删除两张卡片之间的关联
+        pub async fn unlink<'a>(tenant_id: &'a TenantId, src_id: &'a CardId, dest_id: &'a CardId, rs_type: &'a str, member_id: &'a CardId) -> bool {
+            let cypher = format!("MATCH (n:Card {{id:$src_id, org_id:$tenant_id}})-[r:`{}`]->(m:Card {{id:$dest_id, org_id:$tenant_id}}) \
+                DELETE r RETURN DISTINCT n.card_type_id AS card_type_id", rs_type.replace('`', "``"));
+            let unlink_query = neo4rs::query(&cypher)
+                .param("tenant_id", tenant_id.as_str())
+                .param("src_id", src_id.as_str())
+                .param("dest_id", dest_id.as_str());
+            let kind = CardEventKind::Unlinked(LinkDescriptor::Src(String::from(rs_type)), dest_id.clone());
+            Self::execute_and_publish(tenant_id, unlink_query, src_id, kind, member_id).await
+        }
     }
 
     async fn create_rs_with_member_success(row_stream: &mut RowStream, txn: &mut Txn) -> bool {
@@ -114,8 +340,9 @@ pub mod neo4j_store {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::card::{Field, FieldValue, FlowStatus};
+    use crate::card::{CardState, Field, FieldValue, FlowStatus};
     use crate::newtypes::card_id::CardId;
+    use crate::newtypes::tenant_id::TenantId;
     use crate::newtypes::card_type_id::CardTypeId;
     use crate::newtypes::field_id::FieldId;
     use crate::newtypes::timestamp::Timestamp;
@@ -133,8 +360,47 @@ mod tests {
         ];
         let links = HashMap::new();
         let card_type_id = CardTypeId::from_str("t101");
-        let card: Card = Card::new("c106".to_string(), "卡片101".to_string(), &card_type_id, "o101", Some(FlowStatus::new("flow-1", "status-1")), fields, links);
-        assert!(neo4j_store::Neo4jStore::create(&card, &CardId::from_str("m103")).await);
+        let card: Card = Card::new("c106".to_string(), "卡片101".to_string(), &card_type_id, &TenantId::from_str("o101"), Some(FlowStatus::new("flow-1", "status-1")), fields, links);
+        assert!(neo4j_store::Neo4jStore::create(&card, &CardId::from_str("m103")).await.is_some());
+    }
+
+    #[test]
+    fn test_transit_is_guarded() {
+        //卡片已经不在期望的状态时不做修改
+        let cypher = neo4j_store::Neo4jStore::flow_status_cypher(Some(Some("待办")));
+        assert!(cypher.starts_with("MATCH (n:Card {id:$card_id, org_id:$tenant_id}) WHERE n.flow_status_id = $from WITH n"));
+        assert!(neo4j_store::Neo4jStore::flow_status_cypher(Some(None)).contains(" WHERE n.flow_status_id IS NULL WITH n"));
+        assert!(!neo4j_store::Neo4jStore::flow_status_cypher(None).contains("WHERE"));
+    }
+
+    #[test]
+    fn test_create_cypher_escapes_field_ids() {
+        let fields = vec![Field::new(FieldId::from_str("估时"), FieldValue::Int(1)), Field::new(FieldId::from_str("x`}) DETACH DELETE n //"), FieldValue::Int(2))];
+        let card = Card::new(String::new(), String::from("卡片"), "需求", &TenantId::from_str("o1"), None, fields, HashMap::new());
+        let cypher = neo4j_store::Neo4jStore::create_cypher(&card);
+        assert!(cypher.ends_with(",`估时`:$f0,`x``}) DETACH DELETE n //`:$f1})"));
+    }
+
+    #[test]
+    fn test_link_is_tenant_scoped() {
+        //关联的两端都限定在同一个租户中，无法关联到其他租户的卡片
+        let cypher = neo4j_store::Neo4jStore::link_cypher("子任务");
+        assert!(cypher.contains("(n:Card {id:$src_id, org_id:$tenant_id})"));
+        assert!(cypher.contains("(m:Card {id:$dest_id, org_id:$tenant_id})"));
+    }
+
+    #[test]
+    fn test_change_state_is_tenant_scoped() {
+        let query = neo4j_store::Neo4jStore::build_change_state_query(&TenantId::from_str("o1"), &CardId::from_str("c1"), &[CardState::Active], CardState::Abandoned);
+        assert!(query.has_param_key("tenant_id"));
+        assert!(query.has_param_key("from"));
+    }
+
+    #[test]
+    fn test_creator_is_tenant_scoped() {
+        let card: Card = Card::new("c1".to_string(), "卡片1".to_string(), "t1", &TenantId::from_str("o1"), None, vec![], HashMap::new());
+        let query = neo4j_store::Neo4jStore::build_create_rs_with_member_query(&card, &CardId::from_str("m1"));
+        assert!(query.has_param_key("org_id"));
     }
 }
 
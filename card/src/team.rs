@@ -0,0 +1,197 @@
+//团队与成员：成员卡通过member_of关联到团队卡，关联上记录成员在团队中的角色；
+//团队卡通过parent_team关联到上级团队，构成部落-小队这样的层级
+use crate::events;
+use crate::events::{CardEvent, CardEventKind};
+use crate::graph::get_graph;
+use crate::newtypes::card_id::CardId;
+use crate::newtypes::tenant_id::TenantId;
+use crate::types::LinkDescriptor;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::{error, fmt};
+
+pub const MEMBER_OF_RS_TYPE: &str = "member_of";
+pub const PARENT_TEAM_RS_TYPE: &str = "parent_team";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TeamRole {
+    Leader,
+    Member,
+}
+
+impl Display for TeamRole {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TeamRole::Leader => write!(f, "Leader"),
+            TeamRole::Member => write!(f, "Member"),
+        }
+    }
+}
+
+impl TeamRole {
+    fn parse(role: &str) -> Self {
+        match role {
+            "Leader" => TeamRole::Leader,
+            _ => TeamRole::Member,
+        }
+    }
+}
+
+//成员在某个团队中的身份，team_id是成员直接所属的团队
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TeamMember {
+    pub member_id: CardId,
+    pub team_id: CardId,
+    pub role: TeamRole,
+}
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
+
+pub struct TeamStore;
+
+impl TeamStore {
+    //加入团队，已经在团队中时更新角色，成员和团队都必须属于该租户
+    pub async fn add_member(tenant_id: &TenantId, team_id: &CardId, member_id: &CardId, role: TeamRole, operator_id: &CardId) -> Result<()> {
+        let cypher = format!("MATCH (m:Card {{id:$member_id, org_id:$tenant_id}}) MATCH (t:Card {{id:$team_id, org_id:$tenant_id}}) \
+            MERGE (m)-[r:{MEMBER_OF_RS_TYPE}]->(t) SET r.role = $role \
+            RETURN m.card_type_id AS card_type_id");
+        let add_query = neo4rs::query(&cypher)
+            .param("tenant_id", tenant_id.as_str())
+            .param("member_id", member_id.as_str())
+            .param("team_id", team_id.as_str())
+            .param("role", role.to_string());
+        let kind = CardEventKind::Linked(LinkDescriptor::Src(String::from(MEMBER_OF_RS_TYPE)), team_id.clone());
+        Self::execute_and_publish(tenant_id, add_query, member_id, kind, operator_id).await
+    }
+
+    pub async fn remove_member(tenant_id: &TenantId, team_id: &CardId, member_id: &CardId, operator_id: &CardId) -> Result<()> {
+        let cypher = format!("MATCH (m:Card {{id:$member_id, org_id:$tenant_id}})-[r:{MEMBER_OF_RS_TYPE}]->(t:Card {{id:$team_id, org_id:$tenant_id}}) DELETE r \
+            RETURN m.card_type_id AS card_type_id");
+        let remove_query = neo4rs::query(&cypher)
+            .param("tenant_id", tenant_id.as_str())
+            .param("member_id", member_id.as_str())
+            .param("team_id", team_id.as_str());
+        let kind = CardEventKind::Unlinked(LinkDescriptor::Src(String::from(MEMBER_OF_RS_TYPE)), team_id.clone());
+        Self::execute_and_publish(tenant_id, remove_query, member_id, kind, operator_id).await
+    }
+
+    //设置上级团队，一个团队只有一个上级，不能形成环
+    pub async fn set_parent(tenant_id: &TenantId, team_id: &CardId, parent_id: &CardId, operator_id: &CardId) -> Result<()> {
+        let ancestors = Self::ancestors(tenant_id, parent_id).await?;
+        if ancestors.contains(team_id) {
+            return Err(Box::new(TeamError::new(&format!("team {} is already an ancestor of {}", team_id, parent_id))));
+        }
+        let graph = get_graph().await;
+        let detach_query = neo4rs::query(&format!("MATCH (t:Card {{id:$team_id, org_id:$tenant_id}})-[r:{PARENT_TEAM_RS_TYPE}]->() DELETE r"))
+            .param("tenant_id", tenant_id.as_str())
+            .param("team_id", team_id.as_str());
+        graph.run(detach_query).await?;
+        let cypher = format!("MATCH (t:Card {{id:$team_id, org_id:$tenant_id}}) MATCH (p:Card {{id:$parent_id, org_id:$tenant_id}}) \
+            CREATE (t)-[:{PARENT_TEAM_RS_TYPE}]->(p) \
+            RETURN t.card_type_id AS card_type_id");
+        let attach_query = neo4rs::query(&cypher)
+            .param("tenant_id", tenant_id.as_str())
+            .param("team_id", team_id.as_str())
+            .param("parent_id", parent_id.as_str());
+        let kind = CardEventKind::Linked(LinkDescriptor::Src(String::from(PARENT_TEAM_RS_TYPE)), parent_id.clone());
+        Self::execute_and_publish(tenant_id, attach_query, team_id, kind, operator_id).await
+    }
+
+    //团队自身以及所有上级团队
+    pub async fn ancestors(tenant_id: &TenantId, team_id: &CardId) -> Result<Vec<CardId>> {
+        let cypher = format!("MATCH (t:Card {{id:$team_id, org_id:$tenant_id}})-[:{PARENT_TEAM_RS_TYPE}*0..]->(a:Card) RETURN DISTINCT a.id AS id");
+        let ancestors_query = neo4rs::query(&cypher)
+            .param("tenant_id", tenant_id.as_str())
+            .param("team_id", team_id.as_str());
+        Self::fetch_ids(ancestors_query).await
+    }
+
+    //团队及其所有下级团队中的成员，同一成员在多个团队中时会出现多次
+    pub async fn members_of(tenant_id: &TenantId, team_id: &CardId, transitive: bool) -> Result<Vec<TeamMember>> {
+        let depth = if transitive { "*0.." } else { "*0" };
+        let cypher = format!("MATCH (root:Card {{id:$team_id, org_id:$tenant_id}})<-[:{PARENT_TEAM_RS_TYPE}{depth}]-(t:Card)<-[r:{MEMBER_OF_RS_TYPE}]-(m:Card) \
+            RETURN DISTINCT m.id AS member_id, t.id AS team_id, r.role AS role");
+        let members_query = neo4rs::query(&cypher)
+            .param("tenant_id", tenant_id.as_str())
+            .param("team_id", team_id.as_str());
+        let graph = get_graph().await;
+        let mut result = graph.execute(members_query).await?;
+        let mut members = Vec::new();
+        while let Some(row) = result.next().await? {
+            let role: String = row.get("role").unwrap_or_default();
+            members.push(TeamMember {
+                member_id: CardId::from(row.get("member_id")?),
+                team_id: CardId::from(row.get("team_id")?),
+                role: TeamRole::parse(&role),
+            });
+        }
+        Ok(members)
+    }
+
+    //我的团队，transitive为true时包括所属团队的所有上级团队，用于授权判断
+    pub async fn teams_of(tenant_id: &TenantId, member_id: &CardId, transitive: bool) -> Result<Vec<CardId>> {
+        let depth = if transitive { "*0.." } else { "*0" };
+        let cypher = format!("MATCH (m:Card {{id:$member_id, org_id:$tenant_id}})-[:{MEMBER_OF_RS_TYPE}]->(:Card)-[:{PARENT_TEAM_RS_TYPE}{depth}]->(t:Card) \
+            RETURN DISTINCT t.id AS id");
+        let teams_query = neo4rs::query(&cypher)
+            .param("tenant_id", tenant_id.as_str())
+            .param("member_id", member_id.as_str());
+        Self::fetch_ids(teams_query).await
+    }
+
+    async fn fetch_ids(query: neo4rs::Query) -> Result<Vec<CardId>> {
+        let graph = get_graph().await;
+        let mut result = graph.execute(query).await?;
+        let mut ids = Vec::new();
+        while let Some(row) = result.next().await? {
+            ids.push(CardId::from(row.get("id")?));
+        }
+        Ok(ids)
+    }
+
+    async fn execute_and_publish(tenant_id: &TenantId, query: neo4rs::Query, card_id: &CardId, kind: CardEventKind, operator_id: &CardId) -> Result<()> {
+        let graph = get_graph().await;
+        let mut result = graph.execute(query).await?;
+        match result.next().await? {
+            Some(row) => {
+                let card_type_id: String = row.get("card_type_id")?;
+                events::publish(CardEvent::new(tenant_id, card_id, &card_type_id, kind, operator_id));
+                Ok(())
+            }
+            None => Err(Box::new(TeamError::new(&format!("card {} not found in org {}", card_id, tenant_id)))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TeamError {
+    message: String,
+}
+
+impl TeamError {
+    pub fn new(message: &str) -> Self {
+        Self { message: message.to_string() }
+    }
+}
+
+impl Display for TeamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for TeamError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_team_role() {
+        for role in [TeamRole::Leader, TeamRole::Member] {
+            assert_eq!(TeamRole::parse(&role.to_string()), role);
+        }
+        //关联上没有角色时视为普通成员
+        assert_eq!(TeamRole::parse(""), TeamRole::Member);
+    }
+}
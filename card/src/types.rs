@@ -2,6 +2,7 @@ use serde::{Deserialize, Serialize};
 
 //关联描述符，由关联关系类型和方向构成
 #[derive(Debug,
+    Clone,
     PartialEq,
     Eq,
     PartialOrd,
@@ -14,8 +15,18 @@ pub enum LinkDescriptor {
     Dest(String),
 }
 
+impl LinkDescriptor {
+    //从关联关系另一端看到的描述符
+    pub fn reverse(&self) -> LinkDescriptor {
+        match self {
+            LinkDescriptor::Src(rs_type) => LinkDescriptor::Dest(rs_type.clone()),
+            LinkDescriptor::Dest(rs_type) => LinkDescriptor::Src(rs_type.clone()),
+        }
+    }
+}
+
 //关联关系路径
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Path {
     Segment(LinkDescriptor, Box<Path>),
     Nil,
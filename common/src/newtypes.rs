@@ -149,6 +149,51 @@ pub mod card_type_id {
     }
 }
 
+pub mod tenant_id {
+    use super::*;
+
+    /// 租户id，即组织id，所有卡片、查询和定义都必须限定在某个租户内
+    #[derive(
+        Debug,
+        Clone,
+        PartialEq,
+        Eq,
+        PartialOrd,
+        Ord,
+        Hash,
+        Serialize,
+        Deserialize,
+    )] //为了newtype与底层类型相似，需要派生这些属性，然后还要实现Display
+    pub struct TenantId(String);
+
+    impl TenantId {
+        pub fn from(id: String) -> Self {
+            TenantId(id)
+        }
+
+        //与其他id一样直接从字符串构造，不会失败，不需要实现FromStr
+        #[allow(clippy::should_implement_trait)]
+        pub fn from_str(id: &str) -> Self {
+            TenantId(String::from(id))
+        }
+    }
+
+    // 实现 Deref trait
+    impl Deref for TenantId {
+        type Target = String;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    impl Display for TenantId {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
 pub mod timestamp {
     use super::*;
     /// 时间戳
@@ -175,6 +220,12 @@ pub mod timestamp {
         }
     }
 
+    impl From<i64> for Timestamp {
+        fn from(value: i64) -> Self {
+            Timestamp(value)
+        }
+    }
+
     impl Display for Timestamp {
         fn fmt(&self, f: &mut Formatter) -> fmt::Result {
             write!(f, "{}", self.0)
@@ -209,6 +260,7 @@ pub mod timestamp {
 mod test {
     use super::*;
     use crate::newtypes::card_id::CardId;
+    use crate::newtypes::tenant_id::TenantId;
     use crate::newtypes::timestamp::Timestamp;
     #[test]
     pub fn test_card_id() {
@@ -227,6 +279,14 @@ mod test {
         println!("{}", json);
     }
 
+    #[test]
+    pub fn test_tenant_id() {
+        let tenant_id = TenantId::from_str("o1");
+        //序列化后与底层的字符串相同，可以直接替换原来的String类型的组织id
+        assert_eq!(serde_json::to_string(&tenant_id).unwrap(), "\"o1\"");
+        assert_eq!(serde_json::from_str::<TenantId>("\"o1\"").unwrap(), tenant_id);
+    }
+
     pub fn test_timestamp() {
         let timestamp = Timestamp::now();
 
@@ -0,0 +1,293 @@
+//权限和价值流的维护接口：角色和组织范围的授权需要组织范围的ManageSchema权限，卡片类型的授权、可见性和价值流需要该卡片类型上的ManageSchema权限
+//修改先保存到配置存储再更新内存中的注册表，服务重启后从配置存储加载
+use crate::auth::RequestContext;
+use crate::error::ApiError;
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use card::query::{Condition, VisibilityFilter};
+use card::settings::SettingStore;
+use rbac::access::{AccessControl, PermissionSetting, VisibilitySetting, GRANT_SETTING, PERMISSION_SETTING, ROLE_SETTING, VISIBILITY_SETTING};
+use rbac::grant::{Grant, Scope};
+use rbac::role::{Action, Role};
+use schema::card_types::{Grantee, Permission};
+use schema::schema::{Schema, SchemaRegistry};
+use schema::work_flows::{FlowStatusDef, Transition, WorkFlow};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+//价值流在配置存储中的种类
+pub const WORK_FLOW_SETTING: &str = "WorkFlow";
+
+pub struct AdminService {
+    pub access: Arc<AccessControl>,
+    pub work_flows: Arc<SchemaRegistry<WorkFlow>>,
+}
+
+impl AdminService {
+    pub fn new(access: Arc<AccessControl>, work_flows: Arc<SchemaRegistry<WorkFlow>>) -> Self {
+        Self { access, work_flows }
+    }
+
+    fn ensure_org_manageable(&self, context: &RequestContext) -> Result<(), ApiError> {
+        if self.access.check_org(&context.member, Action::ManageSchema) {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden(&format!("member {} is not allowed to manage roles and grants", context.member_id())))
+        }
+    }
+}
+
+//启动时加载保存的价值流
+pub async fn load_work_flows(work_flows: &SchemaRegistry<WorkFlow>) -> Result<usize, Box<dyn std::error::Error>> {
+    let loaded: Vec<WorkFlow> = SettingStore::load_all(WORK_FLOW_SETTING).await?;
+    let size = loaded.len();
+    for work_flow in loaded {
+        work_flows.put(work_flow);
+    }
+    Ok(size)
+}
+
+#[get("/roles")]
+async fn list_roles(context: RequestContext, data: web::Data<AdminService>) -> Result<HttpResponse, ApiError> {
+    data.ensure_org_manageable(&context)?;
+    Ok(HttpResponse::Ok().json(data.access.roles(context.org_id())))
+}
+
+#[derive(Deserialize)]
+struct CreateRoleRequest {
+    name: String,
+    actions: BTreeSet<Action>,
+}
+
+#[post("/roles")]
+async fn create_role(context: RequestContext, data: web::Data<AdminService>, request: web::Json<CreateRoleRequest>) -> Result<HttpResponse, ApiError> {
+    data.ensure_org_manageable(&context)?;
+    let request = request.into_inner();
+    if request.name.trim().is_empty() {
+        return Err(ApiError::bad_request("role's name is empty"));
+    }
+    let role = Role::new(context.org_id(), &request.name, request.actions);
+    SettingStore::save(context.org_id(), ROLE_SETTING, &role.id, &role).await?;
+    data.access.add_role(role.clone());
+    Ok(HttpResponse::Ok().json(role))
+}
+
+//仍然被授予的角色不能删除，先收回授权
+#[delete("/roles/{role_id}")]
+async fn remove_role(context: RequestContext, data: web::Data<AdminService>, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    data.ensure_org_manageable(&context)?;
+    let role_id = path.into_inner();
+    if !data.access.roles(context.org_id()).iter().any(|it| it.id == role_id) {
+        return Err(ApiError::not_found(&format!("role {} not found", role_id)));
+    }
+    if data.access.grants(context.org_id()).iter().any(|it| it.role_id == role_id) {
+        return Err(ApiError::conflict(&format!("role {} is still granted", role_id)));
+    }
+    SettingStore::remove(context.org_id(), ROLE_SETTING, &role_id).await?;
+    data.access.remove_role(context.org_id(), &role_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/grants")]
+async fn list_grants(context: RequestContext, data: web::Data<AdminService>) -> Result<HttpResponse, ApiError> {
+    data.ensure_org_manageable(&context)?;
+    Ok(HttpResponse::Ok().json(data.access.grants(context.org_id())))
+}
+
+#[derive(Deserialize)]
+struct GrantRequest {
+    role_id: String,
+    grantee: Grantee,
+}
+
+//这里只授予组织范围的角色，卡片类型范围的授权通过卡片类型的授权设置维护
+#[post("/grants")]
+async fn create_grant(context: RequestContext, data: web::Data<AdminService>, request: web::Json<GrantRequest>) -> Result<HttpResponse, ApiError> {
+    data.ensure_org_manageable(&context)?;
+    let request = request.into_inner();
+    if !data.access.roles(context.org_id()).iter().any(|it| it.id == request.role_id) {
+        return Err(ApiError::not_found(&format!("role {} not found", request.role_id)));
+    }
+    let grant = Grant::new(context.org_id(), &request.role_id, request.grantee, Scope::Org);
+    SettingStore::save(context.org_id(), GRANT_SETTING, &grant.id, &grant).await?;
+    data.access.grant(grant.clone())?;
+    Ok(HttpResponse::Ok().json(grant))
+}
+
+#[delete("/grants/{grant_id}")]
+async fn revoke_grant(context: RequestContext, data: web::Data<AdminService>, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    data.ensure_org_manageable(&context)?;
+    let grant_id = path.into_inner();
+    if !data.access.grants(context.org_id()).iter().any(|it| it.id == grant_id && it.scope == Scope::Org) {
+        return Err(ApiError::not_found(&format!("grant {} not found", grant_id)));
+    }
+    SettingStore::remove(context.org_id(), GRANT_SETTING, &grant_id).await?;
+    data.access.revoke(context.org_id(), &grant_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+//替换卡片类型范围的授权和属性规则
+#[put("/card-types/{card_type_id}/permission")]
+async fn set_permission(context: RequestContext, data: web::Data<AdminService>, path: web::Path<String>, request: web::Json<Permission>) -> Result<HttpResponse, ApiError> {
+    let card_type_id = path.into_inner();
+    data.access.ensure(&context.member, Action::ManageSchema, context.org_id(), &card_type_id)?;
+    let setting = PermissionSetting { org_id: context.org_id().clone(), card_type_id, permission: request.into_inner() };
+    let roles = data.access.roles(context.org_id());
+    if let Some(grant) = setting.permission.grants().iter().find(|grant| !roles.iter().any(|it| it.id == grant.role_id)) {
+        return Err(ApiError::bad_request(&format!("role {} not found", grant.role_id)));
+    }
+    SettingStore::save(context.org_id(), PERMISSION_SETTING, &setting.card_type_id, &setting).await?;
+    data.access.apply_card_type_permission(&setting.org_id, &setting.card_type_id, &setting.permission)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/card-types/{card_type_id}/visibility")]
+async fn find_visibility(context: RequestContext, data: web::Data<AdminService>, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let card_type_id = path.into_inner();
+    data.access.ensure(&context.member, Action::ManageSchema, context.org_id(), &card_type_id)?;
+    let filter = data.access.visibility_filters(context.org_id()).into_iter()
+        .find(|it| it.card_type_id == card_type_id)
+        .ok_or_else(|| ApiError::not_found(&format!("card type {} has no visibility filter", card_type_id)))?;
+    Ok(HttpResponse::Ok().json(filter))
+}
+
+#[put("/card-types/{card_type_id}/visibility")]
+async fn set_visibility(context: RequestContext, data: web::Data<AdminService>, path: web::Path<String>, request: web::Json<Condition>) -> Result<HttpResponse, ApiError> {
+    let card_type_id = path.into_inner();
+    data.access.ensure(&context.member, Action::ManageSchema, context.org_id(), &card_type_id)?;
+    let setting = VisibilitySetting { org_id: context.org_id().clone(), filter: VisibilityFilter::new(&card_type_id, request.into_inner()) };
+    SettingStore::save(context.org_id(), VISIBILITY_SETTING, &card_type_id, &setting).await?;
+    data.access.set_visibility(&setting.org_id, setting.filter);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/card-types/{card_type_id}/visibility")]
+async fn remove_visibility(context: RequestContext, data: web::Data<AdminService>, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let card_type_id = path.into_inner();
+    data.access.ensure(&context.member, Action::ManageSchema, context.org_id(), &card_type_id)?;
+    SettingStore::remove(context.org_id(), VISIBILITY_SETTING, &card_type_id).await?;
+    if data.access.remove_visibility(context.org_id(), &card_type_id) {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ApiError::not_found(&format!("card type {} has no visibility filter", card_type_id)))
+    }
+}
+
+//只列出成员能查看的卡片类型的价值流
+#[get("/work-flows")]
+async fn list_work_flows(context: RequestContext, data: web::Data<AdminService>) -> HttpResponse {
+    let work_flows: Vec<WorkFlow> = data.work_flows.list(context.org_id()).into_iter()
+        .filter(|it| data.access.check_card_type(&context.member, Action::View, context.org_id(), it.card_type_id()))
+        .collect();
+    HttpResponse::Ok().json(work_flows)
+}
+
+#[derive(Deserialize)]
+struct SaveWorkFlowRequest {
+    name: String,
+    card_type_id: String,
+    statuses: Vec<FlowStatusDef>,
+    #[serde(default)]
+    transitions: Vec<Transition>,
+}
+
+impl SaveWorkFlowRequest {
+    fn check(&self) -> Result<(), ApiError> {
+        if self.name.trim().is_empty() {
+            return Err(ApiError::bad_request("work flow's name is empty"));
+        }
+        if self.statuses.is_empty() {
+            return Err(ApiError::bad_request("work flow has no statuses"));
+        }
+        let defined = |status_id: &str| self.statuses.iter().any(|it| it.id == status_id);
+        match self.transitions.iter().find(|it| !defined(&it.to) || it.from.as_deref().is_some_and(|from| !defined(from))) {
+            Some(transition) => Err(ApiError::bad_request(&format!("transition {:?} -> {} refers to undefined status", transition.from, transition.to))),
+            None => Ok(()),
+        }
+    }
+}
+
+//修改已有的价值流时，原卡片类型和新卡片类型上都需要ManageSchema权限
+#[put("/work-flows/{flow_id}")]
+async fn save_work_flow(context: RequestContext, data: web::Data<AdminService>, path: web::Path<String>, request: web::Json<SaveWorkFlowRequest>) -> Result<HttpResponse, ApiError> {
+    let flow_id = path.into_inner();
+    let request = request.into_inner();
+    request.check()?;
+    if let Some(existing) = data.work_flows.get(context.org_id(), &flow_id) {
+        data.access.ensure(&context.member, Action::ManageSchema, context.org_id(), existing.card_type_id())?;
+    }
+    data.access.ensure(&context.member, Action::ManageSchema, context.org_id(), &request.card_type_id)?;
+    let work_flow = WorkFlow::new(flow_id, request.name, request.card_type_id, context.org_id().clone(), request.statuses, request.transitions);
+    SettingStore::save(context.org_id(), WORK_FLOW_SETTING, work_flow.id(), &work_flow).await?;
+    data.work_flows.put(work_flow.clone());
+    Ok(HttpResponse::Ok().json(work_flow))
+}
+
+#[delete("/work-flows/{flow_id}")]
+async fn remove_work_flow(context: RequestContext, data: web::Data<AdminService>, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let flow_id = path.into_inner();
+    let work_flow = data.work_flows.get(context.org_id(), &flow_id)
+        .filter(|it| data.access.check_card_type(&context.member, Action::View, context.org_id(), it.card_type_id()))
+        .ok_or_else(|| ApiError::not_found(&format!("work flow {} not found", flow_id)))?;
+    data.access.ensure(&context.member, Action::ManageSchema, context.org_id(), work_flow.card_type_id())?;
+    SettingStore::remove(context.org_id(), WORK_FLOW_SETTING, &flow_id).await?;
+    data.work_flows.remove(context.org_id(), &flow_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_roles)
+        .service(create_role)
+        .service(remove_role)
+        .service(list_grants)
+        .service(create_grant)
+        .service(revoke_grant)
+        .service(set_permission)
+        .service(find_visibility)
+        .service(set_visibility)
+        .service(remove_visibility)
+        .service(list_work_flows)
+        .service(save_work_flow)
+        .service(remove_work_flow);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::newtypes::card_id::CardId;
+    use common::newtypes::tenant_id::TenantId;
+    use rbac::access::Member;
+
+    fn member_context(member_id: &str, org_id: &str) -> RequestContext {
+        RequestContext { member: Member::new(CardId::from_str(member_id), &TenantId::from_str(org_id), vec![]), method: crate::auth::AuthMethod::Jwt }
+    }
+
+    fn status(id: &str) -> FlowStatusDef {
+        FlowStatusDef { id: String::from(id), name: String::from(id) }
+    }
+
+    #[test]
+    fn test_admin_permission() {
+        let data = AdminService::new(Arc::new(AccessControl::default()), Arc::new(SchemaRegistry::new()));
+        assert!(data.ensure_org_manageable(&member_context("m1", "o1")).is_err());
+        data.access.bootstrap_admin(&TenantId::from_str("o1"), "m1").unwrap();
+        assert!(data.ensure_org_manageable(&member_context("m1", "o1")).is_ok());
+        assert_eq!(data.ensure_org_manageable(&member_context("m2", "o1")).unwrap_err().to_string(), "forbidden: member m2 is not allowed to manage roles and grants");
+        assert!(data.ensure_org_manageable(&member_context("m1", "o2")).is_err());
+    }
+
+    #[test]
+    fn test_check_work_flow() {
+        let request = SaveWorkFlowRequest {
+            name: String::from("需求流程"),
+            card_type_id: String::from("需求"),
+            statuses: vec![status("待办"), status("进行中")],
+            transitions: vec![Transition { from: None, to: String::from("待办") }, Transition { from: Some(String::from("待办")), to: String::from("进行中") }],
+        };
+        assert!(request.check().is_ok());
+        let request = SaveWorkFlowRequest { transitions: vec![Transition { from: Some(String::from("已完成")), to: String::from("待办") }], ..request };
+        assert!(request.check().is_err());
+        let request = SaveWorkFlowRequest { statuses: vec![], transitions: vec![], ..request };
+        assert!(request.check().is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//集成方使用的API令牌，请求头为 Authorization: Token <secret>
+//只保存令牌明文的SHA-256摘要，明文在签发时返回一次后不再可见
+//令牌先保存到配置存储再加入注册表，服务重启后从配置存储加载
+use crate::auth::{credentials, AuthError, AuthMethod, Authenticator, Principal};
+use actix_web::HttpRequest;
+use card::settings::SettingStore;
+use common::id_generator;
+use common::newtypes::card_id::CardId;
+use common::newtypes::tenant_id::TenantId;
+use common::newtypes::timestamp::Timestamp;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error;
+use std::sync::{Arc, RwLock};
+
+//API令牌在配置存储中的种类
+pub const API_TOKEN_SETTING: &str = "ApiToken";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub principal: Principal,
+    pub create_time: Timestamp,
+}
+
+//保存到配置存储中的令牌，只包含明文的摘要
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiTokenSetting {
+    pub digest: String,
+    pub token: ApiToken,
+}
+
+impl ApiTokenSetting {
+    //令牌以成员的身份访问，权限与该成员相同，同时返回令牌明文
+    pub fn issue(principal: Principal, name: &str) -> (Self, String) {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret = hex::encode(bytes);
+        let token = ApiToken {
+            id: id_generator::generate_id(),
+            name: String::from(name),
+            principal,
+            create_time: Timestamp::now(),
+        };
+        (Self { digest: digest(&secret), token }, secret)
+    }
+}
+
+#[derive(Default)]
+pub struct ApiTokenRegistry {
+    tokens: RwLock<HashMap<String, ApiToken>>, //key为令牌明文的摘要
+}
+
+impl ApiTokenRegistry {
+    //启动时加载保存的令牌
+    pub async fn load(&self) -> Result<usize, Box<dyn error::Error>> {
+        let settings: Vec<ApiTokenSetting> = SettingStore::load_all(API_TOKEN_SETTING).await?;
+        let size = settings.len();
+        for setting in settings {
+            self.add(setting);
+        }
+        Ok(size)
+    }
+
+    pub fn add(&self, setting: ApiTokenSetting) {
+        self.tokens.write().unwrap().insert(setting.digest, setting.token);
+    }
+
+    pub fn get(&self, org_id: &TenantId, token_id: &str) -> Option<ApiToken> {
+        self.tokens.read().unwrap().values()
+            .find(|it| it.id == token_id && it.principal.org_id == *org_id)
+            .cloned()
+    }
+
+    //成员自己签发的令牌，按签发时间排列
+    pub fn list_of_member(&self, org_id: &TenantId, member_id: &CardId) -> Vec<ApiToken> {
+        let mut tokens: Vec<ApiToken> = self.tokens.read().unwrap().values()
+            .filter(|it| it.principal.org_id == *org_id && it.principal.member_id == *member_id)
+            .cloned()
+            .collect();
+        tokens.sort_by(|a, b| a.create_time.cmp(&b.create_time));
+        tokens
+    }
+
+    pub fn revoke(&self, org_id: &TenantId, token_id: &str) -> bool {
+        let mut tokens = self.tokens.write().unwrap();
+        let size = tokens.len();
+        tokens.retain(|_, it| !(it.id == token_id && it.principal.org_id == *org_id));
+        tokens.len() < size
+    }
+
+    pub fn find(&self, secret: &str) -> Option<ApiToken> {
+        self.tokens.read().unwrap().get(&digest(secret)).cloned()
+    }
+}
+
+fn digest(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+pub struct ApiTokenAuthenticator {
+    tokens: Arc<ApiTokenRegistry>,
+}
+
+impl ApiTokenAuthenticator {
+    pub fn new(tokens: Arc<ApiTokenRegistry>) -> Self {
+        Self { tokens }
+    }
+}
+
+impl Authenticator for ApiTokenAuthenticator {
+    fn method(&self) -> AuthMethod {
+        AuthMethod::ApiToken
+    }
+
+    fn authenticate(&self, req: &HttpRequest) -> Option<Result<Principal, AuthError>> {
+        credentials(req, "Token").map(|secret| {
+            self.tokens.find(secret)
+                .map(|it| it.principal)
+                .ok_or_else(|| AuthError::new("invalid api token"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issue_and_revoke() {
+        let registry = ApiTokenRegistry::default();
+        let principal = Principal { member_id: CardId::from_str("m1"), org_id: TenantId::from_str("o1") };
+        let (setting, secret) = ApiTokenSetting::issue(principal.clone(), "ci");
+        //配置存储中只有摘要
+        assert!(!serde_json::to_string(&setting).unwrap().contains(&secret));
+        let api_token = setting.token.clone();
+        registry.add(setting);
+        assert_eq!(registry.find(&secret).unwrap().principal, principal);
+        assert_eq!(registry.list_of_member(&TenantId::from_str("o1"), &CardId::from_str("m1")), vec![api_token.clone()]);
+        assert!(registry.list_of_member(&TenantId::from_str("o1"), &CardId::from_str("m2")).is_empty());
+        assert!(registry.get(&TenantId::from_str("o2"), &api_token.id).is_none());
+        //其他组织不能吊销
+        assert!(!registry.revoke(&TenantId::from_str("o2"), &api_token.id));
+        assert!(registry.revoke(&TenantId::from_str("o1"), &api_token.id));
+        assert!(registry.find(&secret).is_none());
+    }
+}
@@ -0,0 +1,333 @@
+//认证：从请求中的凭证解析出成员和组织，放入请求上下文，后续的查询和权限判断都基于它
+use crate::api_token::{ApiToken, ApiTokenRegistry, ApiTokenSetting, API_TOKEN_SETTING};
+use crate::error::ApiError;
+use crate::jwt::JwtVerifier;
+use crate::session::{SessionStore, SESSION_COOKIE};
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{delete, get, post, web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use card::query::QueryContext;
+use card::settings::SettingStore;
+use common::newtypes::card_id::CardId;
+use common::newtypes::tenant_id::TenantId;
+use rbac::access::{AccessControl, Member};
+use rbac::role::Action;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::future::{ready, Ready};
+use std::sync::Arc;
+use std::{error, fmt};
+
+//凭证对应的成员，尚未加载团队信息
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Principal {
+    pub member_id: CardId,
+    pub org_id: TenantId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthMethod {
+    Jwt,
+    ApiToken,
+    Session,
+}
+
+//一种认证方式，请求中没有该方式的凭证时返回None，交给下一种方式
+pub trait Authenticator: Send + Sync {
+    fn method(&self) -> AuthMethod;
+
+    fn authenticate(&self, req: &HttpRequest) -> Option<Result<Principal, AuthError>>;
+}
+
+//Authorization请求头中指定方案的凭证，例如 Bearer <jwt>、Token <api token>
+pub(crate) fn credentials<'a>(req: &'a HttpRequest, scheme: &str) -> Option<&'a str> {
+    let value = req.headers().get(actix_web::http::header::AUTHORIZATION)?.to_str().ok()?;
+    let (actual, credentials) = value.split_once(' ')?;
+    actual.eq_ignore_ascii_case(scheme).then(|| credentials.trim())
+}
+
+//按顺序尝试各种认证方式
+pub struct Authentication {
+    authenticators: Vec<Box<dyn Authenticator>>,
+}
+
+impl Authentication {
+    pub fn new(authenticators: Vec<Box<dyn Authenticator>>) -> Self {
+        Self { authenticators }
+    }
+
+    pub fn authenticate(&self, req: &HttpRequest) -> Result<(Principal, AuthMethod), AuthError> {
+        for authenticator in &self.authenticators {
+            if let Some(result) = authenticator.authenticate(req) {
+                //凭证无效时不再尝试其他方式，避免用一种凭证掩盖另一种凭证的错误
+                return result.map(|principal| (principal, authenticator.method()));
+            }
+        }
+        Err(AuthError::new("missing credentials"))
+    }
+}
+
+//已认证的请求上下文，处理函数通过提取器取得
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub member: Member,
+    pub method: AuthMethod,
+}
+
+impl RequestContext {
+    pub fn member_id(&self) -> &CardId {
+        &self.member.id
+    }
+
+    pub fn org_id(&self) -> &TenantId {
+        &self.member.org_id
+    }
+
+    //查询限定在成员所在的组织，并带上成员的行级可见性条件
+    pub fn query_context(&self, access: &AccessControl, parameters: HashMap<String, String>) -> QueryContext {
+        access.query_context(&self.member, parameters)
+    }
+}
+
+impl FromRequest for RequestContext {
+    type Error = AuthError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let context = req.extensions().get::<RequestContext>().cloned();
+        ready(context.ok_or_else(|| AuthError::new("request is not authenticated")))
+    }
+}
+
+//认证中间件，认证通过后加载成员的团队，以便按团队授权
+pub async fn authenticate(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let authentication = req.app_data::<web::Data<Authentication>>()
+        .ok_or_else(|| AuthError::new("authentication is not configured"))?;
+    let (principal, method) = authentication.authenticate(req.request())?;
+    let member = Member::load(principal.member_id, &principal.org_id).await;
+    req.extensions_mut().insert(RequestContext { member, method });
+    next.call(req).await
+}
+
+//当前请求的成员、组织以及认证方式
+#[get("/auth/me")]
+async fn me(context: RequestContext) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "member_id": context.member_id(),
+        "org_id": context.org_id(),
+        "team_ids": context.member.team_ids,
+        "method": context.method,
+    }))
+}
+
+//签发令牌要求成员本人登录，持有API令牌不能再签发新的凭证
+fn ensure_interactive(context: &RequestContext) -> Result<(), ApiError> {
+    match context.method {
+        AuthMethod::Jwt | AuthMethod::Session => Ok(()),
+        AuthMethod::ApiToken => Err(ApiError::forbidden("credentials can not be issued with an api token")),
+    }
+}
+
+//会话只能用JWT换取，否则会话可以不断换取新的会话，绕过会话的最长有效期
+fn ensure_jwt(context: &RequestContext) -> Result<(), ApiError> {
+    match context.method {
+        AuthMethod::Jwt => Ok(()),
+        AuthMethod::Session | AuthMethod::ApiToken => Err(ApiError::forbidden("sessions can only be created with a jwt")),
+    }
+}
+
+#[derive(Deserialize)]
+struct IssueApiTokenRequest {
+    name: String,
+}
+
+//为集成方签发API令牌，明文只在签发时返回一次
+#[post("/auth/api-tokens")]
+async fn issue_api_token(context: RequestContext, tokens: web::Data<Arc<ApiTokenRegistry>>, request: web::Json<IssueApiTokenRequest>) -> Result<HttpResponse, ApiError> {
+    ensure_interactive(&context)?;
+    let principal = Principal { member_id: context.member_id().clone(), org_id: context.org_id().clone() };
+    let (setting, secret) = ApiTokenSetting::issue(principal, &request.name);
+    SettingStore::save(context.org_id(), API_TOKEN_SETTING, &setting.token.id, &setting).await?;
+    let api_token = setting.token.clone();
+    tokens.add(setting);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "token": api_token, "secret": secret })))
+}
+
+//只列出成员自己的令牌，不含明文
+#[get("/auth/api-tokens")]
+async fn list_api_tokens(context: RequestContext, tokens: web::Data<Arc<ApiTokenRegistry>>) -> HttpResponse {
+    HttpResponse::Ok().json(tokens.list_of_member(context.org_id(), context.member_id()))
+}
+
+//成员只能吊销自己的令牌，有组织ManageSchema权限的管理员可以吊销任何人的令牌
+fn can_revoke(context: &RequestContext, api_token: &ApiToken, access: &AccessControl) -> bool {
+    api_token.principal.member_id == *context.member_id() || access.check_org(&context.member, Action::ManageSchema)
+}
+
+#[delete("/auth/api-tokens/{token_id}")]
+async fn revoke_api_token(context: RequestContext, tokens: web::Data<Arc<ApiTokenRegistry>>, access: web::Data<Arc<AccessControl>>, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let token_id = path.into_inner();
+    //其他成员的令牌视为不存在
+    tokens.get(context.org_id(), &token_id)
+        .filter(|it| can_revoke(&context, it, &access))
+        .ok_or_else(|| ApiError::not_found(&format!("api token {} not found", token_id)))?;
+    SettingStore::remove(context.org_id(), API_TOKEN_SETTING, &token_id).await?;
+    tokens.revoke(context.org_id(), &token_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+//浏览器用JWT换取会话cookie，之后的请求只需携带cookie
+#[post("/auth/sessions")]
+async fn create_session(context: RequestContext, sessions: web::Data<Arc<SessionStore>>) -> Result<HttpResponse, ApiError> {
+    ensure_jwt(&context)?;
+    let principal = Principal { member_id: context.member_id().clone(), org_id: context.org_id().clone() };
+    let session_id = sessions.create(principal);
+    Ok(HttpResponse::NoContent().cookie(session_cookie(session_id)).finish())
+}
+
+//会话cookie只在HTTPS下发送，并且不随跨站请求发送，避免CSRF
+fn session_cookie(value: String) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, value).path("/").http_only(true).secure(true).same_site(SameSite::Strict).finish()
+}
+
+#[delete("/auth/sessions")]
+async fn delete_session(req: HttpRequest, _context: RequestContext, sessions: web::Data<Arc<SessionStore>>) -> HttpResponse {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        sessions.remove(cookie.value());
+    }
+    let mut cookie = session_cookie(String::new());
+    cookie.make_removal();
+    HttpResponse::NoContent().cookie(cookie).finish()
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(me)
+        .service(issue_api_token)
+        .service(list_api_tokens)
+        .service(revoke_api_token)
+        .service(create_session)
+        .service(delete_session);
+}
+
+//组装默认的认证方式：JWT、API令牌、会话cookie
+pub fn authentication(jwt: JwtVerifier, tokens: Arc<ApiTokenRegistry>, sessions: Arc<SessionStore>) -> Authentication {
+    Authentication::new(vec![
+        Box::new(jwt),
+        Box::new(crate::api_token::ApiTokenAuthenticator::new(tokens)),
+        Box::new(crate::session::SessionAuthenticator::new(sessions)),
+    ])
+}
+
+#[derive(Debug)]
+pub struct AuthError {
+    message: String,
+}
+
+impl AuthError {
+    pub fn new(message: &str) -> Self {
+        Self { message: message.to_string() }
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for AuthError {}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::UNAUTHORIZED
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ApiError::unauthorized(&self.message).error_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jwt;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn test_reject_unauthenticated() {
+        let authentication = authentication(JwtVerifier::new("secret"), Arc::new(ApiTokenRegistry::default()), Arc::new(SessionStore::default()));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(authentication))
+                .service(web::scope("/api").wrap(from_fn(authenticate)).configure(config))
+        ).await;
+        let status = |result: Result<ServiceResponse, actix_web::Error>| match result {
+            Ok(resp) => resp.status(),
+            Err(err) => err.as_response_error().status_code(),
+        };
+        //没有凭证
+        assert_eq!(status(test::try_call_service(&app, test::TestRequest::get().uri("/api/auth/me").to_request()).await), 401);
+        //用其他密钥签名的JWT
+        let forged = jwt::issue("other", &Principal { member_id: CardId::from_str("m1"), org_id: TenantId::from_str("o1") }, 60);
+        let req = test::TestRequest::get().uri("/api/auth/me").insert_header(("Authorization", format!("Bearer {forged}"))).to_request();
+        assert_eq!(status(test::try_call_service(&app, req).await), 401);
+        //不存在的API令牌和会话
+        let req = test::TestRequest::get().uri("/api/auth/me").insert_header(("Authorization", "Token unknown")).to_request();
+        assert_eq!(status(test::try_call_service(&app, req).await), 401);
+        let req = test::TestRequest::get().uri("/api/auth/me").cookie(Cookie::new(SESSION_COOKIE, "unknown")).to_request();
+        assert_eq!(status(test::try_call_service(&app, req).await), 401);
+    }
+
+    #[actix_web::test]
+    async fn test_authenticate_in_order() {
+        let tokens = Arc::new(ApiTokenRegistry::default());
+        let sessions = Arc::new(SessionStore::default());
+        let authentication = authentication(JwtVerifier::new("secret"), tokens.clone(), sessions.clone());
+        let principal = Principal { member_id: CardId::from_str("m1"), org_id: TenantId::from_str("o1") };
+
+        let req = test::TestRequest::default().insert_header(("Authorization", format!("Bearer {}", jwt::issue("secret", &principal, 60)))).to_http_request();
+        assert_eq!(authentication.authenticate(&req).unwrap(), (principal.clone(), AuthMethod::Jwt));
+
+        let (setting, secret) = ApiTokenSetting::issue(principal.clone(), "ci");
+        tokens.add(setting);
+        let req = test::TestRequest::default().insert_header(("Authorization", format!("Token {secret}"))).to_http_request();
+        assert_eq!(authentication.authenticate(&req).unwrap(), (principal.clone(), AuthMethod::ApiToken));
+
+        let session_id = sessions.create(principal.clone());
+        let req = test::TestRequest::default().cookie(Cookie::new(SESSION_COOKIE, session_id)).to_http_request();
+        assert_eq!(authentication.authenticate(&req).unwrap(), (principal, AuthMethod::Session));
+    }
+
+    #[actix_web::test]
+    async fn test_credential_management() {
+        let context = |member_id: &str, method: AuthMethod| RequestContext { member: Member::new(CardId::from_str(member_id), &TenantId::from_str("o1"), vec![]), method };
+        assert!(ensure_interactive(&context("m1", AuthMethod::Jwt)).is_ok());
+        assert!(ensure_interactive(&context("m1", AuthMethod::Session)).is_ok());
+        assert_eq!(ensure_interactive(&context("m1", AuthMethod::ApiToken)).unwrap_err().status_code(), 403);
+        assert!(ensure_jwt(&context("m1", AuthMethod::Jwt)).is_ok());
+        assert_eq!(ensure_jwt(&context("m1", AuthMethod::Session)).unwrap_err().status_code(), 403);
+        assert_eq!(ensure_jwt(&context("m1", AuthMethod::ApiToken)).unwrap_err().status_code(), 403);
+
+        let cookie = session_cookie(String::from("s1"));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.http_only(), Some(true));
+
+        let access = AccessControl::default();
+        let tokens = ApiTokenRegistry::default();
+        let (setting, _) = ApiTokenSetting::issue(Principal { member_id: CardId::from_str("m1"), org_id: TenantId::from_str("o1") }, "ci");
+        let api_token = setting.token.clone();
+        tokens.add(setting);
+        assert!(can_revoke(&context("m1", AuthMethod::Jwt), &api_token, &access));
+        assert!(!can_revoke(&context("m2", AuthMethod::Jwt), &api_token, &access));
+        let admin = rbac::role::Role::admin(&TenantId::from_str("o1"));
+        let grant = rbac::grant::Grant::new(&TenantId::from_str("o1"), &admin.id, schema::card_types::Grantee::Member(String::from("m2")), rbac::grant::Scope::Org);
+        access.add_role(admin);
+        access.grant(grant).unwrap();
+        assert!(can_revoke(&context("m2", AuthMethod::Jwt), &api_token, &access));
+    }
+}
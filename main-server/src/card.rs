@@ -0,0 +1,374 @@
+//卡片接口：增改查、归档丢弃恢复、价值流流转、关联以及按条件搜索
+//卡片不提供物理删除，不再需要的卡片丢弃即可，这样历史和统计不会断档
+use crate::auth::RequestContext;
+use crate::error::ApiError;
+use actix_web::{delete, get, patch, post, put, web, HttpResponse};
+use card::card::{Card, Field, FlowStatus};
+use card::code;
+use card::computed::{ComputedField, ComputedFieldRegistry, COMPUTED_FIELD_SETTING};
+use card::formula::Formula;
+use card::query;
+use card::query::{Condition, Page};
+use card::settings::SettingStore;
+use card::store::neo4j_store::CardScope;
+use common::newtypes::card_id::CardId;
+use common::newtypes::field_id::FieldId;
+use rbac::access::AccessControl;
+use rbac::role::Action;
+use rbac::secured_store::SecuredStore;
+use schema::schema::{Schema, SchemaRegistry};
+use schema::work_flows::WorkFlow;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const DEFAULT_PAGE_SIZE: u8 = 20;
+
+pub struct CardService {
+    pub access: Arc<AccessControl>,
+    pub store: SecuredStore,
+    pub work_flows: Arc<SchemaRegistry<WorkFlow>>,
+    pub computed: Arc<ComputedFieldRegistry>,
+}
+
+impl CardService {
+    pub fn new(access: Arc<AccessControl>, work_flows: Arc<SchemaRegistry<WorkFlow>>, computed: Arc<ComputedFieldRegistry>) -> Self {
+        Self {
+            store: SecuredStore::new(access.clone(), computed.clone()),
+            access,
+            work_flows,
+            computed,
+        }
+    }
+
+    //流转必须符合卡片类型所用价值流的定义
+    fn ensure_transition(&self, context: &RequestContext, card_type_id: &str, from: Option<&str>, to: &FlowStatus) -> Result<(), ApiError> {
+        let work_flow = self.work_flows.get(context.org_id(), &to.flow_id)
+            .filter(|it| it.card_type_id() == card_type_id)
+            .ok_or_else(|| ApiError::bad_request(&format!("flow {} is not defined for card type {}", to.flow_id, card_type_id)))?;
+        if work_flow.allows(from, &to.flow_status_id) {
+            Ok(())
+        } else {
+            Err(ApiError::new(actix_web::http::StatusCode::CONFLICT, "invalid_transition",
+                &format!("transition from {:?} to {} is not allowed", from, to.flow_status_id)))
+        }
+    }
+
+    //卡片类型没有单独的定义，定义了价值流或者已经有卡片的卡片类型才视为存在
+    async fn ensure_card_type(&self, context: &RequestContext, card_type_id: &str) -> Result<(), ApiError> {
+        if self.work_flows.list(context.org_id()).iter().any(|it| it.card_type_id() == card_type_id)
+            || code::has_cards(context.org_id(), card_type_id).await? {
+            Ok(())
+        } else {
+            Err(ApiError::not_found(&format!("card type {} not found", card_type_id)))
+        }
+    }
+
+    async fn scope_of(&self, context: &RequestContext, card_id: &CardId) -> Result<CardScope, ApiError> {
+        Ok(self.store.scope_of(card_id, &context.member).await?)
+    }
+
+    async fn transit_in_scope(&self, context: &RequestContext, card_id: &CardId, scope: &CardScope, to: &FlowStatus) -> Result<(), ApiError> {
+        self.ensure_transition(context, &scope.card_type_id, scope.flow_status_id.as_deref(), to)?;
+        let from = scope.flow_status_id.as_deref();
+        if self.store.change_flow_status(card_id, from, to, &context.member).await? {
+            Ok(())
+        } else {
+            //校验之后卡片被其他请求流转了
+            Err(ApiError::conflict(&format!("flow status of card {} has changed, please retry", card_id)))
+        }
+    }
+
+    pub(crate) async fn transit(&self, context: &RequestContext, card_id: &CardId, to: &FlowStatus) -> Result<(), ApiError> {
+        let scope = self.scope_of(context, card_id).await?;
+        self.transit_in_scope(context, card_id, &scope, to).await
+    }
+
+    //只知道目标状态时沿用卡片当前的价值流，卡片还没有进入价值流时取卡片类型的价值流
+    pub(crate) async fn transit_to_status(&self, context: &RequestContext, card_id: &CardId, flow_status_id: &str) -> Result<(), ApiError> {
+        let scope = self.scope_of(context, card_id).await?;
+        let flow_id = scope.flow_id.clone()
+            .or_else(|| self.work_flows.list(context.org_id()).into_iter()
+                .find(|it| it.card_type_id() == scope.card_type_id)
+                .map(|it| it.id().to_string()))
+            .ok_or_else(|| ApiError::bad_request(&format!("no flow is defined for card type {}", scope.card_type_id)))?;
+        self.transit_in_scope(context, card_id, &scope, &FlowStatus::new(&flow_id, flow_status_id)).await
+    }
+}
+
+//存储层只返回是否成功，权限检查已经确认了卡片存在，失败的原因由调用方决定
+fn changed(success: bool, err: impl FnOnce() -> ApiError) -> Result<HttpResponse, ApiError> {
+    if success {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(err())
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateCardRequest {
+    name: String,
+    card_type_id: String,
+    flow_status: Option<FlowStatus>,
+    #[serde(default)]
+    fields: Vec<Field>,
+}
+
+//卡片总是创建在当前成员所在的组织中
+#[post("/cards")]
+async fn create_card(context: RequestContext, data: web::Data<CardService>, request: web::Json<CreateCardRequest>) -> Result<HttpResponse, ApiError> {
+    let request = request.into_inner();
+    if request.name.trim().is_empty() {
+        return Err(ApiError::bad_request("card's name is empty"));
+    }
+    if let Some(flow_status) = &request.flow_status {
+        data.ensure_transition(&context, &request.card_type_id, None, flow_status)?;
+    }
+    let card = Card::new(String::new(), request.name, &request.card_type_id, context.org_id(), request.flow_status, request.fields, HashMap::new());
+    match data.store.create(&card, &context.member).await? {
+        Some(code) => Ok(HttpResponse::Created().json(serde_json::json!({ "id": card.id, "code": code }))),
+        None => Err(ApiError::internal("failed to create card")),
+    }
+}
+
+#[derive(Deserialize)]
+struct CodePrefixRequest {
+    prefix: Option<String>, //为空时该卡片类型使用组织的序号
+}
+
+//修改前缀不影响已有卡片的编号
+#[put("/card-types/{card_type_id}/code-prefix")]
+async fn set_code_prefix(context: RequestContext, data: web::Data<CardService>, path: web::Path<String>, request: web::Json<CodePrefixRequest>) -> Result<HttpResponse, ApiError> {
+    let card_type_id = path.into_inner();
+    data.access.ensure(&context.member, Action::ManageSchema, context.org_id(), &card_type_id)?;
+    let prefix = request.into_inner().prefix;
+    if let Some(prefix) = &prefix {
+        code::check_prefix(prefix).map_err(|err| ApiError::bad_request(&err.to_string()))?;
+    }
+    data.ensure_card_type(&context, &card_type_id).await?;
+    code::set_prefix(context.org_id(), &card_type_id, prefix.as_deref()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/card-types/{card_type_id}/computed-fields")]
+async fn list_computed_fields(context: RequestContext, data: web::Data<CardService>, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let card_type_id = path.into_inner();
+    data.access.ensure(&context.member, Action::View, context.org_id(), &card_type_id)?;
+    let fields: Vec<ComputedField> = data.computed.list(context.org_id()).into_iter()
+        .filter(|it| it.card_type_id == card_type_id)
+        .collect();
+    Ok(HttpResponse::Ok().json(fields))
+}
+
+#[derive(Deserialize)]
+struct ComputedFieldRequest {
+    formula: Formula,
+}
+
+//注册或者修改公式后立即重新计算该卡片类型的所有卡片，之后由卡片事件增量计算
+#[put("/card-types/{card_type_id}/computed-fields/{field_id}")]
+async fn set_computed_field(context: RequestContext, data: web::Data<CardService>, path: web::Path<(String, String)>, request: web::Json<ComputedFieldRequest>) -> Result<HttpResponse, ApiError> {
+    let (card_type_id, field_id) = path.into_inner();
+    data.access.ensure(&context.member, Action::ManageSchema, context.org_id(), &card_type_id)?;
+    let field = ComputedField::new(context.org_id(), &card_type_id, FieldId::from(field_id), request.into_inner().formula);
+    data.computed.ensure_valid(&field).map_err(|err| ApiError::bad_request(&err.to_string()))?;
+    SettingStore::save(context.org_id(), COMPUTED_FIELD_SETTING, &field.setting_id(), &field).await?;
+    data.computed.add(field.clone()).map_err(|err| ApiError::bad_request(&err.to_string()))?;
+    let changed = data.computed.recompute_all(&field, context.member_id()).await.map_err(|err| {
+        eprintln!("failed to recompute field {}: {}", field.id, err);
+        ApiError::internal("failed to recompute the computed field")
+    })?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "field": field, "changed": changed })))
+}
+
+//删除后属性上已经计算出的值保留，成为普通属性
+#[delete("/card-types/{card_type_id}/computed-fields/{field_id}")]
+async fn remove_computed_field(context: RequestContext, data: web::Data<CardService>, path: web::Path<(String, String)>) -> Result<HttpResponse, ApiError> {
+    let (card_type_id, field_id) = path.into_inner();
+    data.access.ensure(&context.member, Action::ManageSchema, context.org_id(), &card_type_id)?;
+    let field_id = FieldId::from(field_id);
+    if !data.computed.is_computed(context.org_id(), &card_type_id, &field_id) {
+        return Err(ApiError::not_found(&format!("computed field {} not found", field_id)));
+    }
+    SettingStore::remove(context.org_id(), COMPUTED_FIELD_SETTING, &format!("{card_type_id}/{field_id}")).await?;
+    data.computed.remove(context.org_id(), &card_type_id, &field_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+struct FindCardQuery {
+    #[serde(default)]
+    fields: String, //逗号分隔的属性id
+}
+
+#[get("/cards/{card_id}")]
+async fn find_card(context: RequestContext, data: web::Data<CardService>, path: web::Path<String>, find: web::Query<FindCardQuery>) -> Result<HttpResponse, ApiError> {
+    let card_id = CardId::from(path.into_inner());
+    let fields = find.fields.split(',').filter(|it| !it.is_empty()).map(FieldId::from_str).collect();
+    let query_context = context.query_context(&data.access, HashMap::new());
+    let yields = data.access.yields(&context.member, fields);
+    match query::find(&card_id, query_context, yields).await? {
+        Some(card) => Ok(HttpResponse::Ok().json(card)),
+        None => Err(ApiError::not_found(&format!("card {} not found", card_id))),
+    }
+}
+
+#[derive(Deserialize)]
+struct UpdateCardRequest {
+    fields: Vec<Field>,
+}
+
+#[patch("/cards/{card_id}")]
+async fn update_card(context: RequestContext, data: web::Data<CardService>, path: web::Path<String>, request: web::Json<UpdateCardRequest>) -> Result<HttpResponse, ApiError> {
+    let card_id = CardId::from(path.into_inner());
+    let success = data.store.update_fields(&card_id, &request.fields, &context.member).await?;
+    changed(success, || ApiError::internal("failed to update card"))
+}
+
+#[post("/cards/{card_id}/archive")]
+async fn archive_card(context: RequestContext, data: web::Data<CardService>, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let card_id = CardId::from(path.into_inner());
+    let success = data.store.archive(&card_id, &context.member).await?;
+    changed(success, || ApiError::conflict("only active cards can be archived"))
+}
+
+#[derive(Deserialize)]
+struct AbandonCardRequest {
+    #[serde(default)]
+    reason: String,
+}
+
+#[post("/cards/{card_id}/abandon")]
+async fn abandon_card(context: RequestContext, data: web::Data<CardService>, path: web::Path<String>, request: web::Json<AbandonCardRequest>) -> Result<HttpResponse, ApiError> {
+    let card_id = CardId::from(path.into_inner());
+    let success = data.store.abandon(&card_id, &request.reason, &context.member).await?;
+    changed(success, || ApiError::conflict("only active cards can be abandoned"))
+}
+
+#[post("/cards/{card_id}/restore")]
+async fn restore_card(context: RequestContext, data: web::Data<CardService>, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let card_id = CardId::from(path.into_inner());
+    let success = data.store.restore(&card_id, &context.member).await?;
+    changed(success, || ApiError::conflict("only archived or abandoned cards can be restored"))
+}
+
+#[post("/cards/{card_id}/transitions")]
+async fn transit_card(context: RequestContext, data: web::Data<CardService>, path: web::Path<String>, request: web::Json<FlowStatus>) -> Result<HttpResponse, ApiError> {
+    let card_id = CardId::from(path.into_inner());
+    data.transit(&context, &card_id, &request).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+struct LinkRequest {
+    rs_type: String,
+    dest_id: CardId,
+}
+
+#[post("/cards/{card_id}/links")]
+async fn link_card(context: RequestContext, data: web::Data<CardService>, path: web::Path<String>, request: web::Json<LinkRequest>) -> Result<HttpResponse, ApiError> {
+    let card_id = CardId::from(path.into_inner());
+    let success = data.store.link(&card_id, &request.dest_id, &request.rs_type, &context.member).await?;
+    changed(success, || ApiError::not_found(&format!("card {} not found", request.dest_id)))
+}
+
+#[delete("/cards/{card_id}/links/{rs_type}/{dest_id}")]
+async fn unlink_card(context: RequestContext, data: web::Data<CardService>, path: web::Path<(String, String, String)>) -> Result<HttpResponse, ApiError> {
+    let (card_id, rs_type, dest_id) = path.into_inner();
+    let (card_id, dest_id) = (CardId::from(card_id), CardId::from(dest_id));
+    let success = data.store.unlink(&card_id, &dest_id, &rs_type, &context.member).await?;
+    changed(success, || ApiError::not_found(&format!("link {} from {} to {} not found", rs_type, card_id, dest_id)))
+}
+
+#[derive(Deserialize)]
+struct SearchRequest {
+    #[serde(default)]
+    condition: Condition,
+    #[serde(default)]
+    fields: Vec<FieldId>,
+    #[serde(default)]
+    parameters: HashMap<String, String>, //条件中引用的参考点卡片
+    page: Option<PageRequest>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PageRequest {
+    pub num: u32, //从1开始
+    pub size: Option<u8>,
+}
+
+//未指定分页时返回第一页
+pub(crate) fn page_of(page: &Option<PageRequest>) -> Page {
+    match page {
+        Some(page) => Page::Limit(page.num.max(1), page.size.unwrap_or(DEFAULT_PAGE_SIZE)),
+        None => Page::Limit(1, DEFAULT_PAGE_SIZE),
+    }
+}
+
+impl SearchRequest {
+    fn page(&self) -> Page {
+        page_of(&self.page)
+    }
+}
+
+#[post("/cards/search")]
+async fn search_cards(context: RequestContext, data: web::Data<CardService>, request: web::Json<SearchRequest>) -> Result<HttpResponse, ApiError> {
+    let request = request.into_inner();
+    let page = request.page();
+    let query_context = context.query_context(&data.access, request.parameters);
+    let yields = data.access.yields(&context.member, request.fields);
+    let result = query::query(request.condition, query_context, yields, page).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(search_cards)
+        .service(create_card)
+        .service(set_code_prefix)
+        .service(list_computed_fields)
+        .service(set_computed_field)
+        .service(remove_computed_field)
+        .service(find_card)
+        .service(update_card)
+        .service(archive_card)
+        .service(abandon_card)
+        .service(restore_card)
+        .service(transit_card)
+        .service(link_card)
+        .service(unlink_card);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::newtypes::tenant_id::TenantId;
+    use rbac::access::Member;
+    use schema::work_flows::{FlowStatusDef, Transition};
+
+    fn context() -> RequestContext {
+        RequestContext { member: Member::new(CardId::from_str("m1"), &TenantId::from_str("o1"), vec![]), method: crate::auth::AuthMethod::Jwt }
+    }
+
+    #[test]
+    fn test_ensure_transition() {
+        let statuses = vec![FlowStatusDef { id: String::from("待办"), name: String::from("待办") }, FlowStatusDef { id: String::from("进行中"), name: String::from("进行中") }];
+        let transitions = vec![Transition { from: Some(String::from("待办")), to: String::from("进行中") }];
+        let work_flows = Arc::new(SchemaRegistry::new());
+        work_flows.put(WorkFlow::new(String::from("f1"), String::from("需求流程"), String::from("需求"), TenantId::from_str("o1"), statuses, transitions));
+        let data = CardService::new(Arc::new(AccessControl::default()), work_flows, Arc::new(ComputedFieldRegistry::default()));
+        let context = context();
+        assert!(data.ensure_transition(&context, "需求", Some("待办"), &FlowStatus::new("f1", "进行中")).is_ok());
+        assert_eq!(data.ensure_transition(&context, "需求", Some("进行中"), &FlowStatus::new("f1", "待办")).unwrap_err().to_string(),
+                   "invalid_transition: transition from Some(\"进行中\") to 待办 is not allowed");
+        //价值流不属于该卡片类型
+        assert!(data.ensure_transition(&context, "任务", Some("待办"), &FlowStatus::new("f1", "进行中")).is_err());
+    }
+
+    #[test]
+    fn test_search_request() {
+        let request: SearchRequest = serde_json::from_str(r#"{"page": {"num": 0}}"#).unwrap();
+        assert!(matches!(request.page(), Page::Limit(1, DEFAULT_PAGE_SIZE)));
+        let request: SearchRequest = serde_json::from_str(r#"{"fields": ["估时"], "page": {"num": 3, "size": 50}}"#).unwrap();
+        assert!(matches!(request.page(), Page::Limit(3, 50)));
+    }
+}
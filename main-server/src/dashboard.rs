@@ -0,0 +1,309 @@
+//仪表盘接口：仪表盘的可见范围和维护权限与视图相同
+//渲染时共享参数选中的卡片作为所有部件的参考点，每个部件分别按当前成员的可见范围和属性权限查询，单个部件出错不影响其他部件
+//仪表盘先保存到配置存储再更新注册表
+use crate::auth::RequestContext;
+use crate::error::ApiError;
+use crate::stats::StatsService;
+use crate::view::ViewService;
+use actix_web::{delete, get, post, web, HttpResponse};
+use card::aggregate;
+use card::aggregate::Measure;
+use card::query;
+use card::card::CardState;
+use card::settings::SettingStore;
+use card::query::{CardTypeOperator, Condition, ConditionItem, LinkOperator, LinkValue, QueryContext, Yields};
+use card::types::LinkDescriptor;
+use common::newtypes::card_id::CardId;
+use rbac::access::AccessControl;
+use serde::{Deserialize, Serialize};
+use stats::burndown;
+use stats::burndown::{BurnMeasure, Burndown};
+use stats::flow;
+use stats::flow::{CumulativeFlowPoint, DailyCount, Percentiles, WeeklyCount};
+use stats::period::StatsPeriod;
+use std::sync::Arc;
+use view::dashboard::{DashboardDefinition, DashboardParameter, FlowChart, Widget, WidgetKind};
+use view::definition::{ViewDefinition, ViewOwner, ViewType};
+use view::registry::{DashboardRegistry, DASHBOARD_SETTING};
+use view::render;
+use view::render::{RenderOptions, RenderedView};
+
+pub struct DashboardService {
+    pub access: Arc<AccessControl>,
+    pub dashboards: Arc<DashboardRegistry>,
+}
+
+impl DashboardService {
+    pub fn new(access: Arc<AccessControl>, dashboards: Arc<DashboardRegistry>) -> Self {
+        Self { access, dashboards }
+    }
+
+    //不可见的仪表盘视为不存在
+    fn definition(&self, context: &RequestContext, dashboard_id: u32) -> Result<DashboardDefinition, ApiError> {
+        self.dashboards.get(context.org_id(), dashboard_id)
+            .filter(|it| ViewService::can_view(&context.member, it.owner()))
+            .ok_or_else(|| ApiError::not_found(&format!("dashboard {} not found", dashboard_id)))
+    }
+
+    fn ensure_editable(&self, context: &RequestContext, owner: &ViewOwner) -> Result<(), ApiError> {
+        if ViewService::can_edit(&self.access, &context.member, owner) {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden(&format!("member {} is not allowed to edit {:?} dashboards", context.member_id(), owner)))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SaveDashboardRequest {
+    id: Option<u32>, //为空时新建仪表盘，由服务端分配id
+    name: String,
+    #[serde(default)]
+    description: String,
+    owner: Option<ViewOwner>, //未指定时为个人仪表盘
+    parameter: Option<DashboardParameter>,
+    widgets: Vec<Widget>,
+}
+
+//覆盖已有的仪表盘时，对原仪表盘和新仪表盘都要有维护权限，返回保存后的仪表盘
+#[post("/definitions")]
+async fn save_dashboard(context: RequestContext, data: web::Data<DashboardService>, request: web::Json<SaveDashboardRequest>) -> Result<HttpResponse, ApiError> {
+    let request = request.into_inner();
+    if request.name.trim().is_empty() {
+        return Err(ApiError::bad_request("dashboard's name is empty"));
+    }
+    if let Some(dashboard_id) = request.id {
+        let existing = data.definition(&context, dashboard_id)?;
+        data.ensure_editable(&context, existing.owner())?;
+    }
+    let owner = request.owner.unwrap_or_else(|| ViewOwner::Member(context.member_id().clone()));
+    data.ensure_editable(&context, &owner)?;
+    let dashboard_id = request.id.unwrap_or_else(|| data.dashboards.next_id(context.org_id()));
+    let mut definition = DashboardDefinition::new(dashboard_id, &request.name, &request.description, context.org_id().clone(), request.widgets)
+        .with_owner(owner);
+    if let Some(parameter) = request.parameter {
+        definition = definition.with_parameter(parameter);
+    }
+    SettingStore::save(context.org_id(), DASHBOARD_SETTING, &dashboard_id.to_string(), &definition).await?;
+    data.dashboards.put(definition.clone());
+    Ok(HttpResponse::Ok().json(definition))
+}
+
+#[get("/definitions")]
+async fn list_dashboards(context: RequestContext, data: web::Data<DashboardService>) -> HttpResponse {
+    let dashboards: Vec<DashboardDefinition> = data.dashboards.list(context.org_id()).into_iter()
+        .filter(|it| ViewService::can_view(&context.member, it.owner()))
+        .collect();
+    HttpResponse::Ok().json(dashboards)
+}
+
+#[get("/definitions/{dashboard_id}")]
+async fn find_dashboard(context: RequestContext, data: web::Data<DashboardService>, path: web::Path<u32>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(data.definition(&context, path.into_inner())?))
+}
+
+#[delete("/definitions/{dashboard_id}")]
+async fn remove_dashboard(context: RequestContext, data: web::Data<DashboardService>, path: web::Path<u32>) -> Result<HttpResponse, ApiError> {
+    let definition = data.definition(&context, path.into_inner())?;
+    data.ensure_editable(&context, definition.owner())?;
+    SettingStore::remove(context.org_id(), DASHBOARD_SETTING, &definition.id().to_string()).await?;
+    data.dashboards.remove(context.org_id(), definition.id());
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Serialize)]
+struct RenderedWidget {
+    title: String,
+    content: WidgetContent,
+}
+
+#[derive(Serialize)]
+enum WidgetContent {
+    Counter {
+        count: u64,
+    },
+    View(RenderedView), //透视图表和嵌入的列表视图
+    CumulativeFlow(Vec<CumulativeFlowPoint>),
+    LeadTime(Percentiles),
+    CycleTime(Percentiles),
+    Throughput(Vec<WeeklyCount>),
+    Wip(Vec<DailyCount>),
+    Burndown(Burndown),
+    Error {
+        message: String,
+    },
+}
+
+//渲染一次仪表盘所需的上下文，所有部件共用
+struct WidgetRenderer<'a> {
+    context: &'a RequestContext,
+    views: &'a ViewService,
+    stats: &'a StatsService,
+    query_context: QueryContext,
+    selected: Option<CardId>,
+    period: Option<StatsPeriod>,
+}
+
+impl WidgetRenderer<'_> {
+    async fn render(&self, kind: &WidgetKind) -> Result<WidgetContent, ApiError> {
+        match kind {
+            WidgetKind::SnapshotCounter { card_type_id, flow_status_id, team_id } => {
+                //快照是组织级的统计，成员受可见范围限制时改为按成员的可见范围实时统计
+                if !self.query_context.is_restricted(card_type_id.as_deref()) {
+                    let count = self.stats.snapshots.count(self.context.org_id(), card_type_id.as_deref(), flow_status_id.as_deref(), team_id.as_ref());
+                    return Ok(WidgetContent::Counter { count });
+                }
+                let team_link = self.stats.snapshots.team_link(self.context.org_id());
+                let Some(condition) = snapshot_condition(card_type_id.as_deref(), flow_status_id.as_deref(), team_id.as_ref(), team_link) else {
+                    return Ok(WidgetContent::Counter { count: 0 });
+                };
+                self.count(&condition).await
+            }
+            WidgetKind::Counter(condition) => self.count(condition).await,
+            WidgetKind::Pivot(pivot) => {
+                let definition = ViewDefinition::new(0, "", "", self.context.org_id().clone(), ViewType::PivotView(pivot.clone()));
+                self.render_view(&definition, None).await
+            }
+            WidgetKind::ListView { view_id, page_size } => {
+                let definition = self.views.definition(self.context, *view_id)?;
+                if !matches!(definition.view_type(), ViewType::ListView(_)) {
+                    return Err(ApiError::bad_request(&format!("view {} is not a list", view_id)));
+                }
+                self.render_view(&definition, *page_size).await
+            }
+            WidgetKind::FlowMetrics { flow_id, start_status, done_status, condition, chart } => {
+                let period = self.period()?;
+                let stages = self.stats.stages(self.context, flow_id, start_status, done_status)?;
+                let metrics = flow::flow_metrics(&self.stats.history, &stages, condition.clone(), self.query_context.clone(), period).await?;
+                Ok(match chart {
+                    FlowChart::CumulativeFlow => WidgetContent::CumulativeFlow(metrics.cumulative_flow),
+                    FlowChart::LeadTime => WidgetContent::LeadTime(metrics.lead_time),
+                    FlowChart::CycleTime => WidgetContent::CycleTime(metrics.cycle_time),
+                    FlowChart::Throughput => WidgetContent::Throughput(metrics.throughput),
+                    FlowChart::Wip => WidgetContent::Wip(metrics.wip),
+                })
+            }
+            WidgetKind::Burndown { link, flow_id, done_status, measure } => {
+                let period = self.period()?;
+                let container_id = self.selected.as_ref().ok_or_else(|| ApiError::bad_request("no card is selected for the dashboard"))?;
+                let stages = self.stats.stages(self.context, flow_id, done_status, done_status)?;
+                if query::find(container_id, self.query_context.clone(), Yields::default()).await?.is_none() {
+                    return Err(ApiError::not_found(&format!("card {} not found", container_id)));
+                }
+                let measure = measure.clone().map(BurnMeasure::Field).unwrap_or(BurnMeasure::Count);
+                let result = burndown::burndown(&self.stats.history, &stages, container_id, link, &measure, self.query_context.clone(), period).await?;
+                Ok(WidgetContent::Burndown(result))
+            }
+        }
+    }
+
+    async fn count(&self, condition: &Condition) -> Result<WidgetContent, ApiError> {
+        let yields = self.views.access.yields(&self.context.member, vec![]);
+        let rows = aggregate::aggregate(condition, &[], &[Measure::Count], &self.query_context, &yields).await?;
+        let count = rows.first().and_then(|it| it.values[0]).unwrap_or(0.0);
+        Ok(WidgetContent::Counter { count: count as u64 })
+    }
+
+    async fn render_view(&self, definition: &ViewDefinition, page_size: Option<u8>) -> Result<WidgetContent, ApiError> {
+        let yields = self.views.access.yields(&self.context.member, definition.fields());
+        let options = RenderOptions { page_num: 1, page_size, window: None };
+        Ok(WidgetContent::View(render::render(definition, self.query_context.clone(), yields, options).await?))
+    }
+
+    fn period(&self) -> Result<&StatsPeriod, ApiError> {
+        self.period.as_ref().ok_or_else(|| ApiError::bad_request("period is required by flow metrics and burndown widgets"))
+    }
+}
+
+//与快照计数器等价的查询条件，未设置团队关联时快照中没有团队的计数，返回None
+fn snapshot_condition(card_type_id: Option<&str>, flow_status_id: Option<&str>, team_id: Option<&CardId>, team_link: Option<LinkDescriptor>) -> Option<Condition> {
+    let mut condition = Condition::default();
+    condition.and(ConditionItem::State(vec![CardState::Active]));
+    if let Some(card_type_id) = card_type_id {
+        condition.and(ConditionItem::CardType(CardTypeOperator::AnyIn(vec![String::from(card_type_id)])));
+    }
+    if let Some(flow_status_id) = flow_status_id {
+        condition.and(ConditionItem::Status(vec![String::from(flow_status_id)]));
+    }
+    if let Some(team_id) = team_id {
+        condition.and(ConditionItem::Link(team_link?, LinkOperator::AnyIn(LinkValue::StaticValue(vec![team_id.to_string()]))));
+    }
+    Some(condition)
+}
+
+#[derive(Deserialize)]
+struct RenderDashboardRequest {
+    parameter_card_id: Option<CardId>, //未选择时使用参数的默认卡片
+    period: Option<StatsPeriod>, //流动指标和燃尽图的统计区间
+}
+
+#[post("/{dashboard_id}/render")]
+async fn render_dashboard(context: RequestContext, data: web::Data<DashboardService>, views: web::Data<ViewService>, stats: web::Data<StatsService>,
+                          path: web::Path<u32>, request: web::Json<RenderDashboardRequest>) -> Result<HttpResponse, ApiError> {
+    let definition = data.definition(&context, path.into_inner())?;
+    let request = request.into_inner();
+    if request.period.as_ref().is_some_and(|it| it.start > it.end) {
+        return Err(ApiError::bad_request("period starts after it ends"));
+    }
+    let renderer = WidgetRenderer {
+        context: &context,
+        views: &views,
+        stats: &stats,
+        query_context: context.query_context(&data.access, definition.query_parameters(request.parameter_card_id.clone())),
+        selected: definition.selected_card(request.parameter_card_id),
+        period: request.period,
+    };
+    let mut widgets = Vec::new();
+    for widget in definition.widgets() {
+        let content = renderer.render(&widget.kind).await
+            .unwrap_or_else(|err| WidgetContent::Error { message: err.to_string() });
+        widgets.push(RenderedWidget { title: widget.title.clone(), content });
+    }
+    Ok(HttpResponse::Ok().json(widgets))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(save_dashboard)
+        .service(list_dashboards)
+        .service(find_dashboard)
+        .service(remove_dashboard)
+        .service(render_dashboard);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::newtypes::tenant_id::TenantId;
+    use rbac::access::Member;
+
+    fn member_context(member_id: &str, org_id: &str, team_ids: Vec<&str>) -> RequestContext {
+        let team_ids = team_ids.into_iter().map(CardId::from_str).collect();
+        RequestContext { member: Member::new(CardId::from_str(member_id), &TenantId::from_str(org_id), team_ids), method: crate::auth::AuthMethod::Jwt }
+    }
+
+    #[test]
+    fn test_dashboard_visibility() {
+        let data = DashboardService::new(Arc::new(AccessControl::default()), Arc::new(DashboardRegistry::default()));
+        let widgets = vec![Widget { title: String::from("需求数"), kind: WidgetKind::Counter(Condition::default()) }];
+        data.dashboards.put(DashboardDefinition::new(1, "研发概览", "", TenantId::from_str("o1"), widgets)
+            .with_owner(ViewOwner::Team(CardId::from_str("研发"))));
+        assert!(data.definition(&member_context("m1", "o1", vec!["研发"]), 1).is_ok());
+        assert_eq!(data.definition(&member_context("m2", "o1", vec![]), 1).unwrap_err().to_string(), "not_found: dashboard 1 not found");
+        assert!(data.definition(&member_context("m1", "o2", vec!["研发"]), 1).is_err());
+        //团队成员没有ManageView权限时不能维护团队仪表盘
+        assert!(data.ensure_editable(&member_context("m1", "o1", vec!["研发"]), &ViewOwner::Team(CardId::from_str("研发"))).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_condition() {
+        let team = CardId::from_str("研发");
+        let team_link = LinkDescriptor::Src(String::from("负责团队"));
+        let mut expected = Condition::default();
+        expected.and(ConditionItem::State(vec![CardState::Active]))
+            .and(ConditionItem::CardType(CardTypeOperator::AnyIn(vec![String::from("需求")])))
+            .and(ConditionItem::Link(team_link.clone(), LinkOperator::AnyIn(LinkValue::StaticValue(vec![String::from("研发")]))));
+        assert_eq!(snapshot_condition(Some("需求"), None, Some(&team), Some(team_link)), Some(expected));
+        //没有团队关联时快照中不会有团队的计数
+        assert_eq!(snapshot_condition(None, Some("待办"), Some(&team), None), None);
+    }
+}
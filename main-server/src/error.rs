@@ -0,0 +1,141 @@
+//接口统一的错误响应：{"error": {"code": "...", "message": "..."}}
+use crate::auth::AuthError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use card::query::QueryError;
+use rbac::access::AccessDenied;
+use rbac::secured_store::SecuredStoreError;
+use std::fmt::{Display, Formatter};
+use view::render::RenderError;
+use std::{error, fmt};
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: &str) -> Self {
+        Self { status, code, message: message.to_string() }
+    }
+
+    pub fn bad_request(message: &str) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn unauthorized(message: &str) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    pub fn forbidden(message: &str) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    pub fn not_found(message: &str) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn conflict(message: &str) -> Self {
+        Self::new(StatusCode::CONFLICT, "conflict", message)
+    }
+
+    pub fn internal(message: &str) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", message)
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(serde_json::json!({
+            "error": {
+                "code": self.code,
+                "message": self.message,
+            }
+        }))
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(err: AuthError) -> Self {
+        ApiError::unauthorized(&err.to_string())
+    }
+}
+
+impl From<AccessDenied> for ApiError {
+    fn from(err: AccessDenied) -> Self {
+        ApiError::forbidden(&err.to_string())
+    }
+}
+
+//对成员不可见的卡片和不存在的卡片一样返回404，避免泄露卡片是否存在
+impl From<SecuredStoreError> for ApiError {
+    fn from(err: SecuredStoreError) -> Self {
+        match err {
+            SecuredStoreError::NotFound(_) => ApiError::not_found(&err.to_string()),
+            SecuredStoreError::Denied(err) => err.into(),
+            SecuredStoreError::Invalid(message) => ApiError::bad_request(&message),
+            SecuredStoreError::Store(message) => {
+                eprintln!("store error: {}", message);
+                ApiError::internal("failed to access card store")
+            }
+        }
+    }
+}
+
+//查询条件或者视图参数本身有误时是请求的错误，其他是存储的错误
+impl From<Box<dyn error::Error>> for ApiError {
+    fn from(err: Box<dyn error::Error>) -> Self {
+        if let Some(err) = err.downcast_ref::<QueryError>() {
+            return ApiError::new(StatusCode::BAD_REQUEST, "invalid_condition", &err.to_string());
+        }
+        if let Some(err) = err.downcast_ref::<RenderError>() {
+            return ApiError::bad_request(&err.to_string());
+        }
+        eprintln!("store error: {}", err);
+        ApiError::internal("failed to access card store")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use common::newtypes::card_id::CardId;
+
+    #[actix_web::test]
+    async fn test_error_envelope() {
+        let resp = ApiError::not_found("card c1 not found").error_response();
+        assert_eq!(resp.status(), 404);
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"]["code"], "not_found");
+        assert_eq!(json["error"]["message"], "card c1 not found");
+
+        let err: ApiError = AccessDenied::new("denied").into();
+        assert_eq!(err.status_code(), 403);
+        let err: ApiError = SecuredStoreError::NotFound(CardId::from_str("c1")).into();
+        assert_eq!(err.to_string(), "not_found: card c1 not found");
+        let err: ApiError = SecuredStoreError::Denied(AccessDenied::new("denied")).into();
+        assert_eq!(err.status_code(), 403);
+        let err: ApiError = SecuredStoreError::Invalid(String::from("field org_id is a built-in property")).into();
+        assert_eq!(err.status_code(), 400);
+        let err: ApiError = (Box::new(ApiError::conflict("x")) as Box<dyn error::Error>).into();
+        assert_eq!(err.status_code(), 500);
+        let err: ApiError = (Box::new(RenderError::new("calendar view requires a date window")) as Box<dyn error::Error>).into();
+        assert_eq!(err.status_code(), 400);
+    }
+}
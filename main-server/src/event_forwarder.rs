@@ -0,0 +1,134 @@
+//把卡片事件总线上的事件转发给通知服务，订阅和webhook由通知服务匹配
+//事件按发布顺序逐个转发，通知服务不可用时按指数退避重试，重试耗尽后丢弃该事件并记录
+use card::events;
+use card::events::CardEvent;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+pub const DEFAULT_NOTIFICATION_SERVER: &str = "http://127.0.0.1:8081";
+
+pub struct EventForwarder {
+    client: reqwest::Client,
+    url: String,
+    service_token: String, //通知服务只接受内部服务推送事件
+    max_attempts: u32, //包含第一次尝试
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl EventForwarder {
+    pub fn new(notification_server: &str, service_token: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: format!("{}/events", notification_server.trim_end_matches('/')),
+            service_token: String::from(service_token),
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+
+    //第attempt次尝试失败后的等待时间，attempt从1开始
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    async fn post(&self, event: &CardEvent) -> Result<(), String> {
+        let response = self.client.post(&self.url)
+            .header(reqwest::header::AUTHORIZATION, format!("Service {}", self.service_token))
+            .json(event)
+            .send().await
+            .map_err(|err| err.to_string())?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("{} responded with {}", self.url, response.status()))
+        }
+    }
+
+    pub async fn forward(&self, event: &CardEvent) -> Result<(), String> {
+        let mut attempt = 1;
+        loop {
+            match self.post(event).await {
+                Ok(_) => return Ok(()),
+                Err(err) if attempt >= self.max_attempts => return Err(err),
+                Err(_) => {
+                    tokio::time::sleep(self.delay(attempt)).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    //订阅卡片事件总线
+    pub async fn run(self: Arc<Self>) {
+        let mut receiver = events::subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    if let Err(err) = self.forward(&event).await {
+                        eprintln!("failed to forward card event {} to notification server: {}", event.id, err);
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("event forwarder lagged behind, {skipped} card events skipped");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use card::events::CardEventKind;
+    use common::newtypes::card_id::CardId;
+    use common::newtypes::tenant_id::TenantId;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    //模拟通知服务，按顺序返回给定的状态码，没有服务令牌时返回401，返回收到的请求数
+    async fn mock_server(statuses: Vec<u16>) -> (String, tokio::task::JoinHandle<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut received = 0;
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 8192];
+                let size = stream.read(&mut buf).await.unwrap();
+                let authorized = String::from_utf8_lossy(&buf[..size]).to_lowercase().contains("authorization: service service-token");
+                let status = if authorized { status } else { 401 };
+                let response = format!("HTTP/1.1 {status} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+                received += 1;
+            }
+            received
+        });
+        (url, handle)
+    }
+
+    fn event() -> CardEvent {
+        CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "需求", CardEventKind::Created, &CardId::from_str("m1"))
+    }
+
+    fn fast_forwarder(url: &str) -> EventForwarder {
+        EventForwarder { base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(2), ..EventForwarder::new(url, "service-token") }
+    }
+
+    #[tokio::test]
+    async fn test_forward_with_retry() {
+        let (url, handle) = mock_server(vec![503, 502, 202]).await;
+        let forwarder = EventForwarder { max_attempts: 3, ..fast_forwarder(&url) };
+        assert!(forwarder.forward(&event()).await.is_ok());
+        assert_eq!(handle.await.unwrap(), 3);
+
+        let (url, handle) = mock_server(vec![503, 503]).await;
+        let forwarder = EventForwarder { max_attempts: 2, ..fast_forwarder(&url) };
+        assert!(forwarder.forward(&event()).await.unwrap_err().contains("503"));
+        assert_eq!(handle.await.unwrap(), 2);
+    }
+}
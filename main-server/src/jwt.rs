@@ -0,0 +1,81 @@
+//JWT bearer令牌，由登录服务用共享密钥签发(HS256)，这里只做校验
+use crate::auth::{credentials, AuthError, AuthMethod, Authenticator, Principal};
+use actix_web::HttpRequest;
+use common::newtypes::card_id::CardId;
+use common::newtypes::tenant_id::TenantId;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String, //成员id
+    org: String, //组织id
+    exp: u64, //过期时间，秒
+}
+
+#[derive(Clone)]
+pub struct JwtVerifier {
+    decoding_key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtVerifier {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+            validation: Validation::new(Algorithm::HS256),
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Principal, AuthError> {
+        let data = decode::<Claims>(token, &self.decoding_key, &self.validation)
+            .map_err(|err| AuthError::new(&format!("invalid jwt: {}", err)))?;
+        Ok(Principal {
+            member_id: CardId::from(data.claims.sub),
+            org_id: TenantId::from(data.claims.org),
+        })
+    }
+}
+
+impl Authenticator for JwtVerifier {
+    fn method(&self) -> AuthMethod {
+        AuthMethod::Jwt
+    }
+
+    fn authenticate(&self, req: &HttpRequest) -> Option<Result<Principal, AuthError>> {
+        credentials(req, "Bearer").map(|token| self.verify(token))
+    }
+}
+
+//模拟登录服务签发有效期为ttl秒的令牌
+#[cfg(test)]
+pub(crate) fn issue(secret: &str, principal: &Principal, ttl: u64) -> String {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let claims = Claims {
+        sub: principal.member_id.to_string(),
+        org: principal.org_id.to_string(),
+        exp: now + ttl,
+    };
+    encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    #[test]
+    fn test_verify() {
+        let verifier = JwtVerifier::new("secret");
+        let principal = Principal { member_id: CardId::from_str("m1"), org_id: TenantId::from_str("o1") };
+        assert_eq!(verifier.verify(&issue("secret", &principal, 60)).unwrap(), principal);
+        //签名不匹配
+        assert!(verifier.verify(&issue("other", &principal, 60)).is_err());
+        //已过期，超过默认的60秒容差
+        let claims = Claims { sub: String::from("m1"), org: String::from("o1"), exp: 1 };
+        let expired = encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        assert!(verifier.verify(&expired).is_err());
+    }
+}
@@ -1,20 +1,141 @@
+use crate::admin::AdminService;
+use crate::api_token::ApiTokenRegistry;
+use crate::card::CardService;
+use crate::dashboard::DashboardService;
 use crate::demo::hello;
+use crate::event_forwarder::EventForwarder;
+use crate::jwt::JwtVerifier;
+use crate::rule::RuleService;
+use crate::session::SessionStore;
+use crate::stats::StatsService;
+use crate::view::ViewService;
+use actix_web::middleware::from_fn;
 use actix_web::{web, App, HttpServer};
+use biz_rule::engine::RuleEngine;
+use biz_rule::executor::StoreExecutor;
+use common::newtypes::tenant_id::TenantId;
+use rbac::access::{AccessControl, GRANT_SETTING, ROLE_SETTING};
+use schema::schema::SchemaRegistry;
+use std::sync::Arc;
+use std::time::Duration;
+use ::card::computed::ComputedFieldRegistry;
+use ::card::settings::SettingStore;
+use ::stats::history::CardHistory;
+use ::stats::snapshot::StatsSnapshots;
+use ::view::registry::{DashboardRegistry, ViewRegistry};
+mod admin;
+mod api_token;
+mod auth;
 mod card;
+mod dashboard;
 mod demo;
+mod error;
+mod event_forwarder;
+mod jwt;
+mod rule;
+mod session;
+mod stats;
+mod view;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
-        App::new().service(
-            hello_scope()
-        )
+    //JWT由登录服务签发，双方共享密钥
+    let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET is not set");
+    let jwt = JwtVerifier::new(&jwt_secret);
+    //API令牌的摘要保存在配置存储中，启动时加载
+    let api_tokens = Arc::new(ApiTokenRegistry::default());
+    api_tokens.load().await.map_err(|err| std::io::Error::other(format!("failed to load api tokens: {}", err)))?;
+    let sessions = Arc::new(SessionStore::default());
+    let authentication = web::Data::new(auth::authentication(jwt, api_tokens.clone(), sessions.clone()));
+    //卡片事件推送给通知服务，由它匹配订阅和webhook
+    let notification_server = std::env::var("NOTIFICATION_SERVER").unwrap_or_else(|_| String::from(event_forwarder::DEFAULT_NOTIFICATION_SERVER));
+    let service_token = std::env::var("SERVICE_TOKEN").expect("SERVICE_TOKEN is not set");
+    tokio::spawn(Arc::new(EventForwarder::new(&notification_server, &service_token)).run());
+    //角色授权、可见性和价值流保存在配置存储中，启动时加载
+    let access = Arc::new(AccessControl::default());
+    access.load().await.map_err(|err| std::io::Error::other(format!("failed to load access control: {}", err)))?;
+    bootstrap_admin(&access).await?;
+    let work_flows = Arc::new(SchemaRegistry::new());
+    admin::load_work_flows(&work_flows).await.map_err(|err| std::io::Error::other(format!("failed to load work flows: {}", err)))?;
+    let admin_service = web::Data::new(AdminService::new(access.clone(), work_flows.clone()));
+    //计算属性保存在配置存储中，启动时加载后根据卡片事件增量计算
+    let computed = Arc::new(ComputedFieldRegistry::default());
+    computed.load().await.map_err(|err| std::io::Error::other(format!("failed to load computed fields: {}", err)))?;
+    tokio::spawn(computed.clone().run());
+    let card_service = web::Data::new(CardService::new(access.clone(), work_flows.clone(), computed.clone()));
+    //业务规则保存在配置存储中，启动时加载后开始处理卡片事件
+    let rule_engine = Arc::new(RuleEngine::new(StoreExecutor::new(Some(&notification_server), &service_token, computed)));
+    rule_engine.load().await.map_err(|err| std::io::Error::other(format!("failed to load biz rules: {}", err)))?;
+    tokio::spawn(rule_engine.clone().run());
+    let rule_service = web::Data::new(RuleService::new(access.clone(), rule_engine));
+    //视图和成员的个人调整保存在配置存储中，启动时加载
+    let views = Arc::new(ViewRegistry::default());
+    views.load().await.map_err(|err| std::io::Error::other(format!("failed to load views: {}", err)))?;
+    let view_service = web::Data::new(ViewService::new(access.clone(), views));
+    //流动指标和燃尽图依赖卡片的流转和关联历史，历史保存在图数据库中，每天删除超过保留期限的部分
+    let history = Arc::new(CardHistory::default());
+    tokio::spawn(history.clone().run());
+    tokio::spawn(history.clone().run_retention(Duration::from_secs(24 * 60 * 60)));
+    //计数器快照由事件增量更新，每小时与图数据库校准一次
+    let snapshots = Arc::new(StatsSnapshots::default());
+    tokio::spawn(snapshots.clone().run());
+    tokio::spawn(snapshots.clone().run_reconciliation(Duration::from_secs(60 * 60)));
+    //仪表盘保存在配置存储中，启动时加载
+    let dashboards = Arc::new(DashboardRegistry::default());
+    dashboards.load().await.map_err(|err| std::io::Error::other(format!("failed to load dashboards: {}", err)))?;
+    let dashboard_service = web::Data::new(DashboardService::new(access.clone(), dashboards));
+    let access_data = web::Data::new(access.clone());
+    let stats_service = web::Data::new(StatsService::new(access, work_flows, history, snapshots));
+    HttpServer::new(move || {
+        App::new()
+            .app_data(authentication.clone())
+            .app_data(card_service.clone())
+            .app_data(view_service.clone())
+            .app_data(stats_service.clone())
+            .app_data(dashboard_service.clone())
+            .app_data(rule_service.clone())
+            .app_data(admin_service.clone())
+            .app_data(access_data.clone())
+            .app_data(web::Data::new(api_tokens.clone()))
+            .app_data(web::Data::new(sessions.clone()))
+            .service(hello_scope())
+            //需要认证的接口
+            .service(
+                web::scope("/api")
+                    .wrap(from_fn(auth::authenticate))
+                    .configure(auth::config)
+                    .configure(card::config)
+                    .service(view_scope())
+                    .service(web::scope("/stats").configure(stats::config))
+                    .service(web::scope("/dashboard").configure(dashboard::config))
+                    .configure(rule::config)
+                    .service(web::scope("/admin").configure(admin::config))
+            )
     })
         .bind(("127.0.0.1", 8080))?
         .run()
         .await
 }
 
+//新组织还没有管理员时，BOOTSTRAP_ADMIN指定的成员(格式为 组织id:成员id，多个用逗号分隔)成为管理员
+async fn bootstrap_admin(access: &AccessControl) -> std::io::Result<()> {
+    let Ok(admins) = std::env::var("BOOTSTRAP_ADMIN") else {
+        return Ok(());
+    };
+    for admin in admins.split(',').filter(|it| !it.trim().is_empty()) {
+        let (org_id, member_id) = admin.trim().split_once(':')
+            .ok_or_else(|| std::io::Error::other(format!("invalid BOOTSTRAP_ADMIN {}", admin)))?;
+        if let Some((role, grant)) = access.bootstrap_admin(&TenantId::from_str(org_id), member_id) {
+            let saved = match SettingStore::save(&role.org_id, ROLE_SETTING, &role.id, &role).await {
+                Ok(_) => SettingStore::save(&grant.org_id, GRANT_SETTING, &grant.id, &grant).await,
+                Err(err) => Err(err),
+            };
+            saved.map_err(|err| std::io::Error::other(format!("failed to save bootstrap admin: {}", err)))?;
+        }
+    }
+    Ok(())
+}
+
 fn hello_scope() -> actix_web::Scope {
     web::scope("/hello")
         .service(hello)
@@ -23,4 +144,5 @@ fn hello_scope() -> actix_web::Scope {
 //访问视图  -> 视图调用schema api 、card api 组装视图内容，返回
 fn view_scope() -> actix_web::Scope {
     web::scope("/view")
-}
\ No newline at end of file
+        .configure(view::config)
+}
@@ -0,0 +1,175 @@
+//业务规则接口：规则的维护需要组织范围的ManageSchema权限，先保存到配置存储再更新规则引擎，保存后立即生效
+use crate::auth::RequestContext;
+use crate::error::ApiError;
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use biz_rule::engine::RuleEngine;
+use biz_rule::executor::StoreExecutor;
+use biz_rule::rule::{Action as RuleAction, BizRule, Trigger, RULE_SETTING};
+use card::query::Condition;
+use card::settings::SettingStore;
+use rbac::access::AccessControl;
+use rbac::role::Action;
+use serde::Deserialize;
+use std::sync::Arc;
+
+pub struct RuleService {
+    pub access: Arc<AccessControl>,
+    pub engine: Arc<RuleEngine<StoreExecutor>>,
+}
+
+impl RuleService {
+    pub fn new(access: Arc<AccessControl>, engine: Arc<RuleEngine<StoreExecutor>>) -> Self {
+        Self { access, engine }
+    }
+
+    fn ensure_manageable(&self, context: &RequestContext) -> Result<(), ApiError> {
+        if self.access.check_org(&context.member, Action::ManageSchema) {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden(&format!("member {} is not allowed to manage biz rules", context.member_id())))
+        }
+    }
+
+    fn find(&self, context: &RequestContext, rule_id: &str) -> Result<BizRule, ApiError> {
+        self.engine.rules_of(context.org_id()).into_iter()
+            .find(|it| it.id == rule_id)
+            .ok_or_else(|| ApiError::not_found(&format!("biz rule {} not found", rule_id)))
+    }
+
+    async fn save(&self, rule: BizRule) -> Result<(), ApiError> {
+        SettingStore::save(&rule.org_id, RULE_SETTING, &rule.id, &rule).await?;
+        self.engine.put_rule(rule);
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct SaveRuleRequest {
+    name: String,
+    #[serde(default = "enabled")]
+    enabled: bool,
+    trigger: Trigger,
+    #[serde(default)]
+    condition: Condition,
+    actions: Vec<RuleAction>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl SaveRuleRequest {
+    fn check(&self) -> Result<(), ApiError> {
+        if self.name.trim().is_empty() {
+            return Err(ApiError::bad_request("rule's name is empty"));
+        }
+        if self.actions.is_empty() {
+            return Err(ApiError::bad_request("rule has no actions"));
+        }
+        let invalid_webhook = self.actions.iter().any(|it| matches!(it, RuleAction::CallWebhook(url) if !url.starts_with("http://") && !url.starts_with("https://")));
+        if invalid_webhook {
+            return Err(ApiError::bad_request("webhook url must be http or https"));
+        }
+        let reserved = self.actions.iter().find_map(|it| match it {
+            RuleAction::SetField(field) => field.is_reserved().then_some(field),
+            RuleAction::CreateLinkedCard { fields, .. } => card::card::find_reserved(fields),
+            _ => None,
+        });
+        if let Some(field) = reserved {
+            return Err(ApiError::bad_request(&format!("field {} is a built-in property", field.id)));
+        }
+        Ok(())
+    }
+}
+
+#[get("/rules")]
+async fn list_rules(context: RequestContext, data: web::Data<RuleService>) -> Result<HttpResponse, ApiError> {
+    data.ensure_manageable(&context)?;
+    Ok(HttpResponse::Ok().json(data.engine.rules_of(context.org_id())))
+}
+
+#[post("/rules")]
+async fn create_rule(context: RequestContext, data: web::Data<RuleService>, request: web::Json<SaveRuleRequest>) -> Result<HttpResponse, ApiError> {
+    data.ensure_manageable(&context)?;
+    let request = request.into_inner();
+    request.check()?;
+    let mut rule = BizRule::new(context.org_id(), &request.name, request.trigger, request.condition, request.actions);
+    rule.enabled = request.enabled;
+    data.save(rule.clone()).await?;
+    Ok(HttpResponse::Ok().json(rule))
+}
+
+#[put("/rules/{rule_id}")]
+async fn update_rule(context: RequestContext, data: web::Data<RuleService>, path: web::Path<String>, request: web::Json<SaveRuleRequest>) -> Result<HttpResponse, ApiError> {
+    data.ensure_manageable(&context)?;
+    let mut rule = data.find(&context, &path.into_inner())?;
+    let request = request.into_inner();
+    request.check()?;
+    rule.name = request.name;
+    rule.enabled = request.enabled;
+    rule.trigger = request.trigger;
+    rule.condition = request.condition;
+    rule.actions = request.actions;
+    data.save(rule.clone()).await?;
+    Ok(HttpResponse::Ok().json(rule))
+}
+
+#[delete("/rules/{rule_id}")]
+async fn remove_rule(context: RequestContext, data: web::Data<RuleService>, path: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    data.ensure_manageable(&context)?;
+    let rule = data.find(&context, &path.into_inner())?;
+    SettingStore::remove(context.org_id(), RULE_SETTING, &rule.id).await?;
+    data.engine.remove_rule(context.org_id(), &rule.id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_rules)
+        .service(create_rule)
+        .service(update_rule)
+        .service(remove_rule);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use card::computed::ComputedFieldRegistry;
+    use card::events::CardEventType;
+    use common::newtypes::card_id::CardId;
+    use common::newtypes::tenant_id::TenantId;
+    use rbac::access::Member;
+    use rbac::grant::{Grant, Scope};
+    use rbac::role::Role;
+    use schema::card_types::Grantee;
+
+    fn member_context(member_id: &str, org_id: &str) -> RequestContext {
+        RequestContext { member: Member::new(CardId::from_str(member_id), &TenantId::from_str(org_id), vec![]), method: crate::auth::AuthMethod::Jwt }
+    }
+
+    #[test]
+    fn test_rule_management() {
+        let data = RuleService::new(Arc::new(AccessControl::default()), Arc::new(RuleEngine::new(StoreExecutor::new(None, "service-token", Arc::new(ComputedFieldRegistry::default())))));
+        let org_id = TenantId::from_str("o1");
+        let admin = Role::admin(&org_id);
+        let grant = Grant::new(&org_id, &admin.id, Grantee::Member(String::from("m1")), Scope::Org);
+        data.access.add_role(admin);
+        data.access.grant(grant).unwrap();
+        assert!(data.ensure_manageable(&member_context("m1", "o1")).is_ok());
+        assert_eq!(data.ensure_manageable(&member_context("m2", "o1")).unwrap_err().to_string(), "forbidden: member m2 is not allowed to manage biz rules");
+        assert!(data.ensure_manageable(&member_context("m1", "o2")).is_err());
+
+        let trigger = Trigger { card_type_id: String::from("任务"), event_type: CardEventType::Created };
+        let rule = BizRule::new(&org_id, "通知", trigger.clone(), Condition::default(), vec![]);
+        data.engine.put_rule(rule.clone());
+        assert!(data.find(&member_context("m1", "o1"), &rule.id).is_ok());
+        assert!(data.find(&member_context("m1", "o2"), &rule.id).is_err());
+
+        let request = SaveRuleRequest { name: String::from("通知"), enabled: true, trigger, condition: Condition::default(), actions: vec![] };
+        assert!(request.check().is_err());
+        let request = SaveRuleRequest { actions: vec![RuleAction::CallWebhook(String::from("ftp://localhost"))], ..request };
+        assert!(request.check().is_err());
+        let field = card::card::Field::new(common::newtypes::field_id::FieldId::from_str("state"), card::card::FieldValue::Text(String::from("Archived")));
+        let request = SaveRuleRequest { actions: vec![RuleAction::SetField(field)], ..request };
+        assert_eq!(request.check().unwrap_err().to_string(), "bad_request: field state is a built-in property");
+    }
+}
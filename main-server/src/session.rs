@@ -0,0 +1,130 @@
+//浏览器会话，会话id保存在cookie中，一段时间不活动后过期，持续活动的会话也在创建一段时间后过期
+use crate::auth::{AuthError, AuthMethod, Authenticator, Principal};
+use actix_web::HttpRequest;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+pub const SESSION_COOKIE: &str = "session_id";
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(8 * 60 * 60);
+const DEFAULT_MAX_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+struct Session {
+    principal: Principal,
+    create_time: Instant,
+    last_access: Instant,
+}
+
+pub struct SessionStore {
+    sessions: RwLock<HashMap<String, Session>>,
+    idle_timeout: Duration,
+    max_lifetime: Duration, //不论是否活动，超过后都需要重新用JWT换取会话
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_LIFETIME)
+    }
+}
+
+impl SessionStore {
+    pub fn new(idle_timeout: Duration, max_lifetime: Duration) -> Self {
+        Self {
+            sessions: RwLock::new(HashMap::new()),
+            idle_timeout,
+            max_lifetime,
+        }
+    }
+
+    fn is_valid(&self, session: &Session) -> bool {
+        session.last_access.elapsed() < self.idle_timeout && session.create_time.elapsed() < self.max_lifetime
+    }
+
+    pub fn create(&self, principal: Principal) -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let session_id = hex::encode(bytes);
+        let now = Instant::now();
+        let session = Session { principal, create_time: now, last_access: now };
+        let mut sessions = self.sessions.write().unwrap();
+        //顺便清理过期的会话
+        sessions.retain(|_, it| self.is_valid(it));
+        sessions.insert(session_id.clone(), session);
+        session_id
+    }
+
+    //有效的会话每次访问都会延长不活动的有效期，但不会超过最长有效期
+    pub fn touch(&self, session_id: &str) -> Option<Principal> {
+        let mut sessions = self.sessions.write().unwrap();
+        match sessions.get_mut(session_id) {
+            Some(session) if self.is_valid(session) => {
+                session.last_access = Instant::now();
+                Some(session.principal.clone())
+            }
+            Some(_) => {
+                sessions.remove(session_id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn remove(&self, session_id: &str) -> bool {
+        self.sessions.write().unwrap().remove(session_id).is_some()
+    }
+}
+
+pub struct SessionAuthenticator {
+    sessions: Arc<SessionStore>,
+}
+
+impl SessionAuthenticator {
+    pub fn new(sessions: Arc<SessionStore>) -> Self {
+        Self { sessions }
+    }
+}
+
+impl Authenticator for SessionAuthenticator {
+    fn method(&self) -> AuthMethod {
+        AuthMethod::Session
+    }
+
+    fn authenticate(&self, req: &HttpRequest) -> Option<Result<Principal, AuthError>> {
+        req.cookie(SESSION_COOKIE).map(|cookie| {
+            self.sessions.touch(cookie.value()).ok_or_else(|| AuthError::new("session expired"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::newtypes::card_id::CardId;
+    use common::newtypes::tenant_id::TenantId;
+
+    #[test]
+    fn test_session_expire() {
+        let principal = Principal { member_id: CardId::from_str("m1"), org_id: TenantId::from_str("o1") };
+        let store = SessionStore::new(Duration::from_millis(20), DEFAULT_MAX_LIFETIME);
+        let session_id = store.create(principal.clone());
+        assert_eq!(store.touch(&session_id), Some(principal.clone()));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(store.touch(&session_id), None);
+
+        //持续访问的会话到达最长有效期后同样过期
+        let store = SessionStore::new(Duration::from_secs(60), Duration::from_millis(50));
+        let session_id = store.create(principal.clone());
+        for _ in 0..3 {
+            std::thread::sleep(Duration::from_millis(10));
+            assert_eq!(store.touch(&session_id), Some(principal.clone()));
+        }
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(store.touch(&session_id), None);
+
+        let store = SessionStore::default();
+        let session_id = store.create(principal);
+        assert!(store.remove(&session_id));
+        assert_eq!(store.touch(&session_id), None);
+    }
+}
@@ -0,0 +1,160 @@
+//统计接口：流动指标按价值流的状态顺序计算，参与统计的卡片受成员的可见范围约束
+//计数器读取预先聚合的组织级快照，不扫描图数据库
+use crate::auth::RequestContext;
+use crate::error::ApiError;
+use actix_web::{get, post, put, web, HttpResponse};
+use card::query;
+use card::query::{Condition, Yields};
+use card::types::LinkDescriptor;
+use common::newtypes::card_id::CardId;
+use rbac::access::AccessControl;
+use rbac::role::Action;
+use schema::schema::SchemaRegistry;
+use schema::work_flows::WorkFlow;
+use serde::Deserialize;
+use stats::burndown;
+use stats::burndown::BurnMeasure;
+use stats::flow;
+use stats::flow::FlowStages;
+use stats::history::CardHistory;
+use stats::period::StatsPeriod;
+use stats::snapshot::StatsSnapshots;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub struct StatsService {
+    pub access: Arc<AccessControl>,
+    pub work_flows: Arc<SchemaRegistry<WorkFlow>>,
+    pub history: Arc<CardHistory>,
+    pub snapshots: Arc<StatsSnapshots>,
+}
+
+impl StatsService {
+    pub fn new(access: Arc<AccessControl>, work_flows: Arc<SchemaRegistry<WorkFlow>>, history: Arc<CardHistory>, snapshots: Arc<StatsSnapshots>) -> Self {
+        Self { access, work_flows, history, snapshots }
+    }
+
+    //阶段按价值流中状态的定义顺序划分
+    pub(crate) fn stages(&self, context: &RequestContext, flow_id: &str, start_status: &str, done_status: &str) -> Result<FlowStages, ApiError> {
+        let work_flow = self.work_flows.get(context.org_id(), flow_id)
+            .ok_or_else(|| ApiError::not_found(&format!("work flow {} not found", flow_id)))?;
+        let statuses = work_flow.statuses().iter().map(|it| it.id.clone()).collect();
+        FlowStages::new(flow_id, statuses, start_status, done_status)
+            .map_err(|err| ApiError::bad_request(&err.to_string()))
+    }
+}
+
+#[derive(Deserialize)]
+struct FlowMetricsRequest {
+    flow_id: String,
+    start_status: String, //进入该状态及之后的状态视为开始
+    done_status: String, //进入该状态及之后的状态视为完成
+    #[serde(default)]
+    condition: Condition,
+    #[serde(default)]
+    parameters: HashMap<String, String>,
+    period: StatsPeriod,
+}
+
+#[post("/flow-metrics")]
+async fn flow_metrics(context: RequestContext, data: web::Data<StatsService>, request: web::Json<FlowMetricsRequest>) -> Result<HttpResponse, ApiError> {
+    let request = request.into_inner();
+    if request.period.start > request.period.end {
+        return Err(ApiError::bad_request("period starts after it ends"));
+    }
+    let stages = data.stages(&context, &request.flow_id, &request.start_status, &request.done_status)?;
+    let query_context = context.query_context(&data.access, request.parameters);
+    let metrics = flow::flow_metrics(&data.history, &stages, request.condition, query_context, &request.period).await?;
+    Ok(HttpResponse::Ok().json(metrics))
+}
+
+#[derive(Deserialize)]
+struct BurndownRequest {
+    container_id: CardId, //版本、迭代等容器卡片
+    link: LinkDescriptor, //从容器卡片看到的关联
+    flow_id: String,
+    done_status: String,
+    measure: BurnMeasure,
+    period: StatsPeriod,
+}
+
+#[post("/burndown")]
+async fn burndown_of(context: RequestContext, data: web::Data<StatsService>, request: web::Json<BurndownRequest>) -> Result<HttpResponse, ApiError> {
+    let request = request.into_inner();
+    if request.period.start > request.period.end {
+        return Err(ApiError::bad_request("period starts after it ends"));
+    }
+    //只统计已完成的量，不区分开始
+    let stages = data.stages(&context, &request.flow_id, &request.done_status, &request.done_status)?;
+    let query_context = context.query_context(&data.access, HashMap::new());
+    if query::find(&request.container_id, query_context.clone(), Yields::default()).await?.is_none() {
+        return Err(ApiError::not_found(&format!("card {} not found", request.container_id)));
+    }
+    let result = burndown::burndown(&data.history, &stages, &request.container_id, &request.link, &request.measure, query_context, &request.period).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[derive(Deserialize)]
+struct CounterRequest {
+    card_type_id: Option<String>,
+    flow_status_id: Option<String>,
+    team_id: Option<CardId>,
+}
+
+#[get("/counters")]
+async fn count_cards(context: RequestContext, data: web::Data<StatsService>, request: web::Query<CounterRequest>) -> HttpResponse {
+    let count = data.snapshots.count(context.org_id(), request.card_type_id.as_deref(), request.flow_status_id.as_deref(), request.team_id.as_ref());
+    HttpResponse::Ok().json(serde_json::json!({
+        "count": count,
+        "reconciled_at": data.snapshots.reconciled_at(context.org_id()),
+    }))
+}
+
+#[derive(Deserialize)]
+struct TeamLinkRequest {
+    link: Option<LinkDescriptor>, //为空时不按团队统计
+}
+
+//修改后立即校准，使团队计数器与新的关联一致
+#[put("/team-link")]
+async fn set_team_link(context: RequestContext, data: web::Data<StatsService>, request: web::Json<TeamLinkRequest>) -> Result<HttpResponse, ApiError> {
+    if !data.access.check_org(&context.member, Action::ManageSchema) {
+        return Err(ApiError::forbidden(&format!("member {} is not allowed to change stats settings", context.member_id())));
+    }
+    data.snapshots.set_team_link(context.org_id(), request.into_inner().link);
+    data.snapshots.reconcile(context.org_id()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(flow_metrics)
+        .service(burndown_of)
+        .service(count_cards)
+        .service(set_team_link);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::newtypes::card_id::CardId;
+    use common::newtypes::tenant_id::TenantId;
+    use rbac::access::Member;
+    use schema::work_flows::FlowStatusDef;
+
+    fn context(org_id: &str) -> RequestContext {
+        RequestContext { member: Member::new(CardId::from_str("m1"), &TenantId::from_str(org_id), vec![]), method: crate::auth::AuthMethod::Jwt }
+    }
+
+    #[test]
+    fn test_stages() {
+        let statuses = ["待办", "进行中", "已完成"].iter().map(|it| FlowStatusDef { id: String::from(*it), name: String::from(*it) }).collect();
+        let work_flows = Arc::new(SchemaRegistry::new());
+        work_flows.put(WorkFlow::new(String::from("f1"), String::from("需求流程"), String::from("需求"), TenantId::from_str("o1"), statuses, vec![]));
+        let data = StatsService::new(Arc::new(AccessControl::default()), work_flows, Arc::new(CardHistory::default()), Arc::new(StatsSnapshots::default()));
+        let stages = data.stages(&context("o1"), "f1", "进行中", "已完成").unwrap();
+        assert_eq!(stages.statuses(), &[String::from("待办"), String::from("进行中"), String::from("已完成")]);
+        assert_eq!(data.stages(&context("o1"), "f1", "已完成", "进行中").unwrap_err().to_string(), "bad_request: start status is after done status");
+        //其他组织的价值流不可见
+        assert_eq!(data.stages(&context("o2"), "f1", "进行中", "已完成").unwrap_err().to_string(), "not_found: work flow f1 not found");
+    }
+}
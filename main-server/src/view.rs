@@ -0,0 +1,409 @@
+//视图接口：保存视图定义，按视图定义查询卡片并渲染成列表、看板、树、时间线或者日历
+//视图查询和卡片搜索一样受成员的可见范围和属性权限约束，看板和时间线上调整卡片和直接修改卡片一样经过权限和流转校验
+//个人视图只有本人可见，团队视图该团队以及下级团队的成员可见，共享视图上成员可以有自己的调整
+//视图和个人调整先保存到配置存储再更新注册表
+use crate::auth::RequestContext;
+use crate::card::{CardService, PageRequest};
+use crate::error::ApiError;
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use card::card::{Field, FieldValue};
+use card::query;
+use card::query::Condition;
+use card::settings::SettingStore;
+use card::types::LinkDescriptor;
+use common::newtypes::card_id::CardId;
+use common::newtypes::field_id::FieldId;
+use common::newtypes::timestamp::Timestamp;
+use rbac::access::{AccessControl, Member};
+use rbac::role::Action;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use view::calendar::CalendarWindow;
+use view::definition::{GroupBy, ViewDefinition, ViewOverride, ViewOwner, ViewType};
+use view::registry::{ViewOverrideSetting, ViewRegistry, VIEW_OVERRIDE_SETTING, VIEW_SETTING};
+use view::render;
+use view::render::RenderOptions;
+
+pub struct ViewService {
+    pub access: Arc<AccessControl>,
+    pub views: Arc<ViewRegistry>,
+}
+
+impl ViewService {
+    pub fn new(access: Arc<AccessControl>, views: Arc<ViewRegistry>) -> Self {
+        Self { access, views }
+    }
+
+    pub(crate) fn can_view(member: &Member, owner: &ViewOwner) -> bool {
+        match owner {
+            ViewOwner::Org => true,
+            ViewOwner::Team(team_id) => member.team_ids.contains(team_id),
+            ViewOwner::Member(member_id) => member.id == *member_id,
+        }
+    }
+
+    //个人视图由本人维护，共享视图需要ManageView权限，团队视图还要求是团队的成员
+    pub(crate) fn can_edit(access: &AccessControl, member: &Member, owner: &ViewOwner) -> bool {
+        match owner {
+            ViewOwner::Org => access.check_org(member, Action::ManageView),
+            ViewOwner::Team(team_id) => member.team_ids.contains(team_id) && access.check_org(member, Action::ManageView),
+            ViewOwner::Member(member_id) => member.id == *member_id,
+        }
+    }
+
+    //只能访问当前成员所在组织中对他可见的视图，不可见的视图视为不存在
+    fn shared_definition(&self, context: &RequestContext, view_id: u32) -> Result<ViewDefinition, ApiError> {
+        self.views.get(context.org_id(), view_id)
+            .filter(|it| Self::can_view(&context.member, it.owner()))
+            .ok_or_else(|| ApiError::not_found(&format!("view {} not found", view_id)))
+    }
+
+    //叠加了当前成员个人调整的视图
+    pub(crate) fn definition(&self, context: &RequestContext, view_id: u32) -> Result<ViewDefinition, ApiError> {
+        let definition = self.shared_definition(context, view_id)?;
+        Ok(match self.views.get_override(context.org_id(), view_id, context.member_id()) {
+            Some(view_override) => definition.with_override(&view_override),
+            None => definition,
+        })
+    }
+
+    //看板和时间线上只能调整视图中的卡片，视图条件中引用的参考点卡片由parameters给出
+    async fn ensure_in_view(&self, context: &RequestContext, card_id: &CardId, condition: &Condition, parameters: HashMap<String, String>) -> Result<(), ApiError> {
+        let query_context = context.query_context(&self.access, parameters);
+        if query::matches(card_id, condition, &query_context).await? {
+            Ok(())
+        } else {
+            Err(ApiError::not_found(&format!("card {} is not in the view", card_id)))
+        }
+    }
+
+    fn ensure_editable(&self, context: &RequestContext, owner: &ViewOwner) -> Result<(), ApiError> {
+        if Self::can_edit(&self.access, &context.member, owner) {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden(&format!("member {} is not allowed to edit {:?} views", context.member_id(), owner)))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SaveViewRequest {
+    id: Option<u32>, //为空时新建视图，由服务端分配id
+    name: String,
+    #[serde(default)]
+    description: String,
+    owner: Option<ViewOwner>, //未指定时为个人视图
+    view_type: ViewType,
+}
+
+//覆盖已有的视图时，对原视图和新视图都要有维护权限，返回保存后的视图
+#[post("/definitions")]
+async fn save_view(context: RequestContext, data: web::Data<ViewService>, request: web::Json<SaveViewRequest>) -> Result<HttpResponse, ApiError> {
+    let request = request.into_inner();
+    if request.name.trim().is_empty() {
+        return Err(ApiError::bad_request("view's name is empty"));
+    }
+    if let Some(view_id) = request.id {
+        let existing = data.shared_definition(&context, view_id)?;
+        data.ensure_editable(&context, existing.owner())?;
+    }
+    let owner = request.owner.unwrap_or_else(|| ViewOwner::Member(context.member_id().clone()));
+    data.ensure_editable(&context, &owner)?;
+    let view_id = request.id.unwrap_or_else(|| data.views.next_id(context.org_id()));
+    let definition = ViewDefinition::new(view_id, &request.name, &request.description, context.org_id().clone(), request.view_type)
+        .with_owner(owner);
+    SettingStore::save(context.org_id(), VIEW_SETTING, &view_id.to_string(), &definition).await?;
+    data.views.put(definition.clone());
+    Ok(HttpResponse::Ok().json(definition))
+}
+
+#[get("/definitions")]
+async fn list_views(context: RequestContext, data: web::Data<ViewService>) -> HttpResponse {
+    let views: Vec<ViewDefinition> = data.views.list(context.org_id()).into_iter()
+        .filter(|it| ViewService::can_view(&context.member, it.owner()))
+        .collect();
+    HttpResponse::Ok().json(views)
+}
+
+#[get("/definitions/{view_id}")]
+async fn find_view(context: RequestContext, data: web::Data<ViewService>, path: web::Path<u32>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(data.definition(&context, path.into_inner())?))
+}
+
+#[delete("/definitions/{view_id}")]
+async fn remove_view(context: RequestContext, data: web::Data<ViewService>, path: web::Path<u32>) -> Result<HttpResponse, ApiError> {
+    let definition = data.shared_definition(&context, path.into_inner())?;
+    data.ensure_editable(&context, definition.owner())?;
+    SettingStore::remove(context.org_id(), VIEW_SETTING, &definition.id().to_string()).await?;
+    for member_id in data.views.override_members(context.org_id(), definition.id()) {
+        SettingStore::remove(context.org_id(), VIEW_OVERRIDE_SETTING, &ViewOverrideSetting::setting_id(definition.id(), &member_id)).await?;
+    }
+    data.views.remove(context.org_id(), definition.id());
+    Ok(HttpResponse::NoContent().finish())
+}
+
+//成员在可见的视图上保存自己的调整，不需要视图的维护权限
+#[put("/{view_id}/override")]
+async fn save_override(context: RequestContext, data: web::Data<ViewService>, path: web::Path<u32>, request: web::Json<ViewOverride>) -> Result<HttpResponse, ApiError> {
+    let definition = data.shared_definition(&context, path.into_inner())?;
+    let setting = ViewOverrideSetting {
+        org_id: context.org_id().clone(),
+        view_id: definition.id(),
+        member_id: context.member_id().clone(),
+        view_override: request.into_inner(),
+    };
+    SettingStore::save(context.org_id(), VIEW_OVERRIDE_SETTING, &ViewOverrideSetting::setting_id(setting.view_id, &setting.member_id), &setting).await?;
+    data.views.put_override(&setting.org_id, setting.view_id, &setting.member_id, setting.view_override);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/{view_id}/override")]
+async fn find_override(context: RequestContext, data: web::Data<ViewService>, path: web::Path<u32>) -> Result<HttpResponse, ApiError> {
+    let definition = data.shared_definition(&context, path.into_inner())?;
+    let view_override = data.views.get_override(context.org_id(), definition.id(), context.member_id()).unwrap_or_default();
+    Ok(HttpResponse::Ok().json(view_override))
+}
+
+//恢复为共享视图本身的样子
+#[delete("/{view_id}/override")]
+async fn remove_override(context: RequestContext, data: web::Data<ViewService>, path: web::Path<u32>) -> Result<HttpResponse, ApiError> {
+    let definition = data.shared_definition(&context, path.into_inner())?;
+    SettingStore::remove(context.org_id(), VIEW_OVERRIDE_SETTING, &ViewOverrideSetting::setting_id(definition.id(), context.member_id())).await?;
+    data.views.remove_override(context.org_id(), definition.id(), context.member_id());
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+struct RenderRequest {
+    #[serde(default)]
+    parameters: HashMap<String, String>, //视图条件中引用的参考点卡片
+    page: Option<PageRequest>,
+    window: Option<CalendarWindow>, //日历视图当前可见的日期窗口
+}
+
+#[post("/{view_id}/render")]
+async fn render_view(context: RequestContext, data: web::Data<ViewService>, path: web::Path<u32>, request: web::Json<RenderRequest>) -> Result<HttpResponse, ApiError> {
+    let definition = data.definition(&context, path.into_inner())?;
+    let request = request.into_inner();
+    let query_context = context.query_context(&data.access, request.parameters);
+    let yields = data.access.yields(&context.member, definition.fields());
+    let (page_num, page_size) = request.page.map(|it| (it.num, it.size)).unwrap_or((1, None));
+    let options = RenderOptions { page_num, page_size, window: request.window };
+    let rendered = render::render(&definition, query_context, yields, options).await?;
+    Ok(HttpResponse::Ok().json(rendered))
+}
+
+#[derive(Deserialize)]
+struct ChildrenRequest {
+    parent_id: CardId,
+    depth: usize, //展开的卡片所在的层，根卡片为0
+    #[serde(default)]
+    parameters: HashMap<String, String>,
+}
+
+//树视图逐层展开，每次只加载一张卡片的下一层
+#[post("/{view_id}/tree/children")]
+async fn tree_children(context: RequestContext, data: web::Data<ViewService>, path: web::Path<u32>, request: web::Json<ChildrenRequest>) -> Result<HttpResponse, ApiError> {
+    let definition = data.definition(&context, path.into_inner())?;
+    let ViewType::TreeView(tree) = definition.view_type() else {
+        return Err(ApiError::bad_request(&format!("view {} is not a tree", definition.id())));
+    };
+    let request = request.into_inner();
+    if request.depth >= tree.levels().len() {
+        return Err(ApiError::bad_request(&format!("view {} has no level below {}", definition.id(), request.depth)));
+    }
+    let query_context = context.query_context(&data.access, request.parameters);
+    let yields = data.access.yields(&context.member, definition.fields());
+    let nodes = render::children(tree, &request.parent_id, request.depth, query_context, yields).await?;
+    Ok(HttpResponse::Ok().json(nodes))
+}
+
+#[derive(Deserialize)]
+struct RescheduleRequest {
+    card_id: CardId,
+    start: i64,
+    end: i64,
+    #[serde(default)]
+    parameters: HashMap<String, String>,
+}
+
+//在时间线上拖动横条，新的开始、结束时间写回卡片的日期属性
+#[post("/{view_id}/timeline/reschedule")]
+async fn reschedule_card(context: RequestContext, data: web::Data<ViewService>, cards: web::Data<CardService>, path: web::Path<u32>, request: web::Json<RescheduleRequest>) -> Result<HttpResponse, ApiError> {
+    let definition = data.definition(&context, path.into_inner())?;
+    let ViewType::TimelineView(timeline) = definition.view_type() else {
+        return Err(ApiError::bad_request(&format!("view {} is not a timeline", definition.id())));
+    };
+    let request = request.into_inner();
+    if request.start > request.end {
+        return Err(ApiError::bad_request("start is later than end"));
+    }
+    data.ensure_in_view(&context, &request.card_id, timeline.condition(), request.parameters).await?;
+    let fields = [
+        Field::new(timeline.start_field().clone(), FieldValue::Date(Timestamp::from(request.start))),
+        Field::new(timeline.end_field().clone(), FieldValue::Date(Timestamp::from(request.end))),
+    ];
+    if cards.store.update_fields(&request.card_id, &fields, &context.member).await? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ApiError::internal("failed to update card"))
+    }
+}
+
+#[derive(Deserialize)]
+struct MoveRequest {
+    card_id: CardId,
+    column: String,
+    swimlane: Option<String>, //为空时泳道维度不变
+    order: Option<i32>, //按自定义属性排序的看板上卡片在列内的新位置，为空时不变
+    #[serde(default)]
+    parameters: HashMap<String, String>,
+}
+
+//把卡片拖到看板的某一列（以及某一泳道），按分组维度转换为流转、修改枚举属性或者调整关联
+#[post("/{view_id}/board/moves")]
+async fn move_card(context: RequestContext, data: web::Data<ViewService>, cards: web::Data<CardService>, path: web::Path<u32>, request: web::Json<MoveRequest>) -> Result<HttpResponse, ApiError> {
+    let definition = data.definition(&context, path.into_inner())?;
+    let ViewType::BoardView(board) = definition.view_type() else {
+        return Err(ApiError::bad_request(&format!("view {} is not a board", definition.id())));
+    };
+    let request = request.into_inner();
+    let moves = board.moves(&request.column, request.swimlane.as_deref())
+        .ok_or_else(|| ApiError::bad_request("column or swimlane is not on the board"))?;
+    data.ensure_in_view(&context, &request.card_id, board.condition(), request.parameters).await?;
+    //枚举和排序属性的修改合并为一次修改，流转和关联单独进行
+    let mut fields = Vec::new();
+    for board_move in &moves {
+        match board_move.group_by {
+            GroupBy::Status => cards.transit_to_status(&context, &request.card_id, board_move.key).await?,
+            GroupBy::Enum(field_id) => {
+                let current = enum_options(&context, &data, &request.card_id, field_id).await?;
+                fields.push(Field::new(field_id.clone(), FieldValue::Enum(board_move.options(&current))));
+            }
+            GroupBy::Link(descriptor) => relink(&context, &data, &cards, &request.card_id, descriptor, &CardId::from_str(board_move.key)).await?,
+        }
+    }
+    if let (Some(field_id), Some(order)) = (board.order_field(), request.order) {
+        fields.push(Field::new(field_id.clone(), FieldValue::Int(order)));
+    }
+    if !fields.is_empty() && !cards.store.update_fields(&request.card_id, &fields, &context.member).await? {
+        return Err(ApiError::internal("failed to update card"));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+//卡片在枚举属性上当前的选项，属性不可读时视为没有选项
+async fn enum_options(context: &RequestContext, data: &ViewService, card_id: &CardId, field_id: &FieldId) -> Result<Vec<String>, ApiError> {
+    let query_context = context.query_context(&data.access, HashMap::new());
+    let yields = data.access.yields(&context.member, vec![field_id.clone()]);
+    let card = query::find(card_id, query_context, yields).await?
+        .ok_or_else(|| ApiError::not_found(&format!("card {} not found", card_id)))?;
+    Ok(card.fields.into_iter()
+        .filter(|it| it.id == *field_id)
+        .flat_map(|it| match it.value {
+            FieldValue::Enum(options) => options,
+            _ => vec![],
+        })
+        .collect())
+}
+
+//按关联卡片分组时一张卡片只归属一个分组，移动时解除与原分组卡片的关联
+async fn relink(context: &RequestContext, data: &ViewService, cards: &CardService, card_id: &CardId, descriptor: &LinkDescriptor, target: &CardId) -> Result<(), ApiError> {
+    let query_context = context.query_context(&data.access, HashMap::new());
+    let linked = query::linked_ids(std::slice::from_ref(card_id), descriptor, &query_context).await?
+        .remove(card_id)
+        .unwrap_or_default();
+    //关联的方向决定了哪一端是关联关系的起点
+    let ends = |other: &CardId| match descriptor {
+        LinkDescriptor::Src(_) => (card_id.clone(), other.clone()),
+        LinkDescriptor::Dest(_) => (other.clone(), card_id.clone()),
+    };
+    let rs_type = match descriptor {
+        LinkDescriptor::Src(rs_type) | LinkDescriptor::Dest(rs_type) => rs_type,
+    };
+    for other in linked.iter().filter(|it| *it != target) {
+        let (src, dest) = ends(other);
+        cards.store.unlink(&src, &dest, rs_type, &context.member).await?;
+    }
+    if !linked.contains(target) {
+        let (src, dest) = ends(target);
+        if !cards.store.link(&src, &dest, rs_type, &context.member).await? {
+            return Err(ApiError::not_found(&format!("card {} not found", target)));
+        }
+    }
+    Ok(())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(save_view)
+        .service(list_views)
+        .service(find_view)
+        .service(remove_view)
+        .service(save_override)
+        .service(find_override)
+        .service(remove_override)
+        .service(render_view)
+        .service(tree_children)
+        .service(reschedule_card)
+        .service(move_card);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use card::query::Condition;
+    use common::newtypes::card_id::CardId;
+    use common::newtypes::tenant_id::TenantId;
+    use rbac::grant::{Grant, Scope};
+    use rbac::role::Role;
+    use schema::card_types::Grantee;
+    use view::definition::ListViewDefinition;
+
+    fn context(org_id: &str) -> RequestContext {
+        member_context("m1", org_id, vec![])
+    }
+
+    fn member_context(member_id: &str, org_id: &str, team_ids: Vec<&str>) -> RequestContext {
+        let team_ids = team_ids.into_iter().map(CardId::from_str).collect();
+        RequestContext { member: Member::new(CardId::from_str(member_id), &TenantId::from_str(org_id), team_ids), method: crate::auth::AuthMethod::Jwt }
+    }
+
+    #[test]
+    fn test_definition_is_tenant_scoped() {
+        let data = ViewService::new(Arc::new(AccessControl::default()), Arc::new(ViewRegistry::default()));
+        let view_type = ViewType::ListView(ListViewDefinition::new(vec![], Condition::default(), None, 20));
+        data.views.put(ViewDefinition::new(1, "我的需求", "", TenantId::from_str("o1"), view_type));
+        assert_eq!(data.definition(&context("o1"), 1).unwrap().name(), "我的需求");
+        assert_eq!(data.definition(&context("o2"), 1).unwrap_err().to_string(), "not_found: view 1 not found");
+    }
+
+    #[test]
+    fn test_view_sharing() {
+        let access = Arc::new(AccessControl::default());
+        let admin = Role::admin(&TenantId::from_str("o1"));
+        access.add_role(admin.clone());
+        access.grant(Grant::new(&TenantId::from_str("o1"), &admin.id, Grantee::Member(String::from("m3")), Scope::Org)).unwrap();
+        let data = ViewService::new(access, Arc::new(ViewRegistry::default()));
+        let view_type = || ViewType::ListView(ListViewDefinition::new(vec![], Condition::default(), None, 20));
+        data.views.put(ViewDefinition::new(1, "我的需求", "", TenantId::from_str("o1"), view_type()).with_owner(ViewOwner::Member(CardId::from_str("m1"))));
+        data.views.put(ViewDefinition::new(2, "研发看板", "", TenantId::from_str("o1"), view_type()).with_owner(ViewOwner::Team(CardId::from_str("研发"))));
+        let (m1, m2, m3) = (member_context("m1", "o1", vec![]), member_context("m2", "o1", vec!["研发"]), member_context("m3", "o1", vec!["研发"]));
+        //个人视图对其他成员不存在
+        assert!(data.definition(&m1, 1).is_ok());
+        assert!(data.definition(&m2, 1).is_err());
+        //团队视图团队成员可见，但只有拥有ManageView权限的成员可以维护
+        assert!(data.definition(&m1, 2).is_err());
+        assert!(data.definition(&m2, 2).is_ok());
+        assert!(data.ensure_editable(&m2, &ViewOwner::Team(CardId::from_str("研发"))).is_err());
+        assert!(data.ensure_editable(&m3, &ViewOwner::Team(CardId::from_str("研发"))).is_ok());
+        assert!(data.ensure_editable(&m3, &ViewOwner::Org).is_ok());
+        assert!(data.ensure_editable(&m1, &ViewOwner::Member(CardId::from_str("m1"))).is_ok());
+        //成员的调整只影响自己
+        data.views.put_override(&TenantId::from_str("o1"), 2, m2.member_id(), ViewOverride { page_size: Some(50), ..ViewOverride::default() });
+        let ViewType::ListView(list) = data.definition(&m2, 2).unwrap().view_type().clone() else { panic!("not a list view") };
+        assert_eq!(list.page_size(), 50);
+        let ViewType::ListView(list) = data.definition(&m3, 2).unwrap().view_type().clone() else { panic!("not a list view") };
+        assert_eq!(list.page_size(), 20);
+    }
+}
@@ -0,0 +1,32 @@
+//成员的权限和可见性在主服务中维护，通知服务定期从配置存储整体重新加载，加载失败时沿用上一次的结果
+use rbac::access::AccessControl;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct SharedAccess {
+    access: RwLock<Arc<AccessControl>>,
+}
+
+impl SharedAccess {
+    pub fn new(access: AccessControl) -> Self {
+        Self { access: RwLock::new(Arc::new(access)) }
+    }
+
+    pub fn get(&self) -> Arc<AccessControl> {
+        self.access.read().unwrap().clone()
+    }
+
+    pub async fn run_refresh(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let access = AccessControl::default();
+            match access.load().await {
+                Ok(()) => *self.access.write().unwrap() = Arc::new(access),
+                Err(err) => eprintln!("failed to refresh access control: {}", err),
+            }
+        }
+    }
+}
@@ -0,0 +1,444 @@
+use crate::access::SharedAccess;
+use crate::auth::{MemberContext, ServiceContext};
+use crate::channel::Channel;
+use crate::notification::{Inbox, Notification};
+use crate::preference::{DigestFrequency, NotificationPreference, PreferenceRegistry};
+use crate::subscription::{Subscription, SubscriptionRegistry, SubscriptionTarget};
+use crate::webhook::{Webhook, WebhookService, WebhookSetting, DEAD_LETTER_SETTING, WEBHOOK_SETTING};
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use card::events::{CardEvent, CardEventType};
+use card::query::Condition;
+use card::settings::SettingStore;
+use common::newtypes::card_id::CardId;
+use rbac::access::Member;
+use rbac::role::Action;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+pub struct AppState {
+    pub registry: Arc<SubscriptionRegistry>,
+    pub inbox: Arc<Inbox>,
+    pub preferences: Arc<PreferenceRegistry>,
+    pub webhooks: Arc<WebhookService>,
+    pub access: Arc<SharedAccess>,
+    pub event_sender: mpsc::Sender<CardEvent>,
+}
+
+//卡片服务把卡片事件推送到这里
+#[post("/events")]
+async fn receive_event(_service: ServiceContext, data: web::Data<AppState>, event: web::Json<CardEvent>) -> impl Responder {
+    match data.event_sender.send(event.into_inner()).await {
+        Ok(_) => HttpResponse::Accepted().finish(),
+        Err(_) => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct NotifyRequest {
+    event: CardEvent,
+    member_ids: Vec<CardId>,
+    message: String,
+}
+
+//业务规则直接给指定成员发送站内通知，不经过订阅
+#[post("/notifications")]
+async fn notify(_service: ServiceContext, data: web::Data<AppState>, request: web::Json<NotifyRequest>) -> impl Responder {
+    let request = request.into_inner();
+    for member_id in &request.member_ids {
+        if let Err(err) = data.inbox.put(Notification::with_message(&request.event, member_id, &request.message)).await {
+            return HttpResponse::InternalServerError().body(err.to_string());
+        }
+    }
+    HttpResponse::NoContent().finish()
+}
+
+#[derive(Deserialize)]
+struct SubscribeRequest {
+    target: SubscriptionTarget,
+    #[serde(default)]
+    event_types: Vec<CardEventType>,
+    channels: Vec<Channel>,
+}
+
+//成员只能为自己订阅
+#[post("/subscriptions")]
+async fn subscribe(context: MemberContext, data: web::Data<AppState>, request: web::Json<SubscribeRequest>) -> impl Responder {
+    let request = request.into_inner();
+    if request.channels.is_empty() {
+        return HttpResponse::BadRequest().body("channels is empty");
+    }
+    let subscription = Subscription::new(&context.org_id, &context.member_id, request.target, request.event_types, request.channels);
+    match data.registry.add(subscription.clone()).await {
+        Ok(_) => HttpResponse::Ok().json(subscription),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[get("/subscriptions")]
+async fn list_subscriptions(context: MemberContext, data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.registry.list_of_member(&context.org_id, &context.member_id))
+}
+
+//其他成员的订阅视为不存在
+#[delete("/subscriptions/{subscription_id}")]
+async fn unsubscribe(context: MemberContext, data: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+    let subscription_id = path.into_inner();
+    let owned = data.registry.list_of_member(&context.org_id, &context.member_id).iter().any(|it| it.id == subscription_id);
+    if !owned {
+        return HttpResponse::NotFound().finish();
+    }
+    match data.registry.remove(&context.org_id, &subscription_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct InboxQuery {
+    #[serde(default)]
+    unread_only: bool,
+}
+
+#[get("/inbox")]
+async fn list_inbox(context: MemberContext, data: web::Data<AppState>, query: web::Query<InboxQuery>) -> impl Responder {
+    HttpResponse::Ok().json(data.inbox.list(&context.org_id, &context.member_id, query.unread_only))
+}
+
+#[put("/inbox/{notification_id}/read")]
+async fn mark_read(context: MemberContext, data: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+    match data.inbox.mark_read(&context.org_id, &context.member_id, &path.into_inner()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[get("/preferences")]
+async fn get_preference(context: MemberContext, data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.preferences.get(&context.org_id, &context.member_id))
+}
+
+#[put("/preferences")]
+async fn set_preference(context: MemberContext, data: web::Data<AppState>, preference: web::Json<NotificationPreference>) -> impl Responder {
+    let preference = preference.into_inner();
+    if matches!(preference.digest, DigestFrequency::Daily(hour) if hour > 23) {
+        return HttpResponse::BadRequest().body("daily digest hour must be between 0 and 23");
+    }
+    data.preferences.set(&context.org_id, &context.member_id, preference);
+    HttpResponse::NoContent().finish()
+}
+
+#[derive(Deserialize)]
+struct CreateWebhookRequest {
+    url: String,
+    secret: Option<String>, //不指定时自动生成
+    #[serde(default)]
+    card_type_ids: Vec<String>,
+    #[serde(default)]
+    event_types: Vec<CardEventType>,
+    condition: Option<Condition>,
+}
+
+//webhook把组织的卡片事件推送到外部地址，只有组织范围有ManageSchema权限的成员可以维护
+//直接授予成员的权限不需要加载成员的团队
+async fn can_manage_webhooks(context: &MemberContext, data: &AppState) -> bool {
+    let access = data.access.get();
+    if access.check_org(&Member::new(context.member_id.clone(), &context.org_id, vec![]), Action::ManageSchema) {
+        return true;
+    }
+    let member = Member::load(context.member_id.clone(), &context.org_id).await;
+    access.check_org(&member, Action::ManageSchema)
+}
+
+//签名密钥只在创建时返回一次，之后列出webhook时不再包含
+#[post("/webhooks")]
+async fn create_webhook(context: MemberContext, data: web::Data<AppState>, request: web::Json<CreateWebhookRequest>) -> impl Responder {
+    if !can_manage_webhooks(&context, &data).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    let request = request.into_inner();
+    if !request.url.starts_with("http://") && !request.url.starts_with("https://") {
+        return HttpResponse::BadRequest().body("url must be http or https");
+    }
+    let webhook = Webhook::new(&context.org_id, &context.member_id, &request.url, request.secret, request.card_type_ids, request.event_types, request.condition);
+    if let Err(err) = SettingStore::save(&context.org_id, WEBHOOK_SETTING, &webhook.id, &WebhookSetting::from(&webhook)).await {
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+    data.webhooks.add(webhook.clone());
+    HttpResponse::Ok().json(serde_json::json!({ "webhook": webhook, "secret": webhook.secret }))
+}
+
+#[get("/webhooks")]
+async fn list_webhooks(context: MemberContext, data: web::Data<AppState>) -> impl Responder {
+    if !can_manage_webhooks(&context, &data).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    HttpResponse::Ok().json(data.webhooks.list(&context.org_id))
+}
+
+#[delete("/webhooks/{webhook_id}")]
+async fn delete_webhook(context: MemberContext, data: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+    if !can_manage_webhooks(&context, &data).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    let webhook_id = path.into_inner();
+    if !data.webhooks.list(&context.org_id).iter().any(|it| it.id == webhook_id) {
+        return HttpResponse::NotFound().finish();
+    }
+    if let Err(err) = SettingStore::remove(&context.org_id, WEBHOOK_SETTING, &webhook_id).await {
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+    data.webhooks.remove(&context.org_id, &webhook_id);
+    HttpResponse::NoContent().finish()
+}
+
+#[get("/webhooks/dead-letters")]
+async fn list_dead_letters(context: MemberContext, data: web::Data<AppState>) -> impl Responder {
+    if !can_manage_webhooks(&context, &data).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    HttpResponse::Ok().json(data.webhooks.dead_letters(&context.org_id))
+}
+
+#[post("/webhooks/dead-letters/{dead_letter_id}/redeliver")]
+async fn redeliver(context: MemberContext, data: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+    if !can_manage_webhooks(&context, &data).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    let dead_letter_id = path.into_inner();
+    if !data.webhooks.dead_letters(&context.org_id).iter().any(|it| it.id == dead_letter_id) {
+        return HttpResponse::NotFound().finish();
+    }
+    //重新投递的结果同步到配置存储，保存失败时只影响重启后的死信列表
+    let result = data.webhooks.redeliver(&context.org_id, &dead_letter_id).await;
+    let saved = match &result {
+        Ok(_) => SettingStore::remove(&context.org_id, DEAD_LETTER_SETTING, &dead_letter_id).await.map(|_| ()),
+        Err(_) => match data.webhooks.dead_letters(&context.org_id).iter().find(|it| it.id == dead_letter_id) {
+            Some(dead_letter) => SettingStore::save(&context.org_id, DEAD_LETTER_SETTING, &dead_letter_id, dead_letter).await,
+            None => Ok(()),
+        },
+    };
+    if let Err(err) = saved {
+        eprintln!("failed to save dead letter {}: {}", dead_letter_id, err);
+    }
+    match result {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => HttpResponse::BadGateway().body(err.to_string()),
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(receive_event)
+        .service(notify)
+        .service(subscribe)
+        .service(list_subscriptions)
+        .service(unsubscribe)
+        .service(list_inbox)
+        .service(mark_read)
+        .service(get_preference)
+        .service(set_preference)
+        .service(create_webhook)
+        .service(list_webhooks)
+        .service(list_dead_letters)
+        .service(delete_webhook)
+        .service(redeliver);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+    use crate::auth::Authentication;
+    use crate::retry::RetryPolicy;
+    use actix_web::dev::ServiceResponse;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+    use card::events::CardEventKind;
+    use common::newtypes::tenant_id::TenantId;
+    use rbac::access::AccessControl;
+    use rbac::grant::{Grant, Scope};
+    use rbac::role::Role;
+    use schema::card_types::Grantee;
+
+    const SERVICE: (&str, &str) = ("Authorization", "Service service-token");
+
+    //o1中的m1是管理员
+    fn webhook_admins() -> AccessControl {
+        let access = AccessControl::default();
+        let org_id = TenantId::from_str("o1");
+        let admin = Role::admin(&org_id);
+        let grant = Grant::new(&org_id, &admin.id, Grantee::Member(String::from("m1")), Scope::Org);
+        access.add_role(admin);
+        access.grant(grant).unwrap();
+        access
+    }
+
+    fn app_state() -> (web::Data<AppState>, mpsc::Receiver<CardEvent>) {
+        let (event_sender, receiver) = mpsc::channel(16);
+        let data = web::Data::new(AppState {
+            registry: Arc::new(SubscriptionRegistry::default()),
+            inbox: Arc::new(Inbox::default()),
+            preferences: Arc::new(PreferenceRegistry::default()),
+            webhooks: Arc::new(WebhookService::new(RetryPolicy::default())),
+            access: Arc::new(SharedAccess::new(webhook_admins())),
+            event_sender,
+        });
+        (data, receiver)
+    }
+
+    //带认证中间件的测试应用
+    macro_rules! init_app {
+        ($data:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data($data)
+                    .app_data(web::Data::new(Authentication::new("secret", "service-token")))
+                    .service(web::scope("").wrap(from_fn(auth::authenticate)).configure(config))
+            ).await
+        };
+    }
+
+    fn member(member_id: &str, org_id: &str) -> (&'static str, String) {
+        ("Authorization", format!("Bearer {}", auth::issue("secret", member_id, org_id, 60)))
+    }
+
+    fn status(result: Result<ServiceResponse, actix_web::Error>) -> u16 {
+        match result {
+            Ok(resp) => resp.status().as_u16(),
+            Err(err) => err.as_response_error().status_code().as_u16(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_receive_event() {
+        let (data, mut receiver) = app_state();
+        let app = init_app!(data);
+        let event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "t1", CardEventKind::Created, &CardId::from_str("m1"));
+        //成员不能伪造事件
+        let request = test::TestRequest::post().uri("/events").insert_header(member("m1", "o1")).set_json(&event).to_request();
+        assert_eq!(status(test::try_call_service(&app, request).await), 401);
+        let request = test::TestRequest::post().uri("/events").insert_header(SERVICE).set_json(&event).to_request();
+        assert_eq!(status(test::try_call_service(&app, request).await), 202);
+        assert_eq!(receiver.recv().await.unwrap(), event);
+    }
+
+    #[actix_web::test]
+    async fn test_notify() {
+        let (data, _receiver) = app_state();
+        let app = init_app!(data.clone());
+        let event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "t1", CardEventKind::Created, &CardId::from_str("m1"));
+        let request = test::TestRequest::post().uri("/notifications").insert_header(SERVICE).set_json(serde_json::json!({
+            "event": event,
+            "member_ids": ["m2"],
+            "message": "请及时评审",
+        })).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 204);
+        let notifications = data.inbox.list(&TenantId::from_str("o1"), &CardId::from_str("m2"), true);
+        assert_eq!(notifications[0].content, "请及时评审");
+    }
+
+    #[actix_web::test]
+    async fn test_subscribe() {
+        let (data, _receiver) = app_state();
+        let app = init_app!(data.clone());
+        //请求体中的组织和成员被忽略
+        let request = test::TestRequest::post().uri("/subscriptions").insert_header(member("m1", "o1")).set_json(serde_json::json!({
+            "org_id": "o2",
+            "member_id": "m2",
+            "target": "CreatedByMe",
+            "channels": ["InApp"],
+        })).to_request();
+        let subscription: Subscription = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(subscription.member_id, CardId::from_str("m1"));
+        assert_eq!(data.registry.list_of_member(&TenantId::from_str("o1"), &CardId::from_str("m1")).len(), 1);
+
+        //其他成员不能取消
+        let request = test::TestRequest::delete().uri(&format!("/subscriptions/{}", subscription.id)).insert_header(member("m2", "o1")).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 404);
+        let request = test::TestRequest::delete().uri(&format!("/subscriptions/{}", subscription.id)).insert_header(member("m1", "o1")).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 204);
+    }
+
+    #[actix_web::test]
+    async fn test_set_preference() {
+        let (data, _receiver) = app_state();
+        let app = init_app!(data.clone());
+        let request = test::TestRequest::put().uri("/preferences").insert_header(member("m1", "o1")).set_json(serde_json::json!({
+            "digest": "Hourly",
+            "quiet_hours": null,
+            "timezone": "Asia/Shanghai",
+        })).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 204);
+        let request = test::TestRequest::get().uri("/preferences").insert_header(member("m1", "o1")).to_request();
+        let preference: NotificationPreference = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(preference.timezone, chrono_tz::Tz::Asia__Shanghai);
+
+        let request = test::TestRequest::put().uri("/preferences").insert_header(member("m1", "o1")).set_json(serde_json::json!({
+            "digest": { "Daily": 24 },
+            "quiet_hours": null,
+            "timezone": "Asia/Shanghai",
+        })).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_tenant_isolation() {
+        let (data, _receiver) = app_state();
+        let app = init_app!(data.clone());
+        let event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "t1", CardEventKind::Created, &CardId::from_str("m1"));
+        let request = test::TestRequest::post().uri("/notifications").insert_header(SERVICE).set_json(serde_json::json!({
+            "event": event,
+            "member_ids": ["m2"],
+            "message": "请及时评审",
+        })).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 204);
+        let request = test::TestRequest::put().uri("/preferences").insert_header(member("m2", "o1")).set_json(serde_json::json!({
+            "digest": "Hourly",
+            "quiet_hours": null,
+            "timezone": "Asia/Shanghai",
+        })).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 204);
+
+        //同一成员在另一个组织中看不到o1的通知和偏好
+        let request = test::TestRequest::get().uri("/inbox").insert_header(member("m2", "o2")).to_request();
+        let notifications: Vec<Notification> = test::call_and_read_body_json(&app, request).await;
+        assert!(notifications.is_empty());
+        let notification_id = data.inbox.list(&TenantId::from_str("o1"), &CardId::from_str("m2"), false)[0].id.clone();
+        let request = test::TestRequest::put().uri(&format!("/inbox/{}/read", notification_id)).insert_header(member("m2", "o2")).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 404);
+        let request = test::TestRequest::get().uri("/preferences").insert_header(member("m2", "o2")).to_request();
+        let preference: NotificationPreference = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(preference, NotificationPreference::default());
+
+        let request = test::TestRequest::get().uri("/inbox").insert_header(member("m2", "o1")).to_request();
+        let notifications: Vec<Notification> = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(notifications.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_create_webhook() {
+        let (data, _receiver) = app_state();
+        let app = init_app!(data.clone());
+        let request = test::TestRequest::post().uri("/webhooks").insert_header(member("m1", "o1")).set_json(serde_json::json!({
+            "url": "ftp://localhost",
+        })).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 400);
+
+        //列表中不包含密钥，其他组织看不到
+        data.webhooks.add(Webhook::new(&TenantId::from_str("o1"), &CardId::from_str("m1"), "http://localhost:9000/hook", None, vec![], vec![], None));
+        data.webhooks.add(Webhook::new(&TenantId::from_str("o2"), &CardId::from_str("m1"), "http://localhost:9000/hook", None, vec![], vec![], None));
+        let request = test::TestRequest::get().uri("/webhooks").insert_header(member("m1", "o1")).to_request();
+        let webhooks: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(webhooks.as_array().unwrap().len(), 1);
+        assert!(webhooks[0].get("secret").is_none());
+
+        let request = test::TestRequest::delete().uri("/webhooks/unknown").insert_header(member("m1", "o1")).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 404);
+        let request = test::TestRequest::post().uri("/webhooks/dead-letters/unknown/redeliver").insert_header(member("m1", "o1")).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 404);
+        let request = test::TestRequest::get().uri("/webhooks").to_request();
+        assert_eq!(status(test::try_call_service(&app, request).await), 401);
+    }
+}
@@ -0,0 +1,170 @@
+//认证：成员用登录服务签发的JWT访问自己的订阅、收件箱和偏好，组织和成员只取自令牌，不信任路径和请求体
+//卡片服务、业务规则等内部服务用共享的服务令牌推送事件和通知，请求头为 Authorization: Service <token>
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use common::newtypes::card_id::CardId;
+use common::newtypes::tenant_id::TenantId;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter};
+use std::future::{ready, Ready};
+use std::{error, fmt};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String, //成员id
+    org: String, //组织id
+    exp: u64, //过期时间，秒
+}
+
+//已认证的成员
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemberContext {
+    pub member_id: CardId,
+    pub org_id: TenantId,
+}
+
+//已认证的内部服务
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceContext;
+
+pub struct Authentication {
+    decoding_key: DecodingKey,
+    validation: Validation,
+    service_token_digest: Vec<u8>, //只保存服务令牌的摘要，比较摘要避免按长度泄露信息
+}
+
+impl Authentication {
+    pub fn new(jwt_secret: &str, service_token: &str) -> Self {
+        Self {
+            decoding_key: DecodingKey::from_secret(jwt_secret.as_bytes()),
+            validation: Validation::new(Algorithm::HS256),
+            service_token_digest: Sha256::digest(service_token.as_bytes()).to_vec(),
+        }
+    }
+
+    fn authenticate(&self, req: &HttpRequest) -> Result<Caller, AuthError> {
+        let value = req.headers().get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|it| it.to_str().ok())
+            .ok_or_else(|| AuthError::new("missing credentials"))?;
+        let (scheme, credentials) = value.split_once(' ').ok_or_else(|| AuthError::new("malformed authorization header"))?;
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            let data = decode::<Claims>(credentials.trim(), &self.decoding_key, &self.validation)
+                .map_err(|err| AuthError::new(&format!("invalid jwt: {}", err)))?;
+            Ok(Caller::Member(MemberContext { member_id: CardId::from(data.claims.sub), org_id: TenantId::from(data.claims.org) }))
+        } else if scheme.eq_ignore_ascii_case("Service") {
+            if Sha256::digest(credentials.trim().as_bytes()).as_slice() == self.service_token_digest.as_slice() {
+                Ok(Caller::Service(ServiceContext))
+            } else {
+                Err(AuthError::new("invalid service token"))
+            }
+        } else {
+            Err(AuthError::new(&format!("unsupported authorization scheme {scheme}")))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Caller {
+    Member(MemberContext),
+    Service(ServiceContext),
+}
+
+//认证中间件，处理函数通过MemberContext或者ServiceContext提取器限定调用方
+pub async fn authenticate(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let authentication = req.app_data::<web::Data<Authentication>>()
+        .ok_or_else(|| AuthError::new("authentication is not configured"))?;
+    let caller = authentication.authenticate(req.request())?;
+    req.extensions_mut().insert(caller);
+    next.call(req).await
+}
+
+impl FromRequest for MemberContext {
+    type Error = AuthError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let context = match req.extensions().get::<Caller>() {
+            Some(Caller::Member(it)) => Some(it.clone()),
+            _ => None,
+        };
+        ready(context.ok_or_else(|| AuthError::new("member credentials required")))
+    }
+}
+
+impl FromRequest for ServiceContext {
+    type Error = AuthError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let context = match req.extensions().get::<Caller>() {
+            Some(Caller::Service(it)) => Some(it.clone()),
+            _ => None,
+        };
+        ready(context.ok_or_else(|| AuthError::new("service credentials required")))
+    }
+}
+
+#[derive(Debug)]
+pub struct AuthError {
+    message: String,
+}
+
+impl AuthError {
+    pub fn new(message: &str) -> Self {
+        Self { message: message.to_string() }
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for AuthError {}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::UNAUTHORIZED
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::Unauthorized().body(self.message.clone())
+    }
+}
+
+//模拟登录服务签发有效期为ttl秒的令牌
+#[cfg(test)]
+pub(crate) fn issue(secret: &str, member_id: &str, org_id: &str, ttl: u64) -> String {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let claims = Claims { sub: String::from(member_id), org: String::from(org_id), exp: now + ttl };
+    encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    #[actix_web::test]
+    async fn test_authenticate() {
+        let authentication = Authentication::new("secret", "service-token");
+        let req = test::TestRequest::default().insert_header(("Authorization", format!("Bearer {}", issue("secret", "m1", "o1", 60)))).to_http_request();
+        let Caller::Member(member) = authentication.authenticate(&req).unwrap() else { panic!("not a member") };
+        assert_eq!(member, MemberContext { member_id: CardId::from_str("m1"), org_id: TenantId::from_str("o1") });
+        let req = test::TestRequest::default().insert_header(("Authorization", "Service service-token")).to_http_request();
+        assert!(matches!(authentication.authenticate(&req), Ok(Caller::Service(_))));
+        //签名不匹配的JWT、错误的服务令牌、没有凭证
+        let req = test::TestRequest::default().insert_header(("Authorization", format!("Bearer {}", issue("other", "m1", "o1", 60)))).to_http_request();
+        assert!(authentication.authenticate(&req).is_err());
+        let req = test::TestRequest::default().insert_header(("Authorization", "Service guess")).to_http_request();
+        assert!(authentication.authenticate(&req).is_err());
+        assert!(authentication.authenticate(&test::TestRequest::default().to_http_request()).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use crate::notification::{Inbox, Notification};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::{error, fmt};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+//通知的投递渠道
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Channel {
+    InApp, //站内信
+    Email(String), //邮件地址
+    Webhook(String), //接收通知的url
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::InApp => write!(f, "in-app"),
+            Channel::Email(address) => write!(f, "email:{address}"),
+            Channel::Webhook(url) => write!(f, "webhook:{url}"),
+        }
+    }
+}
+
+//本地的SMTP服务，开发环境可以用mailhog等工具代替
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub addr: String,
+    pub from: String,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            addr: String::from("127.0.0.1:1025"),
+            from: String::from("noreply@raccoon.local"),
+        }
+    }
+}
+
+pub struct Deliverer {
+    inbox: Arc<Inbox>,
+    smtp: SmtpConfig,
+    http: reqwest::Client,
+}
+
+impl Deliverer {
+    pub fn new(inbox: Arc<Inbox>, smtp: SmtpConfig) -> Self {
+        Self {
+            inbox,
+            smtp,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn deliver(&self, channel: &Channel, notification: &Notification) -> Result<(), DeliveryError> {
+        match channel {
+            Channel::InApp => self.inbox.put(notification.clone()).await
+                .map_err(|e| DeliveryError::new(&format!("failed to save notification: {e}"))),
+            Channel::Email(address) => {
+                send_mail(&self.smtp, address, &notification.title, &notification.content).await
+            }
+            Channel::Webhook(url) => {
+                let response = self.http.post(url).json(notification).send().await
+                    .map_err(|e| DeliveryError::new(&format!("failed to post webhook: {e}")))?;
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(DeliveryError::new(&format!("webhook responded {}", response.status())))
+                }
+            }
+        }
+    }
+}
+
+/*
+    最简单的SMTP会话：
+    S: 220  C: HELO  S: 250  C: MAIL FROM  S: 250  C: RCPT TO  S: 250
+    C: DATA  S: 354  C: 邮件内容 + "\r\n.\r\n"  S: 250  C: QUIT  S: 221
+    标题和地址来自卡片内容，写入前去掉换行以免注入额外的头或命令，正文中以"."开头的行需要再加一个"."
+ */
+pub(crate) async fn send_mail(smtp: &SmtpConfig, to: &str, subject: &str, body: &str) -> Result<(), DeliveryError> {
+    let from = header_value(&smtp.from);
+    let to = header_value(to);
+    let subject = encode_subject(&header_value(subject));
+    let body = dot_stuff(body);
+    let stream = TcpStream::connect(&smtp.addr).await
+        .map_err(|e| DeliveryError::new(&format!("failed to connect smtp server: {e}")))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    expect_reply(&mut reader, "220").await?;
+    let commands = [
+        (String::from("HELO raccoon\r\n"), "250"),
+        (format!("MAIL FROM:<{from}>\r\n"), "250"),
+        (format!("RCPT TO:<{to}>\r\n"), "250"),
+        (String::from("DATA\r\n"), "354"),
+        (format!("From: {from}\r\nTo: {to}\r\nSubject: {subject}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{body}\r\n.\r\n"), "250"),
+        (String::from("QUIT\r\n"), "221"),
+    ];
+    for (command, code) in commands {
+        writer.write_all(command.as_bytes()).await
+            .map_err(|e| DeliveryError::new(&format!("failed to write smtp command: {e}")))?;
+        expect_reply(&mut reader, code).await?;
+    }
+    Ok(())
+}
+
+fn header_value(value: &str) -> String {
+    value.chars().filter(|c| *c != '\r' && *c != '\n').collect()
+}
+
+//RFC 2047：邮件头只能是ASCII，其他字符整体按UTF-8做base64编码
+fn encode_subject(subject: &str) -> String {
+    if subject.is_ascii() {
+        subject.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(subject))
+    }
+}
+
+//正文统一使用CRLF换行，单独一行的"."会被当作正文结束
+fn dot_stuff(body: &str) -> String {
+    body.lines()
+        .map(|line| if line.starts_with('.') { format!(".{line}") } else { line.to_string() })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+//多行回复形如"250-xxx"，最后一行形如"250 xxx"
+async fn expect_reply<R: AsyncBufReadExt + Unpin>(reader: &mut R, code: &str) -> Result<(), DeliveryError> {
+    loop {
+        let mut line = String::new();
+        let size = reader.read_line(&mut line).await
+            .map_err(|e| DeliveryError::new(&format!("failed to read smtp reply: {e}")))?;
+        if size == 0 {
+            return Err(DeliveryError::new("smtp connection closed"));
+        }
+        if !line.starts_with(code) {
+            return Err(DeliveryError::new(&format!("unexpected smtp reply, expect {code} but got {}", line.trim_end())));
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DeliveryError {
+    message: String,
+}
+
+impl DeliveryError {
+    pub fn new(message: &str) -> Self {
+        Self { message: message.to_string() }
+    }
+}
+
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for DeliveryError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    //模拟一个只会回复成功的SMTP服务，返回收到的全部内容
+    async fn mock_smtp_server(listener: TcpListener) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut received = String::new();
+        writer.write_all(b"220 mock\r\n").await.unwrap();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap() == 0 {
+                break;
+            }
+            received.push_str(&line);
+            let reply: &[u8] = if in_data {
+                if line != ".\r\n" {
+                    continue;
+                }
+                in_data = false;
+                b"250 queued\r\n"
+            } else if line.starts_with("HELO") {
+                b"250-mock\r\n250 ok\r\n"
+            } else if line.starts_with("DATA") {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line.starts_with("QUIT") {
+                writer.write_all(b"221 bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn test_send_mail() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let smtp = SmtpConfig {
+            addr: listener.local_addr().unwrap().to_string(),
+            from: String::from("noreply@raccoon.local"),
+        };
+        let server = tokio::spawn(mock_smtp_server(listener));
+        send_mail(&smtp, "m1@raccoon.local", "卡片c1已归档", "卡片c1已归档\n.\n完").await.unwrap();
+        let received = server.await.unwrap();
+        assert!(received.contains("RCPT TO:<m1@raccoon.local>"));
+        assert!(received.contains(&format!("Subject: =?UTF-8?B?{}?=", STANDARD.encode("卡片c1已归档"))));
+        assert!(received.contains("卡片c1已归档\r\n..\r\n完\r\n.\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_send_mail_strips_line_breaks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let smtp = SmtpConfig {
+            addr: listener.local_addr().unwrap().to_string(),
+            from: String::from("noreply@raccoon.local"),
+        };
+        let server = tokio::spawn(mock_smtp_server(listener));
+        send_mail(&smtp, "m1@raccoon.local>\r\nRCPT TO:<m2@raccoon.local", "s\r\nBcc: m3@raccoon.local", "b").await.unwrap();
+        let received = server.await.unwrap();
+        assert!(!received.contains("\r\nRCPT TO:<m2@raccoon.local>"));
+        assert!(received.contains("Subject: sBcc: m3@raccoon.local\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_send_mail_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let smtp = SmtpConfig {
+            addr: listener.local_addr().unwrap().to_string(),
+            from: String::from("noreply@raccoon.local"),
+        };
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"554 no service\r\n").await.unwrap();
+        });
+        assert!(send_mail(&smtp, "m1@raccoon.local", "s", "b").await.is_err());
+    }
+}
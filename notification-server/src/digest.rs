@@ -0,0 +1,112 @@
+use crate::channel::Channel;
+use crate::notification::{DigestItem, Notification, NotificationSource};
+use crate::preference::PreferenceRegistry;
+use chrono::{DateTime, Utc};
+use common::newtypes::card_id::CardId;
+use common::newtypes::tenant_id::TenantId;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+//某个成员尚未发送的通知
+struct PendingDigest {
+    since: DateTime<Utc>, //第一条待发送通知的产生时间
+    channels: HashSet<Channel>,
+    items: Vec<DigestItem>, //按卡片第一次变更的先后排列
+}
+
+//汇总缓冲区，批量导入等场景下避免成员被大量通知淹没
+#[derive(Default)]
+pub struct DigestBuffer {
+    pending: Mutex<HashMap<(TenantId, CardId), PendingDigest>>, //同一成员在不同组织中的通知分别汇总
+}
+
+impl DigestBuffer {
+    //同一张卡片的多次变更合并到一项中
+    pub fn push(&self, notification: &Notification, channels: &HashSet<Channel>, now: DateTime<Utc>) {
+        let card_id = match &notification.source {
+            NotificationSource::Event { card_id, .. } => card_id,
+            NotificationSource::Digest(_) => return,
+        };
+        let mut pending = self.pending.lock().unwrap();
+        let digest = pending.entry((notification.org_id.clone(), notification.member_id.clone())).or_insert_with(|| PendingDigest {
+            since: now,
+            channels: HashSet::new(),
+            items: Vec::new(),
+        });
+        digest.channels.extend(channels.iter().cloned());
+        match digest.items.iter_mut().find(|it| &it.card_id == card_id) {
+            Some(item) => item.changes.push(notification.title.clone()),
+            None => digest.items.push(DigestItem {
+                card_id: card_id.clone(),
+                changes: vec![notification.title.clone()],
+            }),
+        }
+    }
+
+    //取出所有到期的汇总，到期与否取决于成员的汇总频率和免打扰时段
+    pub fn take_due(&self, preferences: &PreferenceRegistry, now: DateTime<Utc>) -> Vec<(Notification, HashSet<Channel>)> {
+        let mut pending = self.pending.lock().unwrap();
+        let due_members: Vec<(TenantId, CardId)> = pending.iter()
+            .filter(|((org_id, member_id), digest)| preferences.get(org_id, member_id).is_due(digest.since, now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut digests = Vec::new();
+        for key in due_members {
+            if let Some(digest) = pending.remove(&key) {
+                let (org_id, member_id) = key;
+                digests.push((Notification::digest(&org_id, &member_id, digest.items), digest.channels));
+            }
+        }
+        digests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preference::{DigestFrequency, NotificationPreference};
+    use card::events::{CardEvent, CardEventKind};
+    use chrono_tz::Tz;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn notification(card_id: &str, kind: CardEventKind) -> Notification {
+        let event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str(card_id), "t1", kind, &CardId::from_str("m2"));
+        Notification::from_event(&event, &CardId::from_str("m1"))
+    }
+
+    #[test]
+    fn test_collapse_and_take_due() {
+        let buffer = DigestBuffer::default();
+        let preferences = PreferenceRegistry::default();
+        let member_id = CardId::from_str("m1");
+        preferences.set(&TenantId::from_str("o1"), &member_id, NotificationPreference {
+            digest: DigestFrequency::Hourly,
+            quiet_hours: None,
+            timezone: Tz::UTC,
+        });
+        let in_app = HashSet::from([Channel::InApp]);
+        let email = HashSet::from([Channel::Email(String::from("m1@raccoon.local"))]);
+        buffer.push(&notification("c1", CardEventKind::Created), &in_app, utc("2024-05-01T10:01:00Z"));
+        buffer.push(&notification("c2", CardEventKind::Created), &in_app, utc("2024-05-01T10:02:00Z"));
+        buffer.push(&notification("c1", CardEventKind::Archived), &email, utc("2024-05-01T10:03:00Z"));
+
+        assert!(buffer.take_due(&preferences, utc("2024-05-01T10:59:00Z")).is_empty());
+        let digests = buffer.take_due(&preferences, utc("2024-05-01T11:00:00Z"));
+        assert_eq!(digests.len(), 1);
+        let (digest, channels) = &digests[0];
+        assert_eq!(channels.len(), 2);
+        match &digest.source {
+            NotificationSource::Digest(items) => {
+                assert_eq!(items.len(), 2);
+                assert_eq!(items[0].card_id, CardId::from_str("c1"));
+                assert_eq!(items[0].changes, vec!["卡片c1已创建".to_string(), "卡片c1已归档".to_string()]);
+            }
+            _ => panic!("expect digest"),
+        }
+        //已取出的汇总不会重复发送
+        assert!(buffer.take_due(&preferences, utc("2024-05-01T12:00:00Z")).is_empty());
+    }
+}
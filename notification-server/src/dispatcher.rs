@@ -0,0 +1,258 @@
+use crate::channel::{Channel, Deliverer};
+use crate::digest::DigestBuffer;
+use crate::notification::Notification;
+use crate::preference::{DigestFrequency, PreferenceRegistry};
+use crate::retry::RetryPolicy;
+use crate::subscription::{Subscription, SubscriptionRegistry};
+use crate::webhook::WebhookService;
+use card::events::CardEvent;
+use card::query;
+use card::query::CURRENT_CARD_PARAMETER;
+use chrono::Utc;
+use common::newtypes::card_id::CardId;
+use crate::access::SharedAccess;
+use rbac::access::Member;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+//事件分发器：为事件找到匹配的订阅，生成通知并通过各个渠道投递
+pub struct Dispatcher {
+    registry: Arc<SubscriptionRegistry>,
+    preferences: Arc<PreferenceRegistry>,
+    webhooks: Arc<WebhookService>,
+    deliverer: Deliverer,
+    retry_policy: RetryPolicy,
+    deduplicator: Deduplicator,
+    digests: DigestBuffer,
+    access: Arc<SharedAccess>, //订阅者的权限和可见性
+}
+
+impl Dispatcher {
+    pub fn new(registry: Arc<SubscriptionRegistry>, preferences: Arc<PreferenceRegistry>, webhooks: Arc<WebhookService>, deliverer: Deliverer, retry_policy: RetryPolicy) -> Self {
+        Self {
+            registry,
+            preferences,
+            webhooks,
+            deliverer,
+            retry_policy,
+            deduplicator: Deduplicator::new(DEDUPLICATION_CAPACITY),
+            digests: DigestBuffer::default(),
+            access: Arc::new(SharedAccess::default()),
+        }
+    }
+
+    pub fn with_access(self, access: Arc<SharedAccess>) -> Self {
+        Self { access, ..self }
+    }
+
+    pub async fn run(self: Arc<Self>, mut receiver: mpsc::Receiver<CardEvent>) {
+        while let Some(event) = receiver.recv().await {
+            let dispatcher = self.clone();
+            //每个事件单独投递，避免一个渠道的重试阻塞后续事件
+            tokio::spawn(async move {
+                dispatcher.dispatch(&event).await;
+                let access = dispatcher.access.get();
+                dispatcher.webhooks.dispatch(&event, &access).await;
+            });
+        }
+    }
+
+    //定期检查汇总缓冲区，把到期的汇总发出去
+    pub async fn run_digest(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.flush_digests().await;
+        }
+    }
+
+    pub(crate) async fn flush_digests(&self) {
+        for (digest, channels) in self.digests.take_due(&self.preferences, Utc::now()) {
+            self.deliver(&digest, &channels).await;
+        }
+    }
+
+    pub async fn dispatch(&self, event: &CardEvent) {
+        //同一成员的多个订阅同时命中时只生成一条通知，渠道取并集
+        let mut channels_of_member: HashMap<CardId, HashSet<Channel>> = HashMap::new();
+        for subscription in self.registry.candidates(event) {
+            if channels_of_member.get(&subscription.member_id)
+                .is_some_and(|channels| subscription.channels.iter().all(|c| channels.contains(c))) {
+                continue;
+            }
+            if self.matches(&subscription, event).await {
+                channels_of_member.entry(subscription.member_id.clone()).or_default().extend(subscription.channels);
+            }
+        }
+        for (member_id, channels) in channels_of_member {
+            let notification = Notification::from_event(event, &member_id);
+            self.deliver_or_buffer(&notification, &channels).await;
+        }
+    }
+
+    //立即发送的成员在非免打扰时段直接投递，其余的进入汇总缓冲区
+    async fn deliver_or_buffer(&self, notification: &Notification, channels: &HashSet<Channel>) {
+        let preference = self.preferences.get(&notification.org_id, &notification.member_id);
+        let now = Utc::now();
+        if preference.digest == DigestFrequency::Immediate && !preference.is_quiet(now) {
+            self.deliver(notification, channels).await;
+        } else {
+            self.digests.push(notification, channels, now);
+        }
+    }
+
+    async fn matches(&self, subscription: &Subscription, event: &CardEvent) -> bool {
+        let mut parameters = HashMap::new();
+        parameters.insert(String::from(CURRENT_CARD_PARAMETER), event.card_id.to_string());
+        //订阅者只能收到自己可见的卡片的通知
+        let access = self.access.get();
+        let member = Member::load(subscription.member_id.clone(), &event.org_id).await;
+        let query_context = access.query_context(&member, parameters);
+        let condition = subscription.target.to_condition();
+        match query::matches(&event.card_id, &condition, &query_context).await {
+            Ok(matched) => matched,
+            Err(err) => {
+                eprintln!("failed to match subscription {}: {}", subscription.id, err);
+                false
+            }
+        }
+    }
+
+    pub(crate) async fn deliver(&self, notification: &Notification, channels: &HashSet<Channel>) {
+        for channel in channels {
+            //同一事件对同一成员的同一渠道只投递一次，重复到达的事件直接忽略
+            let key = format!("{}:{}:{}", notification.deduplication_key(), notification.member_id, channel);
+            if !self.deduplicator.first_seen(&key) {
+                continue;
+            }
+            let result = self.retry_policy.run(|| self.deliverer.deliver(channel, notification)).await;
+            if let Err(err) = result {
+                eprintln!("failed to deliver notification {} through {}: {}", notification.id, channel, err);
+                //投递失败时允许事件重新投递
+                self.deduplicator.forget(&key);
+            }
+        }
+    }
+}
+
+const DEDUPLICATION_CAPACITY: usize = 100_000;
+
+//记录最近投递过的通知，超过容量后淘汰最早的记录
+pub(crate) struct Deduplicator {
+    capacity: usize,
+    seen: Mutex<(HashSet<String>, VecDeque<String>)>,
+}
+
+impl Deduplicator {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: Mutex::new((HashSet::new(), VecDeque::new())),
+        }
+    }
+
+    //第一次见到时返回true
+    pub(crate) fn first_seen(&self, key: &str) -> bool {
+        let mut guard = self.seen.lock().unwrap();
+        let (keys, order) = &mut *guard;
+        if !keys.insert(String::from(key)) {
+            return false;
+        }
+        order.push_back(String::from(key));
+        if order.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                keys.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub(crate) fn forget(&self, key: &str) {
+        let mut guard = self.seen.lock().unwrap();
+        let (keys, order) = &mut *guard;
+        keys.remove(key);
+        order.retain(|it| it != key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::newtypes::tenant_id::TenantId;
+    use crate::channel::SmtpConfig;
+    use crate::notification::Inbox;
+    use crate::preference::{NotificationPreference, QuietHours};
+    use card::events::CardEventKind;
+    use chrono::NaiveTime;
+    use chrono_tz::Tz;
+    use std::time::Duration;
+
+    #[test]
+    fn test_deduplicator() {
+        let deduplicator = Deduplicator::new(2);
+        assert!(deduplicator.first_seen("a"));
+        assert!(!deduplicator.first_seen("a"));
+        assert!(deduplicator.first_seen("b"));
+        assert!(deduplicator.first_seen("c")); //a被淘汰
+        assert!(deduplicator.first_seen("a"));
+        deduplicator.forget("a");
+        assert!(deduplicator.first_seen("a"));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_once() {
+        let inbox = Arc::new(Inbox::default());
+        let smtp = SmtpConfig {
+            addr: String::from("127.0.0.1:1"), //不可达的地址，邮件投递必然失败
+            from: String::from("noreply@raccoon.local"),
+        };
+        let retry_policy = RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        };
+        let dispatcher = Dispatcher::new(Arc::new(SubscriptionRegistry::default()), Arc::new(PreferenceRegistry::default()), Arc::new(WebhookService::new(RetryPolicy::default())), Deliverer::new(inbox.clone(), smtp), retry_policy);
+        let event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "t1", CardEventKind::Archived, &CardId::from_str("m2"));
+        let member_id = CardId::from_str("m1");
+        let notification = Notification::from_event(&event, &member_id);
+        let channels = HashSet::from([Channel::InApp, Channel::Email(String::from("m1@raccoon.local"))]);
+
+        dispatcher.deliver(&notification, &channels).await;
+        dispatcher.deliver(&notification, &channels).await;
+        assert_eq!(inbox.list(&TenantId::from_str("o1"), &member_id, false).len(), 1);
+        //邮件投递失败，不应被记为已投递
+        assert!(dispatcher.deduplicator.first_seen(&format!("{}:m1:email:m1@raccoon.local", event.id)));
+    }
+
+    #[tokio::test]
+    async fn test_buffer_during_quiet_hours() {
+        let inbox = Arc::new(Inbox::default());
+        let preferences = Arc::new(PreferenceRegistry::default());
+        let member_id = CardId::from_str("m1");
+        //全天免打扰
+        preferences.set(&TenantId::from_str("o1"), &member_id, NotificationPreference {
+            digest: DigestFrequency::Immediate,
+            quiet_hours: Some(QuietHours {
+                start: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
+            }),
+            timezone: Tz::UTC,
+        });
+        let dispatcher = Dispatcher::new(Arc::new(SubscriptionRegistry::default()), preferences.clone(), Arc::new(WebhookService::new(RetryPolicy::default())), Deliverer::new(inbox.clone(), SmtpConfig::default()), RetryPolicy::default());
+        let channels = HashSet::from([Channel::InApp]);
+        for kind in [CardEventKind::Created, CardEventKind::Archived] {
+            let event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "t1", kind, &CardId::from_str("m2"));
+            dispatcher.deliver_or_buffer(&Notification::from_event(&event, &member_id), &channels).await;
+        }
+        assert!(inbox.list(&TenantId::from_str("o1"), &member_id, false).is_empty());
+
+        //免打扰结束后，两次变更合并成一条汇总
+        preferences.set(&TenantId::from_str("o1"), &member_id, NotificationPreference::default());
+        dispatcher.flush_digests().await;
+        let notifications = inbox.list(&TenantId::from_str("o1"), &member_id, false);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].title, "1张卡片发生了2次变更");
+    }
+}
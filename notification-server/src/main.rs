@@ -1,3 +1,76 @@
-fn main() {
-    println!("Hello, world!");
+use crate::access::SharedAccess;
+use crate::api::AppState;
+use crate::auth::Authentication;
+use crate::channel::{Deliverer, SmtpConfig};
+use crate::dispatcher::Dispatcher;
+use crate::notification::Inbox;
+use crate::preference::PreferenceRegistry;
+use crate::retry::RetryPolicy;
+use crate::subscription::SubscriptionRegistry;
+use crate::webhook::WebhookService;
+use actix_web::middleware::from_fn;
+use actix_web::{web, App, HttpServer};
+use rbac::access::AccessControl;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+mod access;
+mod api;
+mod auth;
+mod channel;
+mod digest;
+mod dispatcher;
+mod notification;
+mod preference;
+mod retry;
+mod subscription;
+mod webhook;
+
+const EVENT_QUEUE_CAPACITY: usize = 10_000;
+const DIGEST_CHECK_PERIOD: Duration = Duration::from_secs(60);
+const ACCESS_REFRESH_PERIOD: Duration = Duration::from_secs(60);
+
+//通知服务：接收卡片事件，匹配成员的订阅后通过站内信、邮件、webhook投递通知，并推送给组织的webhook订阅
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    //成员的JWT与卡片服务使用相同的签发密钥，内部服务使用共享的服务令牌
+    let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET is not set");
+    let service_token = std::env::var("SERVICE_TOKEN").expect("SERVICE_TOKEN is not set");
+    let authentication = web::Data::new(Authentication::new(&jwt_secret, &service_token));
+    let registry = SubscriptionRegistry::load().await.map_err(|err| std::io::Error::other(format!("failed to load subscriptions: {}", err)))?;
+    let registry = Arc::new(registry);
+    let inbox = Inbox::load().await.map_err(|err| std::io::Error::other(format!("failed to load inbox: {}", err)))?;
+    let inbox = Arc::new(inbox);
+    let preferences = Arc::new(PreferenceRegistry::default());
+    let webhooks = Arc::new(WebhookService::new(RetryPolicy::default()));
+    webhooks.load().await.map_err(|err| std::io::Error::other(format!("failed to load webhooks: {}", err)))?;
+    let (event_sender, event_receiver) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+
+    let deliverer = Deliverer::new(inbox.clone(), SmtpConfig::default());
+    let access = AccessControl::default();
+    access.load().await.map_err(|err| std::io::Error::other(format!("failed to load access control: {}", err)))?;
+    let access = Arc::new(SharedAccess::new(access));
+    let dispatcher = Arc::new(Dispatcher::new(registry.clone(), preferences.clone(), webhooks.clone(), deliverer, RetryPolicy::default()).with_access(access.clone()));
+    tokio::spawn(dispatcher.clone().run(event_receiver));
+    tokio::spawn(access.clone().run_refresh(ACCESS_REFRESH_PERIOD));
+    tokio::spawn(dispatcher.run_digest(DIGEST_CHECK_PERIOD));
+
+    let app_state = web::Data::new(AppState {
+        registry,
+        inbox,
+        preferences,
+        webhooks,
+        access,
+        event_sender,
+    });
+    HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .app_data(authentication.clone())
+            .service(web::scope("").wrap(from_fn(auth::authenticate)).configure(api::config))
+    })
+        .bind(("127.0.0.1", 8081))?
+        .run()
+        .await
 }
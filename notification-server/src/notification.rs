@@ -0,0 +1,208 @@
+use card::events::{CardEvent, CardEventKind};
+use card::settings::SettingStore;
+use common::id_generator;
+use common::newtypes::tenant_id::TenantId;
+use common::newtypes::card_id::CardId;
+use common::newtypes::timestamp::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error;
+use std::sync::Mutex;
+
+pub const NOTIFICATION_SETTING: &str = "Notification";
+
+//发给某个成员的一条通知
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    pub id: String,
+    pub org_id: TenantId,
+    pub member_id: CardId,
+    pub source: NotificationSource,
+    pub title: String,
+    pub content: String,
+    pub create_time: Timestamp,
+    pub read: bool,
+}
+
+//通知的来源，单个卡片事件或者多个事件的汇总
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NotificationSource {
+    Event { event_id: String, card_id: CardId },
+    Digest(Vec<DigestItem>),
+}
+
+//汇总中的一张卡片，同一张卡片的多次变更合并为一项
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DigestItem {
+    pub card_id: CardId,
+    pub changes: Vec<String>,
+}
+
+impl Notification {
+    pub fn from_event(event: &CardEvent, member_id: &CardId) -> Self {
+        let title = title_of(event);
+        Self {
+            id: id_generator::generate_id(),
+            org_id: event.org_id.clone(),
+            member_id: member_id.clone(),
+            source: NotificationSource::Event {
+                event_id: event.id.clone(),
+                card_id: event.card_id.clone(),
+            },
+            content: title.clone(),
+            title,
+            create_time: Timestamp::now(),
+            read: false,
+        }
+    }
+
+    //业务规则发出的通知，内容由规则指定
+    pub fn with_message(event: &CardEvent, member_id: &CardId, message: &str) -> Self {
+        let mut notification = Self::from_event(event, member_id);
+        notification.content = String::from(message);
+        notification
+    }
+
+    pub fn digest(org_id: &TenantId, member_id: &CardId, items: Vec<DigestItem>) -> Self {
+        let change_count: usize = items.iter().map(|it| it.changes.len()).sum();
+        let title = format!("{}张卡片发生了{}次变更", items.len(), change_count);
+        let mut content = String::new();
+        for item in &items {
+            content.push_str(&format!("{}：{}\n", item.card_id, item.changes.join("；")));
+        }
+        Self {
+            id: id_generator::generate_id(),
+            org_id: org_id.clone(),
+            member_id: member_id.clone(),
+            source: NotificationSource::Digest(items),
+            title,
+            content,
+            create_time: Timestamp::now(),
+            read: false,
+        }
+    }
+
+    //用于投递去重，同一事件生成的通知key相同
+    pub fn deduplication_key(&self) -> &str {
+        match &self.source {
+            NotificationSource::Event { event_id, .. } => event_id,
+            NotificationSource::Digest(_) => &self.id,
+        }
+    }
+}
+
+fn title_of(event: &CardEvent) -> String {
+    let card_id = &event.card_id;
+    match &event.kind {
+        CardEventKind::Created => format!("卡片{card_id}已创建"),
+        CardEventKind::Updated(_) => format!("卡片{card_id}已更新"),
+        CardEventKind::Archived => format!("卡片{card_id}已归档"),
+        CardEventKind::Abandoned(reason) => format!("卡片{card_id}已丢弃：{reason}"),
+        CardEventKind::Restored => format!("卡片{card_id}已还原"),
+        CardEventKind::FlowStatusChanged { to, .. } => format!("卡片{card_id}流转到了{to}"),
+        CardEventKind::Linked(_, target) => format!("卡片{card_id}关联了卡片{target}"),
+        CardEventKind::Unlinked(_, target) => format!("卡片{card_id}解除了与卡片{target}的关联"),
+    }
+}
+
+//站内信收件箱，按组织和成员分开存放
+//从配置存储加载的收件箱在修改时先保存，默认的收件箱只在内存中
+#[derive(Default)]
+pub struct Inbox {
+    notifications: Mutex<HashMap<(TenantId, CardId), Vec<Notification>>>,
+    persistent: bool,
+}
+
+impl Inbox {
+    pub async fn load() -> Result<Self, Box<dyn error::Error>> {
+        let mut loaded: Vec<Notification> = SettingStore::load_all(NOTIFICATION_SETTING).await?;
+        loaded.sort_by(|a, b| a.create_time.cmp(&b.create_time));
+        let inbox = Self { persistent: true, ..Self::default() };
+        for notification in loaded {
+            inbox.insert(notification);
+        }
+        Ok(inbox)
+    }
+
+    pub async fn put(&self, notification: Notification) -> Result<(), Box<dyn error::Error>> {
+        if self.persistent {
+            SettingStore::save(&notification.org_id, NOTIFICATION_SETTING, &notification.id, &notification).await?;
+        }
+        self.insert(notification);
+        Ok(())
+    }
+
+    fn insert(&self, notification: Notification) {
+        let mut notifications = self.notifications.lock().unwrap();
+        notifications.entry((notification.org_id.clone(), notification.member_id.clone())).or_default().push(notification);
+    }
+
+    //按时间倒序返回
+    pub fn list(&self, org_id: &TenantId, member_id: &CardId, unread_only: bool) -> Vec<Notification> {
+        let notifications = self.notifications.lock().unwrap();
+        let mut list: Vec<Notification> = notifications.get(&(org_id.clone(), member_id.clone()))
+            .map(|it| it.iter().filter(|n| !unread_only || !n.read).cloned().collect())
+            .unwrap_or_default();
+        list.reverse();
+        list
+    }
+
+    pub async fn mark_read(&self, org_id: &TenantId, member_id: &CardId, notification_id: &str) -> Result<bool, Box<dyn error::Error>> {
+        let found = self.notifications.lock().unwrap().get(&(org_id.clone(), member_id.clone()))
+            .and_then(|it| it.iter().find(|n| n.id == notification_id).cloned());
+        let Some(mut notification) = found else {
+            return Ok(false);
+        };
+        notification.read = true;
+        if self.persistent {
+            SettingStore::save(org_id, NOTIFICATION_SETTING, notification_id, &notification).await?;
+        }
+        let mut notifications = self.notifications.lock().unwrap();
+        if let Some(n) = notifications.get_mut(&(org_id.clone(), member_id.clone()))
+            .and_then(|it| it.iter_mut().find(|n| n.id == notification_id)) {
+            n.read = true;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_inbox() {
+        let inbox = Inbox::default();
+        let member_id = CardId::from_str("m1");
+        let event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "t1", CardEventKind::Archived, &CardId::from_str("m2"));
+        let first = Notification::from_event(&event, &member_id);
+        let second = Notification::from_event(&event, &member_id);
+        inbox.put(first.clone()).await.unwrap();
+        inbox.put(second.clone()).await.unwrap();
+        assert_eq!(first.title, "卡片c1已归档");
+
+        let org_id = TenantId::from_str("o1");
+        assert!(inbox.mark_read(&org_id, &member_id, &first.id).await.unwrap());
+        let unread = inbox.list(&org_id, &member_id, true);
+        assert_eq!(unread, vec![second.clone()]);
+        let all = inbox.list(&org_id, &member_id, false);
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].id, second.id);
+        assert!(inbox.list(&org_id, &CardId::from_str("m2"), false).is_empty());
+        //同一成员在其他组织中看不到这些通知
+        assert!(inbox.list(&TenantId::from_str("o2"), &member_id, false).is_empty());
+        assert!(!inbox.mark_read(&TenantId::from_str("o2"), &member_id, &second.id).await.unwrap());
+    }
+
+    #[test]
+    fn test_digest() {
+        let member_id = CardId::from_str("m1");
+        let digest = Notification::digest(&TenantId::from_str("o1"), &member_id, vec![
+            DigestItem { card_id: CardId::from_str("c1"), changes: vec!["卡片c1已更新".to_string(), "卡片c1已归档".to_string()] },
+            DigestItem { card_id: CardId::from_str("c2"), changes: vec!["卡片c2已创建".to_string()] },
+        ]);
+        assert_eq!(digest.title, "2张卡片发生了3次变更");
+        assert_eq!(digest.content, "c1：卡片c1已更新；卡片c1已归档\nc2：卡片c2已创建\n");
+        assert_eq!(digest.deduplication_key(), digest.id);
+    }
+}
@@ -0,0 +1,176 @@
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use common::newtypes::card_id::CardId;
+use common::newtypes::tenant_id::TenantId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+//成员的通知偏好
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NotificationPreference {
+    pub digest: DigestFrequency,
+    pub quiet_hours: Option<QuietHours>,
+    pub timezone: Tz,
+}
+
+//通知汇总的频率
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DigestFrequency {
+    Immediate, //立即发送
+    Hourly, //每小时汇总发送一次
+    Daily(u32), //每天在成员所在时区的某个整点汇总发送一次
+}
+
+//免打扰时段，成员所在时区的本地时间，结束时间早于开始时间表示跨越午夜
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl Default for NotificationPreference {
+    fn default() -> Self {
+        Self {
+            digest: DigestFrequency::Immediate,
+            quiet_hours: None,
+            timezone: Tz::UTC,
+        }
+    }
+}
+
+impl NotificationPreference {
+    pub fn is_quiet(&self, now: DateTime<Utc>) -> bool {
+        match &self.quiet_hours {
+            Some(quiet_hours) => {
+                let local = now.with_timezone(&self.timezone).time();
+                if quiet_hours.start <= quiet_hours.end {
+                    local >= quiet_hours.start && local < quiet_hours.end
+                } else {
+                    local >= quiet_hours.start || local < quiet_hours.end
+                }
+            }
+            None => false,
+        }
+    }
+
+    //当前时刻所在汇总周期的起点，两个时刻的周期起点不同说明跨越了汇总周期
+    pub fn period_start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let local = time.with_timezone(&self.timezone);
+        let start = match self.digest {
+            DigestFrequency::Immediate => return time,
+            DigestFrequency::Hourly => local.with_minute(0).and_then(|it| it.with_second(0)).and_then(|it| it.with_nanosecond(0)),
+            DigestFrequency::Daily(hour) => {
+                let today = self.timezone
+                    .with_ymd_and_hms(local.year(), local.month(), local.day(), hour, 0, 0)
+                    .earliest();
+                match today {
+                    Some(today) if today > local => today.checked_sub_signed(chrono::Duration::days(1)),
+                    other => other,
+                }
+            }
+        };
+        //夏令时切换导致本地时间不存在时，退化为按小时汇总
+        start.map(|it| it.with_timezone(&Utc))
+            .unwrap_or_else(|| time - chrono::Duration::seconds(time.timestamp() % 3600))
+    }
+
+    //从第一条待发送通知产生到现在，是否应该把汇总发出去
+    pub fn is_due(&self, pending_since: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        if self.is_quiet(now) {
+            return false;
+        }
+        match self.digest {
+            DigestFrequency::Immediate => true,
+            _ => self.period_start(now) > self.period_start(pending_since),
+        }
+    }
+}
+
+//同一成员在不同组织中的偏好互不影响
+#[derive(Default)]
+pub struct PreferenceRegistry {
+    preferences: Mutex<HashMap<(TenantId, CardId), NotificationPreference>>,
+}
+
+impl PreferenceRegistry {
+    pub fn get(&self, org_id: &TenantId, member_id: &CardId) -> NotificationPreference {
+        let preferences = self.preferences.lock().unwrap();
+        preferences.get(&(org_id.clone(), member_id.clone())).cloned().unwrap_or_default()
+    }
+
+    pub fn set(&self, org_id: &TenantId, member_id: &CardId, preference: NotificationPreference) {
+        let mut preferences = self.preferences.lock().unwrap();
+        preferences.insert((org_id.clone(), member_id.clone()), preference);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_quiet_hours_across_midnight() {
+        let preference = NotificationPreference {
+            digest: DigestFrequency::Immediate,
+            quiet_hours: Some(QuietHours {
+                start: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
+            }),
+            timezone: Tz::Asia__Shanghai,
+        };
+        //北京时间23:00
+        assert!(preference.is_quiet(utc("2024-05-01T15:00:00Z")));
+        //北京时间07:59
+        assert!(preference.is_quiet(utc("2024-05-01T23:59:00Z")));
+        //北京时间08:00
+        assert!(!preference.is_quiet(utc("2024-05-02T00:00:00Z")));
+        assert!(!preference.is_due(utc("2024-05-01T15:00:00Z"), utc("2024-05-01T16:00:00Z")));
+        assert!(preference.is_due(utc("2024-05-01T15:00:00Z"), utc("2024-05-02T00:00:00Z")));
+    }
+
+    #[test]
+    fn test_hourly_digest() {
+        let preference = NotificationPreference {
+            digest: DigestFrequency::Hourly,
+            quiet_hours: None,
+            timezone: Tz::UTC,
+        };
+        assert!(!preference.is_due(utc("2024-05-01T10:05:00Z"), utc("2024-05-01T10:59:59Z")));
+        assert!(preference.is_due(utc("2024-05-01T10:05:00Z"), utc("2024-05-01T11:00:00Z")));
+    }
+
+    #[test]
+    fn test_daily_digest_in_timezone() {
+        let preference = NotificationPreference {
+            digest: DigestFrequency::Daily(9),
+            quiet_hours: None,
+            timezone: Tz::Asia__Shanghai,
+        };
+        //北京时间 5月1日 10:00 之后产生的通知，在 5月2日 09:00 发出
+        assert_eq!(preference.period_start(utc("2024-05-01T02:00:00Z")), utc("2024-05-01T01:00:00Z"));
+        assert_eq!(preference.period_start(utc("2024-05-01T00:30:00Z")), utc("2024-04-30T01:00:00Z"));
+        assert!(!preference.is_due(utc("2024-05-01T02:00:00Z"), utc("2024-05-02T00:59:00Z")));
+        assert!(preference.is_due(utc("2024-05-01T02:00:00Z"), utc("2024-05-02T01:00:00Z")));
+    }
+
+    #[test]
+    fn test_preference_serde() {
+        let registry = PreferenceRegistry::default();
+        let org_id = TenantId::from_str("o1");
+        let member_id = CardId::from_str("m1");
+        assert_eq!(registry.get(&org_id, &member_id), NotificationPreference::default());
+        let preference: NotificationPreference = serde_json::from_str(r#"{
+            "digest": {"Daily": 9},
+            "quiet_hours": {"start": "22:00:00", "end": "08:00:00"},
+            "timezone": "Asia/Shanghai"
+        }"#).unwrap();
+        registry.set(&org_id, &member_id, preference.clone());
+        assert_eq!(registry.get(&org_id, &member_id), preference);
+        assert_eq!(registry.get(&TenantId::from_str("o2"), &member_id), NotificationPreference::default());
+    }
+}
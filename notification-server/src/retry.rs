@@ -0,0 +1,89 @@
+use std::future::Future;
+use std::time::Duration;
+
+//重试策略，重试间隔按指数增长，直到达到最大间隔
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32, //包含第一次尝试
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    //第attempt次尝试失败后，等待多久再发起下一次尝试，attempt从1开始
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    pub async fn run<T, E, F, Fut>(&self, mut f: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(err) if attempt >= self.max_attempts => return Err(err),
+                Err(_) => {
+                    tokio::time::sleep(self.delay(attempt)).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+        };
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+        assert_eq!(policy.delay(5), Duration::from_secs(10));
+        assert_eq!(policy.delay(100), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        };
+        let counter = AtomicU32::new(0);
+        let result: Result<u32, &str> = policy.run(|| async {
+            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            if n < 3 { Err("failed") } else { Ok(n) }
+        }).await;
+        assert_eq!(result, Ok(3));
+
+        counter.store(0, Ordering::SeqCst);
+        let result: Result<u32, &str> = policy.run(|| async {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Err("failed")
+        }).await;
+        assert_eq!(result, Err("failed"));
+        assert_eq!(counter.load(Ordering::SeqCst), 3);
+    }
+}
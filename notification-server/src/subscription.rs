@@ -0,0 +1,185 @@
+use crate::channel::Channel;
+use card::events::{CardEvent, CardEventType};
+use card::settings::SettingStore;
+use card::query::{Condition, ConditionItem, LinkOperator, LinkValue, ReferPoint};
+use card::types::LinkDescriptor;
+use common::id_generator;
+use common::newtypes::tenant_id::TenantId;
+use common::newtypes::card_id::CardId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error;
+use std::sync::Mutex;
+
+pub const SUBSCRIPTION_SETTING: &str = "Subscription";
+
+//成员的通知订阅
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    pub org_id: TenantId,
+    pub member_id: CardId,
+    pub target: SubscriptionTarget,
+    pub event_types: Vec<CardEventType>, //为空时订阅所有类型的事件
+    pub channels: Vec<Channel>,
+}
+
+//订阅哪些卡片
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SubscriptionTarget {
+    CreatedByMe, //我创建的卡片
+    OwnedByMe, //我作为负责人关联的卡片
+    Condition(Condition), //满足保存条件的卡片
+}
+
+//卡片与创建人、负责人之间的关联关系类型
+pub const CREATOR_RS_TYPE: &str = "creator";
+pub const OWNER_RS_TYPE: &str = "owner";
+
+impl SubscriptionTarget {
+    //所有订阅目标都统一转换成条件，在事件到达时交给图数据库判断卡片是否满足
+    pub fn to_condition(&self) -> Condition {
+        match self {
+            SubscriptionTarget::CreatedByMe => linked_to_current_member(CREATOR_RS_TYPE),
+            SubscriptionTarget::OwnedByMe => linked_to_current_member(OWNER_RS_TYPE),
+            SubscriptionTarget::Condition(condition) => condition.clone(),
+        }
+    }
+}
+
+fn linked_to_current_member(rs_type: &str) -> Condition {
+    let mut condition = Condition::default();
+    condition.and(ConditionItem::Link(
+        LinkDescriptor::Src(String::from(rs_type)),
+        LinkOperator::AnyIn(LinkValue::ReferValue(ReferPoint::CurrentMember, vec![])),
+    ));
+    condition
+}
+
+impl Subscription {
+    pub fn new(org_id: &TenantId, member_id: &CardId, target: SubscriptionTarget, event_types: Vec<CardEventType>, channels: Vec<Channel>) -> Self {
+        Self {
+            id: id_generator::generate_id(),
+            org_id: org_id.clone(),
+            member_id: member_id.clone(),
+            target,
+            event_types,
+            channels,
+        }
+    }
+
+    //只做不需要访问数据库的初步过滤，自己触发的事件不通知自己
+    pub fn accepts(&self, event: &CardEvent) -> bool {
+        self.org_id == event.org_id
+            && self.member_id != event.operator_id
+            && (self.event_types.is_empty() || self.event_types.contains(&event.event_type()))
+    }
+}
+
+//订阅注册表，按组织分组保存
+//从配置存储加载的注册表在修改时先保存，默认的注册表只在内存中
+#[derive(Default)]
+pub struct SubscriptionRegistry {
+    subscriptions: Mutex<HashMap<TenantId, Vec<Subscription>>>,
+    persistent: bool,
+}
+
+impl SubscriptionRegistry {
+    pub async fn load() -> Result<Self, Box<dyn error::Error>> {
+        let loaded: Vec<Subscription> = SettingStore::load_all(SUBSCRIPTION_SETTING).await?;
+        let registry = Self { persistent: true, ..Self::default() };
+        for subscription in loaded {
+            registry.insert(subscription);
+        }
+        Ok(registry)
+    }
+
+    pub async fn add(&self, subscription: Subscription) -> Result<(), Box<dyn error::Error>> {
+        if self.persistent {
+            SettingStore::save(&subscription.org_id, SUBSCRIPTION_SETTING, &subscription.id, &subscription).await?;
+        }
+        self.insert(subscription);
+        Ok(())
+    }
+
+    fn insert(&self, subscription: Subscription) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.entry(subscription.org_id.clone()).or_default().push(subscription);
+    }
+
+    pub async fn remove(&self, org_id: &TenantId, subscription_id: &str) -> Result<bool, Box<dyn error::Error>> {
+        if self.persistent {
+            SettingStore::remove(org_id, SUBSCRIPTION_SETTING, subscription_id).await?;
+        }
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if let Some(org_subscriptions) = subscriptions.get_mut(org_id) {
+            let size = org_subscriptions.len();
+            org_subscriptions.retain(|it| it.id != subscription_id);
+            return Ok(org_subscriptions.len() < size);
+        }
+        Ok(false)
+    }
+
+    pub fn list_of_member(&self, org_id: &TenantId, member_id: &CardId) -> Vec<Subscription> {
+        let subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.get(org_id)
+            .map(|it| it.iter().filter(|s| &s.member_id == member_id).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    //找出可能对事件感兴趣的订阅
+    pub fn candidates(&self, event: &CardEvent) -> Vec<Subscription> {
+        let subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.get(&event.org_id)
+            .map(|it| it.iter().filter(|s| s.accepts(event)).cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use card::events::CardEventKind;
+
+    fn event(operator: &str) -> CardEvent {
+        CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), "t1", CardEventKind::Archived, &CardId::from_str(operator))
+    }
+
+    #[tokio::test]
+    async fn test_candidates() {
+        let registry = SubscriptionRegistry::default();
+        let m1 = CardId::from_str("m1");
+        let m2 = CardId::from_str("m2");
+        registry.add(Subscription::new(&TenantId::from_str("o1"), &m1, SubscriptionTarget::CreatedByMe, vec![], vec![Channel::InApp])).await.unwrap();
+        registry.add(Subscription::new(&TenantId::from_str("o1"), &m2, SubscriptionTarget::OwnedByMe, vec![CardEventType::Created], vec![Channel::InApp])).await.unwrap();
+        registry.add(Subscription::new(&TenantId::from_str("o2"), &m2, SubscriptionTarget::OwnedByMe, vec![], vec![Channel::InApp])).await.unwrap();
+
+        //m2只订阅了创建事件，m1是事件的触发人
+        assert!(registry.candidates(&event("m1")).is_empty());
+        let candidates = registry.candidates(&event("m3"));
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].member_id, m1);
+    }
+
+    #[tokio::test]
+    async fn test_remove() {
+        let registry = SubscriptionRegistry::default();
+        let m1 = CardId::from_str("m1");
+        let subscription = Subscription::new(&TenantId::from_str("o1"), &m1, SubscriptionTarget::CreatedByMe, vec![], vec![Channel::InApp]);
+        let id = subscription.id.clone();
+        registry.add(subscription).await.unwrap();
+        assert_eq!(registry.list_of_member(&TenantId::from_str("o1"), &m1).len(), 1);
+        assert!(registry.remove(&TenantId::from_str("o1"), &id).await.unwrap());
+        assert!(!registry.remove(&TenantId::from_str("o1"), &id).await.unwrap());
+        assert!(registry.list_of_member(&TenantId::from_str("o1"), &m1).is_empty());
+    }
+
+    #[test]
+    fn test_target_serde() {
+        let subscription = Subscription::new(&TenantId::from_str("o1"), &CardId::from_str("m1"), SubscriptionTarget::OwnedByMe, vec![], vec![Channel::Email("a@b.c".to_string())]);
+        let json = serde_json::to_string(&subscription.target.to_condition()).unwrap();
+        assert!(json.contains(OWNER_RS_TYPE));
+        let json = serde_json::to_string(&subscription).unwrap();
+        let _: Subscription = serde_json::from_str(&json).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+use crate::channel::DeliveryError;
+use crate::retry::RetryPolicy;
+use card::events::{CardEvent, CardEventType};
+use card::query;
+use card::query::{Condition, CURRENT_CARD_PARAMETER};
+use card::settings::SettingStore;
+use common::id_generator;
+use common::newtypes::card_id::CardId;
+use common::newtypes::tenant_id::TenantId;
+use common::newtypes::timestamp::Timestamp;
+use hmac::{Hmac, Mac};
+use rbac::access::{AccessControl, Member};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub const SIGNATURE_HEADER: &str = "X-Raccoon-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Raccoon-Timestamp";
+pub const EVENT_HEADER: &str = "X-Raccoon-Event";
+pub const DELIVERY_HEADER: &str = "X-Raccoon-Delivery";
+
+//webhook和死信在配置存储中的种类
+pub const WEBHOOK_SETTING: &str = "Webhook";
+pub const DEAD_LETTER_SETTING: &str = "WebhookDeadLetter";
+
+//组织级别的webhook订阅，供其他内部系统响应卡片变化；只推送创建者可见的卡片上的事件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    pub org_id: TenantId,
+    pub creator_id: CardId,
+    pub url: String,
+    #[serde(skip_serializing, default)]
+    pub secret: String, //签名密钥，只在创建时返回一次
+    pub card_type_ids: Vec<String>, //为空时不限卡片类型
+    pub event_types: Vec<CardEventType>, //为空时不限事件类型
+    pub condition: Option<Condition>,
+}
+
+impl Webhook {
+    pub fn new(org_id: &TenantId, creator_id: &CardId, url: &str, secret: Option<String>, card_type_ids: Vec<String>, event_types: Vec<CardEventType>, condition: Option<Condition>) -> Self {
+        Self {
+            id: id_generator::generate_id(),
+            org_id: org_id.clone(),
+            creator_id: creator_id.clone(),
+            url: String::from(url),
+            secret: secret.unwrap_or_else(|| format!("{}{}", id_generator::generate_id(), id_generator::generate_id())),
+            card_type_ids,
+            event_types,
+            condition,
+        }
+    }
+
+    //只做不需要访问数据库的过滤
+    pub fn accepts(&self, event: &CardEvent) -> bool {
+        self.org_id == event.org_id
+            && (self.card_type_ids.is_empty() || self.card_type_ids.contains(&event.card_type_id))
+            && (self.event_types.is_empty() || self.event_types.contains(&event.event_type()))
+    }
+}
+
+//序列化webhook时不包含密钥，保存时单独保存
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookSetting {
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+impl From<&Webhook> for WebhookSetting {
+    fn from(webhook: &Webhook) -> Self {
+        Self { webhook: webhook.clone(), secret: webhook.secret.clone() }
+    }
+}
+
+impl From<WebhookSetting> for Webhook {
+    fn from(setting: WebhookSetting) -> Self {
+        Self { secret: setting.secret, ..setting.webhook }
+    }
+}
+
+//投递给订阅方的负载
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    pub delivery_id: String,
+    pub webhook_id: String,
+    pub event: CardEvent,
+}
+
+//多次重试仍然失败的投递
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: String, //即delivery_id
+    pub webhook_id: String,
+    pub event: CardEvent,
+    pub attempts: u32,
+    pub last_error: String,
+    pub update_time: Timestamp,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+//签名内容为"时间戳.负载"，订阅方用相同的密钥计算后比对，并可根据时间戳拒绝重放的请求
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+pub struct WebhookService {
+    webhooks: Mutex<HashMap<TenantId, Vec<Webhook>>>,
+    dead_letters: Mutex<HashMap<TenantId, Vec<DeadLetter>>>,
+    http: reqwest::Client,
+    retry_policy: RetryPolicy,
+}
+
+impl WebhookService {
+    pub fn new(retry_policy: RetryPolicy) -> Self {
+        Self {
+            webhooks: Mutex::new(HashMap::new()),
+            dead_letters: Mutex::new(HashMap::new()),
+            http: reqwest::Client::new(),
+            retry_policy,
+        }
+    }
+
+    //启动时加载保存的webhook和死信
+    pub async fn load(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for setting in SettingStore::load_all::<WebhookSetting>(WEBHOOK_SETTING).await? {
+            self.add(Webhook::from(setting));
+        }
+        let loaded = SettingStore::load_all::<DeadLetter>(DEAD_LETTER_SETTING).await?;
+        let mut dead_letters = self.dead_letters.lock().unwrap();
+        for dead_letter in loaded {
+            dead_letters.entry(dead_letter.event.org_id.clone()).or_default().push(dead_letter);
+        }
+        Ok(())
+    }
+
+    pub fn add(&self, webhook: Webhook) {
+        let mut webhooks = self.webhooks.lock().unwrap();
+        webhooks.entry(webhook.org_id.clone()).or_default().push(webhook);
+    }
+
+    pub fn remove(&self, org_id: &TenantId, webhook_id: &str) -> bool {
+        let mut webhooks = self.webhooks.lock().unwrap();
+        if let Some(org_webhooks) = webhooks.get_mut(org_id) {
+            let size = org_webhooks.len();
+            org_webhooks.retain(|it| it.id != webhook_id);
+            return org_webhooks.len() < size;
+        }
+        false
+    }
+
+    pub fn list(&self, org_id: &TenantId) -> Vec<Webhook> {
+        let webhooks = self.webhooks.lock().unwrap();
+        webhooks.get(org_id).cloned().unwrap_or_default()
+    }
+
+    pub fn dead_letters(&self, org_id: &TenantId) -> Vec<DeadLetter> {
+        let dead_letters = self.dead_letters.lock().unwrap();
+        dead_letters.get(org_id).cloned().unwrap_or_default()
+    }
+
+    pub async fn dispatch(&self, event: &CardEvent, access: &AccessControl) {
+        let candidates: Vec<Webhook> = self.list(&event.org_id).into_iter().filter(|it| it.accepts(event)).collect();
+        for webhook in candidates {
+            if self.matches(&webhook, event, access).await {
+                let payload = WebhookPayload {
+                    delivery_id: id_generator::generate_id(),
+                    webhook_id: webhook.id.clone(),
+                    event: event.clone(),
+                };
+                if let Some(dead_letter) = self.deliver_with_retry(&webhook, payload).await {
+                    //死信保存失败时仍然保留在内存中，只是重启后丢失
+                    let saved = SettingStore::save(&dead_letter.event.org_id, DEAD_LETTER_SETTING, &dead_letter.id, &dead_letter).await.map_err(|err| err.to_string());
+                    if let Err(err) = saved {
+                        eprintln!("failed to save dead letter {}: {}", dead_letter.id, err);
+                    }
+                }
+            }
+        }
+    }
+
+    //按创建者的可见范围匹配，没有条件时卡片也必须对创建者可见，条件中的当前成员指创建者
+    async fn matches(&self, webhook: &Webhook, event: &CardEvent, access: &AccessControl) -> bool {
+        let mut parameters = HashMap::new();
+        parameters.insert(String::from(CURRENT_CARD_PARAMETER), event.card_id.to_string());
+        let creator = Member::load(webhook.creator_id.clone(), &event.org_id).await;
+        let query_context = access.query_context(&creator, parameters);
+        let condition = webhook.condition.clone().unwrap_or_default();
+        match query::matches(&event.card_id, &condition, &query_context).await {
+            Ok(matched) => matched,
+            Err(err) => {
+                eprintln!("failed to match webhook {}: {}", webhook.id, err);
+                false
+            }
+        }
+    }
+
+    //多次重试仍然失败时记为死信并返回
+    async fn deliver_with_retry(&self, webhook: &Webhook, payload: WebhookPayload) -> Option<DeadLetter> {
+        let mut attempts = 0;
+        let result = self.retry_policy.run(|| {
+            attempts += 1;
+            self.post(webhook, &payload)
+        }).await;
+        if let Err(err) = result {
+            eprintln!("failed to deliver webhook {} after {} attempts: {}", payload.delivery_id, attempts, err);
+            let dead_letter = DeadLetter {
+                id: payload.delivery_id,
+                webhook_id: payload.webhook_id,
+                event: payload.event,
+                attempts,
+                last_error: err.to_string(),
+                update_time: Timestamp::now(),
+            };
+            let mut dead_letters = self.dead_letters.lock().unwrap();
+            dead_letters.entry(webhook.org_id.clone()).or_default().push(dead_letter.clone());
+            return Some(dead_letter);
+        }
+        None
+    }
+
+    //手动重新投递死信，只尝试一次，成功后移出死信列表
+    pub async fn redeliver(&self, org_id: &TenantId, dead_letter_id: &str) -> Result<(), DeliveryError> {
+        let dead_letter = self.dead_letters(org_id).into_iter().find(|it| it.id == dead_letter_id)
+            .ok_or_else(|| DeliveryError::new("dead letter not found"))?;
+        let webhook = self.list(org_id).into_iter().find(|it| it.id == dead_letter.webhook_id)
+            .ok_or_else(|| DeliveryError::new("webhook not found"))?;
+        let payload = WebhookPayload {
+            delivery_id: dead_letter.id.clone(),
+            webhook_id: webhook.id.clone(),
+            event: dead_letter.event,
+        };
+        let result = self.post(&webhook, &payload).await;
+        let mut dead_letters = self.dead_letters.lock().unwrap();
+        let org_dead_letters = dead_letters.entry(org_id.clone()).or_default();
+        match &result {
+            Ok(_) => org_dead_letters.retain(|it| it.id != dead_letter_id),
+            Err(err) => {
+                if let Some(it) = org_dead_letters.iter_mut().find(|it| it.id == dead_letter_id) {
+                    it.attempts += 1;
+                    it.last_error = err.to_string();
+                    it.update_time = Timestamp::now();
+                }
+            }
+        }
+        result
+    }
+
+    async fn post(&self, webhook: &Webhook, payload: &WebhookPayload) -> Result<(), DeliveryError> {
+        let body = serde_json::to_vec(payload)
+            .map_err(|e| DeliveryError::new(&format!("failed to serialize payload: {e}")))?;
+        let timestamp = *Timestamp::now();
+        let response = self.http.post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, sign(&webhook.secret, timestamp, &body))
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(EVENT_HEADER, format!("{:?}", payload.event.event_type()))
+            .header(DELIVERY_HEADER, payload.delivery_id.as_str())
+            .body(body)
+            .send().await
+            .map_err(|e| DeliveryError::new(&format!("failed to post webhook: {e}")))?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(DeliveryError::new(&format!("webhook responded {}", response.status())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use card::events::CardEventKind;
+    use common::newtypes::card_id::CardId;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    //订阅方校验签名的方式
+    fn verify(secret: &str, timestamp: i64, body: &[u8], signature: &str) -> bool {
+        let Some(signature) = signature.strip_prefix("sha256=").and_then(|it| hex::decode(it).ok()) else {
+            return false;
+        };
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        mac.verify_slice(&signature).is_ok()
+    }
+
+    fn event(card_type_id: &str, kind: CardEventKind) -> CardEvent {
+        CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str("c1"), card_type_id, kind, &CardId::from_str("m1"))
+    }
+
+    //是否推送由创建者的可见范围决定，需要图数据库，这里直接投递
+    fn payload(webhook: &Webhook, event: CardEvent) -> WebhookPayload {
+        WebhookPayload { delivery_id: id_generator::generate_id(), webhook_id: webhook.id.clone(), event }
+    }
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+        }
+    }
+
+    //模拟订阅方，按顺序返回给定的状态码，并把收到的原始请求发送出去
+    async fn mock_receiver(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                //读到完整的请求头和负载
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some(pos) = text.find("\r\n\r\n") {
+                        let length = text.lines()
+                            .find_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                            .unwrap_or(0);
+                        if request.len() >= pos + 4 + length {
+                            break;
+                        }
+                    }
+                    if n == 0 {
+                        break;
+                    }
+                }
+                let response = format!("HTTP/1.1 {status} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+                sender.send(String::from_utf8_lossy(&request).to_string()).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    #[test]
+    fn test_webhook_setting() {
+        let webhook = Webhook::new(&TenantId::from_str("o1"), &CardId::from_str("m1"), "http://localhost", Some("secret".to_string()), vec![], vec![], None);
+        let json = serde_json::to_string(&WebhookSetting::from(&webhook)).unwrap();
+        let loaded = Webhook::from(serde_json::from_str::<WebhookSetting>(&json).unwrap());
+        assert_eq!(loaded.secret, "secret");
+        assert_eq!(loaded.creator_id, webhook.creator_id);
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let body = br#"{"a":1}"#;
+        let signature = sign("secret", 1700000000000, body);
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(signature, sign("secret", 1700000000000, body));
+        assert!(verify("secret", 1700000000000, body, &signature));
+        assert!(!verify("other", 1700000000000, body, &signature));
+        assert!(!verify("secret", 1700000000001, body, &signature));
+        assert!(!verify("secret", 1700000000000, body, "sha256=zz"));
+    }
+
+    #[test]
+    fn test_accepts() {
+        let webhook = Webhook::new(&TenantId::from_str("o1"), &CardId::from_str("m1"), "http://localhost", None, vec!["需求".to_string()], vec![CardEventType::Created], None);
+        assert!(webhook.accepts(&event("需求", CardEventKind::Created)));
+        assert!(!webhook.accepts(&event("任务", CardEventKind::Created)));
+        assert!(!webhook.accepts(&event("需求", CardEventKind::Archived)));
+        let webhook = Webhook::new(&TenantId::from_str("o2"), &CardId::from_str("m1"), "http://localhost", None, vec![], vec![], None);
+        assert!(!webhook.accepts(&event("需求", CardEventKind::Created)));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_signed_payload() {
+        let (url, mut requests) = mock_receiver(vec![500, 200]).await;
+        let service = WebhookService::new(retry_policy());
+        let webhook = Webhook::new(&TenantId::from_str("o1"), &CardId::from_str("m1"), &url, Some("secret".to_string()), vec![], vec![], None);
+        assert!(service.deliver_with_retry(&webhook, payload(&webhook, event("需求", CardEventKind::Created))).await.is_none());
+
+        requests.recv().await.unwrap();
+        let request = requests.recv().await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let header = |name: &str| head.lines()
+            .find_map(|l| l.split_once(": ").filter(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.to_string()))
+            .unwrap();
+        let timestamp: i64 = header(TIMESTAMP_HEADER).parse().unwrap();
+        assert!(verify("secret", timestamp, body.as_bytes(), &header(SIGNATURE_HEADER)));
+        assert_eq!(header(EVENT_HEADER), "Created");
+        let payload: WebhookPayload = serde_json::from_str(body).unwrap();
+        assert_eq!(payload.delivery_id, header(DELIVERY_HEADER));
+        assert!(service.dead_letters(&TenantId::from_str("o1")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_and_redeliver() {
+        let (url, _requests) = mock_receiver(vec![500, 500, 500, 200]).await;
+        let service = WebhookService::new(retry_policy());
+        let webhook = Webhook::new(&TenantId::from_str("o1"), &CardId::from_str("m1"), &url, None, vec![], vec![], None);
+        service.add(webhook.clone());
+        assert!(service.deliver_with_retry(&webhook, payload(&webhook, event("需求", CardEventKind::Archived))).await.is_some());
+
+        let dead_letters = service.dead_letters(&TenantId::from_str("o1"));
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].attempts, 3);
+        service.redeliver(&TenantId::from_str("o1"), &dead_letters[0].id).await.unwrap();
+        assert!(service.dead_letters(&TenantId::from_str("o1")).is_empty());
+        assert!(service.redeliver(&TenantId::from_str("o1"), &dead_letters[0].id).await.is_err());
+    }
+}
@@ -0,0 +1,505 @@
+use crate::grant::{Grant, Scope};
+use crate::role::{Action, Role};
+use card::card::Card;
+use card::settings::SettingStore;
+use card::team::TeamStore;
+use card::query::{FieldRestriction, QueryContext, VisibilityFilter, Yields};
+use common::newtypes::card_id::CardId;
+use common::newtypes::field_id::FieldId;
+use common::newtypes::tenant_id::TenantId;
+use schema::card_types::{FieldAccess, FieldRule, Grantee, Permission};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::sync::RwLock;
+use std::{error, fmt};
+
+//发起操作的成员，以及他所在的团队
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub id: CardId,
+    pub org_id: TenantId,
+    pub team_ids: Vec<CardId>,
+}
+
+impl Member {
+    pub fn new(id: CardId, org_id: &TenantId, team_ids: Vec<CardId>) -> Self {
+        Self {
+            id,
+            org_id: org_id.clone(),
+            team_ids,
+        }
+    }
+
+    //授予上级团队的角色对下级团队的成员同样生效，所以团队包括所有上级团队
+    pub async fn load(id: CardId, org_id: &TenantId) -> Self {
+        let team_ids = TeamStore::teams_of(org_id, &id, true).await.unwrap_or_else(|err| {
+            eprintln!("failed to load teams of member {}: {}", id, err);
+            Vec::new()
+        });
+        Self::new(id, org_id, team_ids)
+    }
+
+    fn is(&self, grantee: &Grantee) -> bool {
+        match grantee {
+            Grantee::Member(member_id) => self.id.as_str() == member_id,
+            Grantee::Team(team_id) => self.team_ids.iter().any(|it| it.as_str() == team_id),
+        }
+    }
+}
+
+//角色、授权和可见性在配置存储中的种类；组织范围的授权单独保存，卡片类型范围的授权和属性规则随卡片类型的授权设置保存
+pub const ROLE_SETTING: &str = "Role";
+pub const GRANT_SETTING: &str = "Grant";
+pub const PERMISSION_SETTING: &str = "CardTypePermission";
+pub const VISIBILITY_SETTING: &str = "VisibilityFilter";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PermissionSetting {
+    pub org_id: TenantId,
+    pub card_type_id: String,
+    pub permission: Permission,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VisibilitySetting {
+    pub org_id: TenantId,
+    pub filter: VisibilityFilter,
+}
+
+#[derive(Default)]
+pub struct AccessControl {
+    roles: RwLock<HashMap<String, Role>>, //key为角色id
+    grants: RwLock<HashMap<TenantId, Vec<Grant>>>, //按组织分组
+    visibility_filters: RwLock<HashMap<TenantId, Vec<VisibilityFilter>>>, //按组织分组，每个卡片类型最多一个
+    field_rules: RwLock<HashMap<(TenantId, String), Vec<FieldRule>>>, //key为组织id和卡片类型id
+}
+
+impl AccessControl {
+    pub fn add_role(&self, role: Role) {
+        self.roles.write().unwrap().insert(role.id.clone(), role);
+    }
+
+    //删除角色时一并收回该角色的授权
+    pub fn remove_role(&self, org_id: &TenantId, role_id: &str) -> bool {
+        let mut roles = self.roles.write().unwrap();
+        if roles.get(role_id).is_none_or(|it| it.org_id != *org_id) {
+            return false;
+        }
+        roles.remove(role_id);
+        if let Some(org_grants) = self.grants.write().unwrap().get_mut(org_id) {
+            org_grants.retain(|it| it.role_id != role_id);
+        }
+        true
+    }
+
+    pub fn roles(&self, org_id: &TenantId) -> Vec<Role> {
+        self.roles.read().unwrap().values().filter(|it| it.org_id == *org_id).cloned().collect()
+    }
+
+    pub fn grant(&self, grant: Grant) -> Result<(), AccessDenied> {
+        if self.roles.read().unwrap().get(&grant.role_id).is_none_or(|it| it.org_id != grant.org_id) {
+            return Err(AccessDenied::new(&format!("role {} not found in org {}", grant.role_id, grant.org_id)));
+        }
+        self.grants.write().unwrap().entry(grant.org_id.clone()).or_default().push(grant);
+        Ok(())
+    }
+
+    pub fn revoke(&self, org_id: &TenantId, grant_id: &str) -> bool {
+        if let Some(org_grants) = self.grants.write().unwrap().get_mut(org_id) {
+            let size = org_grants.len();
+            org_grants.retain(|it| it.id != grant_id);
+            return org_grants.len() < size;
+        }
+        false
+    }
+
+    pub fn grants(&self, org_id: &TenantId) -> Vec<Grant> {
+        self.grants.read().unwrap().get(org_id).cloned().unwrap_or_default()
+    }
+
+    //用卡片类型上定义的授权替换该卡片类型原有的授权
+    pub fn apply_card_type_permission(&self, org_id: &TenantId, card_type_id: &str, permission: &Permission) -> Result<(), AccessDenied> {
+        let scope = Scope::CardType(String::from(card_type_id));
+        let grants: Vec<Grant> = permission.grants().iter()
+            .map(|it| Grant::new(org_id, &it.role_id, it.grantee.clone(), scope.clone()))
+            .collect();
+        {
+            let roles = self.roles.read().unwrap();
+            if let Some(grant) = grants.iter().find(|it| roles.get(&it.role_id).is_none_or(|role| role.org_id != *org_id)) {
+                return Err(AccessDenied::new(&format!("role {} not found in org {}", grant.role_id, org_id)));
+            }
+        }
+        let mut all_grants = self.grants.write().unwrap();
+        let org_grants = all_grants.entry(org_id.clone()).or_default();
+        org_grants.retain(|it| it.scope != scope);
+        org_grants.extend(grants);
+        self.field_rules.write().unwrap().insert((org_id.clone(), String::from(card_type_id)), permission.field_rules().to_vec());
+        Ok(())
+    }
+
+    //成员在某个卡片类型上拥有的角色，包括组织范围的角色
+    fn role_ids(&self, member: &Member, card_type_id: &str) -> HashSet<String> {
+        self.grants.read().unwrap().get(&member.org_id).map(|org_grants| {
+            org_grants.iter()
+                .filter(|it| it.covers(card_type_id) && member.is(&it.grantee))
+                .map(|it| it.role_id.clone())
+                .collect()
+        }).unwrap_or_default()
+    }
+
+    //查询时返回的属性，去掉成员不可读的属性
+    pub fn yields(&self, member: &Member, fields: Vec<FieldId>) -> Yields {
+        let mut restrictions = Vec::new();
+        for ((org_id, card_type_id), rules) in self.field_rules.read().unwrap().iter() {
+            if *org_id != member.org_id {
+                continue;
+            }
+            let role_ids = self.role_ids(member, card_type_id);
+            for rule in rules {
+                let Some(read) = &rule.read else { continue };
+                let readable_in = if !has_role(read, &role_ids) {
+                    None
+                } else if !read.flow_status_ids.is_empty() {
+                    Some(read.flow_status_ids.clone())
+                } else {
+                    continue;
+                };
+                restrictions.push(FieldRestriction {
+                    card_type_id: card_type_id.clone(),
+                    field_id: FieldId::from_str(&rule.field_id),
+                    readable_in,
+                });
+            }
+        }
+        Yields::new(fields).with_restrictions(restrictions)
+    }
+
+    //修改属性前检查每个属性都可写，flow_status_id为卡片当前的价值流状态
+    pub fn ensure_writable(&self, member: &Member, card_type_id: &str, flow_status_id: Option<&str>, field_ids: &[&FieldId]) -> Result<(), AccessDenied> {
+        let field_rules = self.field_rules.read().unwrap();
+        let Some(rules) = field_rules.get(&(member.org_id.clone(), String::from(card_type_id))) else {
+            return Ok(());
+        };
+        let role_ids = self.role_ids(member, card_type_id);
+        for field_id in field_ids {
+            let writable = rules.iter()
+                .filter(|it| it.field_id == field_id.as_str())
+                .filter_map(|it| it.write.as_ref())
+                .all(|it| has_role(it, &role_ids) && in_flow_status(it, flow_status_id));
+            if !writable {
+                return Err(AccessDenied::new(&format!("member {} is not allowed to edit field {} on card type {}", member.id, field_id, card_type_id)));
+            }
+        }
+        Ok(())
+    }
+
+    //设置卡片类型的行级可见性，替换原有的设置
+    pub fn set_visibility(&self, org_id: &TenantId, filter: VisibilityFilter) {
+        let mut filters = self.visibility_filters.write().unwrap();
+        let org_filters = filters.entry(org_id.clone()).or_default();
+        org_filters.retain(|it| it.card_type_id != filter.card_type_id);
+        org_filters.push(filter);
+    }
+
+    pub fn remove_visibility(&self, org_id: &TenantId, card_type_id: &str) -> bool {
+        if let Some(org_filters) = self.visibility_filters.write().unwrap().get_mut(org_id) {
+            let size = org_filters.len();
+            org_filters.retain(|it| it.card_type_id != card_type_id);
+            return org_filters.len() < size;
+        }
+        false
+    }
+
+    pub fn visibility_filters(&self, org_id: &TenantId) -> Vec<VisibilityFilter> {
+        self.visibility_filters.read().unwrap().get(org_id).cloned().unwrap_or_default()
+    }
+
+    //成员发起查询时使用的上下文，可见性条件和可以查看的卡片类型会注入到每一次查询中
+    pub fn query_context(&self, member: &Member, parameters: HashMap<String, String>) -> QueryContext {
+        let context = QueryContext::new(&member.org_id, &member.id, parameters).with_visibility_filters(self.visibility_filters(&member.org_id));
+        match self.viewable_card_types(member) {
+            Some(card_type_ids) => context.with_viewable_card_types(card_type_ids),
+            None => context,
+        }
+    }
+
+    //成员有查看权限的卡片类型，组织范围的授权包含查看权限时返回None，表示不限
+    fn viewable_card_types(&self, member: &Member) -> Option<Vec<String>> {
+        if self.check_org(member, Action::View) {
+            return None;
+        }
+        let grants = self.grants.read().unwrap();
+        let roles = self.roles.read().unwrap();
+        let card_type_ids = grants.get(&member.org_id).into_iter().flatten()
+            .filter(|it| member.is(&it.grantee) && roles.get(&it.role_id).is_some_and(|role| role.allows(Action::View)))
+            .filter_map(|it| match &it.scope {
+                Scope::CardType(card_type_id) => Some(card_type_id.clone()),
+                Scope::Org => None,
+            })
+            .collect::<BTreeSet<String>>();
+        Some(card_type_ids.into_iter().collect())
+    }
+
+    //启动时加载保存在图数据库中的角色、授权和可见性，角色先于引用它的授权加载
+    pub async fn load(&self) -> Result<(), Box<dyn error::Error>> {
+        for role in SettingStore::load_all::<Role>(ROLE_SETTING).await? {
+            self.add_role(role);
+        }
+        for grant in SettingStore::load_all::<Grant>(GRANT_SETTING).await? {
+            if let Err(err) = self.grant(grant) {
+                eprintln!("skipped grant: {}", err);
+            }
+        }
+        for setting in SettingStore::load_all::<PermissionSetting>(PERMISSION_SETTING).await? {
+            if let Err(err) = self.apply_card_type_permission(&setting.org_id, &setting.card_type_id, &setting.permission) {
+                eprintln!("skipped permission of card type {}: {}", setting.card_type_id, err);
+            }
+        }
+        for setting in SettingStore::load_all::<VisibilitySetting>(VISIBILITY_SETTING).await? {
+            self.set_visibility(&setting.org_id, setting.filter);
+        }
+        Ok(())
+    }
+
+    //组织还没有任何人能维护定义时，创建管理员角色并授予给指定成员，返回需要保存的角色和授权
+    pub fn bootstrap_admin(&self, org_id: &TenantId, member_id: &str) -> Option<(Role, Grant)> {
+        let managed = {
+            let grants = self.grants.read().unwrap();
+            let roles = self.roles.read().unwrap();
+            grants.get(org_id).into_iter().flatten()
+                .filter(|it| it.scope == Scope::Org)
+                .filter_map(|it| roles.get(&it.role_id))
+                .any(|it| it.allows(Action::ManageSchema))
+        };
+        if managed {
+            return None;
+        }
+        let admin = Role::admin(org_id);
+        let grant = Grant::new(org_id, &admin.id, Grantee::Member(String::from(member_id)), Scope::Org);
+        self.add_role(admin.clone());
+        self.grant(grant.clone()).ok()?;
+        Some((admin, grant))
+    }
+
+    pub fn check(&self, member: &Member, action: Action, card: &Card) -> bool {
+        self.check_card_type(member, action, &card.org_id, card.card_type_id)
+    }
+
+    //创建卡片、维护卡片类型定义等没有具体卡片的操作
+    pub fn check_card_type(&self, member: &Member, action: Action, org_id: &TenantId, card_type_id: &str) -> bool {
+        //不能跨组织操作
+        if member.org_id != *org_id {
+            return false;
+        }
+        let grants = self.grants.read().unwrap();
+        let roles = self.roles.read().unwrap();
+        grants.get(org_id).is_some_and(|org_grants| {
+            org_grants.iter()
+                .filter(|it| it.covers(card_type_id) && member.is(&it.grantee))
+                .filter_map(|it| roles.get(&it.role_id))
+                .any(|it| it.allows(action))
+        })
+    }
+
+    //与卡片类型无关的操作，只看组织范围的授权
+    pub fn check_org(&self, member: &Member, action: Action) -> bool {
+        let grants = self.grants.read().unwrap();
+        let roles = self.roles.read().unwrap();
+        grants.get(&member.org_id).is_some_and(|org_grants| {
+            org_grants.iter()
+                .filter(|it| it.scope == Scope::Org && member.is(&it.grantee))
+                .filter_map(|it| roles.get(&it.role_id))
+                .any(|it| it.allows(action))
+        })
+    }
+
+    pub fn ensure(&self, member: &Member, action: Action, org_id: &TenantId, card_type_id: &str) -> Result<(), AccessDenied> {
+        if self.check_card_type(member, action, org_id, card_type_id) {
+            Ok(())
+        } else {
+            Err(AccessDenied::new(&format!("member {} is not allowed to {:?} on card type {}", member.id, action, card_type_id)))
+        }
+    }
+}
+
+fn has_role(access: &FieldAccess, role_ids: &HashSet<String>) -> bool {
+    access.role_ids.is_empty() || access.role_ids.iter().any(|it| role_ids.contains(it))
+}
+
+fn in_flow_status(access: &FieldAccess, flow_status_id: Option<&str>) -> bool {
+    access.flow_status_ids.is_empty() || flow_status_id.is_some_and(|status_id| access.flow_status_ids.iter().any(|it| it == status_id))
+}
+
+#[derive(Debug)]
+pub struct AccessDenied {
+    message: String,
+}
+
+impl AccessDenied {
+    pub fn new(message: &str) -> Self {
+        Self { message: message.to_string() }
+    }
+}
+
+impl Display for AccessDenied {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for AccessDenied {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use card::query::{Condition, ConditionItem, LinkOperator, LinkValue, ReferPoint};
+    use card::types::LinkDescriptor;
+    use schema::card_types::PermissionGrant;
+
+    fn org() -> TenantId {
+        TenantId::from_str("o1")
+    }
+
+    fn member(id: &str, team_ids: Vec<&str>) -> Member {
+        Member::new(CardId::from_str(id), &org(), team_ids.into_iter().map(CardId::from_str).collect())
+    }
+
+    #[test]
+    fn test_check() {
+        let access = AccessControl::default();
+        let viewer = Role::viewer(&org());
+        let editor = Role::editor(&org());
+        access.add_role(viewer.clone());
+        access.add_role(editor.clone());
+        //整个组织可见，研发团队可以编辑需求
+        access.grant(Grant::new(&org(), &viewer.id, Grantee::Member(String::from("m1")), Scope::Org)).unwrap();
+        access.grant(Grant::new(&org(), &editor.id, Grantee::Team(String::from("研发")), Scope::CardType(String::from("需求")))).unwrap();
+
+        let m1 = member("m1", vec![]);
+        let m2 = member("m2", vec!["研发"]);
+        let demand = Card::new(String::from("1"), String::from("需求1"), "需求", &TenantId::from_str("o1"), None, vec![], HashMap::new());
+        let task = Card::new(String::from("2"), String::from("任务1"), "任务", &TenantId::from_str("o1"), None, vec![], HashMap::new());
+        assert!(access.check(&m1, Action::View, &task));
+        assert!(!access.check(&m1, Action::EditField, &demand));
+        assert!(access.check(&m2, Action::Transition, &demand));
+        assert!(!access.check(&m2, Action::Transition, &task));
+        assert!(!access.check(&m2, Action::ManageSchema, &demand));
+        //卡片类型范围的授权不能用于与卡片类型无关的操作
+        assert!(access.check_org(&m1, Action::View));
+        assert!(!access.check_org(&m2, Action::EditField));
+
+        //其他组织的成员没有任何权限
+        let outsider = Member::new(CardId::from_str("m1"), &TenantId::from_str("o2"), vec![]);
+        assert!(!access.check(&outsider, Action::View, &task));
+
+        assert!(access.remove_role(&org(), &viewer.id));
+        assert!(!access.check(&m1, Action::View, &task));
+        assert_eq!(access.grants(&org()).len(), 1);
+    }
+
+    #[test]
+    fn test_query_context() {
+        let access = AccessControl::default();
+        let mut condition = Condition::default();
+        condition.and(ConditionItem::Link(
+            LinkDescriptor::Src(String::from("member")),
+            LinkOperator::AnyIn(LinkValue::ReferValue(ReferPoint::CurrentMember, vec![])),
+        ));
+        access.set_visibility(&org(), VisibilityFilter::new("私有任务", condition.clone()));
+        access.set_visibility(&org(), VisibilityFilter::new("私有任务", condition));
+        let context = access.query_context(&member("m1", vec![]), HashMap::new());
+        assert_eq!(context.tenant_id(), &org());
+        assert_eq!(context.member_id(), "m1");
+        //没有任何授权的成员查不到卡片
+        assert_eq!(context.viewable_card_types(), Some(&[][..]));
+        assert!(access.remove_visibility(&org(), "私有任务"));
+        assert!(!access.remove_visibility(&org(), "私有任务"));
+    }
+
+    #[test]
+    fn test_viewable_card_types() {
+        let access = AccessControl::default();
+        let viewer = Role::viewer(&org());
+        access.add_role(viewer.clone());
+        access.grant(Grant::new(&org(), &viewer.id, Grantee::Team(String::from("研发")), Scope::CardType(String::from("需求")))).unwrap();
+        let context = access.query_context(&member("m1", vec!["研发"]), HashMap::new());
+        assert_eq!(context.viewable_card_types(), Some(&[String::from("需求")][..]));
+        //组织范围的查看权限不限卡片类型
+        access.grant(Grant::new(&org(), &viewer.id, Grantee::Member(String::from("m1")), Scope::Org)).unwrap();
+        assert_eq!(access.query_context(&member("m1", vec!["研发"]), HashMap::new()).viewable_card_types(), None);
+    }
+
+    #[test]
+    fn test_bootstrap_admin() {
+        let access = AccessControl::default();
+        let (admin, grant) = access.bootstrap_admin(&org(), "m1").unwrap();
+        assert_eq!(grant.role_id, admin.id);
+        assert!(access.check_org(&member("m1", vec![]), Action::ManageSchema));
+        //已经有管理员时不再创建
+        assert!(access.bootstrap_admin(&org(), "m2").is_none());
+        assert!(access.bootstrap_admin(&TenantId::from_str("o2"), "m2").is_some());
+    }
+
+    #[test]
+    fn test_apply_card_type_permission() {
+        let access = AccessControl::default();
+        let admin = Role::admin(&org());
+        access.add_role(admin.clone());
+        let permission = Permission::new(vec![PermissionGrant {
+            role_id: admin.id.clone(),
+            grantee: Grantee::Member(String::from("m1")),
+        }], vec![]);
+        access.apply_card_type_permission(&org(), "需求", &permission).unwrap();
+        access.apply_card_type_permission(&org(), "需求", &permission).unwrap();
+        assert_eq!(access.grants(&org()).len(), 1);
+        assert!(access.ensure(&member("m1", vec![]), Action::ManageSchema, &org(), "需求").is_ok());
+        assert!(access.ensure(&member("m1", vec![]), Action::ManageSchema, &org(), "任务").is_err());
+
+        let unknown = Permission::new(vec![PermissionGrant {
+            role_id: String::from("unknown"),
+            grantee: Grantee::Member(String::from("m1")),
+        }], vec![]);
+        assert!(access.apply_card_type_permission(&org(), "需求", &unknown).is_err());
+    }
+
+    #[test]
+    fn test_field_rules() {
+        let access = AccessControl::default();
+        let editor = Role::editor(&org());
+        let finance = Role::new(&org(), "财务", BTreeSet::from([Action::View, Action::EditField]));
+        access.add_role(editor.clone());
+        access.add_role(finance.clone());
+        //成本只有财务可见，估时只能在待办时修改
+        let permission = Permission::new(vec![
+            PermissionGrant { role_id: editor.id.clone(), grantee: Grantee::Team(String::from("研发")) },
+            PermissionGrant { role_id: finance.id.clone(), grantee: Grantee::Member(String::from("m2")) },
+        ], vec![
+            FieldRule {
+                field_id: String::from("成本"),
+                read: Some(FieldAccess { role_ids: vec![finance.id.clone()], flow_status_ids: vec![] }),
+                write: Some(FieldAccess { role_ids: vec![finance.id.clone()], flow_status_ids: vec![] }),
+            },
+            FieldRule {
+                field_id: String::from("估时"),
+                read: None,
+                write: Some(FieldAccess { role_ids: vec![], flow_status_ids: vec![String::from("待办")] }),
+            },
+        ]);
+        access.apply_card_type_permission(&org(), "需求", &permission).unwrap();
+        let developer = member("m1", vec!["研发"]);
+        let accountant = member("m2", vec![]);
+
+        let cost = FieldId::from_str("成本");
+        let estimate = FieldId::from_str("估时");
+        assert!(!access.yields(&developer, vec![cost.clone()]).is_readable("需求", None, &cost));
+        assert!(access.yields(&accountant, vec![cost.clone()]).is_readable("需求", None, &cost));
+        assert!(access.yields(&developer, vec![estimate.clone()]).is_readable("需求", None, &estimate));
+
+        assert!(access.ensure_writable(&developer, "需求", Some("待办"), &[&estimate]).is_ok());
+        assert!(access.ensure_writable(&developer, "需求", Some("进行中"), &[&estimate]).is_err());
+        assert!(access.ensure_writable(&developer, "需求", Some("待办"), &[&cost]).is_err());
+        assert!(access.ensure_writable(&accountant, "需求", None, &[&cost]).is_ok());
+        assert!(access.ensure_writable(&developer, "任务", None, &[&cost]).is_ok());
+    }
+}
@@ -0,0 +1,39 @@
+//把角色授予成员或团队，生效范围是整个组织或某个卡片类型
+use common::id_generator;
+use common::newtypes::tenant_id::TenantId;
+use schema::card_types::Grantee;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    Org,
+    CardType(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Grant {
+    pub id: String,
+    pub org_id: TenantId,
+    pub role_id: String,
+    pub grantee: Grantee,
+    pub scope: Scope,
+}
+
+impl Grant {
+    pub fn new(org_id: &TenantId, role_id: &str, grantee: Grantee, scope: Scope) -> Self {
+        Self {
+            id: id_generator::generate_id(),
+            org_id: org_id.clone(),
+            role_id: String::from(role_id),
+            grantee,
+            scope,
+        }
+    }
+
+    pub fn covers(&self, card_type_id: &str) -> bool {
+        match &self.scope {
+            Scope::Org => true,
+            Scope::CardType(it) => it == card_type_id,
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//角色是一组操作权限的集合
+use common::id_generator;
+use common::newtypes::tenant_id::TenantId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Action {
+    View,
+    Create,
+    EditField, //修改属性以及关联
+    Transition, //价值流状态流转
+    Archive,
+    Abandon,
+    ManageSchema, //维护卡片类型、属性、价值流等定义
+    ManageView, //维护团队和组织共享的视图，个人视图由本人维护
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Role {
+    pub id: String,
+    pub org_id: TenantId,
+    pub name: String,
+    pub actions: BTreeSet<Action>,
+}
+
+impl Role {
+    pub fn new(org_id: &TenantId, name: &str, actions: BTreeSet<Action>) -> Self {
+        Self {
+            id: id_generator::generate_id(),
+            org_id: org_id.clone(),
+            name: String::from(name),
+            actions,
+        }
+    }
+
+    //只读
+    pub fn viewer(org_id: &TenantId) -> Self {
+        Self::new(org_id, "访客", BTreeSet::from([Action::View]))
+    }
+
+    //日常处理卡片，不能维护定义
+    pub fn editor(org_id: &TenantId) -> Self {
+        Self::new(org_id, "成员", BTreeSet::from([
+            Action::View,
+            Action::Create,
+            Action::EditField,
+            Action::Transition,
+            Action::Archive,
+            Action::Abandon,
+        ]))
+    }
+
+    pub fn admin(org_id: &TenantId) -> Self {
+        let mut role = Self::editor(org_id);
+        role.name = String::from("管理员");
+        role.actions.insert(Action::ManageSchema);
+        role.actions.insert(Action::ManageView);
+        role
+    }
+
+    pub fn allows(&self, action: Action) -> bool {
+        self.actions.contains(&action)
+    }
+}
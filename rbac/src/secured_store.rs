@@ -0,0 +1,176 @@
+//在卡片存储之前做权限判断，HTTP层通过它来变更卡片
+use crate::access::{AccessControl, AccessDenied, Member};
+use crate::role::Action;
+use card::card::{Card, Field, FlowStatus};
+use card::computed::ComputedFieldRegistry;
+use card::store::neo4j_store::{CardScope, Neo4jStore};
+use common::newtypes::card_id::CardId;
+use common::newtypes::field_id::FieldId;
+use common::newtypes::tenant_id::TenantId;
+use card::query;
+use card::query::Yields;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::{error, fmt};
+
+pub struct SecuredStore {
+    access: Arc<AccessControl>,
+    computed: Arc<ComputedFieldRegistry>,
+}
+
+impl SecuredStore {
+    pub fn new(access: Arc<AccessControl>, computed: Arc<ComputedFieldRegistry>) -> Self {
+        Self { access, computed }
+    }
+
+    //成功时返回分配的编号
+    pub async fn create(&self, card: &Card<'_>, member: &Member) -> Result<Option<String>, SecuredStoreError> {
+        self.access.ensure(member, Action::Create, &card.org_id, card.card_type_id)?;
+        ensure_not_reserved(&card.fields)?;
+        let field_ids: Vec<&FieldId> = card.fields.iter().map(|it| &it.id).collect();
+        let flow_status_id = card.flow_status.as_ref().map(|it| it.flow_status_id.as_str());
+        self.access.ensure_writable(member, card.card_type_id, flow_status_id, &field_ids)?;
+        self.ensure_not_computed(&card.org_id, card.card_type_id, &field_ids)?;
+        Ok(Neo4jStore::create(card, &member.id).await)
+    }
+
+    pub async fn update_fields(&self, card_id: &CardId, fields: &[Field], member: &Member) -> Result<bool, SecuredStoreError> {
+        ensure_not_reserved(fields)?;
+        let scope = self.ensure(card_id, member, Action::EditField).await?;
+        let field_ids: Vec<&FieldId> = fields.iter().map(|it| &it.id).collect();
+        self.access.ensure_writable(member, &scope.card_type_id, scope.flow_status_id.as_deref(), &field_ids)?;
+        self.ensure_not_computed(&scope.org_id, &scope.card_type_id, &field_ids)?;
+        Ok(Neo4jStore::update_fields(&member.org_id, card_id, fields, &member.id).await)
+    }
+
+    //from为调用方校验流转时卡片所处的状态，卡片已不在该状态时返回false
+    pub async fn change_flow_status(&self, card_id: &CardId, from: Option<&str>, flow_status: &FlowStatus, member: &Member) -> Result<bool, SecuredStoreError> {
+        self.ensure(card_id, member, Action::Transition).await?;
+        Ok(Neo4jStore::transit(&member.org_id, card_id, from, flow_status, &member.id).await)
+    }
+
+    //建立关联视为修改关联的起点卡片，关联的终点卡片也必须对成员可见
+    pub async fn link(&self, src_id: &CardId, dest_id: &CardId, rs_type: &str, member: &Member) -> Result<bool, SecuredStoreError> {
+        self.ensure(src_id, member, Action::EditField).await?;
+        self.scope_of(dest_id, member).await?;
+        Ok(Neo4jStore::link(&member.org_id, src_id, dest_id, rs_type, &member.id).await)
+    }
+
+    pub async fn unlink(&self, src_id: &CardId, dest_id: &CardId, rs_type: &str, member: &Member) -> Result<bool, SecuredStoreError> {
+        self.ensure(src_id, member, Action::EditField).await?;
+        self.scope_of(dest_id, member).await?;
+        Ok(Neo4jStore::unlink(&member.org_id, src_id, dest_id, rs_type, &member.id).await)
+    }
+
+    pub async fn archive(&self, card_id: &CardId, member: &Member) -> Result<bool, SecuredStoreError> {
+        self.ensure(card_id, member, Action::Archive).await?;
+        Ok(Neo4jStore::archive(&member.org_id, card_id, &member.id).await)
+    }
+
+    pub async fn abandon(&self, card_id: &CardId, reason: &str, member: &Member) -> Result<bool, SecuredStoreError> {
+        self.ensure(card_id, member, Action::Abandon).await?;
+        Ok(Neo4jStore::abandon(&member.org_id, card_id, reason, &member.id).await)
+    }
+
+    //恢复归档和丢弃的卡片都要求有归档权限
+    pub async fn restore(&self, card_id: &CardId, member: &Member) -> Result<bool, SecuredStoreError> {
+        self.ensure(card_id, member, Action::Archive).await?;
+        Ok(Neo4jStore::restore(&member.org_id, card_id, &member.id).await)
+    }
+
+    //计算属性的值只由公式得出，任何成员都不能直接修改
+    fn ensure_not_computed(&self, org_id: &TenantId, card_type_id: &str, field_ids: &[&FieldId]) -> Result<(), AccessDenied> {
+        match field_ids.iter().find(|it| self.computed.is_computed(org_id, card_type_id, it)) {
+            Some(field_id) => Err(AccessDenied::new(&format!("field {} of card type {} is computed", field_id, card_type_id))),
+            None => Ok(()),
+        }
+    }
+
+    //按成员的查询上下文查找卡片，不存在的卡片、其他租户的卡片和行级不可见的卡片都视为不存在，避免泄露卡片是否存在
+    pub async fn scope_of(&self, card_id: &CardId, member: &Member) -> Result<CardScope, SecuredStoreError> {
+        let query_context = self.access.query_context(member, HashMap::new());
+        let found = query::find(card_id, query_context, Yields::default()).await
+            .map_err(|err| SecuredStoreError::Store(err.to_string()))?;
+        let card = found.ok_or_else(|| SecuredStoreError::NotFound(card_id.clone()))?;
+        Ok(CardScope {
+            org_id: card.org_id,
+            card_type_id: card.card_type_id,
+            flow_id: card.flow_status.as_ref().map(|it| it.flow_id.clone()),
+            flow_status_id: card.flow_status.map(|it| it.flow_status_id),
+        })
+    }
+
+    //成员能看到卡片但没有权限时才拒绝
+    async fn ensure(&self, card_id: &CardId, member: &Member, action: Action) -> Result<CardScope, SecuredStoreError> {
+        let scope = self.scope_of(card_id, member).await?;
+        self.access.ensure(member, action, &scope.org_id, &scope.card_type_id)?;
+        Ok(scope)
+    }
+}
+
+//内置属性只能通过专门的操作修改，否则可以绕过流转、归档、编号分配的校验，甚至把卡片移到其他组织
+fn ensure_not_reserved(fields: &[Field]) -> Result<(), SecuredStoreError> {
+    match card::card::find_reserved(fields) {
+        Some(field) => Err(SecuredStoreError::Invalid(format!("field {} is a built-in property", field.id))),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug)]
+pub enum SecuredStoreError {
+    NotFound(CardId), //卡片不存在或者对成员不可见
+    Denied(AccessDenied),
+    Invalid(String), //请求本身不合法
+    Store(String),
+}
+
+impl Display for SecuredStoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SecuredStoreError::NotFound(card_id) => write!(f, "card {} not found", card_id),
+            SecuredStoreError::Denied(err) => write!(f, "{}", err),
+            SecuredStoreError::Invalid(message) | SecuredStoreError::Store(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl error::Error for SecuredStoreError {}
+
+impl From<AccessDenied> for SecuredStoreError {
+    fn from(err: AccessDenied) -> Self {
+        SecuredStoreError::Denied(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use card::card::FieldValue;
+    use card::computed::ComputedField;
+    use card::formula::Formula;
+
+    #[test]
+    fn test_reject_computed_fields() {
+        let computed = Arc::new(ComputedFieldRegistry::default());
+        let org_id = TenantId::from_str("o1");
+        computed.add(ComputedField::new(&org_id, "需求", FieldId::from_str("总估时"), Formula::Field(FieldId::from_str("估时")))).unwrap();
+        let store = SecuredStore::new(Arc::new(AccessControl::default()), computed);
+        let (total, estimate) = (FieldId::from_str("总估时"), FieldId::from_str("估时"));
+        assert!(store.ensure_not_computed(&org_id, "需求", &[&estimate]).is_ok());
+        assert_eq!(store.ensure_not_computed(&org_id, "需求", &[&estimate, &total]).unwrap_err().to_string(), "field 总估时 of card type 需求 is computed");
+        //其他卡片类型和其他组织的同名属性不受影响
+        assert!(store.ensure_not_computed(&org_id, "任务", &[&total]).is_ok());
+        assert!(store.ensure_not_computed(&TenantId::from_str("o2"), "需求", &[&total]).is_ok());
+    }
+
+    #[test]
+    fn test_reject_reserved_fields() {
+        let estimate = Field::new(FieldId::from_str("估时"), FieldValue::Int(1));
+        assert!(ensure_not_reserved(std::slice::from_ref(&estimate)).is_ok());
+        for name in ["org_id", "state", "flow_status_id", "card_type_id", "code"] {
+            let fields = vec![estimate.clone(), Field::new(FieldId::from_str(name), FieldValue::Text(String::from("x")))];
+            assert_eq!(ensure_not_reserved(&fields).unwrap_err().to_string(), format!("field {name} is a built-in property"));
+        }
+    }
+}
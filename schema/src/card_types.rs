@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
 use crate::schema::Schema;
+use common::newtypes::tenant_id::TenantId;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CardType {
     MemberType(MemberType),
     TeamType(TeamType),
@@ -10,16 +11,16 @@ pub enum CardType {
 }
 
 ///公共特性类型，被其他卡片类所继承，达到拥有公共属性或关联的目的
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommonTraitType {
     id: String,
     name: String,
-    org_id: String,
+    org_id: TenantId,
     description: Option<String>,
 }
 
 impl CommonTraitType {
-    pub fn new(id: String, name: String, org_id: String, description: Option<String>) -> Self {
+    pub fn new(id: String, name: String, org_id: TenantId, description: Option<String>) -> Self {
         Self {
             id,
             name,
@@ -29,37 +30,48 @@ impl CommonTraitType {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MemberType {
     id: String,
     name: String,
-    org_id: String,
+    org_id: TenantId,
     description: Option<String>,
     trait_ids: Option<Vec<String>>,
     permission: Option<Permission>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TeamType {
     id: String,
     name: String,
-    org_id: String,
+    org_id: TenantId,
     description: Option<String>,
     trait_ids: Option<Vec<String>>,
     permission: Option<Permission>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WorkItemType {
     id: String,
     name: String,
-    org_id: String,
+    org_id: TenantId,
     description: Option<String>,
     trait_ids: Option<Vec<String>>,
     permission: Option<Permission>,
     card_faces: Vec<CardFace>, //普通版只能定义一个卡片，并且卡面的自定义度也有限
 }
 
+impl CardType {
+    ///公共特性类型不能直接创建卡片，所以没有授权
+    pub fn permission(&self) -> Option<&Permission> {
+        match self {
+            CardType::MemberType(it) => it.permission.as_ref(),
+            CardType::TeamType(it) => it.permission.as_ref(),
+            CardType::WorkItemType(it) => it.permission.as_ref(),
+            CardType::CommonTraitType(_) => None,
+        }
+    }
+}
 
 impl Schema for CardType {
     fn id(&self) -> &str {
@@ -96,7 +108,7 @@ impl Schema for CardType {
         }
     }
 
-    fn org_id(&self) -> &str {
+    fn org_id(&self) -> &TenantId {
         match self {
             CardType::MemberType(it) => {
                 &it.org_id
@@ -148,8 +160,55 @@ impl Schema for CardType {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Permission {}
+///卡片类型上的授权，授予的角色只在该卡片类型范围内生效，角色和权限的判定见rbac
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Permission {
+    grants: Vec<PermissionGrant>,
+    #[serde(default)]
+    field_rules: Vec<FieldRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PermissionGrant {
+    pub role_id: String,
+    pub grantee: Grantee,
+}
+
+///被授权的对象，成员或者团队(团队的所有成员)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Grantee {
+    Member(String),
+    Team(String),
+}
+
+///属性级权限，没有规则的属性不受限制
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldRule {
+    pub field_id: String,
+    pub read: Option<FieldAccess>,
+    pub write: Option<FieldAccess>,
+}
+
+///满足全部条件才能读或写，列表为空表示不限制
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FieldAccess {
+    pub role_ids: Vec<String>, //拥有其中之一的角色
+    pub flow_status_ids: Vec<String>, //卡片处于其中之一的价值流状态
+}
+
+impl Permission {
+    pub fn new(grants: Vec<PermissionGrant>, field_rules: Vec<FieldRule>) -> Self {
+        Self { grants, field_rules }
+    }
+
+    pub fn grants(&self) -> &[PermissionGrant] {
+        &self.grants
+    }
+
+    pub fn field_rules(&self) -> &[FieldRule] {
+        &self.field_rules
+    }
+}
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CardFace {}
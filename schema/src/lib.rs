@@ -1,14 +1,15 @@
-mod card_types;
+pub mod card_types;
 mod customize_fields;
 mod relationships;
 mod biz_rules;
-mod schema;
-mod work_flows;
+pub mod schema;
+pub mod work_flows;
 
 #[cfg(test)]
 mod tests {
     use common::id_generator::IdGenerator;
     use crate::card_types::{CardType, CommonTraitType};
+    use common::newtypes::tenant_id::TenantId;
 
     #[test]
     fn it_works() {
@@ -21,7 +22,7 @@ mod tests {
         let common_trait_type = CardType::CommonTraitType(CommonTraitType::new(
             String::generate_id(),
             String::generate_id(),
-            String::generate_id(),
+            TenantId::from(String::generate_id()),
             Some(String::generate_id()),
         ));
         //println!("{:?}", common_trait_type);
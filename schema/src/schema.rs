@@ -1,8 +1,66 @@
+use common::newtypes::tenant_id::TenantId;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
 pub trait Schema {
     fn id(&self) -> &str; //保持和self相同的生命周期
     fn name(&self) -> &str;
-    fn org_id(&self) -> &str;
+    fn org_id(&self) -> &TenantId;
     ///Schema的二级索引
     fn secondary_indexes(&self) -> Option<Vec<String>>;
     fn description(&self) -> &Option<String>;
-}
\ No newline at end of file
+}
+
+///按租户隔离的Schema注册表，所有查找都必须指定租户，不同租户的定义互不可见
+pub struct SchemaRegistry<T: Schema> {
+    schemas: RwLock<HashMap<TenantId, HashMap<String, T>>>,
+}
+
+impl<T: Schema + Clone> SchemaRegistry<T> {
+    pub fn new() -> Self {
+        Self { schemas: RwLock::new(HashMap::new()) }
+    }
+
+    pub fn put(&self, schema: T) {
+        let mut schemas = self.schemas.write().unwrap();
+        schemas.entry(schema.org_id().clone()).or_default().insert(String::from(schema.id()), schema);
+    }
+
+    pub fn get(&self, tenant_id: &TenantId, id: &str) -> Option<T> {
+        self.schemas.read().unwrap().get(tenant_id).and_then(|it| it.get(id)).cloned()
+    }
+
+    pub fn list(&self, tenant_id: &TenantId) -> Vec<T> {
+        self.schemas.read().unwrap().get(tenant_id).map(|it| it.values().cloned().collect()).unwrap_or_default()
+    }
+
+    pub fn remove(&self, tenant_id: &TenantId, id: &str) -> Option<T> {
+        self.schemas.write().unwrap().get_mut(tenant_id).and_then(|it| it.remove(id))
+    }
+}
+
+impl<T: Schema + Clone> Default for SchemaRegistry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::card_types::{CardType, CommonTraitType};
+
+    #[test]
+    fn test_cross_tenant_lookup() {
+        let registry = SchemaRegistry::new();
+        let tenant_id = TenantId::from_str("o1");
+        registry.put(CardType::CommonTraitType(CommonTraitType::new(String::from("t1"), String::from("可估算"), tenant_id.clone(), None)));
+        assert!(registry.get(&tenant_id, "t1").is_some());
+        //其他租户即使知道id也查不到
+        let other = TenantId::from_str("o2");
+        assert!(registry.get(&other, "t1").is_none());
+        assert!(registry.list(&other).is_empty());
+        assert!(registry.remove(&other, "t1").is_none());
+        assert!(registry.remove(&tenant_id, "t1").is_some());
+    }
+}
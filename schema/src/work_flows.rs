@@ -1,14 +1,59 @@
 //工作流
 use serde::{Deserialize, Serialize};
 use crate::schema::Schema;
+use common::newtypes::tenant_id::TenantId;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WorkFlow {
     id: String,
     name: String,
     card_type_id: String, //工作项卡片类型id
-    org_id: String,
+    org_id: TenantId,
     description: Option<String>,
+    #[serde(default)]
+    statuses: Vec<FlowStatusDef>,
+    #[serde(default)]
+    transitions: Vec<Transition>, //为空时状态之间可以任意流转
+}
+
+//价值流中的一个状态
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlowStatusDef {
+    pub id: String,
+    pub name: String,
+}
+
+//允许的状态流转，from为None表示卡片还没有进入价值流
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transition {
+    pub from: Option<String>,
+    pub to: String,
+}
+
+impl WorkFlow {
+    pub fn new(id: String, name: String, card_type_id: String, org_id: TenantId, statuses: Vec<FlowStatusDef>, transitions: Vec<Transition>) -> Self {
+        Self { id, name, card_type_id, org_id, description: None, statuses, transitions }
+    }
+
+    pub fn card_type_id(&self) -> &str {
+        &self.card_type_id
+    }
+
+    pub fn statuses(&self) -> &[FlowStatusDef] {
+        &self.statuses
+    }
+
+    pub fn transitions(&self) -> &[Transition] {
+        &self.transitions
+    }
+
+    //目标状态必须属于该价值流，定义了流转规则时还必须有对应的流转
+    pub fn allows(&self, from: Option<&str>, to: &str) -> bool {
+        if !self.statuses.iter().any(|it| it.id == to) {
+            return false;
+        }
+        self.transitions.is_empty() || self.transitions.iter().any(|it| it.from.as_deref() == from && it.to == to)
+    }
 }
 
 impl Schema for WorkFlow {
@@ -20,7 +65,7 @@ impl Schema for WorkFlow {
         &self.name
     }
 
-    fn org_id(&self) -> &str {
+    fn org_id(&self) -> &TenantId {
         &self.org_id
     }
 
@@ -31,4 +76,30 @@ impl Schema for WorkFlow {
     fn description(&self) -> &Option<String> {
         &self.description
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(id: &str) -> FlowStatusDef {
+        FlowStatusDef { id: String::from(id), name: String::from(id) }
+    }
+
+    #[test]
+    fn test_allows() {
+        let statuses = vec![status("待办"), status("进行中"), status("已完成")];
+        let free = WorkFlow::new(String::from("f1"), String::from("需求流程"), String::from("需求"), TenantId::from_str("o1"), statuses.clone(), vec![]);
+        assert!(free.allows(Some("已完成"), "待办"));
+        assert!(!free.allows(None, "已关闭"));
+
+        let transitions = vec![
+            Transition { from: None, to: String::from("待办") },
+            Transition { from: Some(String::from("待办")), to: String::from("进行中") },
+            Transition { from: Some(String::from("进行中")), to: String::from("已完成") },
+        ];
+        let strict = WorkFlow::new(String::from("f2"), String::from("需求流程"), String::from("需求"), TenantId::from_str("o1"), statuses, transitions);
+        assert!(strict.allows(None, "待办"));
+        assert!(strict.allows(Some("待办"), "进行中"));
+        assert!(!strict.allows(Some("待办"), "已完成"));
+    }
+}
@@ -0,0 +1,181 @@
+//燃尽图和燃起图：统计版本、迭代等容器卡片所关联的工作项每天结束时的范围和完成量
+//工作项的完成按流转历史回溯，数值属性取当前值；范围变化单独作为一个序列，便于区分是完成了工作还是移出了工作
+use crate::flow;
+use crate::flow::{CardFlow, FlowStages};
+use crate::history::{CardHistory, LinkChange};
+use crate::period::StatsPeriod;
+use card::card::{Field, FieldValue};
+use card::query;
+use card::query::{Condition, ConditionItem, LinkOperator, LinkValue, Page, QueryContext, Yields};
+use card::types::LinkDescriptor;
+use chrono::NaiveDate;
+use common::newtypes::card_id::CardId;
+use common::newtypes::field_id::FieldId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error;
+
+//按卡片数或者按数值属性（如估算）统计
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BurnMeasure {
+    Count,
+    Field(FieldId),
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Burndown {
+    pub points: Vec<BurnPoint>,
+    pub scope_changes: Vec<ScopeChange>,
+}
+
+//当天结束时的数值；燃尽图使用remaining和ideal，燃起图使用scope和done
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BurnPoint {
+    pub date: NaiveDate,
+    pub scope: f64,
+    pub done: f64,
+    pub remaining: f64,
+    pub ideal: f64, //从统计开始时的范围匀速下降到0
+}
+
+//当天加入和移出范围的工作量
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ScopeChange {
+    pub date: NaiveDate,
+    pub added: f64,
+    pub removed: f64,
+}
+
+//工作项在某一时刻是否在范围中；没有关联记录的工作项是在记录历史之前关联的，一直在范围中
+fn in_scope(changes: &[&LinkChange], time: i64) -> bool {
+    match changes.iter().rev().find(|it| it.time < time) {
+        Some(change) => change.linked,
+        None => changes.first().is_none_or(|it| !it.linked),
+    }
+}
+
+pub(crate) fn compute(values: &HashMap<CardId, f64>, link_changes: &[LinkChange], flows: &HashMap<CardId, CardFlow>, stages: &FlowStages, period: &StatsPeriod) -> Burndown {
+    let mut changes_of: HashMap<&CardId, Vec<&LinkChange>> = HashMap::new();
+    for change in link_changes.iter().filter(|it| values.contains_key(&it.card_id)) {
+        changes_of.entry(&change.card_id).or_default().push(change);
+    }
+    let total_at = |time: i64, done_only: bool| -> f64 {
+        values.iter()
+            .filter(|(id, _)| in_scope(changes_of.get(id).map(Vec::as_slice).unwrap_or_default(), time))
+            .filter(|(id, _)| !done_only || flows.get(id).and_then(|it| it.status_at(time)).is_some_and(|it| stages.is_done(it)))
+            .map(|(_, value)| value)
+            .sum()
+    };
+    let days = period.days();
+    let initial_scope = total_at(period.start_of(period.start), false);
+    let points = days.iter().enumerate()
+        .map(|(i, date)| {
+            let end = period.end_of(*date);
+            let (scope, done) = (total_at(end, false), total_at(end, true));
+            let ideal = initial_scope * (1.0 - (i + 1) as f64 / days.len() as f64);
+            BurnPoint { date: *date, scope, done, remaining: scope - done, ideal }
+        })
+        .collect();
+    let scope_changes = days.iter()
+        .map(|date| {
+            let (start, end) = (period.start_of(*date), period.end_of(*date));
+            let mut change = ScopeChange { date: *date, added: 0.0, removed: 0.0 };
+            //只比较当天开始和结束时的范围，当天加入又移出的工作项不计入
+            for (id, value) in values {
+                let changes = changes_of.get(id).map(Vec::as_slice).unwrap_or_default();
+                match (in_scope(changes, start), in_scope(changes, end)) {
+                    (false, true) => change.added += value,
+                    (true, false) => change.removed += value,
+                    _ => {}
+                }
+            }
+            change
+        })
+        .collect();
+    Burndown { points, scope_changes }
+}
+
+fn value_of(fields: &[Field], measure: &BurnMeasure) -> f64 {
+    let BurnMeasure::Field(field_id) = measure else {
+        return 1.0;
+    };
+    fields.iter()
+        .find(|it| it.id == *field_id)
+        .map(|it| match &it.value {
+            FieldValue::Int(v) => *v as f64,
+            FieldValue::Float(v) => *v as f64,
+            _ => 0.0,
+        })
+        .unwrap_or(0.0)
+}
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+//link为从容器卡片看到的关联，如版本 -[包含]-> 需求 为Src("包含")；当前成员不可见的工作项不参与统计
+pub async fn burndown(history: &CardHistory, stages: &FlowStages, container_id: &CardId, link: &LinkDescriptor, measure: &BurnMeasure,
+                      query_context: QueryContext, period: &StatsPeriod) -> Result<Burndown> {
+    let link_changes = history.link_changes(query_context.tenant_id(), container_id, link).await?;
+    let linked = query::linked_ids(std::slice::from_ref(container_id), link, &query_context).await?;
+    let mut item_ids: HashSet<CardId> = linked.into_values().flatten().collect();
+    item_ids.extend(link_changes.iter().map(|it| it.card_id.clone()));
+    if item_ids.is_empty() {
+        return Ok(compute(&HashMap::new(), &[], &HashMap::new(), stages, period));
+    }
+    let mut condition = Condition::default();
+    condition.and(ConditionItem::MySelf(LinkOperator::AnyIn(LinkValue::StaticValue(item_ids.iter().map(|it| it.to_string()).collect()))));
+    let fields = match measure {
+        BurnMeasure::Count => vec![],
+        BurnMeasure::Field(field_id) => vec![field_id.clone()],
+    };
+    let items = query::query(condition, query_context.clone(), Yields::new(fields), Page::None).await?;
+    let values: HashMap<CardId, f64> = items.cards.into_iter().map(|it| (it.id.clone(), value_of(&it.fields, measure))).collect();
+    let visible_ids = values.keys().cloned().collect();
+    let flows = flow::card_flows(stages, &history.events_of(query_context.tenant_id(), &visible_ids).await?);
+    Ok(compute(&values, &link_changes, &flows, stages, period))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use card::events::{CardEvent, CardEventKind};
+    use chrono_tz::Tz;
+    use common::newtypes::tenant_id::TenantId;
+    use common::newtypes::timestamp::Timestamp;
+
+    const HOUR: i64 = 3600 * 1000;
+
+    fn id(id: &str) -> CardId {
+        CardId::from_str(id)
+    }
+
+    fn time(hours: i64) -> i64 {
+        "2024-05-06".parse::<NaiveDate>().unwrap().and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp_millis() + hours * HOUR
+    }
+
+    fn transit(card_id: &str, to: &str, hours: i64) -> CardEvent {
+        let kind = CardEventKind::FlowStatusChanged { flow_id: String::from("f1"), from: None, to: String::from(to) };
+        let mut event = CardEvent::new(&TenantId::from_str("o1"), &id(card_id), "需求", kind, &id("m1"));
+        event.occur_time = Timestamp::from(time(hours));
+        event
+    }
+
+    #[test]
+    fn test_compute() {
+        let stages = FlowStages::new("f1", vec![String::from("进行中"), String::from("已完成")], "进行中", "已完成").unwrap();
+        //a、b在迭代开始前就已关联，c在第2天加入，b在第3天移出
+        let values = HashMap::from([(id("a"), 3.0), (id("b"), 5.0), (id("c"), 2.0)]);
+        let link_changes = vec![
+            LinkChange { time: time(30), card_id: id("c"), linked: true },
+            LinkChange { time: time(50), card_id: id("b"), linked: false },
+        ];
+        let flows = flow::card_flows(&stages, &[transit("a", "进行中", 1), transit("a", "已完成", 20), transit("c", "已完成", 60)]);
+        let period = StatsPeriod::new("2024-05-06".parse().unwrap(), "2024-05-08".parse().unwrap(), Tz::UTC);
+        let burndown = compute(&values, &link_changes, &flows, &stages, &period);
+        let points: Vec<(f64, f64, f64)> = burndown.points.iter().map(|it| (it.scope, it.done, it.remaining)).collect();
+        assert_eq!(points, vec![(8.0, 3.0, 5.0), (10.0, 3.0, 7.0), (5.0, 5.0, 0.0)]);
+        let ideal = [8.0 * 2.0 / 3.0, 8.0 / 3.0, 0.0];
+        assert!(burndown.points.iter().zip(ideal).all(|(it, expected)| (it.ideal - expected).abs() < 1e-9));
+        let changes: Vec<(f64, f64)> = burndown.scope_changes.iter().map(|it| (it.added, it.removed)).collect();
+        assert_eq!(changes, vec![(0.0, 0.0), (2.0, 0.0), (0.0, 5.0)]);
+    }
+}
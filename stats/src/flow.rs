@@ -0,0 +1,305 @@
+//流动指标：累积流图、前置时间和周期时间分布、每周吞吐量、每日在制品数，都由卡片的流转历史计算
+//参与统计的卡片由条件筛选，条件按卡片当前的状态匹配
+use crate::history::CardHistory;
+use crate::period::{monday_of, StatsPeriod};
+use card::events::{CardEvent, CardEventKind};
+use card::query;
+use card::query::{Condition, Page, QueryContext, Yields};
+use chrono::NaiveDate;
+use common::newtypes::card_id::CardId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::{error, fmt};
+
+//价值流的阶段划分：statuses按价值流中的顺序排列，处于start及之后的状态视为已开始，处于done及之后的状态视为已完成
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlowStages {
+    flow_id: String,
+    statuses: Vec<String>,
+    start: usize,
+    done: usize,
+}
+
+//一张卡片在价值流中的经历
+#[derive(Debug, Default)]
+pub(crate) struct CardFlow {
+    created: Option<i64>,
+    changes: Vec<(i64, String)>, //进入各状态的时间
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FlowMetrics {
+    pub cumulative_flow: Vec<CumulativeFlowPoint>,
+    pub lead_time: Percentiles, //从创建到完成
+    pub cycle_time: Percentiles, //从开始到完成
+    pub throughput: Vec<WeeklyCount>,
+    pub wip: Vec<DailyCount>,
+}
+
+//counts与价值流的状态一一对应，为当天结束时处于各状态的卡片数
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CumulativeFlowPoint {
+    pub date: NaiveDate,
+    pub counts: Vec<u32>,
+}
+
+//统计范围内完成的卡片所用时间的分布，单位为毫秒，没有完成的卡片时为空
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Percentiles {
+    pub count: usize,
+    pub p50: Option<i64>,
+    pub p85: Option<i64>,
+    pub p95: Option<i64>,
+}
+
+//week为周一的日期
+#[derive(Debug, PartialEq, Serialize)]
+pub struct WeeklyCount {
+    pub week: NaiveDate,
+    pub count: u32,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DailyCount {
+    pub date: NaiveDate,
+    pub count: u32,
+}
+
+impl FlowStages {
+    pub fn new(flow_id: &str, statuses: Vec<String>, start: &str, done: &str) -> std::result::Result<Self, StatsError> {
+        let position = |status: &str| statuses.iter().position(|it| it == status)
+            .ok_or_else(|| StatsError::new(&format!("status {status} is not in flow {flow_id}")));
+        let (start, done) = (position(start)?, position(done)?);
+        if start > done {
+            return Err(StatsError::new("start status is after done status"));
+        }
+        Ok(Self { flow_id: String::from(flow_id), statuses, start, done })
+    }
+
+    pub fn flow_id(&self) -> &str {
+        &self.flow_id
+    }
+
+    pub fn statuses(&self) -> &[String] {
+        &self.statuses
+    }
+
+    fn rank(&self, status: &str) -> Option<usize> {
+        self.statuses.iter().position(|it| it == status)
+    }
+
+    pub(crate) fn is_started(&self, status: &str) -> bool {
+        self.rank(status).is_some_and(|it| it >= self.start)
+    }
+
+    pub(crate) fn is_done(&self, status: &str) -> bool {
+        self.rank(status).is_some_and(|it| it >= self.done)
+    }
+}
+
+impl CardFlow {
+    //某一时刻之前最后进入的状态
+    pub(crate) fn status_at(&self, time: i64) -> Option<&str> {
+        self.changes.iter().take_while(|(it, _)| *it < time).last().map(|(_, status)| status.as_str())
+    }
+
+    fn started_at(&self, stages: &FlowStages) -> Option<i64> {
+        self.changes.iter().find(|(_, status)| stages.is_started(status)).map(|(time, _)| *time)
+    }
+
+    //最后一次进入完成阶段的时间，完成后又被重新打开的卡片视为未完成
+    pub(crate) fn done_at(&self, stages: &FlowStages) -> Option<i64> {
+        let mut done_at = None;
+        for (time, status) in &self.changes {
+            match (stages.is_done(status), done_at) {
+                (true, None) => done_at = Some(*time),
+                (false, _) => done_at = None,
+                _ => {}
+            }
+        }
+        done_at
+    }
+}
+
+//只有在该价值流中流转过的卡片参与统计
+pub(crate) fn card_flows(stages: &FlowStages, events: &[CardEvent]) -> HashMap<CardId, CardFlow> {
+    let mut flows: HashMap<CardId, CardFlow> = HashMap::new();
+    for event in events {
+        match &event.kind {
+            CardEventKind::Created => flows.entry(event.card_id.clone()).or_default().created = Some(*event.occur_time),
+            CardEventKind::FlowStatusChanged { flow_id, to, .. } if *flow_id == stages.flow_id => {
+                flows.entry(event.card_id.clone()).or_default().changes.push((*event.occur_time, to.clone()));
+            }
+            _ => {}
+        }
+    }
+    flows.retain(|_, it| !it.changes.is_empty());
+    flows
+}
+
+pub fn compute(stages: &FlowStages, events: &[CardEvent], period: &StatsPeriod) -> FlowMetrics {
+    let flows = card_flows(stages, events);
+    let days = period.days();
+    let cumulative_flow = days.iter()
+        .map(|date| {
+            let end = period.end_of(*date);
+            let counts = stages.statuses.iter()
+                .map(|status| flows.values().filter(|it| it.status_at(end) == Some(status.as_str())).count() as u32)
+                .collect();
+            CumulativeFlowPoint { date: *date, counts }
+        })
+        .collect();
+    let wip = days.iter()
+        .map(|date| {
+            let end = period.end_of(*date);
+            let count = flows.values()
+                .filter(|it| it.status_at(end).is_some_and(|status| stages.is_started(status) && !stages.is_done(status)))
+                .count() as u32;
+            DailyCount { date: *date, count }
+        })
+        .collect();
+    let mut lead_times = Vec::new();
+    let mut cycle_times = Vec::new();
+    let mut throughput: Vec<WeeklyCount> = period.weeks().into_iter().map(|week| WeeklyCount { week, count: 0 }).collect();
+    for flow in flows.values() {
+        let Some(done_at) = flow.done_at(stages).filter(|it| period.contains(*it)) else {
+            continue;
+        };
+        //没有创建事件的卡片以第一次流转作为创建时间
+        let created = flow.created.unwrap_or(flow.changes[0].0);
+        lead_times.push(done_at - created);
+        //跳过进行中直接完成的卡片，开始时间即完成时间
+        cycle_times.push(done_at - flow.started_at(stages).unwrap_or(done_at));
+        let week = period.date_of(done_at).map(monday_of);
+        if let Some(weekly) = throughput.iter_mut().find(|it| Some(it.week) == week) {
+            weekly.count += 1;
+        }
+    }
+    FlowMetrics {
+        cumulative_flow,
+        lead_time: percentiles(lead_times),
+        cycle_time: percentiles(cycle_times),
+        throughput,
+        wip,
+    }
+}
+
+//最近秩法
+fn percentiles(mut durations: Vec<i64>) -> Percentiles {
+    durations.sort();
+    let count = durations.len();
+    let percentile = |p: usize| match count {
+        0 => None,
+        _ => Some(durations[(p * count).div_ceil(100) - 1]),
+    };
+    Percentiles { count, p50: percentile(50), p85: percentile(85), p95: percentile(95) }
+}
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+//满足条件的卡片在当前成员的可见范围内
+pub async fn flow_metrics(history: &CardHistory, stages: &FlowStages, condition: Condition, query_context: QueryContext, period: &StatsPeriod) -> Result<FlowMetrics> {
+    let card_ids = card_ids(condition, query_context.clone()).await?;
+    let events = history.events_of(query_context.tenant_id(), &card_ids).await?;
+    Ok(compute(stages, &events, period))
+}
+
+pub(crate) async fn card_ids(condition: Condition, query_context: QueryContext) -> Result<HashSet<CardId>> {
+    let result = query::query(condition, query_context, Yields::default(), Page::None).await?;
+    Ok(result.cards.into_iter().map(|it| it.id).collect())
+}
+
+#[derive(Debug)]
+pub struct StatsError {
+    message: String,
+}
+
+impl StatsError {
+    pub fn new(message: &str) -> Self {
+        Self { message: message.to_string() }
+    }
+}
+
+impl Display for StatsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for StatsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Tz;
+    use common::newtypes::tenant_id::TenantId;
+    use common::newtypes::timestamp::Timestamp;
+
+    const HOUR: i64 = 3600 * 1000;
+
+    fn stages() -> FlowStages {
+        let statuses = vec![String::from("待办"), String::from("进行中"), String::from("测试中"), String::from("已完成")];
+        FlowStages::new("f1", statuses, "进行中", "已完成").unwrap()
+    }
+
+    fn date(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    //2024-05-06是周一，时间为当天UTC零点后的小时数
+    fn event(card_id: &str, kind: CardEventKind, hours: i64) -> CardEvent {
+        let mut event = CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str(card_id), "需求", kind, &CardId::from_str("m1"));
+        event.occur_time = Timestamp::from(date("2024-05-06").and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp_millis() + hours * HOUR);
+        event
+    }
+
+    fn transit(card_id: &str, to: &str, hours: i64) -> CardEvent {
+        event(card_id, CardEventKind::FlowStatusChanged { flow_id: String::from("f1"), from: None, to: String::from(to) }, hours)
+    }
+
+    #[test]
+    fn test_flow_stages() {
+        let statuses = vec![String::from("待办"), String::from("已完成")];
+        assert!(FlowStages::new("f1", statuses.clone(), "已完成", "待办").is_err());
+        assert!(FlowStages::new("f1", statuses, "待办", "已关闭").is_err());
+    }
+
+    #[test]
+    fn test_compute() {
+        let events = vec![
+            event("a", CardEventKind::Created, 0),
+            transit("a", "待办", 2),
+            transit("b", "待办", 5),
+            transit("d", "进行中", 12),
+            //其他价值流的流转不参与统计
+            event("d", CardEventKind::FlowStatusChanged { flow_id: String::from("f2"), from: None, to: String::from("已完成") }, 24),
+            transit("a", "进行中", 36),
+            transit("b", "进行中", 60),
+            transit("a", "已完成", 84),
+            transit("c", "待办", 86),
+            //c跳过进行中直接完成，之后被重新打开，又再次完成
+            transit("c", "已完成", 108),
+            transit("c", "测试中", 132),
+            transit("c", "已完成", 204),
+        ];
+        let period = StatsPeriod::new(date("2024-05-06"), date("2024-05-14"), Tz::UTC);
+        let metrics = compute(&stages(), &events, &period);
+        let counts = |date: &str| metrics.cumulative_flow.iter().find(|it| it.date.to_string() == date).unwrap().counts.clone();
+        assert_eq!(counts("2024-05-06"), vec![2, 1, 0, 0]);
+        assert_eq!(counts("2024-05-09"), vec![1, 2, 0, 1]);
+        assert_eq!(counts("2024-05-12"), vec![0, 2, 1, 1]);
+        assert_eq!(metrics.wip.iter().map(|it| it.count).collect::<Vec<_>>(), vec![1, 2, 3, 2, 2, 3, 3, 3, 2]);
+        //a：创建到完成84小时，开始到完成48小时；c：以第一次流转为创建，直接完成也算开始，以最后一次完成为准
+        assert_eq!(metrics.lead_time, Percentiles { count: 2, p50: Some(84 * HOUR), p85: Some(118 * HOUR), p95: Some(118 * HOUR) });
+        assert_eq!((metrics.cycle_time.p50, metrics.cycle_time.p95), (Some(48 * HOUR), Some(96 * HOUR)));
+        assert_eq!(metrics.throughput, vec![WeeklyCount { week: date("2024-05-06"), count: 1 }, WeeklyCount { week: date("2024-05-13"), count: 1 }]);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let result = percentiles((1..=20).rev().collect());
+        assert_eq!((result.p50, result.p85, result.p95), (Some(10), Some(17), Some(19)));
+        assert_eq!(percentiles(vec![]).p50, None);
+    }
+}
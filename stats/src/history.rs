@@ -0,0 +1,161 @@
+//统计所需的卡片历史：从事件总线上记录卡片的创建、流转、关联等变化，保存在图数据库中，超过保留期限的历史定期删除
+use card::events;
+use card::events::{CardEvent, CardEventKind};
+use card::history::HistoryStore;
+use card::types::LinkDescriptor;
+use common::newtypes::card_id::CardId;
+use common::newtypes::tenant_id::TenantId;
+use common::newtypes::timestamp::Timestamp;
+use std::collections::HashSet;
+use std::error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+const DEFAULT_RETENTION: Duration = Duration::from_secs(2 * 365 * 24 * 60 * 60);
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+//卡片与某张卡片之间关联的建立或解除
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkChange {
+    pub time: i64,
+    pub card_id: CardId, //关联另一端的卡片
+    pub linked: bool,
+}
+
+pub struct CardHistory {
+    retention: Duration,
+}
+
+impl Default for CardHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_RETENTION)
+    }
+}
+
+impl CardHistory {
+    pub fn new(retention: Duration) -> Self {
+        Self { retention }
+    }
+
+    //保留期限内最早的时间
+    fn since(&self) -> i64 {
+        *Timestamp::now() - self.retention.as_millis() as i64
+    }
+
+    //属性修改不影响流动指标，不记录
+    pub fn is_recorded(event: &CardEvent) -> bool {
+        !matches!(event.kind, CardEventKind::Updated(_))
+    }
+
+    pub async fn record(&self, event: &CardEvent) -> Result<()> {
+        if !Self::is_recorded(event) {
+            return Ok(());
+        }
+        HistoryStore::save(event).await
+    }
+
+    //某些卡片上发生的事件，按发生时间排列
+    pub async fn events_of(&self, org_id: &TenantId, card_ids: &HashSet<CardId>) -> Result<Vec<CardEvent>> {
+        if card_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        HistoryStore::events_of(org_id, card_ids, self.since()).await
+    }
+
+    //卡片沿关联描述符关联的卡片的变化
+    pub async fn link_changes(&self, org_id: &TenantId, card_id: &CardId, descriptor: &LinkDescriptor) -> Result<Vec<LinkChange>> {
+        let events = HistoryStore::link_events_of(org_id, card_id, self.since()).await?;
+        Ok(link_changes(&events, card_id, descriptor))
+    }
+
+    //订阅卡片事件总线
+    pub async fn run(self: Arc<Self>) {
+        let mut receiver = events::subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    if let Err(err) = self.record(&event).await.map_err(|err| err.to_string()) {
+                        eprintln!("failed to record card event {}: {}", event.id, err);
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("card history lagged behind, {skipped} card events skipped");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    //定期删除超过保留期限的历史
+    pub async fn run_retention(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(err) = HistoryStore::prune(self.since()).await.map_err(|err| err.to_string()) {
+                eprintln!("failed to prune card history: {}", err);
+            }
+        }
+    }
+}
+
+//关联事件只发布在关联的起点上，两个方向都要查找
+fn link_changes(events: &[CardEvent], card_id: &CardId, descriptor: &LinkDescriptor) -> Vec<LinkChange> {
+    let reversed = descriptor.reverse();
+    events.iter()
+        .filter_map(|event| {
+            let (link, other_id, linked) = match &event.kind {
+                CardEventKind::Linked(link, other_id) => (link, other_id, true),
+                CardEventKind::Unlinked(link, other_id) => (link, other_id, false),
+                _ => return None,
+            };
+            if event.card_id == *card_id && link == descriptor {
+                Some(LinkChange { time: *event.occur_time, card_id: other_id.clone(), linked })
+            } else if other_id == card_id && *link == reversed {
+                Some(LinkChange { time: *event.occur_time, card_id: event.card_id.clone(), linked })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::newtypes::field_id::FieldId;
+
+    fn event(org_id: &str, card_id: &str, kind: CardEventKind, time: i64) -> CardEvent {
+        let mut event = CardEvent::new(&TenantId::from_str(org_id), &CardId::from_str(card_id), "需求", kind, &CardId::from_str("m1"));
+        event.occur_time = Timestamp::from(time);
+        event
+    }
+
+    #[test]
+    fn test_is_recorded() {
+        assert!(CardHistory::is_recorded(&event("o1", "c1", CardEventKind::Created, 10)));
+        assert!(CardHistory::is_recorded(&event("o1", "c1", CardEventKind::Archived, 10)));
+        assert!(!CardHistory::is_recorded(&event("o1", "c1", CardEventKind::Updated(vec![FieldId::from_str("估算")]), 20)));
+        let history = CardHistory::new(Duration::from_secs(60));
+        assert!(*Timestamp::now() - history.since() >= 60_000);
+    }
+
+    #[test]
+    fn test_link_changes() {
+        let contains = LinkDescriptor::Src(String::from("包含"));
+        let events = vec![
+            event("o1", "v1", CardEventKind::Linked(contains.clone(), CardId::from_str("c1")), 10),
+            //从需求一端建立的关联
+            event("o1", "c2", CardEventKind::Linked(LinkDescriptor::Dest(String::from("包含")), CardId::from_str("v1")), 20),
+            event("o1", "v1", CardEventKind::Unlinked(contains.clone(), CardId::from_str("c1")), 30),
+            event("o1", "v1", CardEventKind::Linked(LinkDescriptor::Src(String::from("依赖")), CardId::from_str("c3")), 40),
+        ];
+        let changes = link_changes(&events, &CardId::from_str("v1"), &contains);
+        assert_eq!(changes, vec![
+            LinkChange { time: 10, card_id: CardId::from_str("c1"), linked: true },
+            LinkChange { time: 20, card_id: CardId::from_str("c2"), linked: true },
+            LinkChange { time: 30, card_id: CardId::from_str("c1"), linked: false },
+        ]);
+    }
+}
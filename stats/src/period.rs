@@ -0,0 +1,53 @@
+//统计的时间范围，按组织所在时区划分日期，周从周一开始
+use chrono::{Datelike, Days, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+//start和end都包含在内
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsPeriod {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub timezone: Tz,
+}
+
+impl StatsPeriod {
+    pub fn new(start: NaiveDate, end: NaiveDate, timezone: Tz) -> Self {
+        Self { start, end, timezone }
+    }
+
+    pub fn days(&self) -> Vec<NaiveDate> {
+        self.start.iter_days().take_while(|it| *it <= self.end).collect()
+    }
+
+    //覆盖整个范围的各周的周一
+    pub fn weeks(&self) -> Vec<NaiveDate> {
+        let first = monday_of(self.start);
+        first.iter_weeks().take_while(|it| *it <= self.end).collect()
+    }
+
+    //某天零点的毫秒时间戳，夏令时切换导致零点不存在时取UTC零点
+    pub fn start_of(&self, date: NaiveDate) -> i64 {
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+        self.timezone.from_local_datetime(&midnight).earliest()
+            .map(|it| it.timestamp_millis())
+            .unwrap_or_else(|| midnight.and_utc().timestamp_millis())
+    }
+
+    //某天结束时的毫秒时间戳，即第二天零点
+    pub fn end_of(&self, date: NaiveDate) -> i64 {
+        self.start_of(date + Days::new(1))
+    }
+
+    pub fn date_of(&self, timestamp: i64) -> Option<NaiveDate> {
+        Utc.timestamp_millis_opt(timestamp).single().map(|it| it.with_timezone(&self.timezone).date_naive())
+    }
+
+    pub fn contains(&self, timestamp: i64) -> bool {
+        self.start_of(self.start) <= timestamp && timestamp < self.end_of(self.end)
+    }
+}
+
+pub fn monday_of(date: NaiveDate) -> NaiveDate {
+    date - Days::new(date.weekday().num_days_from_monday() as u64)
+}
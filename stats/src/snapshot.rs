@@ -0,0 +1,315 @@
+//统计快照：按(组织, 卡片类型, 价值流状态, 团队)预先聚合活动卡片数，由卡片事件增量更新，定期与图数据库全量校准
+//看板首页等读取快照，不再扫描图数据库；快照是组织级的统计，不区分成员的可见范围
+use card::aggregate;
+use card::aggregate::CardGroupKeys;
+use card::events;
+use card::events::{CardEvent, CardEventKind};
+use card::types::LinkDescriptor;
+use common::newtypes::card_id::CardId;
+use common::newtypes::tenant_id::TenantId;
+use common::newtypes::timestamp::Timestamp;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+//team_id为空的计数器是不分团队的总数；关联了多个团队的卡片在每个团队中各计一次
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct CounterKey {
+    pub card_type_id: String,
+    pub flow_status_id: Option<String>,
+    pub team_id: Option<CardId>,
+}
+
+//一张活动卡片当前所在的分组
+#[derive(Debug, Clone, PartialEq)]
+struct CardKey {
+    card_type_id: String,
+    flow_status_id: Option<String>,
+    team_ids: Vec<CardId>,
+}
+
+#[derive(Debug, Default)]
+struct OrgSnapshot {
+    cards: HashMap<CardId, CardKey>,
+    counters: HashMap<CounterKey, u64>,
+    reconciled_at: Option<Timestamp>,
+    pending: Option<Vec<CardEvent>>, //校准期间到达的事件，校准完成后重放
+}
+
+#[derive(Default)]
+pub struct StatsSnapshots {
+    team_links: RwLock<HashMap<TenantId, LinkDescriptor>>, //卡片沿该关联找到所属的团队，如Src("负责团队")
+    orgs: RwLock<HashMap<TenantId, OrgSnapshot>>,
+}
+
+impl CardKey {
+    fn counter_keys(&self) -> Vec<CounterKey> {
+        let key = |team_id: Option<&CardId>| CounterKey {
+            card_type_id: self.card_type_id.clone(),
+            flow_status_id: self.flow_status_id.clone(),
+            team_id: team_id.cloned(),
+        };
+        let mut keys = vec![key(None)];
+        keys.extend(self.team_ids.iter().map(|it| key(Some(it))));
+        keys
+    }
+}
+
+impl From<CardGroupKeys> for CardKey {
+    fn from(key: CardGroupKeys) -> Self {
+        CardKey { card_type_id: key.card_type_id, flow_status_id: key.flow_status_id, team_ids: key.linked_ids }
+    }
+}
+
+impl OrgSnapshot {
+    fn count(&mut self, key: &CardKey, delta: i64) {
+        for counter_key in key.counter_keys() {
+            let count = self.counters.entry(counter_key.clone()).or_default();
+            *count = count.saturating_add_signed(delta);
+            if *count == 0 {
+                self.counters.remove(&counter_key);
+            }
+        }
+    }
+
+    //修改一张卡片的分组，旧分组减一，新分组加一
+    fn update(&mut self, card_id: &CardId, change: impl FnOnce(Option<CardKey>) -> Option<CardKey>) {
+        let old = self.cards.remove(card_id);
+        if let Some(old) = &old {
+            self.count(old, -1);
+        }
+        if let Some(new) = change(old) {
+            self.count(&new, 1);
+            self.cards.insert(card_id.clone(), new);
+        }
+    }
+
+    //事件按“设置为某个状态”处理，重复应用结果不变，校准后可以安全地重放
+    //快照中没有的卡片（如在校准之前创建的卡片）等下一次校准时补上；恢复的卡片由StatsSnapshots::restore从图数据库读取
+    fn apply(&mut self, event: &CardEvent, team_link: Option<&LinkDescriptor>) {
+        if let Some(pending) = &mut self.pending {
+            pending.push(event.clone());
+        }
+        match &event.kind {
+            CardEventKind::Created => self.update(&event.card_id, |old| old.or_else(|| Some(CardKey {
+                card_type_id: event.card_type_id.clone(),
+                flow_status_id: None,
+                team_ids: vec![],
+            }))),
+            CardEventKind::FlowStatusChanged { to, .. } => self.update(&event.card_id, |old| old.map(|it| CardKey { flow_status_id: Some(to.clone()), ..it })),
+            CardEventKind::Archived | CardEventKind::Abandoned(_) => self.update(&event.card_id, |_| None),
+            CardEventKind::Linked(link, other_id) | CardEventKind::Unlinked(link, other_id) => {
+                let Some(team_link) = team_link else {
+                    return;
+                };
+                //关联事件发布在关联的起点上，可能是卡片，也可能是团队
+                let (card_id, team_id) = if link == team_link {
+                    (&event.card_id, other_id)
+                } else if *link == team_link.reverse() {
+                    (other_id, &event.card_id)
+                } else {
+                    return;
+                };
+                let linked = matches!(event.kind, CardEventKind::Linked(_, _));
+                self.update(card_id, |old| old.map(|mut it| {
+                    it.team_ids.retain(|id| id != team_id);
+                    if linked {
+                        it.team_ids.push(team_id.clone());
+                    }
+                    it
+                }));
+            }
+            _ => {}
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+impl StatsSnapshots {
+    pub fn set_team_link(&self, org_id: &TenantId, team_link: Option<LinkDescriptor>) {
+        let mut team_links = self.team_links.write().unwrap();
+        match team_link {
+            Some(team_link) => team_links.insert(org_id.clone(), team_link),
+            None => team_links.remove(org_id),
+        };
+    }
+
+    pub fn team_link(&self, org_id: &TenantId) -> Option<LinkDescriptor> {
+        self.team_links.read().unwrap().get(org_id).cloned()
+    }
+
+    pub fn apply(&self, event: &CardEvent) {
+        let team_link = self.team_link(&event.org_id);
+        let mut orgs = self.orgs.write().unwrap();
+        orgs.entry(event.org_id.clone()).or_default().apply(event, team_link.as_ref());
+    }
+
+    //恢复事件不携带卡片恢复后的状态和关联，从图数据库中读取卡片当前的分组
+    async fn restore(&self, event: &CardEvent) -> Result<()> {
+        let team_link = self.team_link(&event.org_id);
+        let key = aggregate::active_card_key(&event.org_id, &event.card_id, team_link.as_ref()).await?;
+        let mut orgs = self.orgs.write().unwrap();
+        orgs.entry(event.org_id.clone()).or_default().update(&event.card_id, |_| key.map(CardKey::from));
+        Ok(())
+    }
+
+    //满足条件的活动卡片数，条件为空表示不限
+    pub fn count(&self, org_id: &TenantId, card_type_id: Option<&str>, flow_status_id: Option<&str>, team_id: Option<&CardId>) -> u64 {
+        let orgs = self.orgs.read().unwrap();
+        orgs.get(org_id).into_iter()
+            .flat_map(|it| it.counters.iter())
+            .filter(|(key, _)| key.team_id.as_ref() == team_id)
+            .filter(|(key, _)| card_type_id.is_none_or(|it| key.card_type_id == it))
+            .filter(|(key, _)| flow_status_id.is_none_or(|it| key.flow_status_id.as_deref() == Some(it)))
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    pub fn counters(&self, org_id: &TenantId) -> Vec<(CounterKey, u64)> {
+        let orgs = self.orgs.read().unwrap();
+        orgs.get(org_id).map(|it| it.counters.iter().map(|(key, count)| (key.clone(), *count)).collect()).unwrap_or_default()
+    }
+
+    //最近一次全量校准的时间，从未校准过时快照只包含启动后创建的卡片
+    pub fn reconciled_at(&self, org_id: &TenantId) -> Option<Timestamp> {
+        self.orgs.read().unwrap().get(org_id).and_then(|it| it.reconciled_at.clone())
+    }
+
+    //用图数据库中的全量数据重建快照
+    pub async fn reconcile(&self, org_id: &TenantId) -> Result<()> {
+        self.orgs.write().unwrap().entry(org_id.clone()).or_default().pending = Some(vec![]);
+        let team_link = self.team_link(org_id);
+        let keys = aggregate::active_card_keys(org_id, team_link.as_ref()).await;
+        let mut orgs = self.orgs.write().unwrap();
+        let snapshot = orgs.entry(org_id.clone()).or_default();
+        let pending = snapshot.pending.take().unwrap_or_default();
+        let keys = keys?;
+        *snapshot = rebuild(keys, &pending, team_link.as_ref());
+        Ok(())
+    }
+
+    //订阅卡片事件总线
+    pub async fn run(self: Arc<Self>) {
+        let mut receiver = events::subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    self.apply(&event);
+                    if event.kind == CardEventKind::Restored {
+                        if let Err(err) = self.restore(&event).await.map_err(|err| err.to_string()) {
+                            eprintln!("failed to restore card {} in stats snapshot: {}", event.card_id, err);
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    //丢失的事件由下一次校准修正
+                    eprintln!("stats snapshots lagged behind, {skipped} card events skipped");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    //定期校准图数据库中的所有组织，第一次校准在启动时立即进行，启动前就存在的组织也有完整的快照
+    pub async fn run_reconciliation(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let mut org_ids: HashSet<TenantId> = {
+                let orgs = self.orgs.read().unwrap();
+                let team_links = self.team_links.read().unwrap();
+                orgs.keys().chain(team_links.keys()).cloned().collect()
+            };
+            match aggregate::org_ids().await.map_err(|err| err.to_string()) {
+                Ok(ids) => org_ids.extend(ids),
+                Err(err) => eprintln!("failed to list organizations for stats snapshots: {}", err),
+            }
+            for org_id in org_ids {
+                if let Err(err) = self.reconcile(&org_id).await {
+                    eprintln!("failed to reconcile stats snapshot of {}: {}", org_id, err);
+                }
+            }
+        }
+    }
+}
+
+fn rebuild(keys: Vec<CardGroupKeys>, pending: &[CardEvent], team_link: Option<&LinkDescriptor>) -> OrgSnapshot {
+    let mut snapshot = OrgSnapshot { reconciled_at: Some(Timestamp::now()), ..OrgSnapshot::default() };
+    for key in keys {
+        let card_id = key.card_id.clone();
+        snapshot.update(&card_id, |_| Some(CardKey::from(key)));
+    }
+    for event in pending {
+        snapshot.apply(event, team_link);
+    }
+    snapshot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(card_id: &str, kind: CardEventKind) -> CardEvent {
+        CardEvent::new(&TenantId::from_str("o1"), &CardId::from_str(card_id), "需求", kind, &CardId::from_str("m1"))
+    }
+
+    fn transit(card_id: &str, to: &str) -> CardEvent {
+        event(card_id, CardEventKind::FlowStatusChanged { flow_id: String::from("f1"), from: None, to: String::from(to) })
+    }
+
+    #[test]
+    fn test_apply() {
+        let snapshots = StatsSnapshots::default();
+        let org_id = TenantId::from_str("o1");
+        let team_link = LinkDescriptor::Src(String::from("负责团队"));
+        snapshots.set_team_link(&org_id, Some(team_link.clone()));
+        let team = CardId::from_str("研发");
+        snapshots.apply(&event("c1", CardEventKind::Created));
+        snapshots.apply(&transit("c1", "待办"));
+        snapshots.apply(&event("c2", CardEventKind::Created));
+        snapshots.apply(&transit("c2", "待办"));
+        snapshots.apply(&event("c1", CardEventKind::Linked(team_link.clone(), team.clone())));
+        //从团队一端建立的关联
+        snapshots.apply(&CardEvent::new(&org_id, &team, "团队", CardEventKind::Linked(team_link.reverse(), CardId::from_str("c2")), &CardId::from_str("m1")));
+        snapshots.apply(&transit("c2", "进行中"));
+        assert_eq!(snapshots.count(&org_id, Some("需求"), None, None), 2);
+        assert_eq!(snapshots.count(&org_id, None, Some("待办"), None), 1);
+        assert_eq!(snapshots.count(&org_id, None, Some("进行中"), Some(&team)), 1);
+        assert_eq!(snapshots.count(&org_id, None, None, Some(&team)), 2);
+        //重复的事件不影响计数
+        snapshots.apply(&event("c1", CardEventKind::Created));
+        snapshots.apply(&event("c1", CardEventKind::Linked(team_link.clone(), team.clone())));
+        assert_eq!(snapshots.count(&org_id, None, None, Some(&team)), 2);
+        snapshots.apply(&event("c1", CardEventKind::Unlinked(team_link.clone(), team.clone())));
+        snapshots.apply(&event("c2", CardEventKind::Archived));
+        assert_eq!(snapshots.count(&org_id, None, None, Some(&team)), 0);
+        assert_eq!(snapshots.count(&org_id, None, None, None), 1);
+        //恢复后的分组要从图数据库读取，事件本身不改变计数
+        snapshots.apply(&event("c2", CardEventKind::Restored));
+        assert_eq!(snapshots.count(&org_id, None, None, None), 1);
+        assert_eq!(snapshots.counters(&org_id), vec![(CounterKey { card_type_id: String::from("需求"), flow_status_id: Some(String::from("待办")), team_id: None }, 1)]);
+        assert_eq!(snapshots.count(&TenantId::from_str("o2"), None, None, None), 0);
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let team_link = LinkDescriptor::Src(String::from("负责团队"));
+        let keys = vec![
+            CardGroupKeys { card_id: CardId::from_str("c1"), card_type_id: String::from("需求"), flow_status_id: Some(String::from("待办")), linked_ids: vec![CardId::from_str("研发")] },
+            CardGroupKeys { card_id: CardId::from_str("c2"), card_type_id: String::from("需求"), flow_status_id: Some(String::from("待办")), linked_ids: vec![] },
+        ];
+        //校准期间c1已经流转，c3已经创建，重放后c1的流转仍然生效
+        let pending = vec![transit("c1", "进行中"), event("c3", CardEventKind::Created)];
+        let mut snapshot = rebuild(keys, &pending, Some(&team_link));
+        assert!(snapshot.reconciled_at.is_some());
+        assert_eq!(snapshot.cards.len(), 3);
+        assert_eq!(snapshot.counters[&CounterKey { card_type_id: String::from("需求"), flow_status_id: Some(String::from("进行中")), team_id: Some(CardId::from_str("研发")) }], 1);
+        assert_eq!(snapshot.counters[&CounterKey { card_type_id: String::from("需求"), flow_status_id: Some(String::from("待办")), team_id: None }], 1);
+        snapshot.apply(&event("c3", CardEventKind::Abandoned(String::from("重复"))), Some(&team_link));
+        assert_eq!(snapshot.counters.values().sum::<u64>(), 3);
+    }
+}
@@ -0,0 +1,185 @@
+//日历视图：每次只查询当前可见的日期窗口，卡片按日期属性落到窗口中的某一天
+use crate::definition::{CalendarField, CalendarViewDefinition, DatePrecision};
+use card::card::FieldValue;
+use card::query::{CardRecord, Condition, ConditionItem, DateOperator, LogicConditionBulk, LogicConditionGroup, PropertyValue};
+use chrono::{Datelike, Days, Months, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CalendarScale {
+    Day,
+    Week, //周一开始
+    Month,
+}
+
+//查看者当前看到的日期窗口，date为窗口中的任意一天
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalendarWindow {
+    pub scale: CalendarScale,
+    pub date: NaiveDate,
+    pub timezone: Tz,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CalendarDay {
+    pub date: NaiveDate,
+    pub entries: Vec<CalendarEntry>,
+}
+
+//同一张卡片的多个日期属性可能落在不同的日子里
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CalendarEntry {
+    pub field_id: String,
+    pub card: CardRecord,
+}
+
+impl CalendarWindow {
+    //窗口包含的日期，左闭右开
+    pub fn days(&self) -> (NaiveDate, NaiveDate) {
+        let first = match self.scale {
+            CalendarScale::Day => self.date,
+            CalendarScale::Week => self.date - Days::new(self.date.weekday().num_days_from_monday() as u64),
+            CalendarScale::Month => self.date.with_day(1).unwrap(),
+        };
+        let last = match self.scale {
+            CalendarScale::Day => first + Days::new(1),
+            CalendarScale::Week => first + Days::new(7),
+            CalendarScale::Month => first + Months::new(1),
+        };
+        (first, last)
+    }
+
+    //窗口在某种精度的日期属性上对应的毫秒时间戳范围，左闭右开
+    fn bounds(&self, precision: DatePrecision) -> (i64, i64) {
+        let (first, last) = self.days();
+        (self.start_of(first, precision), self.start_of(last, precision))
+    }
+
+    fn start_of(&self, date: NaiveDate, precision: DatePrecision) -> i64 {
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+        match precision {
+            DatePrecision::Day => midnight.and_utc().timestamp_millis(),
+            //夏令时切换导致本地零点不存在时，取UTC零点
+            DatePrecision::Minute => self.timezone.from_local_datetime(&midnight).earliest()
+                .map(|it| it.timestamp_millis())
+                .unwrap_or_else(|| midnight.and_utc().timestamp_millis()),
+        }
+    }
+
+    //时间戳在查看者看来是哪一天
+    fn date_of(&self, timestamp: i64, precision: DatePrecision) -> Option<NaiveDate> {
+        let time = Utc.timestamp_millis_opt(timestamp).single()?;
+        match precision {
+            DatePrecision::Day => Some(time.date_naive()),
+            DatePrecision::Minute => Some(time.with_timezone(&self.timezone).date_naive()),
+        }
+    }
+}
+
+//视图保存的条件上，再要求任一日期属性落在窗口中
+pub(crate) fn window_condition(calendar: &CalendarViewDefinition, window: &CalendarWindow) -> Condition {
+    let mut condition = calendar.condition().clone();
+    let items = calendar.date_fields().iter()
+        .map(|it| {
+            let (start, end) = window.bounds(it.precision);
+            ConditionItem::Date(it.field_id.clone(), DateOperator::Between(PropertyValue::StaticValue(start), PropertyValue::StaticValue(end - 1)))
+        })
+        .collect();
+    condition.and_logic(LogicConditionBulk::new(vec![LogicConditionGroup::new(items)]));
+    condition
+}
+
+fn timestamp_of(card: &CardRecord, date_field: &CalendarField) -> Option<i64> {
+    card.fields.iter()
+        .find(|it| it.id == date_field.field_id)
+        .and_then(|it| match &it.value {
+            FieldValue::Date(v) | FieldValue::DateTime(v) => Some(**v),
+            FieldValue::Int(v) => Some(*v as i64),
+            _ => None,
+        })
+}
+
+pub(crate) fn into_days(calendar: &CalendarViewDefinition, window: &CalendarWindow, cards: Vec<CardRecord>) -> Vec<CalendarDay> {
+    let (first, last) = window.days();
+    let mut days: Vec<CalendarDay> = first.iter_days().take_while(|it| *it < last)
+        .map(|date| CalendarDay { date, entries: vec![] })
+        .collect();
+    for card in cards {
+        for date_field in calendar.date_fields() {
+            let date = timestamp_of(&card, date_field).and_then(|it| window.date_of(it, date_field.precision));
+            if let Some(day) = days.iter_mut().find(|it| Some(it.date) == date) {
+                day.entries.push(CalendarEntry { field_id: date_field.field_id.to_string(), card: card.clone() });
+            }
+        }
+    }
+    days
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use card::card::{CardState, Field};
+    use common::newtypes::card_id::CardId;
+    use common::newtypes::field_id::FieldId;
+    use common::newtypes::tenant_id::TenantId;
+    use common::newtypes::timestamp::Timestamp;
+
+    fn window(scale: CalendarScale, date: &str) -> CalendarWindow {
+        CalendarWindow { scale, date: date.parse().unwrap(), timezone: Tz::Asia__Shanghai }
+    }
+
+    fn millis(time: &str) -> i64 {
+        chrono::DateTime::parse_from_rfc3339(time).unwrap().timestamp_millis()
+    }
+
+    #[test]
+    fn test_window_days() {
+        let (first, last) = window(CalendarScale::Week, "2024-05-01").days();
+        assert_eq!((first.to_string(), last.to_string()), (String::from("2024-04-29"), String::from("2024-05-06")));
+        let (first, last) = window(CalendarScale::Month, "2024-02-15").days();
+        assert_eq!((first.to_string(), last.to_string()), (String::from("2024-02-01"), String::from("2024-03-01")));
+        //具体时刻按查看者的时区划分日期，只有日期的属性不受时区影响
+        let day = window(CalendarScale::Day, "2024-05-01");
+        assert_eq!(day.bounds(DatePrecision::Minute), (millis("2024-04-30T16:00:00Z"), millis("2024-05-01T16:00:00Z")));
+        assert_eq!(day.bounds(DatePrecision::Day), (millis("2024-05-01T00:00:00Z"), millis("2024-05-02T00:00:00Z")));
+    }
+
+    #[test]
+    fn test_into_days() {
+        let calendar = CalendarViewDefinition::new(Condition::default(), vec![
+            CalendarField { field_id: FieldId::from_str("计划完成时间"), precision: DatePrecision::Day },
+            CalendarField { field_id: FieldId::from_str("评审时间"), precision: DatePrecision::Minute },
+        ], vec![]);
+        let window = window(CalendarScale::Week, "2024-05-01");
+        let card = CardRecord {
+            id: CardId::from_str("c1"),
+            code: String::from("c1"),
+            name: String::from("c1"),
+            card_type_id: String::from("需求"),
+            org_id: TenantId::from_str("o1"),
+            state: CardState::Active,
+            flow_status: None,
+            fields: vec![
+                Field::new(FieldId::from_str("计划完成时间"), FieldValue::Date(Timestamp::from(millis("2024-05-03T00:00:00Z")))),
+                //UTC时间4月30日20点，上海时间已是5月1日
+                Field::new(FieldId::from_str("评审时间"), FieldValue::DateTime(Timestamp::from(millis("2024-04-30T20:00:00Z")))),
+            ],
+        };
+        let days = into_days(&calendar, &window, vec![card]);
+        assert_eq!(days.len(), 7);
+        assert_eq!(days[2].entries.len(), 1);
+        assert_eq!(days[2].entries[0].field_id, "评审时间");
+        assert_eq!(days[4].entries[0].field_id, "计划完成时间");
+        assert!(days[1].entries.is_empty());
+        //一次查询覆盖所有日期属性
+        let condition = window_condition(&calendar, &window);
+        let expected = LogicConditionBulk::new(vec![LogicConditionGroup::new(vec![
+            ConditionItem::Date(FieldId::from_str("计划完成时间"), DateOperator::Between(PropertyValue::StaticValue(millis("2024-04-29T00:00:00Z")), PropertyValue::StaticValue(millis("2024-05-06T00:00:00Z") - 1))),
+            ConditionItem::Date(FieldId::from_str("评审时间"), DateOperator::Between(PropertyValue::StaticValue(millis("2024-04-28T16:00:00Z")), PropertyValue::StaticValue(millis("2024-05-05T16:00:00Z") - 1))),
+        ])]);
+        let mut condition_expected = Condition::default();
+        condition_expected.and_logic(expected);
+        assert_eq!(condition, condition_expected);
+    }
+}
@@ -0,0 +1,164 @@
+//仪表盘：由多个部件组成，部件包括计数器、透视图表、嵌入的列表视图、流动指标图表和燃尽图
+//仪表盘可以定义一个共享参数（如选中的版本），部件条件中的ReferPoint::Parameter都引用这张卡片
+use crate::definition::{PivotViewDefinition, ViewOwner};
+use card::query::{Condition, PARAMETER_CARD_PARAMETER};
+use card::types::LinkDescriptor;
+use common::newtypes::card_id::CardId;
+use common::newtypes::field_id::FieldId;
+use common::newtypes::tenant_id::TenantId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DashboardDefinition {
+    id: u32,
+    name: String,
+    description: String,
+    tenant_id: TenantId,
+    #[serde(default)]
+    owner: ViewOwner, //可见范围和维护权限与视图相同
+    #[serde(default)]
+    parameter: Option<DashboardParameter>,
+    widgets: Vec<Widget>, //按显示顺序排列
+}
+
+//共享参数的候选卡片为某个卡片类型的卡片，如所有版本
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct DashboardParameter {
+    pub title: String,
+    pub card_type_id: String,
+    #[serde(default)]
+    pub default_card_id: Option<CardId>, //查看时没有选择卡片时使用
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Widget {
+    pub title: String,
+    pub kind: WidgetKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum WidgetKind {
+    //读取预先聚合的组织级快照，不受共享参数影响；成员受可见范围限制时按成员的可见范围实时统计
+    SnapshotCounter {
+        card_type_id: Option<String>,
+        flow_status_id: Option<String>,
+        team_id: Option<CardId>,
+    },
+    //按条件实时统计当前成员可见的卡片数
+    Counter(Condition),
+    Pivot(PivotViewDefinition),
+    //嵌入已保存的列表视图，视图对当前成员不可见时部件不显示内容
+    ListView {
+        view_id: u32,
+        #[serde(default)]
+        page_size: Option<u8>,
+    },
+    FlowMetrics {
+        flow_id: String,
+        start_status: String,
+        done_status: String,
+        #[serde(default)]
+        condition: Condition,
+        chart: FlowChart,
+    },
+    //容器卡片为共享参数选中的卡片，link为从容器卡片看到的关联
+    Burndown {
+        link: LinkDescriptor,
+        flow_id: String,
+        done_status: String,
+        #[serde(default)]
+        measure: Option<FieldId>, //未设置时按卡片数统计
+    },
+}
+
+//流动指标部件展示的图表
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum FlowChart {
+    CumulativeFlow,
+    LeadTime,
+    CycleTime,
+    Throughput,
+    Wip,
+}
+
+impl DashboardDefinition {
+    pub fn new(id: u32, name: &str, description: &str, tenant_id: TenantId, widgets: Vec<Widget>) -> Self {
+        Self {
+            id,
+            name: String::from(name),
+            description: String::from(description),
+            tenant_id,
+            owner: ViewOwner::Org,
+            parameter: None,
+            widgets,
+        }
+    }
+
+    pub fn with_owner(mut self, owner: ViewOwner) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    pub fn with_parameter(mut self, parameter: DashboardParameter) -> Self {
+        self.parameter = Some(parameter);
+        self
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn tenant_id(&self) -> &TenantId {
+        &self.tenant_id
+    }
+
+    pub fn owner(&self) -> &ViewOwner {
+        &self.owner
+    }
+
+    pub fn parameter(&self) -> Option<&DashboardParameter> {
+        self.parameter.as_ref()
+    }
+
+    pub fn widgets(&self) -> &[Widget] {
+        &self.widgets
+    }
+
+    //查看时选中的卡片优先，其次为参数的默认卡片；仪表盘没有定义参数时忽略选中的卡片
+    pub fn selected_card(&self, selected: Option<CardId>) -> Option<CardId> {
+        let parameter = self.parameter.as_ref()?;
+        selected.or_else(|| parameter.default_card_id.clone())
+    }
+
+    //所有部件共用的查询参数
+    pub fn query_parameters(&self, selected: Option<CardId>) -> HashMap<String, String> {
+        self.selected_card(selected).into_iter()
+            .map(|it| (String::from(PARAMETER_CARD_PARAMETER), it.to_string()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_parameters() {
+        let widgets = vec![Widget { title: String::from("需求数"), kind: WidgetKind::Counter(Condition::default()) }];
+        let dashboard = DashboardDefinition::new(1, "版本概览", "", TenantId::from_str("o1"), widgets);
+        //没有定义参数
+        assert!(dashboard.query_parameters(Some(CardId::from_str("v2"))).is_empty());
+        let dashboard = dashboard.with_parameter(DashboardParameter {
+            title: String::from("版本"),
+            card_type_id: String::from("版本"),
+            default_card_id: Some(CardId::from_str("v1")),
+        });
+        assert_eq!(dashboard.query_parameters(None).get(PARAMETER_CARD_PARAMETER).map(String::as_str), Some("v1"));
+        assert_eq!(dashboard.query_parameters(Some(CardId::from_str("v2"))).get(PARAMETER_CARD_PARAMETER).map(String::as_str), Some("v2"));
+    }
+}
@@ -1,31 +1,598 @@
+use card::aggregate::{Dimension, Measure};
+use card::query::{BuiltInProperty, Condition, ConditionItem, LinkOperator, LinkValue, Page, Sort};
+use card::types::{LinkDescriptor, Path};
+use common::newtypes::card_id::CardId;
+use common::newtypes::field_id::FieldId;
+use common::newtypes::tenant_id::TenantId;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ViewDefinition {
     id: u32,
     name: String,
     description: String,
-    tenant_id: u32,
+    tenant_id: TenantId,
+    #[serde(default)]
+    owner: ViewOwner,
     view_type: ViewType,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+//视图的归属决定了谁能看到它：个人视图只有本人可见，团队视图团队（包括下级团队）的成员可见，组织视图所有人可见
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub enum ViewOwner {
+    #[default]
+    Org,
+    Team(CardId),
+    Member(CardId),
+}
+
+//成员在共享视图上的个人调整，叠加在视图定义上，不修改视图本身
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct ViewOverride {
+    #[serde(default)]
+    pub column_widths: HashMap<String, u16>, //key为列标题
+    #[serde(default)]
+    pub condition: Option<Condition>, //临时过滤条件，与视图保存的条件同时生效
+    #[serde(default)]
+    pub sort: Option<Sort>,
+    #[serde(default)]
+    pub page_size: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum ViewType {
     ListView(ListViewDefinition),
-    BoardView,
+    BoardView(BoardViewDefinition),
+    TreeView(TreeViewDefinition),
+    TimelineView(TimelineViewDefinition),
+    CalendarView(CalendarViewDefinition),
+    PivotView(PivotViewDefinition),
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+//列表视图完整描述了一个查询以及结果的展示方式
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct ListViewDefinition {
     columns: Vec<Column>,
+    #[serde(default)]
+    condition: Condition, //视图保存的过滤条件
+    #[serde(default)]
+    sort: Option<Sort>, //未设置时按创建时间倒序
+    #[serde(default = "default_page_size")]
+    page_size: u8,
+}
+
+fn default_page_size() -> u8 {
+    20
+}
+
+//看板视图：卡片按分组维度分列，还可以按第二个维度分泳道
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BoardViewDefinition {
+    #[serde(default)]
+    condition: Condition,
+    group_by: GroupBy,
+    columns: Vec<BoardColumn>, //按显示顺序排列
+    #[serde(default)]
+    swimlanes: Option<Swimlanes>,
+    #[serde(default)]
+    sort: Option<Sort>, //列内卡片的顺序，未设置时按创建时间倒序
+    #[serde(default)]
+    fields: Vec<FieldId>, //卡片上显示的属性
+}
+
+//看板的分组维度，分组的key分别是价值流状态id、枚举选项、关联卡片id
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum GroupBy {
+    Status,
+    Enum(FieldId),
+    Link(LinkDescriptor), //如按所属版本分组
+}
+
+//看板的列，在制品限制只做提示，不阻止卡片移入
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BoardColumn {
+    key: String,
+    title: String,
+    #[serde(default)]
+    wip_limit: Option<u32>,
+}
+
+//泳道，不属于任何泳道的卡片归入最后的"其他"泳道
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Swimlanes {
+    group_by: GroupBy,
+    lanes: Vec<Group>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Group {
+    key: String,
+    title: String,
+}
+
+//树视图：从满足条件的根卡片出发，沿关联逐层展开，如部落 -> 小队 -> 成员、版本 -> 需求 -> 系统任务
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TreeViewDefinition {
+    #[serde(default)]
+    condition: Condition, //根卡片的条件
+    levels: Vec<TreeLevel>, //第n个元素描述第n层到第n+1层的展开方式
+    #[serde(default)]
+    fields: Vec<FieldId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TreeLevel {
+    link: LinkDescriptor, //从上一层卡片看到的关联
+    #[serde(default)]
+    condition: Condition, //本层卡片还需要满足的条件
+    #[serde(default)]
+    done_statuses: Vec<String>, //本层卡片处于这些价值流状态时视为完成，用于计算上一层的完成比例
+}
+
+//时间线（甘特图）视图：卡片按开始、结束两个日期属性排成横条
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TimelineViewDefinition {
+    #[serde(default)]
+    condition: Condition,
+    start_field: FieldId, //如计划开始时间
+    end_field: FieldId, //如计划完成时间
+    #[serde(default)]
+    group_by: Option<GroupBy>,
+    #[serde(default)]
+    dependency: Option<String>, //依赖的关联类型，关联的起点完成后终点才能开始
+    #[serde(default)]
+    fields: Vec<FieldId>,
+}
+
+//日历视图：卡片按一个或多个日期属性落到日历的格子里
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CalendarViewDefinition {
+    #[serde(default)]
+    condition: Condition,
+    date_fields: Vec<CalendarField>,
+    #[serde(default)]
+    fields: Vec<FieldId>,
+}
+
+//透视视图：按一个或两个维度分组统计卡片，如各团队处于各状态的需求数、各版本的估算之和
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PivotViewDefinition {
+    #[serde(default)]
+    condition: Condition,
+    rows: Dimension,
+    #[serde(default)]
+    columns: Option<Dimension>,
+    measures: Vec<Measure>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CalendarField {
+    pub field_id: FieldId,
+    pub precision: DatePrecision,
+}
+
+//日期属性的精度
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum DatePrecision {
+    Day, //只有日期，保存为UTC零点，不随查看者的时区变化
+    Minute, //具体的时刻，按查看者的时区确定落在哪一天
+}
+
+//拖动卡片到某一列（以及某一泳道）时，卡片在对应分组维度上要变更到的值，keys为该维度上看板所有分组的值
+#[derive(Clone, PartialEq, Debug)]
+pub struct BoardMove<'a> {
+    pub group_by: &'a GroupBy,
+    pub key: &'a str,
+    pub keys: Vec<&'a str>,
+}
+
+impl BoardMove<'_> {
+    //多值枚举移动后去掉看板上其他分组的选项，换成目标分组的选项，不在看板上的选项保留
+    pub fn options(&self, current: &[String]) -> Vec<String> {
+        let mut options: Vec<String> = current.iter()
+            .filter(|it| !self.keys.contains(&it.as_str()))
+            .cloned()
+            .collect();
+        options.push(self.key.to_string());
+        options
+    }
+}
+
+//列表的列
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Column {
+    kind: ColumnKind,
+    title: String,
+    #[serde(default)]
+    width: Option<u16>, //像素宽度，未设置时自适应
+    #[serde(default)]
+    format: ColumnFormat,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum ColumnKind {
+    Property(BuiltInProperty), //卡片的内置属性
+    Field(FieldId), //卡片的自定义属性
+    LinkedField(Path, FieldId), //沿关联路径到达的卡片上的属性，如父需求的负责人
+    Computed(FieldId), //计算属性，值由计算属性定义的公式得出
+}
+
+//列的显示格式，只影响展示，不影响查询
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub enum ColumnFormat {
+    #[default]
+    Plain,
+    Date(String), //日期格式，如 yyyy-MM-dd
+    Number(u8), //保留的小数位数
+    Percent(u8),
+}
+
+impl ViewDefinition {
+    pub fn new(id: u32, name: &str, description: &str, tenant_id: TenantId, view_type: ViewType) -> Self {
+        Self {
+            id,
+            name: String::from(name),
+            description: String::from(description),
+            tenant_id,
+            owner: ViewOwner::Org,
+            view_type,
+        }
+    }
+
+    pub fn with_owner(mut self, owner: ViewOwner) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn owner(&self) -> &ViewOwner {
+        &self.owner
+    }
+
+    //叠加成员的个人调整，列宽、排序和分页大小只对列表视图有效
+    pub fn with_override(&self, view_override: &ViewOverride) -> ViewDefinition {
+        let mut definition = self.clone();
+        if let Some(condition) = &view_override.condition {
+            definition.view_type.condition_mut().and_condition(condition.clone());
+        }
+        if let ViewType::ListView(list) = &mut definition.view_type {
+            for column in &mut list.columns {
+                if let Some(width) = view_override.column_widths.get(&column.title) {
+                    column.width = Some(*width);
+                }
+            }
+            if view_override.sort.is_some() {
+                list.sort = view_override.sort.clone();
+            }
+            if let Some(page_size) = view_override.page_size {
+                list.page_size = page_size;
+            }
+        }
+        definition
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn tenant_id(&self) -> &TenantId {
+        &self.tenant_id
+    }
+
+    pub fn view_type(&self) -> &ViewType {
+        &self.view_type
+    }
+
+    //渲染时需要从卡片上读取的属性
+    pub fn fields(&self) -> Vec<FieldId> {
+        match &self.view_type {
+            ViewType::ListView(list) => list.columns.iter().filter_map(|it| it.field_id()).cloned().collect(),
+            ViewType::TreeView(tree) => tree.fields.clone(),
+            //透视视图在数据库中聚合，不读取卡片
+            ViewType::PivotView(_) => vec![],
+            ViewType::CalendarView(calendar) => {
+                let mut fields = calendar.fields.clone();
+                for date_field in &calendar.date_fields {
+                    if !fields.contains(&date_field.field_id) {
+                        fields.push(date_field.field_id.clone());
+                    }
+                }
+                fields
+            }
+            ViewType::TimelineView(timeline) => {
+                let mut fields = timeline.fields.clone();
+                let mut required = vec![&timeline.start_field, &timeline.end_field];
+                if let Some(GroupBy::Enum(field_id)) = &timeline.group_by {
+                    required.push(field_id);
+                }
+                for field_id in required {
+                    if !fields.contains(field_id) {
+                        fields.push(field_id.clone());
+                    }
+                }
+                fields
+            }
+            ViewType::BoardView(board) => {
+                //按枚举分组时需要读取分组属性
+                let mut fields = board.fields.clone();
+                let group_fields = [Some(&board.group_by), board.swimlanes.as_ref().map(|it| &it.group_by)];
+                for group_by in group_fields.into_iter().flatten() {
+                    if let GroupBy::Enum(field_id) = group_by {
+                        if !fields.contains(field_id) {
+                            fields.push(field_id.clone());
+                        }
+                    }
+                }
+                fields
+            }
+        }
+    }
+}
+
+impl Column {
+    pub fn new(kind: ColumnKind, title: &str, width: Option<u16>, format: ColumnFormat) -> Self {
+        Self { kind, title: String::from(title), width, format }
+    }
+
+    pub fn kind(&self) -> &ColumnKind {
+        &self.kind
+    }
+
+    //卡片自身上的属性，关联卡片的属性不在卡片自身上
+    fn field_id(&self) -> Option<&FieldId> {
+        match &self.kind {
+            ColumnKind::Field(field_id) | ColumnKind::Computed(field_id) => Some(field_id),
+            ColumnKind::Property(_) | ColumnKind::LinkedField(_, _) => None,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub struct Column {}
+impl ViewType {
+    fn condition_mut(&mut self) -> &mut Condition {
+        match self {
+            ViewType::ListView(list) => &mut list.condition,
+            ViewType::BoardView(board) => &mut board.condition,
+            ViewType::TreeView(tree) => &mut tree.condition,
+            ViewType::TimelineView(timeline) => &mut timeline.condition,
+            ViewType::CalendarView(calendar) => &mut calendar.condition,
+            ViewType::PivotView(pivot) => &mut pivot.condition,
+        }
+    }
+}
+
+impl ListViewDefinition {
+    pub fn new(columns: Vec<Column>, condition: Condition, sort: Option<Sort>, page_size: u8) -> Self {
+        Self { columns, condition, sort, page_size }
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    pub fn condition(&self) -> &Condition {
+        &self.condition
+    }
+
+    pub fn sort(&self) -> Option<&Sort> {
+        self.sort.as_ref()
+    }
 
+    pub fn page_size(&self) -> u8 {
+        self.page_size
+    }
+
+    //页码从1开始，未指定分页大小时使用视图保存的分页大小
+    pub fn page(&self, num: u32, size: Option<u8>) -> Page {
+        let size = size.unwrap_or(self.page_size);
+        match &self.sort {
+            Some(sort) => Page::LimitAfterSort(sort.clone(), num.max(1), size),
+            None => Page::Limit(num.max(1), size),
+        }
+    }
+}
+
+impl BoardViewDefinition {
+    pub fn new(condition: Condition, group_by: GroupBy, columns: Vec<BoardColumn>, swimlanes: Option<Swimlanes>, sort: Option<Sort>, fields: Vec<FieldId>) -> Self {
+        Self { condition, group_by, columns, swimlanes, sort, fields }
+    }
+
+    pub fn condition(&self) -> &Condition {
+        &self.condition
+    }
+
+    pub fn group_by(&self) -> &GroupBy {
+        &self.group_by
+    }
+
+    pub fn columns(&self) -> &[BoardColumn] {
+        &self.columns
+    }
 
+    pub fn swimlanes(&self) -> Option<&Swimlanes> {
+        self.swimlanes.as_ref()
+    }
+
+    //看板展示全部卡片，不分页
+    pub fn page(&self) -> Page {
+        match &self.sort {
+            Some(sort) => Page::AllAfterSort(sort.clone()),
+            None => Page::None,
+        }
+    }
+
+    //移动的目标必须是看板上的列和泳道，拖到"其他"泳道时泳道维度不变
+    pub fn moves(&self, column: &str, swimlane: Option<&str>) -> Option<Vec<BoardMove<'_>>> {
+        let column = self.columns.iter().find(|it| it.key == column)?;
+        let keys = self.columns.iter().map(|it| it.key.as_str()).collect();
+        let mut moves = vec![BoardMove { group_by: &self.group_by, key: &column.key, keys }];
+        if let (Some(swimlanes), Some(swimlane)) = (&self.swimlanes, swimlane) {
+            let lane = swimlanes.lanes.iter().find(|it| it.key == swimlane)?;
+            let keys = swimlanes.lanes.iter().map(|it| it.key.as_str()).collect();
+            moves.push(BoardMove { group_by: &swimlanes.group_by, key: &lane.key, keys });
+        }
+        Some(moves)
+    }
+
+    //按自定义属性排序时，列内调整顺序即修改该属性
+    pub fn order_field(&self) -> Option<&FieldId> {
+        match &self.sort {
+            Some(Sort::Field(field_id, _)) => Some(field_id),
+            _ => None,
+        }
+    }
+}
+
+impl BoardColumn {
+    pub fn new(key: &str, title: &str, wip_limit: Option<u32>) -> Self {
+        Self { key: String::from(key), title: String::from(title), wip_limit }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn wip_limit(&self) -> Option<u32> {
+        self.wip_limit
+    }
+}
+
+impl Swimlanes {
+    pub fn new(group_by: GroupBy, lanes: Vec<Group>) -> Self {
+        Self { group_by, lanes }
+    }
+
+    pub fn group_by(&self) -> &GroupBy {
+        &self.group_by
+    }
+
+    pub fn lanes(&self) -> &[Group] {
+        &self.lanes
+    }
+}
+
+impl TreeViewDefinition {
+    pub fn new(condition: Condition, levels: Vec<TreeLevel>, fields: Vec<FieldId>) -> Self {
+        Self { condition, levels, fields }
+    }
+
+    pub fn condition(&self) -> &Condition {
+        &self.condition
+    }
+
+    pub fn levels(&self) -> &[TreeLevel] {
+        &self.levels
+    }
+}
+
+impl TimelineViewDefinition {
+    pub fn new(condition: Condition, start_field: FieldId, end_field: FieldId, group_by: Option<GroupBy>, dependency: Option<String>, fields: Vec<FieldId>) -> Self {
+        Self { condition, start_field, end_field, group_by, dependency, fields }
+    }
+
+    pub fn condition(&self) -> &Condition {
+        &self.condition
+    }
+
+    pub fn start_field(&self) -> &FieldId {
+        &self.start_field
+    }
+
+    pub fn end_field(&self) -> &FieldId {
+        &self.end_field
+    }
+
+    pub fn group_by(&self) -> Option<&GroupBy> {
+        self.group_by.as_ref()
+    }
+
+    pub fn dependency(&self) -> Option<&str> {
+        self.dependency.as_deref()
+    }
+}
+
+impl CalendarViewDefinition {
+    pub fn new(condition: Condition, date_fields: Vec<CalendarField>, fields: Vec<FieldId>) -> Self {
+        Self { condition, date_fields, fields }
+    }
+
+    pub fn condition(&self) -> &Condition {
+        &self.condition
+    }
+
+    pub fn date_fields(&self) -> &[CalendarField] {
+        &self.date_fields
+    }
+}
+
+impl PivotViewDefinition {
+    pub fn new(condition: Condition, rows: Dimension, columns: Option<Dimension>, measures: Vec<Measure>) -> Self {
+        Self { condition, rows, columns, measures }
+    }
+
+    pub fn condition(&self) -> &Condition {
+        &self.condition
+    }
+
+    pub fn measures(&self) -> &[Measure] {
+        &self.measures
+    }
+
+    //行维度在前，列维度在后
+    pub fn dimensions(&self) -> Vec<Dimension> {
+        let mut dimensions = vec![self.rows.clone()];
+        dimensions.extend(self.columns.clone());
+        dimensions
+    }
+}
+
+impl TreeLevel {
+    pub fn new(link: LinkDescriptor, condition: Condition, done_statuses: Vec<String>) -> Self {
+        Self { link, condition, done_statuses }
+    }
+
+    //本层中与上一层某些卡片相关联的卡片的条件
+    pub fn children_of(&self, parent_ids: &[CardId]) -> Condition {
+        let mut condition = self.condition.clone();
+        let parent_ids = parent_ids.iter().map(|it| it.to_string()).collect();
+        condition.and(ConditionItem::Link(self.link.reverse(), LinkOperator::AnyIn(LinkValue::StaticValue(parent_ids))));
+        condition
+    }
+
+    pub fn link(&self) -> &LinkDescriptor {
+        &self.link
+    }
+
+    pub fn is_done(&self, flow_status_id: Option<&str>) -> bool {
+        flow_status_id.is_some_and(|status_id| self.done_statuses.iter().any(|it| it == status_id))
+    }
+}
+
+impl Group {
+    pub fn new(key: &str, title: &str) -> Self {
+        Self { key: String::from(key), title: String::from(title) }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use card::query::Order;
 
     #[test]
     fn list_view_definition() {
@@ -33,10 +600,14 @@ mod tests {
             id: 1,
             name: String::from("列表视图"),
             description: String::from("这是一个列表视图"),
-            tenant_id: 1,
+            tenant_id: TenantId::from_str("1"),
+            owner: ViewOwner::Org,
             view_type: ViewType::ListView(
                 ListViewDefinition {
-                    columns: vec![]
+                    columns: vec![],
+                    condition: Condition::default(),
+                    sort: None,
+                    page_size: 20,
                 }
             ),
         };
@@ -46,9 +617,118 @@ mod tests {
         assert_eq!(list_view_def.id, 1);
         assert_eq!(list_view_def.view_type, ViewType::ListView(
             ListViewDefinition {
-                columns: vec![]
+                columns: vec![],
+                condition: Condition::default(),
+                sort: None,
+                page_size: 20,
             }
         ));
         assert_eq!(list_view_def.name, "列表视图");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_list_view_columns() {
+        let columns = vec![
+            Column::new(ColumnKind::Property(BuiltInProperty::Name), "标题", Some(240), ColumnFormat::Plain),
+            Column::new(ColumnKind::Field(FieldId::from_str("估时")), "估时", None, ColumnFormat::Number(1)),
+            Column::new(ColumnKind::LinkedField(Path::Segment(LinkDescriptor::Dest(String::from("子需求")), Box::new(Path::Nil)), FieldId::from_str("负责人")), "父需求负责人", None, ColumnFormat::Plain),
+            Column::new(ColumnKind::Computed(FieldId::from_str("剩余工时")), "剩余工时", None, ColumnFormat::Number(1)),
+        ];
+        let list = ListViewDefinition::new(columns, Condition::default(), Some(Sort::Field(FieldId::from_str("估时"), Order::Desc)), 50);
+        let view = ViewDefinition::new(1, "需求列表", "", TenantId::from_str("1"), ViewType::ListView(list));
+        //只有卡片自身的属性需要随查询返回
+        assert_eq!(view.fields(), vec![FieldId::from_str("估时"), FieldId::from_str("剩余工时")]);
+        //未设置的宽度、格式和分页大小使用默认值
+        let list: ListViewDefinition = serde_json::from_str(r#"{"columns": [{"kind": {"Field": "估时"}, "title": "估时"}]}"#).unwrap();
+        assert_eq!(list.columns()[0].format, ColumnFormat::Plain);
+        assert_eq!(list.page_size(), 20);
+        assert_eq!(list.page(0, None), Page::Limit(1, 20));
+    }
+
+    fn saved_condition() -> Condition {
+        let mut saved = Condition::default();
+        saved.and(ConditionItem::CardType(card::query::CardTypeOperator::AnyIn(vec![String::from("需求")])));
+        saved
+    }
+
+    #[test]
+    fn test_with_override() {
+        let columns = vec![Column::new(ColumnKind::Property(BuiltInProperty::Name), "标题", Some(240), ColumnFormat::Plain)];
+        let list = ListViewDefinition::new(columns, saved_condition(), None, 20);
+        let view = ViewDefinition::new(1, "需求列表", "", TenantId::from_str("1"), ViewType::ListView(list))
+            .with_owner(ViewOwner::Team(CardId::from_str("t1")));
+        let status = ConditionItem::Status(vec![String::from("进行中")]);
+        let mut temporary = Condition::default();
+        temporary.and(status.clone());
+        let view_override = ViewOverride {
+            column_widths: HashMap::from([(String::from("标题"), 400)]),
+            condition: Some(temporary),
+            sort: None,
+            page_size: Some(50),
+        };
+        let layered = view.with_override(&view_override);
+        let ViewType::ListView(list) = layered.view_type() else { panic!("not a list view") };
+        assert_eq!(list.columns()[0].width, Some(400));
+        assert_eq!(list.page_size(), 50);
+        //临时条件与保存的条件同时生效
+        let mut expected = saved_condition();
+        expected.and(status);
+        assert_eq!(list.condition(), &expected);
+        assert_eq!(layered.owner(), &ViewOwner::Team(CardId::from_str("t1")));
+        //共享的视图本身不变
+        let ViewType::ListView(list) = view.view_type() else { panic!("not a list view") };
+        assert_eq!(list.columns()[0].width, Some(240));
+        assert_eq!(list.condition(), &saved_condition());
+    }
+
+    #[test]
+    fn test_board_moves() {
+        let columns = vec![BoardColumn::new("待办", "待办", None), BoardColumn::new("进行中", "进行中", Some(3))];
+        let swimlanes = Swimlanes::new(GroupBy::Link(LinkDescriptor::Dest(String::from("包含"))), vec![Group::new("v1", "1.0版本")]);
+        let board = BoardViewDefinition::new(Condition::default(), GroupBy::Enum(FieldId::from_str("优先级")), columns, Some(swimlanes), None, vec![]);
+        let moves = board.moves("进行中", Some("v1")).unwrap();
+        assert_eq!(moves.len(), 2);
+        assert_eq!(moves[0], BoardMove { group_by: &GroupBy::Enum(FieldId::from_str("优先级")), key: "进行中", keys: vec!["待办", "进行中"] });
+        assert_eq!(moves[1].key, "v1");
+        //多值枚举只替换看板上的选项
+        assert_eq!(moves[0].options(&[String::from("待办"), String::from("紧急")]), vec![String::from("紧急"), String::from("进行中")]);
+        assert_eq!(board.order_field(), None);
+        assert_eq!(board.moves("进行中", None).unwrap().len(), 1);
+        //不在看板上的列和泳道
+        assert!(board.moves("已完成", None).is_none());
+        assert!(board.moves("待办", Some("v2")).is_none());
+        let view = ViewDefinition::new(1, "需求看板", "", TenantId::from_str("1"), ViewType::BoardView(board));
+        assert_eq!(view.fields(), vec![FieldId::from_str("优先级")]);
+    }
+
+    #[test]
+    fn test_timeline_fields() {
+        let timeline = TimelineViewDefinition::new(Condition::default(), FieldId::from_str("计划开始时间"), FieldId::from_str("计划完成时间"),
+                                                   Some(GroupBy::Enum(FieldId::from_str("负责团队"))), Some(String::from("前置")), vec![FieldId::from_str("计划开始时间")]);
+        let view = ViewDefinition::new(1, "版本计划", "", TenantId::from_str("1"), ViewType::TimelineView(timeline));
+        //开始、结束和分组属性总是随查询返回
+        assert_eq!(view.fields(), vec![FieldId::from_str("计划开始时间"), FieldId::from_str("计划完成时间"), FieldId::from_str("负责团队")]);
+    }
+
+    #[test]
+    fn test_pivot_dimensions() {
+        let json = r#"{"rows":{"Enum":"负责团队"},"columns":"Status","measures":["Count",{"Sum":"估算"}]}"#;
+        let pivot: PivotViewDefinition = serde_json::from_str(json).unwrap();
+        assert_eq!(pivot.dimensions(), vec![Dimension::Enum(FieldId::from_str("负责团队")), Dimension::Status]);
+        assert_eq!(pivot.measures(), &[Measure::Count, Measure::Sum(FieldId::from_str("估算"))]);
+        let pivot = PivotViewDefinition::new(Condition::default(), Dimension::CardType, None, vec![Measure::Count]);
+        assert_eq!(pivot.dimensions(), vec![Dimension::CardType]);
+    }
+
+    #[test]
+    fn test_tree_level() {
+        //版本 -[包含]-> 需求，需求一层的卡片从自身看是被版本包含
+        let level = TreeLevel::new(LinkDescriptor::Src(String::from("包含")), Condition::default(), vec![String::from("已完成")]);
+        let mut expected = Condition::default();
+        expected.and(ConditionItem::Link(LinkDescriptor::Dest(String::from("包含")), LinkOperator::AnyIn(LinkValue::StaticValue(vec![String::from("v1")]))));
+        assert_eq!(level.children_of(&[CardId::from_str("v1")]), expected);
+        assert!(level.is_done(Some("已完成")));
+        assert!(!level.is_done(Some("进行中")));
+        assert!(!level.is_done(None));
+    }
+}
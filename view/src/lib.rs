@@ -1,4 +1,9 @@
-mod definition;
+pub mod calendar;
+pub mod dashboard;
+pub mod definition;
+pub mod registry;
+pub mod render;
+pub mod timeline;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
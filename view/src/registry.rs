@@ -0,0 +1,209 @@
+//视图和仪表盘定义按租户保存，查找时必须指定租户
+//修改先保存到配置存储再更新注册表，服务启动时从配置存储加载
+use crate::dashboard::DashboardDefinition;
+use crate::definition::{ViewDefinition, ViewOverride};
+use card::settings::SettingStore;
+use common::newtypes::card_id::CardId;
+use common::newtypes::tenant_id::TenantId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error;
+use std::sync::{Mutex, RwLock};
+
+//视图和成员个人调整在配置存储中的种类
+pub const VIEW_SETTING: &str = "View";
+pub const VIEW_OVERRIDE_SETTING: &str = "ViewOverride";
+//仪表盘在配置存储中的种类
+pub const DASHBOARD_SETTING: &str = "Dashboard";
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+//成员的个人调整本身不带组织、视图和成员，保存时需要一并记录
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ViewOverrideSetting {
+    pub org_id: TenantId,
+    pub view_id: u32,
+    pub member_id: CardId,
+    pub view_override: ViewOverride,
+}
+
+impl ViewOverrideSetting {
+    pub fn setting_id(view_id: u32, member_id: &CardId) -> String {
+        format!("{view_id}/{member_id}")
+    }
+}
+
+#[derive(Default)]
+pub struct ViewRegistry {
+    views: RwLock<HashMap<TenantId, HashMap<u32, ViewDefinition>>>,
+    overrides: RwLock<HashMap<(TenantId, u32, CardId), ViewOverride>>, //key为组织、视图和成员
+    next_ids: Mutex<HashMap<TenantId, u32>>, //已经分配出去的最大视图id之后的一个
+}
+
+impl ViewRegistry {
+    //启动时加载保存的视图和个人调整
+    pub async fn load(&self) -> Result<usize> {
+        let views: Vec<ViewDefinition> = SettingStore::load_all(VIEW_SETTING).await?;
+        let overrides: Vec<ViewOverrideSetting> = SettingStore::load_all(VIEW_OVERRIDE_SETTING).await?;
+        let size = views.len();
+        for view in views {
+            self.put(view);
+        }
+        for setting in overrides {
+            self.put_override(&setting.org_id, setting.view_id, &setting.member_id, setting.view_override);
+        }
+        Ok(size)
+    }
+
+    //视图id由服务端在组织内递增分配，分配出去的id即使没有保存成功也不再使用
+    pub fn next_id(&self, tenant_id: &TenantId) -> u32 {
+        let max_id = self.views.read().unwrap().get(tenant_id).and_then(|it| it.keys().max().copied()).unwrap_or(0);
+        let mut next_ids = self.next_ids.lock().unwrap();
+        let next_id = next_ids.entry(tenant_id.clone()).or_insert(1);
+        let id = (*next_id).max(max_id + 1);
+        *next_id = id + 1;
+        id
+    }
+
+    pub fn put(&self, view: ViewDefinition) {
+        let mut views = self.views.write().unwrap();
+        views.entry(view.tenant_id().clone()).or_default().insert(view.id(), view);
+    }
+
+    pub fn get(&self, tenant_id: &TenantId, view_id: u32) -> Option<ViewDefinition> {
+        self.views.read().unwrap().get(tenant_id).and_then(|it| it.get(&view_id)).cloned()
+    }
+
+    pub fn list(&self, tenant_id: &TenantId) -> Vec<ViewDefinition> {
+        let views = self.views.read().unwrap();
+        let mut list: Vec<ViewDefinition> = views.get(tenant_id).map(|it| it.values().cloned().collect()).unwrap_or_default();
+        list.sort_by_key(|it| it.id());
+        list
+    }
+
+    //删除视图时一并删除成员在视图上的个人调整
+    pub fn remove(&self, tenant_id: &TenantId, view_id: u32) -> Option<ViewDefinition> {
+        let removed = self.views.write().unwrap().get_mut(tenant_id).and_then(|it| it.remove(&view_id));
+        if removed.is_some() {
+            self.overrides.write().unwrap().retain(|(org_id, id, _), _| !(org_id == tenant_id && *id == view_id));
+        }
+        removed
+    }
+
+    pub fn put_override(&self, tenant_id: &TenantId, view_id: u32, member_id: &CardId, view_override: ViewOverride) {
+        self.overrides.write().unwrap().insert((tenant_id.clone(), view_id, member_id.clone()), view_override);
+    }
+
+    pub fn get_override(&self, tenant_id: &TenantId, view_id: u32, member_id: &CardId) -> Option<ViewOverride> {
+        self.overrides.read().unwrap().get(&(tenant_id.clone(), view_id, member_id.clone())).cloned()
+    }
+
+    pub fn remove_override(&self, tenant_id: &TenantId, view_id: u32, member_id: &CardId) -> Option<ViewOverride> {
+        self.overrides.write().unwrap().remove(&(tenant_id.clone(), view_id, member_id.clone()))
+    }
+
+    //在视图上保存了个人调整的成员
+    pub fn override_members(&self, tenant_id: &TenantId, view_id: u32) -> Vec<CardId> {
+        self.overrides.read().unwrap().keys()
+            .filter(|(org_id, id, _)| org_id == tenant_id && *id == view_id)
+            .map(|(_, _, member_id)| member_id.clone())
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct DashboardRegistry {
+    dashboards: RwLock<HashMap<TenantId, HashMap<u32, DashboardDefinition>>>,
+    next_ids: Mutex<HashMap<TenantId, u32>>,
+}
+
+impl DashboardRegistry {
+    //启动时加载保存的仪表盘
+    pub async fn load(&self) -> Result<usize> {
+        let dashboards: Vec<DashboardDefinition> = SettingStore::load_all(DASHBOARD_SETTING).await?;
+        let size = dashboards.len();
+        for dashboard in dashboards {
+            self.put(dashboard);
+        }
+        Ok(size)
+    }
+
+    //与视图相同，仪表盘id由服务端在组织内递增分配
+    pub fn next_id(&self, tenant_id: &TenantId) -> u32 {
+        let max_id = self.dashboards.read().unwrap().get(tenant_id).and_then(|it| it.keys().max().copied()).unwrap_or(0);
+        let mut next_ids = self.next_ids.lock().unwrap();
+        let next_id = next_ids.entry(tenant_id.clone()).or_insert(1);
+        let id = (*next_id).max(max_id + 1);
+        *next_id = id + 1;
+        id
+    }
+
+    pub fn put(&self, dashboard: DashboardDefinition) {
+        let mut dashboards = self.dashboards.write().unwrap();
+        dashboards.entry(dashboard.tenant_id().clone()).or_default().insert(dashboard.id(), dashboard);
+    }
+
+    pub fn get(&self, tenant_id: &TenantId, dashboard_id: u32) -> Option<DashboardDefinition> {
+        self.dashboards.read().unwrap().get(tenant_id).and_then(|it| it.get(&dashboard_id)).cloned()
+    }
+
+    pub fn list(&self, tenant_id: &TenantId) -> Vec<DashboardDefinition> {
+        let dashboards = self.dashboards.read().unwrap();
+        let mut list: Vec<DashboardDefinition> = dashboards.get(tenant_id).map(|it| it.values().cloned().collect()).unwrap_or_default();
+        list.sort_by_key(|it| it.id());
+        list
+    }
+
+    pub fn remove(&self, tenant_id: &TenantId, dashboard_id: u32) -> Option<DashboardDefinition> {
+        self.dashboards.write().unwrap().get_mut(tenant_id).and_then(|it| it.remove(&dashboard_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definition::{ListViewDefinition, ViewType};
+    use card::query::Condition;
+
+    #[test]
+    fn test_cross_tenant_lookup() {
+        let registry = ViewRegistry::default();
+        let tenant_id = TenantId::from_str("o1");
+        let view_type = ViewType::ListView(ListViewDefinition::new(vec![], Condition::default(), None, 20));
+        registry.put(ViewDefinition::new(1, "我的需求", "", tenant_id.clone(), view_type));
+        assert_eq!(registry.get(&tenant_id, 1).unwrap().name(), "我的需求");
+        let other = TenantId::from_str("o2");
+        assert!(registry.get(&other, 1).is_none());
+        assert!(registry.remove(&other, 1).is_none());
+        assert_eq!(registry.list(&tenant_id).len(), 1);
+    }
+
+    #[test]
+    fn test_overrides_are_removed_with_view() {
+        let registry = ViewRegistry::default();
+        let tenant_id = TenantId::from_str("o1");
+        let member_id = CardId::from_str("m1");
+        let view_type = ViewType::ListView(ListViewDefinition::new(vec![], Condition::default(), None, 20));
+        registry.put(ViewDefinition::new(1, "我的需求", "", tenant_id.clone(), view_type));
+        registry.put_override(&tenant_id, 1, &member_id, ViewOverride { page_size: Some(50), ..ViewOverride::default() });
+        assert_eq!(registry.get_override(&tenant_id, 1, &member_id).unwrap().page_size, Some(50));
+        assert!(registry.get_override(&TenantId::from_str("o2"), 1, &member_id).is_none());
+        assert_eq!(registry.override_members(&tenant_id, 1), vec![member_id.clone()]);
+        registry.remove(&tenant_id, 1);
+        assert!(registry.get_override(&tenant_id, 1, &member_id).is_none());
+        assert!(registry.override_members(&tenant_id, 1).is_empty());
+    }
+
+    #[test]
+    fn test_next_id() {
+        let registry = ViewRegistry::default();
+        let tenant_id = TenantId::from_str("o1");
+        assert_eq!(registry.next_id(&tenant_id), 1);
+        let view_type = ViewType::ListView(ListViewDefinition::new(vec![], Condition::default(), None, 20));
+        registry.put(ViewDefinition::new(5, "我的需求", "", tenant_id.clone(), view_type));
+        assert_eq!(registry.next_id(&tenant_id), 6);
+        //分配出去但没有保存的id不会重复分配
+        assert_eq!(registry.next_id(&tenant_id), 7);
+        assert_eq!(registry.next_id(&TenantId::from_str("o2")), 1);
+    }
+}
@@ -0,0 +1,437 @@
+//渲染视图：根据视图定义构造查询，查询结果组装成列表的行、看板的列、树的节点、时间线的横条、日历的日子或者透视表的单元格
+//查询上下文和返回属性由调用方按成员的权限构造，视图只负责查询和组装
+use crate::definition::{BoardViewDefinition, ColumnKind, GroupBy, ListViewDefinition, TreeLevel, TreeViewDefinition, ViewDefinition, ViewType};
+use crate::calendar;
+use crate::calendar::{CalendarDay, CalendarWindow};
+use crate::timeline;
+use crate::timeline::{Dependency, TimelineGroup};
+use card::aggregate;
+use card::aggregate::AggregateRow;
+use card::card::{Field, FieldValue};
+use card::query;
+use card::query::{CardRecord, Condition, ConditionItem, LinkOperator, LinkValue, Order, Page, QueryContext, Sort, Yields};
+use card::types::{LinkDescriptor, Path};
+use common::newtypes::field_id::FieldId;
+use common::newtypes::card_id::CardId;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::{error, fmt};
+
+#[derive(Debug, Serialize)]
+pub enum RenderedView {
+    List {
+        rows: Vec<CardRecord>,
+        total: u64,
+        linked_columns: Vec<LinkedColumn>,
+    },
+    Board {
+        columns: Vec<ColumnSummary>,
+        swimlanes: Vec<Swimlane>,
+    },
+    Tree {
+        nodes: Vec<TreeNode>,
+    },
+    Timeline {
+        groups: Vec<TimelineGroup>,
+        dependencies: Vec<Dependency>,
+        critical_path: Vec<CardId>,
+    },
+    Calendar {
+        days: Vec<CalendarDay>,
+    },
+    //每个单元格的keys依次为行、列维度的值，values与视图的度量一一对应，没有卡片的单元格不返回
+    Pivot {
+        cells: Vec<AggregateRow>,
+    },
+}
+
+//列表中关联卡片属性列的值，column为列在视图中的序号，values的key为行的卡片id，
+//沿路径可能到达多张卡片，值按到达的卡片依次排列，不可见的卡片和不可读的属性不返回
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LinkedColumn {
+    pub column: usize,
+    pub values: HashMap<CardId, Vec<FieldValue>>,
+}
+
+//列的卡片数统计所有泳道
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ColumnSummary {
+    pub key: String,
+    pub title: String,
+    pub count: u32,
+    pub wip_limit: Option<u32>,
+    pub over_limit: bool,
+}
+
+//没有设置泳道时只有一个key为None的泳道，cells与columns一一对应
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Swimlane {
+    pub key: Option<String>,
+    pub title: String,
+    pub cells: Vec<Vec<CardRecord>>,
+}
+
+//树的节点只带有下一层的统计，下一层的卡片在展开时再加载
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TreeNode {
+    pub card: CardRecord,
+    pub depth: usize, //根卡片为0
+    pub expandable: bool, //是否还有下一层
+    pub child_count: u32,
+    pub done_count: u32,
+    pub done_percent: Option<u32>, //没有下一层卡片时为空
+}
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+//渲染视图时的运行时参数，分页只对列表有效，日期窗口只对日历有效
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    pub page_num: u32,
+    pub page_size: Option<u8>,
+    pub window: Option<CalendarWindow>,
+}
+
+//列表视图按视图保存的排序和分页大小分页，关联卡片列沿列的路径再查询
+pub async fn render(definition: &ViewDefinition, query_context: QueryContext, yields: Yields, options: RenderOptions) -> Result<RenderedView> {
+    match definition.view_type() {
+        ViewType::ListView(list) => {
+            let result = query::query(list.condition().clone(), query_context.clone(), yields.clone(), list.page(options.page_num, options.page_size)).await?;
+            let linked_columns = linked_columns(list, &result.cards, &query_context, &yields).await?;
+            Ok(RenderedView::List { rows: result.cards, total: result.total, linked_columns })
+        }
+        ViewType::BoardView(board) => {
+            let result = query::query(board.condition().clone(), query_context.clone(), yields, board.page()).await?;
+            let card_ids: Vec<CardId> = result.cards.iter().map(|it| it.id.clone()).collect();
+            let column_links = links_of(board.group_by(), &card_ids, &query_context).await?;
+            let lane_links = match board.swimlanes() {
+                Some(swimlanes) => links_of(swimlanes.group_by(), &card_ids, &query_context).await?,
+                None => HashMap::new(),
+            };
+            let (columns, swimlanes) = into_board(board, result.cards, &column_links, &lane_links);
+            Ok(RenderedView::Board { columns, swimlanes })
+        }
+        ViewType::TreeView(tree) => {
+            let result = query::query(tree.condition().clone(), query_context.clone(), yields, Page::None).await?;
+            let nodes = into_nodes(tree, 0, result.cards, &query_context).await?;
+            Ok(RenderedView::Tree { nodes })
+        }
+        ViewType::TimelineView(timeline) => {
+            //时间线展示全部卡片，按开始时间排列
+            let page = Page::AllAfterSort(Sort::Field(timeline.start_field().clone(), Order::Asc));
+            let result = query::query(timeline.condition().clone(), query_context.clone(), yields, page).await?;
+            let card_ids: Vec<CardId> = result.cards.iter().map(|it| it.id.clone()).collect();
+            let group_links = match timeline.group_by() {
+                Some(group_by) => links_of(group_by, &card_ids, &query_context).await?,
+                None => HashMap::new(),
+            };
+            let dependency_links = match timeline.dependency() {
+                Some(rs_type) if !card_ids.is_empty() => query::linked_ids(&card_ids, &LinkDescriptor::Src(rs_type.to_string()), &query_context).await?,
+                _ => HashMap::new(),
+            };
+            let dependencies = timeline::dependencies(&result.cards, &dependency_links);
+            let (groups, critical_path) = timeline::into_timeline(timeline, result.cards, &group_links, &dependencies);
+            Ok(RenderedView::Timeline { groups, dependencies, critical_path })
+        }
+        ViewType::CalendarView(calendar) => {
+            let window = options.window.ok_or_else(|| RenderError::new("calendar view requires a date window"))?;
+            let result = query::query(calendar::window_condition(calendar, &window), query_context, yields, Page::None).await?;
+            Ok(RenderedView::Calendar { days: calendar::into_days(calendar, &window, result.cards) })
+        }
+        ViewType::PivotView(pivot) => {
+            let cells = aggregate::aggregate(pivot.condition(), &pivot.dimensions(), pivot.measures(), &query_context, &yields).await?;
+            Ok(RenderedView::Pivot { cells })
+        }
+    }
+}
+
+//展开树上的某张卡片，depth为这张卡片所在的层，超出树的层数时返回空
+pub async fn children(tree: &TreeViewDefinition, parent_id: &CardId, depth: usize, query_context: QueryContext, yields: Yields) -> Result<Vec<TreeNode>> {
+    let Some(level) = tree.levels().get(depth) else {
+        return Ok(vec![]);
+    };
+    let condition = level.children_of(std::slice::from_ref(parent_id));
+    let result = query::query(condition, query_context.clone(), yields, Page::None).await?;
+    into_nodes(tree, depth + 1, result.cards, &query_context).await
+}
+
+//统计同一层所有卡片在下一层的卡片，下一层的卡片同样受可见范围和该层条件约束
+async fn into_nodes(tree: &TreeViewDefinition, depth: usize, cards: Vec<CardRecord>, query_context: &QueryContext) -> Result<Vec<TreeNode>> {
+    let mut stats = HashMap::new();
+    let next = tree.levels().get(depth);
+    if let (Some(next), false) = (next, cards.is_empty()) {
+        let card_ids: Vec<CardId> = cards.iter().map(|it| it.id.clone()).collect();
+        let children = query::query(next.children_of(&card_ids), query_context.clone(), Yields::default(), Page::None).await?.cards;
+        let child_ids: Vec<CardId> = children.iter().map(|it| it.id.clone()).collect();
+        let parents = if child_ids.is_empty() {
+            HashMap::new()
+        } else {
+            query::linked_ids(&child_ids, &next.link().reverse(), query_context).await?
+        };
+        stats = aggregate(next, &children, &parents);
+    }
+    Ok(cards.into_iter()
+        .map(|card| {
+            let (child_count, done_count) = stats.get(&card.id).copied().unwrap_or_default();
+            TreeNode {
+                card,
+                depth,
+                expandable: next.is_some(),
+                child_count,
+                done_count,
+                done_percent: (child_count > 0).then(|| done_count * 100 / child_count),
+            }
+        })
+        .collect())
+}
+
+//一张卡片可能同时挂在上一层的多张卡片下，分别计入每张上层卡片
+fn aggregate(level: &TreeLevel, children: &[CardRecord], parents: &HashMap<CardId, Vec<CardId>>) -> HashMap<CardId, (u32, u32)> {
+    let mut stats: HashMap<CardId, (u32, u32)> = HashMap::new();
+    for child in children {
+        let done = level.is_done(child.flow_status.as_ref().map(|it| it.flow_status_id.as_str()));
+        for parent_id in parents.get(&child.id).into_iter().flatten() {
+            let stat = stats.entry(parent_id.clone()).or_default();
+            stat.0 += 1;
+            if done {
+                stat.1 += 1;
+            }
+        }
+    }
+    stats
+}
+
+//只有按关联卡片分组时才需要额外查询关联关系
+//关联卡片同样按成员的可见范围和属性读限制查询
+async fn linked_columns(list: &ListViewDefinition, rows: &[CardRecord], query_context: &QueryContext, yields: &Yields) -> Result<Vec<LinkedColumn>> {
+    let mut linked_columns = Vec::new();
+    for (column, it) in list.columns().iter().enumerate() {
+        let ColumnKind::LinkedField(path, field_id) = it.kind() else { continue };
+        let mut reached: HashMap<CardId, Vec<CardId>> = rows.iter().map(|it| (it.id.clone(), vec![it.id.clone()])).collect();
+        let mut path = path;
+        while let Path::Segment(descriptor, next) = path {
+            let card_ids: Vec<CardId> = reached.values().flatten().cloned().collect();
+            let links = if card_ids.is_empty() { HashMap::new() } else { query::linked_ids(&card_ids, descriptor, query_context).await? };
+            reached = follow(reached, &links);
+            path = next;
+        }
+        let mut card_ids: Vec<CardId> = reached.values().flatten().cloned().collect();
+        card_ids.sort();
+        card_ids.dedup();
+        let cards = if card_ids.is_empty() {
+            vec![]
+        } else {
+            let mut condition = Condition::default();
+            condition.and(ConditionItem::MySelf(LinkOperator::AnyIn(LinkValue::StaticValue(card_ids.iter().map(|it| it.to_string()).collect()))));
+            query::query(condition, query_context.clone(), yields.with_fields(vec![field_id.clone()]), Page::None).await?.cards
+        };
+        linked_columns.push(LinkedColumn { column, values: linked_values(&reached, &cards, field_id) });
+    }
+    Ok(linked_columns)
+}
+
+//沿一段关联前进一步，到达的卡片去重并保持顺序
+fn follow(reached: HashMap<CardId, Vec<CardId>>, links: &HashMap<CardId, Vec<CardId>>) -> HashMap<CardId, Vec<CardId>> {
+    reached.into_iter().map(|(row_id, card_ids)| {
+        let mut next: Vec<CardId> = Vec::new();
+        for linked_id in card_ids.iter().filter_map(|it| links.get(it)).flatten() {
+            if !next.contains(linked_id) {
+                next.push(linked_id.clone());
+            }
+        }
+        (row_id, next)
+    }).collect()
+}
+
+fn linked_values(reached: &HashMap<CardId, Vec<CardId>>, cards: &[CardRecord], field_id: &FieldId) -> HashMap<CardId, Vec<FieldValue>> {
+    let values_of: HashMap<&CardId, &Field> = cards.iter()
+        .filter_map(|card| card.fields.iter().find(|it| it.id == *field_id).map(|it| (&card.id, it)))
+        .collect();
+    reached.iter()
+        .map(|(row_id, card_ids)| (row_id.clone(), card_ids.iter().filter_map(|it| values_of.get(it)).map(|it| it.value.clone()).collect::<Vec<FieldValue>>()))
+        .filter(|(_, values)| !values.is_empty())
+        .collect()
+}
+
+async fn links_of(group_by: &GroupBy, card_ids: &[CardId], query_context: &QueryContext) -> Result<HashMap<CardId, Vec<CardId>>> {
+    match group_by {
+        GroupBy::Link(descriptor) if !card_ids.is_empty() => query::linked_ids(card_ids, descriptor, query_context).await,
+        _ => Ok(HashMap::new()),
+    }
+}
+
+//卡片在某个分组维度上的取值，多值时按看板上列或泳道的顺序取第一个匹配的
+pub(crate) fn group_keys(group_by: &GroupBy, card: &CardRecord, links: &HashMap<CardId, Vec<CardId>>) -> Vec<String> {
+    match group_by {
+        GroupBy::Status => card.flow_status.iter().map(|it| it.flow_status_id.clone()).collect(),
+        GroupBy::Enum(field_id) => card.fields.iter()
+            .filter(|it| it.id == *field_id)
+            .flat_map(|it| match &it.value {
+                FieldValue::Enum(options) => options.clone(),
+                _ => vec![],
+            })
+            .collect(),
+        GroupBy::Link(_) => links.get(&card.id).map(|it| it.iter().map(|it| it.to_string()).collect()).unwrap_or_default(),
+    }
+}
+
+//不在任何一列中的卡片不显示，卡片保持查询返回的顺序
+fn into_board(board: &BoardViewDefinition, cards: Vec<CardRecord>, column_links: &HashMap<CardId, Vec<CardId>>, lane_links: &HashMap<CardId, Vec<CardId>>) -> (Vec<ColumnSummary>, Vec<Swimlane>) {
+    let columns = board.columns();
+    let empty_cells = || columns.iter().map(|_| Vec::new()).collect::<Vec<Vec<CardRecord>>>();
+    let mut swimlanes: Vec<Swimlane> = board.swimlanes()
+        .map(|swimlanes| swimlanes.lanes().iter()
+            .map(|it| Swimlane { key: Some(it.key().to_string()), title: it.title().to_string(), cells: empty_cells() })
+            .collect())
+        .unwrap_or_default();
+    let mut others = Swimlane { key: None, title: String::new(), cells: empty_cells() };
+    let mut counts = vec![0u32; columns.len()];
+    for card in cards {
+        let keys = group_keys(board.group_by(), &card, column_links);
+        let Some(column) = columns.iter().position(|it| keys.iter().any(|key| key == it.key())) else {
+            continue;
+        };
+        counts[column] += 1;
+        let lane = board.swimlanes().and_then(|swimlanes| {
+            let keys = group_keys(swimlanes.group_by(), &card, lane_links);
+            swimlanes.lanes().iter().position(|it| keys.iter().any(|key| key == it.key()))
+        });
+        match lane {
+            Some(lane) => swimlanes[lane].cells[column].push(card),
+            None => others.cells[column].push(card),
+        }
+    }
+    //设置了泳道时，只有存在未归入泳道的卡片才显示"其他"泳道
+    if board.swimlanes().is_none() {
+        swimlanes.push(others);
+    } else if others.cells.iter().any(|it| !it.is_empty()) {
+        others.title = String::from("其他");
+        swimlanes.push(others);
+    }
+    let summaries = columns.iter().zip(counts)
+        .map(|(column, count)| ColumnSummary {
+            key: column.key().to_string(),
+            title: column.title().to_string(),
+            count,
+            wip_limit: column.wip_limit(),
+            over_limit: column.wip_limit().is_some_and(|limit| count > limit),
+        })
+        .collect();
+    (summaries, swimlanes)
+}
+
+//渲染参数与视图类型不符
+#[derive(Debug)]
+pub struct RenderError {
+    message: String,
+}
+
+impl RenderError {
+    pub fn new(message: &str) -> Self {
+        Self { message: message.to_string() }
+    }
+}
+
+impl Display for RenderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for RenderError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definition::{BoardColumn, Group, Swimlanes};
+    use card::card::{CardState, Field, FlowStatus};
+    use card::query::Condition;
+    use card::types::LinkDescriptor;
+    use common::newtypes::field_id::FieldId;
+    use common::newtypes::tenant_id::TenantId;
+
+    fn card(id: &str, status: Option<&str>, priority: &str) -> CardRecord {
+        CardRecord {
+            id: CardId::from_str(id),
+            code: String::from(id),
+            name: String::from(id),
+            card_type_id: String::from("需求"),
+            org_id: TenantId::from_str("o1"),
+            state: CardState::Active,
+            flow_status: status.map(|it| FlowStatus::new("f1", it)),
+            fields: vec![Field::new(FieldId::from_str("优先级"), FieldValue::Enum(vec![String::from(priority)]))],
+        }
+    }
+
+    #[test]
+    fn test_into_board() {
+        let columns = vec![BoardColumn::new("待办", "待办", None), BoardColumn::new("进行中", "进行中", Some(1)), BoardColumn::new("已完成", "已完成", None)];
+        let board = BoardViewDefinition::new(Condition::default(), GroupBy::Status, columns, None, None, vec![]);
+        let cards = vec![card("c1", Some("进行中"), "高"), card("c2", Some("待办"), "高"), card("c3", None, "高"), card("c4", Some("进行中"), "低")];
+        let (columns, swimlanes) = into_board(&board, cards, &HashMap::new(), &HashMap::new());
+        assert_eq!(swimlanes.len(), 1);
+        assert_eq!(swimlanes[0].key, None);
+        assert_eq!(swimlanes[0].cells[0], vec![card("c2", Some("待办"), "高")]);
+        //列内保持查询返回的顺序
+        assert_eq!(swimlanes[0].cells[1], vec![card("c1", Some("进行中"), "高"), card("c4", Some("进行中"), "低")]);
+        assert!(swimlanes[0].cells[2].is_empty());
+        assert_eq!(columns[1].count, 2);
+        assert!(columns[1].over_limit);
+        assert!(!columns[0].over_limit);
+    }
+
+    #[test]
+    fn test_into_board_with_swimlanes() {
+        let columns = vec![BoardColumn::new("v1", "1.0版本", None), BoardColumn::new("v2", "2.0版本", None)];
+        let swimlanes = Swimlanes::new(GroupBy::Enum(FieldId::from_str("优先级")), vec![Group::new("高", "高优先级")]);
+        let board = BoardViewDefinition::new(Condition::default(), GroupBy::Link(LinkDescriptor::Dest(String::from("包含"))), columns, Some(swimlanes), None, vec![]);
+        let mut links = HashMap::new();
+        links.insert(CardId::from_str("c1"), vec![CardId::from_str("v2")]);
+        links.insert(CardId::from_str("c2"), vec![CardId::from_str("v1")]);
+        let cards = vec![card("c1", None, "高"), card("c2", None, "低"), card("c3", None, "高")];
+        let (columns, swimlanes) = into_board(&board, cards, &links, &HashMap::new());
+        assert_eq!(columns.iter().map(|it| it.count).collect::<Vec<u32>>(), vec![1, 1]);
+        assert_eq!(swimlanes.len(), 2);
+        assert_eq!(swimlanes[0].key.as_deref(), Some("高"));
+        assert_eq!(swimlanes[0].cells[1], vec![card("c1", None, "高")]);
+        assert_eq!(swimlanes[1].title, "其他");
+        assert_eq!(swimlanes[1].cells[0], vec![card("c2", None, "低")]);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let level = TreeLevel::new(LinkDescriptor::Src(String::from("包含")), Condition::default(), vec![String::from("已完成")]);
+        let children = vec![card("d1", Some("已完成"), "高"), card("d2", Some("进行中"), "高"), card("d3", Some("已完成"), "低")];
+        let mut parents = HashMap::new();
+        parents.insert(CardId::from_str("d1"), vec![CardId::from_str("v1")]);
+        parents.insert(CardId::from_str("d2"), vec![CardId::from_str("v1")]);
+        parents.insert(CardId::from_str("d3"), vec![CardId::from_str("v1"), CardId::from_str("v2")]);
+        let stats = aggregate(&level, &children, &parents);
+        assert_eq!(stats[&CardId::from_str("v1")], (3, 2));
+        assert_eq!(stats[&CardId::from_str("v2")], (1, 1));
+    }
+
+    #[test]
+    fn test_linked_values() {
+        //c1的父需求是p1，p1的父需求是g1和g2；c2没有父需求；g2对成员不可见，查询不会返回
+        let mut reached: HashMap<CardId, Vec<CardId>> = HashMap::new();
+        reached.insert(CardId::from_str("c1"), vec![CardId::from_str("c1")]);
+        reached.insert(CardId::from_str("c2"), vec![CardId::from_str("c2")]);
+        let mut links = HashMap::new();
+        links.insert(CardId::from_str("c1"), vec![CardId::from_str("p1")]);
+        let reached = follow(reached, &links);
+        assert_eq!(reached[&CardId::from_str("c1")], vec![CardId::from_str("p1")]);
+        let mut links = HashMap::new();
+        links.insert(CardId::from_str("p1"), vec![CardId::from_str("g1"), CardId::from_str("g2")]);
+        let reached = follow(reached, &links);
+        assert_eq!(reached[&CardId::from_str("c1")], vec![CardId::from_str("g1"), CardId::from_str("g2")]);
+        assert!(reached[&CardId::from_str("c2")].is_empty());
+
+        let values = linked_values(&reached, &[card("g1", None, "高")], &FieldId::from_str("优先级"));
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[&CardId::from_str("c1")], vec![FieldValue::Enum(vec![String::from("高")])]);
+        //不可读的属性不在查询结果中，也不返回值
+        assert!(linked_values(&reached, &[card("g1", None, "高")], &FieldId::from_str("负责人")).is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+//时间线视图：卡片按开始、结束时间排成横条，按依赖关系画出箭头并计算关键路径
+use crate::definition::TimelineViewDefinition;
+use crate::render::group_keys;
+use card::card::FieldValue;
+use card::query::CardRecord;
+use common::newtypes::card_id::CardId;
+use common::newtypes::field_id::FieldId;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+//开始或结束时间为空的卡片没有排期，仍然显示在分组中
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Bar {
+    pub card: CardRecord,
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub critical: bool,
+}
+
+//没有设置分组时只有一个key为None的分组
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TimelineGroup {
+    pub key: Option<String>,
+    pub bars: Vec<Bar>,
+}
+
+//from完成后to才能开始
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Dependency {
+    pub from: CardId,
+    pub to: CardId,
+}
+
+//日期属性可能以数字或者字符串形式保存
+fn timestamp_of(card: &CardRecord, field_id: &FieldId) -> Option<i64> {
+    card.fields.iter()
+        .find(|it| it.id == *field_id)
+        .and_then(|it| match &it.value {
+            FieldValue::Date(v) | FieldValue::DateTime(v) => Some(**v),
+            FieldValue::Int(v) => Some(*v as i64),
+            FieldValue::Text(v) => v.parse().ok(),
+            _ => None,
+        })
+}
+
+//只保留两端都在视图中的依赖
+pub(crate) fn dependencies(cards: &[CardRecord], links: &HashMap<CardId, Vec<CardId>>) -> Vec<Dependency> {
+    let ids: HashSet<&CardId> = cards.iter().map(|it| &it.id).collect();
+    cards.iter()
+        .flat_map(|card| links.get(&card.id).into_iter().flatten()
+            .filter(|it| ids.contains(it))
+            .map(|it| Dependency { from: card.id.clone(), to: it.clone() }))
+        .collect()
+}
+
+pub(crate) fn into_timeline(timeline: &TimelineViewDefinition, cards: Vec<CardRecord>, group_links: &HashMap<CardId, Vec<CardId>>, dependencies: &[Dependency]) -> (Vec<TimelineGroup>, Vec<CardId>) {
+    let durations: Vec<(CardId, i64)> = cards.iter()
+        .filter_map(|card| match (timestamp_of(card, timeline.start_field()), timestamp_of(card, timeline.end_field())) {
+            (Some(start), Some(end)) if end >= start => Some((card.id.clone(), end - start)),
+            _ => None,
+        })
+        .collect();
+    let critical_path = critical_path(&durations, dependencies);
+    let critical: HashSet<&CardId> = critical_path.iter().collect();
+    let mut groups: Vec<TimelineGroup> = Vec::new();
+    for card in cards {
+        let key = timeline.group_by().and_then(|group_by| group_keys(group_by, &card, group_links).into_iter().next());
+        let bar = Bar {
+            start: timestamp_of(&card, timeline.start_field()),
+            end: timestamp_of(&card, timeline.end_field()),
+            critical: critical.contains(&card.id),
+            card,
+        };
+        //分组按首次出现的顺序排列
+        match groups.iter_mut().find(|it| it.key == key) {
+            Some(group) => group.bars.push(bar),
+            None => groups.push(TimelineGroup { key, bars: vec![bar] }),
+        }
+    }
+    (groups, critical_path)
+}
+
+//关键路径是依赖链上工期之和最长的一条，按拓扑顺序计算每张卡片的最早完成时间
+//处于循环依赖中的卡片无法排序，不参与计算
+fn critical_path(durations: &[(CardId, i64)], dependencies: &[Dependency]) -> Vec<CardId> {
+    let durations_of: HashMap<&CardId, i64> = durations.iter().map(|(id, duration)| (id, *duration)).collect();
+    let edges: Vec<&Dependency> = dependencies.iter()
+        .filter(|it| durations_of.contains_key(&it.from) && durations_of.contains_key(&it.to))
+        .collect();
+    let mut in_degrees: HashMap<&CardId, usize> = durations_of.keys().map(|it| (*it, 0)).collect();
+    for edge in &edges {
+        *in_degrees.get_mut(&edge.to).unwrap() += 1;
+    }
+    let mut queue: VecDeque<&CardId> = durations.iter().map(|(id, _)| id).filter(|it| in_degrees[it] == 0).collect();
+    let mut finishes: HashMap<&CardId, i64> = HashMap::new();
+    let mut previous: HashMap<&CardId, &CardId> = HashMap::new();
+    let mut ordered = Vec::new();
+    while let Some(id) = queue.pop_front() {
+        let finish = finishes.get(id).copied().unwrap_or(0) + durations_of[id];
+        finishes.insert(id, finish);
+        ordered.push(id);
+        for edge in edges.iter().filter(|it| it.from == *id) {
+            //后继卡片的最早开始时间取所有前置卡片最早完成时间的最大值，暂存在finishes中
+            if finishes.get(&edge.to).is_none_or(|it| finish > *it) {
+                finishes.insert(&edge.to, finish);
+                previous.insert(&edge.to, id);
+            }
+            let in_degree = in_degrees.get_mut(&edge.to).unwrap();
+            *in_degree -= 1;
+            if *in_degree == 0 {
+                queue.push_back(&edge.to);
+            }
+        }
+    }
+    let mut last = None;
+    for id in ordered {
+        if last.is_none_or(|last| finishes[id] > finishes[last]) {
+            last = Some(id);
+        }
+    }
+    let mut path = Vec::new();
+    while let Some(id) = last {
+        path.push(id.clone());
+        last = previous.get(id).copied();
+    }
+    path.reverse();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definition::GroupBy;
+    use card::card::{CardState, Field};
+    use card::query::Condition;
+    use common::newtypes::tenant_id::TenantId;
+
+    fn id(id: &str) -> CardId {
+        CardId::from_str(id)
+    }
+
+    fn dependency(from: &str, to: &str) -> Dependency {
+        Dependency { from: id(from), to: id(to) }
+    }
+
+    #[test]
+    fn test_critical_path() {
+        //a(2) -> b(5) -> d(1)，a(2) -> c(3) -> d(1)，e(4)独立
+        let durations = vec![(id("a"), 2), (id("b"), 5), (id("c"), 3), (id("d"), 1), (id("e"), 4)];
+        let dependencies = vec![dependency("a", "b"), dependency("a", "c"), dependency("b", "d"), dependency("c", "d")];
+        assert_eq!(critical_path(&durations, &dependencies), vec![id("a"), id("b"), id("d")]);
+        //循环依赖中的卡片不参与计算
+        let dependencies = vec![dependency("a", "b"), dependency("b", "a")];
+        assert_eq!(critical_path(&durations, &dependencies), vec![id("e")]);
+        assert!(critical_path(&[], &[]).is_empty());
+    }
+
+    fn card(card_id: &str, start: Option<i64>, end: Option<i64>, team: &str) -> CardRecord {
+        let mut fields = vec![Field::new(FieldId::from_str("负责团队"), FieldValue::Enum(vec![String::from(team)]))];
+        if let Some(start) = start {
+            fields.push(Field::new(FieldId::from_str("计划开始时间"), FieldValue::Int(start as i32)));
+        }
+        if let Some(end) = end {
+            fields.push(Field::new(FieldId::from_str("计划完成时间"), FieldValue::Text(end.to_string())));
+        }
+        CardRecord {
+            id: id(card_id),
+            code: String::from(card_id),
+            name: String::from(card_id),
+            card_type_id: String::from("需求"),
+            org_id: TenantId::from_str("o1"),
+            state: CardState::Active,
+            flow_status: None,
+            fields,
+        }
+    }
+
+    #[test]
+    fn test_into_timeline() {
+        let timeline = TimelineViewDefinition::new(Condition::default(), FieldId::from_str("计划开始时间"), FieldId::from_str("计划完成时间"),
+                                                   Some(GroupBy::Enum(FieldId::from_str("负责团队"))), Some(String::from("前置")), vec![]);
+        let cards = vec![card("a", Some(0), Some(10), "前端"), card("b", Some(10), Some(30), "后端"), card("c", None, None, "前端"), card("x", Some(0), Some(5), "后端")];
+        let mut links = HashMap::new();
+        links.insert(id("a"), vec![id("b"), id("outside")]);
+        let dependencies = dependencies(&cards, &links);
+        assert_eq!(dependencies, vec![dependency("a", "b")]);
+        let (groups, critical_path) = into_timeline(&timeline, cards, &HashMap::new(), &dependencies);
+        assert_eq!(critical_path, vec![id("a"), id("b")]);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].key.as_deref(), Some("前端"));
+        assert_eq!(groups[0].bars.iter().map(|it| (it.start, it.end, it.critical)).collect::<Vec<_>>(), vec![(Some(0), Some(10), true), (None, None, false)]);
+        assert!(!groups[1].bars[1].critical);
+    }
+}